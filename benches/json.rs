@@ -0,0 +1,48 @@
+//! Compares `serde_json` with preroll's configured JSON serializer, on a large list response.
+//!
+//! Run with `cargo bench --bench json --features simd-json` to measure `simd-json`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Item {
+    id: u64,
+    name: String,
+    price: f64,
+    tags: Vec<&'static str>,
+    available: bool,
+}
+
+fn items(count: u64) -> Vec<Item> {
+    (0..count)
+        .map(|id| Item {
+            id,
+            name: format!("Item number {}", id),
+            price: id as f64 * 1.25,
+            tags: vec!["alpha", "beta", "gamma"],
+            available: id % 2 == 0,
+        })
+        .collect()
+}
+
+fn serialize_list(c: &mut Criterion) {
+    let items = items(10_000);
+
+    let mut group = c.benchmark_group("serialize 10k item list");
+    group.bench_function("serde_json", |b| {
+        b.iter(|| serde_json::to_vec(black_box(&items)))
+    });
+    group.bench_function(
+        if cfg!(feature = "simd-json") {
+            "preroll::json (simd-json)"
+        } else {
+            "preroll::json (serde_json)"
+        },
+        |b| b.iter(|| preroll::json::to_vec(black_box(&items))),
+    );
+    group.finish();
+}
+
+criterion_group!(benches, serialize_list);
+criterion_main!(benches);
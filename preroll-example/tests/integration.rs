@@ -50,7 +50,7 @@ async fn test_preroll_main_test_utils() {
     //     assert_json_error(
     //         &mut response,
     //         500,
-    //         "Internal Server Error (correlation_id=00000000-0000-0000-0000-000000000000)",
+    //         "Internal Server Error (correlation_id=00000000-0000-0000-0000-000000000001)",
     //     )
     //     .await;
     // }
@@ -0,0 +1,229 @@
+//! Streaming request bodies, with size and time limits.
+//!
+//! `req.body_bytes()` buffers the whole body in memory, which is not viable for large uploads.
+//! [`BodyStreamExt::body_stream`][] instead hands the body to a handler chunk by chunk, failing with a `413`
+//! once more than `max_bytes` have been read, or a `408` if reading the body takes longer than `timeout`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use preroll::body::BodyLimits;
+//! use preroll::prelude::*;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! async fn ingest(mut req: Request<()>) -> tide::Result {
+//!     let limits = BodyLimits::new().max_bytes(500 * 1024 * 1024);
+//!     let mut body = req.body_stream(limits)?;
+//!
+//!     while let Some(chunk) = body.next_chunk().await? {
+//!         // ... process `chunk` ...
+//!         # let _ = chunk;
+//!     }
+//!
+//!     Ok(format!("ingested {} bytes", body.bytes_read()).into())
+//! }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::io::ReadExt;
+use async_std::stream::Stream;
+use tide::{Body, Request, StatusCode};
+
+/// Limits on a streamed request body.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimits {
+    max_bytes: u64,
+    timeout: Duration,
+    chunk_size: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 512 * 1024 * 1024,
+            timeout: Duration::from_secs(5 * 60),
+            chunk_size: 64 * 1024,
+        }
+    }
+}
+
+impl BodyLimits {
+    /// The default limits: 512 MiB, read within 5 minutes, in chunks of up to 64 KiB.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The most bytes which may be read before failing with a `413 Payload Too Large`.
+    #[must_use]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// The longest the whole body may take to read before failing with a `408 Request Timeout`.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The largest chunk which is returned at once.
+    #[must_use]
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+}
+
+/// A request body, read chunk by chunk within [`BodyLimits`][].
+#[derive(Debug)]
+pub struct BodyStream {
+    body: Body,
+    limits: BodyLimits,
+    bytes_read: u64,
+    deadline: Instant,
+    done: bool,
+}
+
+impl BodyStream {
+    /// Stream `body` within `limits`, starting the timeout from now.
+    #[must_use]
+    pub fn new(body: Body, limits: BodyLimits) -> Self {
+        Self {
+            body,
+            limits,
+            bytes_read: 0,
+            deadline: Instant::now() + limits.timeout,
+            done: false,
+        }
+    }
+
+    /// The next chunk of the body, or `None` once it has all been read.
+    pub async fn next_chunk(&mut self) -> tide::Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let mut chunk = vec![0; self.limits.chunk_size];
+        let read = match timeout(remaining, self.body.read(&mut chunk)).await {
+            Ok(read) => read?,
+            Err(_) => {
+                self.done = true;
+                return Err(tide::Error::from_str(
+                    StatusCode::RequestTimeout,
+                    format!("Request body not received within {:?}", self.limits.timeout),
+                ));
+            }
+        };
+
+        if read == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+
+        self.bytes_read += read as u64;
+        if self.bytes_read > self.limits.max_bytes {
+            self.done = true;
+            return Err(too_large(self.limits.max_bytes));
+        }
+
+        chunk.truncate(read);
+        Ok(Some(chunk))
+    }
+
+    /// Read the rest of the body into memory.
+    pub async fn into_bytes(mut self) -> tide::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = self.next_chunk().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// The number of bytes read so far.
+    #[must_use]
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Convert into a [`Stream`][] of chunks, which ends after the first error.
+    pub fn into_stream(self) -> impl Stream<Item = tide::Result<Vec<u8>>> + Send + Unpin {
+        Box::pin(futures_lite::stream::unfold(self, |mut body| async move {
+            body.next_chunk()
+                .await
+                .transpose()
+                .map(|chunk| (chunk, body))
+        }))
+    }
+}
+
+fn too_large(max_bytes: u64) -> tide::Error {
+    tide::Error::from_str(
+        StatusCode::PayloadTooLarge,
+        format!("Request body exceeds {} bytes", max_bytes),
+    )
+}
+
+/// An extension trait for streaming the request body.
+pub trait BodyStreamExt {
+    /// Take the request body as a [`BodyStream`][].
+    ///
+    /// Fails immediately with a `413` if the request's `Content-Length` exceeds `limits`.
+    fn body_stream(&mut self, limits: BodyLimits) -> tide::Result<BodyStream>;
+}
+
+impl<State> BodyStreamExt for Request<State> {
+    fn body_stream(&mut self, limits: BodyLimits) -> tide::Result<BodyStream> {
+        if let Some(len) = self.len() {
+            if len as u64 > limits.max_bytes {
+                return Err(too_large(limits.max_bytes));
+            }
+        }
+
+        Ok(BodyStream::new(self.take_body(), limits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::stream::StreamExt;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn streams_chunks_within_limits() -> tide::Result<()> {
+        let limits = BodyLimits::new().chunk_size(4);
+        let mut body = BodyStream::new(Body::from("0123456789"), limits);
+
+        let mut chunks = Vec::new();
+        while let Some(chunk) = body.next_chunk().await? {
+            chunks.push(chunk);
+        }
+
+        assert_eq!(
+            chunks,
+            vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]
+        );
+        assert_eq!(body.bytes_read(), 10);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_bodies_over_max_bytes() {
+        let limits = BodyLimits::new().chunk_size(4).max_bytes(6);
+        let mut stream = BodyStream::new(Body::from("0123456789"), limits).into_stream();
+
+        assert!(matches!(stream.next().await, Some(Ok(_))));
+        let error = stream.next().await.and_then(Result::err);
+        assert_eq!(
+            error.map(|error| error.status()),
+            Some(StatusCode::PayloadTooLarge)
+        );
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::logging::log_kv;
+
+/// Renamed settings, as `(deprecated name, current name)`.
+///
+/// The deprecated name is still read if the current name is not set.
+const RENAMED_VARS: [(&str, &str); 2] = [
+    ("HONEYCOMBIO_WRITE_KEY", "HONEYCOMB_WRITEKEY"),
+    ("DEBUG_DOTENV", "FORCE_DOTENV"),
+];
+
+static IN_USE: Lazy<Mutex<Vec<Deprecation>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Whether startup deprecations have been logged, after which deprecations are logged as soon as they are used.
+static LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// A deprecated setting or API which this service uses, reported under `"deprecations"` in `/monitor/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Deprecation {
+    /// `"env"` for settings, or `"api"` for functions.
+    pub kind: &'static str,
+    pub name: &'static str,
+    pub replacement: &'static str,
+    pub hint: String,
+}
+
+impl Deprecation {
+    fn log(&self) {
+        log_kv!(
+            warn,
+            {
+                deprecation_kind: self.kind,
+                deprecated: self.name,
+                replacement: self.replacement,
+            },
+            "Deprecated preroll {} in use: {}", self.kind, self.hint
+        );
+    }
+}
+
+/// The deprecated name of a renamed setting.
+pub(crate) fn renamed_from(name: &str) -> Option<&'static str> {
+    RENAMED_VARS
+        .iter()
+        .find(|(_, current)| *current == name)
+        .map(|(deprecated, _)| *deprecated)
+}
+
+/// Record that the deprecated name of a renamed setting was read.
+pub(crate) fn record_renamed_var(deprecated: &'static str) {
+    if let Some((name, replacement)) = RENAMED_VARS.iter().find(|(name, _)| *name == deprecated) {
+        record(Deprecation {
+            kind: "env",
+            name,
+            replacement,
+            hint: format!("{} has been renamed, set {} instead", name, replacement),
+        });
+    }
+}
+
+/// Record the use of a deprecated preroll function, e.g. from within it.
+#[doc(hidden)]
+pub fn record_deprecated_api(name: &'static str, replacement: &'static str, hint: &str) {
+    record(Deprecation {
+        kind: "api",
+        name,
+        replacement,
+        hint: hint.to_string(),
+    });
+}
+
+/// Record a deprecation, logging it once.
+fn record(deprecation: Deprecation) {
+    let mut in_use = match IN_USE.lock() {
+        Ok(in_use) => in_use,
+        Err(_) => return,
+    };
+
+    if in_use
+        .iter()
+        .any(|existing| existing.name == deprecation.name)
+    {
+        return;
+    }
+
+    if LOGGED.load(Ordering::Relaxed) {
+        deprecation.log();
+    }
+    in_use.push(deprecation);
+}
+
+/// Check for deprecated settings, and log every deprecation recorded so far, once the logger has started.
+pub(crate) fn log_startup_deprecations() {
+    for (deprecated, _) in RENAMED_VARS.iter() {
+        if crate::config::var(deprecated).is_ok() {
+            record_renamed_var(deprecated);
+        }
+    }
+
+    if let Ok(in_use) = IN_USE.lock() {
+        for deprecation in in_use.iter() {
+            deprecation.log();
+        }
+    }
+    LOGGED.store(true, Ordering::Relaxed);
+}
+
+/// Every deprecation in use.
+pub fn deprecations() -> Vec<Deprecation> {
+    IN_USE
+        .lock()
+        .map(|in_use| in_use.clone())
+        .unwrap_or_default()
+}
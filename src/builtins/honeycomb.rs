@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::task;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, eyre, Result};
+use libhoney::transmission::Transmission;
+use libhoney::{json, Client, FieldHolder, Value};
+use sha1::{Digest, Sha1};
+use surf::Url;
+use tracing::field::{Field, Visit};
+use tracing_distributed::{Event, Span, Telemetry};
+use tracing_honeycomb::{Reporter, SpanId, TelemetryLayer, TraceId};
+
+use super::sampling::{SampleRules, Sampled, Sampler};
+use crate::config;
+use crate::logging::log_kv;
+
+/// The longest wait between background exporter setup attempts.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A honeycomb reporter which drops data until an exporter client has been connected.
+///
+/// This keeps the telemetry layer, and so trace ids, in place from startup,
+/// while the exporter itself may be set up later in the background.
+///
+/// With [sampling rules][ResilientReporter::with_sampling], traces are sampled once their outcome is known.
+#[derive(Clone, Default)]
+pub struct ResilientReporter {
+    client: Arc<Mutex<Option<Client<Transmission>>>>,
+    sampler: Arc<Mutex<Option<Sampler>>>,
+}
+
+impl Debug for ResilientReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connected = self
+            .client
+            .lock()
+            .map(|client| client.is_some())
+            .unwrap_or(false);
+
+        let sampling = self
+            .sampler
+            .lock()
+            .map(|sampler| sampler.is_some())
+            .unwrap_or(false);
+
+        f.debug_struct("ResilientReporter")
+            .field("connected", &connected)
+            .field("sampling", &sampling)
+            .finish()
+    }
+}
+
+impl ResilientReporter {
+    /// Sample traces by the response status and request path of their root span, rather than sending all of them.
+    #[must_use]
+    pub fn with_sampling(self, rules: SampleRules) -> Self {
+        self.set_sampling(Some(rules));
+        self
+    }
+
+    /// Replace the sampling rules, e.g. when configuration is reloaded, or send every trace with `None`.
+    ///
+    /// Traces buffered for sampling are dropped when sampling is turned off.
+    pub fn set_sampling(&self, rules: Option<SampleRules>) {
+        if let Ok(mut sampler) = self.sampler.lock() {
+            match (sampler.as_mut(), rules) {
+                (Some(sampler), Some(rules)) => sampler.set_rules(rules),
+                (_, rules) => *sampler = rules.map(Sampler::new),
+            }
+        }
+    }
+
+    fn connect(&self, client: Client<Transmission>) {
+        if let Ok(mut current) = self.client.lock() {
+            *current = Some(client);
+        }
+    }
+
+    fn send(&self, batch: Vec<Sampled>) {
+        let mut guard = match self.client.lock() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+
+        if let Some(client) = guard.as_mut() {
+            for sampled in batch {
+                let mut event = client.new_event();
+                event.add(sampled.data);
+                event.set_timestamp(sampled.timestamp);
+                // Already sampled, so only the rate is recorded, for honeycomb to weight counts by.
+                event.set_sample_rate(sampled.rate as usize);
+                // Not logged via `log`, which may be forwarded back into tracing while the client is locked.
+                if let Err(error) = event.send_presampled(client) {
+                    eprintln!("Error sending event to honeycomb: {:?}", error);
+                }
+            }
+        }
+    }
+}
+
+impl Reporter for ResilientReporter {
+    fn report_data(&self, data: HashMap<String, Value>, timestamp: DateTime<Utc>) {
+        let batch = match self.sampler.lock() {
+            Ok(mut sampler) => match sampler.as_mut() {
+                Some(sampler) => sampler.sample(data, timestamp),
+                None => vec![Sampled {
+                    data,
+                    timestamp,
+                    rate: 1,
+                }],
+            },
+            Err(_) => return,
+        };
+
+        if !batch.is_empty() {
+            self.send(batch);
+        }
+    }
+}
+
+/// Build a honeycomb telemetry layer which reports to `reporter`, such as a [`ResilientReporter`][].
+///
+/// tracing-honeycomb's own `Builder` can only report to stdout, or to a libhoney client which it connects itself.
+///
+/// Whole traces are kept 1 in `sample_rate` times, by their trace id, as beelines do.
+pub fn telemetry_layer<R: Reporter>(
+    service_name: &'static str,
+    reporter: R,
+    sample_rate: HeadSampleRate,
+) -> TelemetryLayer<ReporterTelemetry<R>, SpanId, TraceId> {
+    TelemetryLayer::new(
+        service_name,
+        ReporterTelemetry {
+            reporter,
+            sample_rate,
+        },
+        |tracing_id: tracing::span::Id| {
+            // A `SpanId` can only be built from outside tracing-honeycomb by parsing its hex form.
+            format!("{:x}", tracing_id.into_u64())
+                .parse()
+                .expect("tracing span ids are non-zero")
+        },
+    )
+}
+
+/// The rate at which [`telemetry_layer`][] keeps whole traces, which can be changed while it is in use.
+#[derive(Clone, Debug, Default)]
+pub struct HeadSampleRate(Arc<AtomicU32>);
+
+impl HeadSampleRate {
+    /// Keep 1 in `sample_rate` traces, or every trace with `None`.
+    #[must_use]
+    pub fn new(sample_rate: Option<u32>) -> Self {
+        let head_sample_rate = Self::default();
+        head_sample_rate.set(sample_rate);
+        head_sample_rate
+    }
+
+    /// Replace the sample rate, e.g. when configuration is reloaded.
+    pub fn set(&self, sample_rate: Option<u32>) {
+        self.0.store(sample_rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Option<u32> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            sample_rate => Some(sample_rate),
+        }
+    }
+}
+
+/// Re-read `HONEYCOMB_SAMPLE_RULES` and `HONEYCOMB_SAMPLE_RATE` into the `reporter` and `sample_rate`
+/// of a [`telemetry_layer`][].
+///
+/// With sampling rules, the reporter samples whole traces once their outcome is known, and every trace is reported to it.
+pub fn reload_sampling(reporter: &ResilientReporter, sample_rate: &HeadSampleRate) -> Result<()> {
+    let sample_rules = SampleRules::from_config()?;
+    sample_rate.set(match sample_rules {
+        Some(_) => None,
+        None => config::var("HONEYCOMB_SAMPLE_RATE")
+            .ok()
+            .and_then(|rate| rate.parse().ok()),
+    });
+    reporter.set_sampling(sample_rules);
+    Ok(())
+}
+
+/// Telemetry which reports honeycomb spans and events to a [`Reporter`][].
+#[derive(Debug)]
+pub struct ReporterTelemetry<R> {
+    reporter: R,
+    sample_rate: HeadSampleRate,
+}
+
+impl<R: Reporter> ReporterTelemetry<R> {
+    fn should_report(&self, trace_id: &TraceId) -> bool {
+        match self.sample_rate.get() {
+            Some(sample_rate) => keep_trace(sample_rate, trace_id),
+            None => true,
+        }
+    }
+}
+
+impl<R: Reporter> Telemetry for ReporterTelemetry<R> {
+    type Visitor = FieldVisitor;
+    type TraceId = TraceId;
+    type SpanId = SpanId;
+
+    fn mk_visitor(&self) -> Self::Visitor {
+        FieldVisitor::default()
+    }
+
+    fn report_span(&self, span: Span<Self::Visitor, Self::SpanId, Self::TraceId>) {
+        if !self.should_report(&span.trace_id) {
+            return;
+        }
+
+        let mut data = span.values.0;
+        data.insert("trace.span_id".to_string(), json!(span.id.to_string()));
+        insert_trace_fields(
+            &mut data,
+            &span.trace_id,
+            span.parent_id.as_ref(),
+            span.service_name,
+            span.meta,
+        );
+        if let Ok(duration) = span.completed_at.duration_since(span.initialized_at) {
+            data.insert(
+                "duration_ms".to_string(),
+                json!(duration.as_secs_f64() * 1000.0),
+            );
+        }
+
+        self.reporter.report_data(data, span.initialized_at.into());
+    }
+
+    fn report_event(&self, event: Event<Self::Visitor, Self::SpanId, Self::TraceId>) {
+        if !self.should_report(&event.trace_id) {
+            return;
+        }
+
+        let mut data = event.values.0;
+        insert_trace_fields(
+            &mut data,
+            &event.trace_id,
+            event.parent_id.as_ref(),
+            event.service_name,
+            event.meta,
+        );
+
+        self.reporter.report_data(data, event.initialized_at.into());
+    }
+}
+
+/// The fields which honeycomb expects of every span and event.
+fn insert_trace_fields(
+    data: &mut HashMap<String, Value>,
+    trace_id: &TraceId,
+    parent_id: Option<&SpanId>,
+    service_name: &str,
+    meta: &tracing::Metadata<'_>,
+) {
+    data.insert("trace.trace_id".to_string(), json!(trace_id.to_string()));
+    data.insert(
+        "trace.parent_id".to_string(),
+        parent_id.map_or(Value::Null, |parent_id| json!(parent_id.to_string())),
+    );
+    data.insert("service_name".to_string(), json!(service_name));
+    data.insert("level".to_string(), json!(meta.level().to_string()));
+    data.insert("name".to_string(), json!(meta.name()));
+    data.insert("target".to_string(), json!(meta.target()));
+}
+
+/// Whether to keep a trace sampled at `sample_rate`, compatibly with beelines' deterministic sampler.
+fn keep_trace(sample_rate: u32, trace_id: &TraceId) -> bool {
+    let sum = Sha1::digest(trace_id.as_ref());
+    let upper_bound = u32::MAX / sample_rate.max(1);
+    u32::from_be_bytes([sum[0], sum[1], sum[2], sum[3]]) <= upper_bound
+}
+
+/// Records span and event fields as honeycomb values.
+#[derive(Debug, Default)]
+pub struct FieldVisitor(HashMap<String, Value>);
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Fields which honeycomb or preroll set on every span are renamed, rather than overwritten.
+        let name = match field.name() {
+            name @ ("trace.span_id" | "trace.trace_id" | "trace.parent_id" | "service_name"
+            | "level" | "Timestamp" | "name" | "target" | "duration_ms") => {
+                format!("tracing.{}", name)
+            }
+            name => name.to_string(),
+        };
+        self.0.insert(name, value);
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.insert(field, json!(format!("{:?}", value)));
+    }
+}
+
+/// The settings needed to connect a honeycomb exporter.
+#[derive(Clone, Debug)]
+pub struct HoneycombExporter {
+    pub api_key: String,
+    pub api_host: String,
+    pub dataset: String,
+}
+
+impl HoneycombExporter {
+    fn libhoney_config(&self) -> libhoney::Config {
+        libhoney::Config {
+            options: libhoney::client::Options {
+                api_key: self.api_key.clone(),
+                api_host: self.api_host.clone(),
+                dataset: self.dataset.clone(),
+                ..libhoney::client::Options::default()
+            },
+            transmission_options: libhoney::transmission::Options::default(),
+        }
+    }
+
+    /// Connect the exporter immediately, without checking that honeycomb is reachable.
+    pub fn connect(&self, reporter: &ResilientReporter) {
+        reporter.connect(libhoney::init(self.libhoney_config()));
+    }
+
+    /// Check the write key against honeycomb, then initialize libhoney.
+    async fn try_connect(&self) -> Result<Client<Transmission>> {
+        let auth_url = Url::parse(&self.api_host)?.join("1/auth")?;
+        let res = surf::get(auth_url)
+            .header("X-Honeycomb-Team", self.api_key.as_str())
+            .await
+            .map_err(|error| eyre!(error.into_inner()))?;
+
+        if !res.status().is_success() {
+            bail!("honeycomb auth check returned {}", res.status());
+        }
+
+        let config = self.libhoney_config();
+        panic::catch_unwind(AssertUnwindSafe(|| libhoney::init(config)))
+            .map_err(|_| eyre!("libhoney initialization panicked"))
+    }
+
+    /// Connect the exporter in the background, retrying with backoff until it succeeds.
+    ///
+    /// Until then, telemetry is dropped, but requests are served as normal.
+    pub fn spawn_connect(self, reporter: ResilientReporter) {
+        task::spawn(async move {
+            let mut retry_interval = Duration::from_secs(1);
+            loop {
+                match self.try_connect().await {
+                    Ok(client) => {
+                        reporter.connect(client);
+                        log::info!("Honeycomb exporter connected - dataset: {}", self.dataset);
+                        return;
+                    }
+                    Err(error) => {
+                        log_kv!(
+                            warn,
+                            {
+                                honeycomb_api_host: self.api_host,
+                                retry_ms: retry_interval.as_millis() as u64,
+                            },
+                            "Honeycomb exporter setup failed, telemetry is disabled until it succeeds: {}", error
+                        );
+                    }
+                }
+
+                task::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    use tracing_subscriber::prelude::*;
+
+    #[derive(Clone, Default)]
+    struct Collected(Arc<Mutex<Vec<Reported>>>);
+
+    type Reported = (HashMap<String, Value>, DateTime<Utc>);
+
+    impl Reporter for Collected {
+        fn report_data(&self, data: HashMap<String, Value>, timestamp: DateTime<Utc>) {
+            if let Ok(mut collected) = self.0.lock() {
+                collected.push((data, timestamp));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_spans_and_events() {
+        let collected = Collected::default();
+        let subscriber = tracing_subscriber::registry().with(telemetry_layer(
+            "preroll-test",
+            collected.clone(),
+            HeadSampleRate::default(),
+        ));
+
+        let started = Utc::now();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", duration_ms = 1.5_f64);
+            let _entered = span.enter();
+            tracing_honeycomb::register_dist_tracing_root(TraceId::new(), None)
+                .expect("a root span can be registered");
+            tracing::info!(status = 200_u64, "responded");
+        });
+
+        let collected = collected.0.lock().expect("collected reports");
+        assert_eq!(collected.len(), 2);
+
+        let (event, timestamp) = &collected[0];
+        assert_eq!(event["level"], json!("INFO"));
+        assert_eq!(event["target"], json!(module_path!()));
+        assert_eq!(event["status"], json!(200));
+        assert_eq!(event["service_name"], json!("preroll-test"));
+        assert!(*timestamp >= started);
+
+        let (span, _) = &collected[1];
+        assert_eq!(span["name"], json!("request"));
+        assert_eq!(span["trace.trace_id"], event["trace.trace_id"]);
+        assert_eq!(span["trace.span_id"], event["trace.parent_id"]);
+        assert_eq!(span["tracing.duration_ms"], json!(1.5));
+        assert!(span["duration_ms"].is_number());
+    }
+
+    #[test]
+    fn samples_whole_traces() {
+        let trace_id = TraceId::new();
+        assert!(keep_trace(1, &trace_id));
+        let kept = (0..1000)
+            .filter(|_| keep_trace(10, &TraceId::new()))
+            .count();
+        assert!((50..200).contains(&kept), "kept {} of 1000", kept);
+        assert_eq!(keep_trace(10, &trace_id), keep_trace(10, &trace_id));
+    }
+
+    #[test]
+    fn reloads_sampling() -> Result<()> {
+        let reporter = ResilientReporter::default();
+        let sample_rate = HeadSampleRate::new(Some(10));
+        let previous = Config::current();
+
+        previous.clone().set("HONEYCOMB_SAMPLE_RATE", 20).install();
+        reload_sampling(&reporter, &sample_rate)?;
+        assert_eq!(sample_rate.get(), Some(20));
+        assert!(format!("{:?}", reporter).contains("sampling: false"));
+
+        previous
+            .clone()
+            .set("HONEYCOMB_SAMPLE_RATE", 20)
+            .set("HONEYCOMB_SAMPLE_RULES", "/api/v1/health=100")
+            .install();
+        reload_sampling(&reporter, &sample_rate)?;
+        assert_eq!(sample_rate.get(), None);
+        assert!(format!("{:?}", reporter).contains("sampling: true"));
+
+        previous.install();
+        reload_sampling(&reporter, &sample_rate)?;
+        assert_eq!(sample_rate.get(), None);
+        assert!(format!("{:?}", reporter).contains("sampling: false"));
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use chrono::{TimeZone, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::builtins::startup::enabled_features;
+use crate::config;
+
+static SERVICE_BUILD: OnceCell<ServiceBuild> = OnceCell::new();
+
+/// Build information from the service's own crate, captured by `preroll::main!`.
+#[derive(Debug)]
+struct ServiceBuild {
+    name: &'static str,
+    version: &'static str,
+    git_commit: Option<&'static str>,
+}
+
+/// Record the service crate's build information. Called by `preroll::main!`.
+#[doc(hidden)]
+pub fn set_service_build(
+    name: &'static str,
+    version: &'static str,
+    git_commit: Option<&'static str>,
+) {
+    SERVICE_BUILD
+        .set(ServiceBuild {
+            name,
+            version,
+            git_commit,
+        })
+        .ok();
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    crate_name: Option<&'static str>,
+    version: Option<&'static str>,
+    preroll_version: &'static str,
+    git_commit: Option<String>,
+    build_timestamp: Option<String>,
+    rustc: &'static str,
+    features: Vec<&'static str>,
+}
+
+/// The build information reported by `/monitor/info`.
+///
+/// `git_commit` is `GIT_COMMIT` at compile time if it was set, otherwise at runtime.
+pub fn build_info() -> BuildInfo {
+    let service = SERVICE_BUILD.get();
+
+    BuildInfo {
+        crate_name: service.map(|build| build.name),
+        version: service.map(|build| build.version),
+        preroll_version: env!("CARGO_PKG_VERSION"),
+        git_commit: service
+            .and_then(|build| build.git_commit)
+            .map(str::to_string)
+            .or_else(|| config::var("GIT_COMMIT").ok()),
+        build_timestamp: env!("PREROLL_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .map(|time| time.to_rfc3339()),
+        rustc: env!("PREROLL_RUSTC_VERSION"),
+        features: enabled_features(),
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{Result, WrapErr};
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgPool;
+
+use crate::builtins::schema::MIGRATION_LOCK_KEY;
+use crate::builtins::startup::record_startup;
+use crate::config;
+
+/// Load the migrations in `MIGRATIONS_DIR` (default `./migrations`).
+pub(crate) async fn migrator() -> Result<(PathBuf, Migrator)> {
+    let dir =
+        PathBuf::from(config::var("MIGRATIONS_DIR").unwrap_or_else(|_| "./migrations".to_string()));
+    let migrator = Migrator::new(dir.as_path())
+        .await
+        .wrap_err_with(|| format!("Unable to read migrations from {}", dir.display()))?;
+    Ok((dir, migrator))
+}
+
+/// If `RUN_MIGRATIONS=true`, run the migrations in `MIGRATIONS_DIR` (default `./migrations`).
+///
+/// Other instances wait for the lock, and then find the migrations already applied.
+pub(crate) async fn run_if_enabled(pool: &PgPool) -> Result<()> {
+    let enabled = config::var("RUN_MIGRATIONS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(());
+    }
+
+    let (dir, migrator) = migrator().await?;
+
+    let mut conn = crate::builtins::pool_stats::acquire(pool).await?;
+
+    log::info!("Waiting for the migration lock");
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+
+    let result = migrator.run(&mut conn).await;
+
+    // Advisory locks are held by the session, so must be released before the connection returns to the pool.
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+
+    result.wrap_err_with(|| format!("Migrations from {} failed", dir.display()))?;
+
+    let count = migrator.iter().count();
+    log::info!(
+        "Migrations applied from {} ({} total)",
+        dir.display(),
+        count
+    );
+    record_startup(|summary| {
+        summary.migrations = Some(format!("{} ({} total)", dir.display(), count));
+    });
+
+    Ok(())
+}
@@ -1 +1,22 @@
+pub mod deprecations;
+pub mod info;
 pub mod monitor;
+pub mod relay;
+pub mod reload;
+pub mod scheduling;
+pub mod shutdown;
+pub mod startup;
+pub mod stats;
+
+#[cfg(all(feature = "honeycomb", not(feature = "lambda-http")))]
+pub mod honeycomb;
+#[cfg(all(feature = "honeycomb", not(feature = "lambda-http")))]
+pub mod sampling;
+
+#[cfg(feature = "postgres-migrate")]
+pub mod migrate;
+
+#[cfg(feature = "postgres")]
+pub mod pool_stats;
+#[cfg(feature = "postgres")]
+pub mod schema;
@@ -1,12 +1,23 @@
-use std::env;
-use std::sync::Arc;
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use once_cell::sync::OnceCell;
-use serde::Serialize;
-use tide::{Body, Server};
+use async_std::future::timeout;
+use async_std::sync::Mutex;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tide::{Body, Request, Server, StatusCode};
 
-use crate::utils::HOSTNAME;
+use crate::builtins::deprecations::{deprecations, Deprecation};
+use crate::builtins::info::build_info;
+use crate::builtins::reload::{reload, reload_setting};
+use crate::builtins::stats::{stats_snapshot, ScopedStats, StatsSnapshot};
+use crate::config::{self, Config};
+use crate::logging::LogLevel;
+use crate::utils::{self, HOSTNAME};
 
 static SERVICE_NAME: OnceCell<&'static str> = OnceCell::new();
 static START_TIME: OnceCell<Instant> = OnceCell::new();
@@ -28,32 +39,371 @@ where
         .at("/monitor/ping")
         .get(|_| async { Ok(PING_RESPONSE.as_str()) });
 
-    server.at("/monitor/status").get(|_| async {
-        let status = Status {
-            git: env::var("GIT_COMMIT")
-                .unwrap_or_else(|_| "No GIT_COMMIT environment variable.".to_string()),
-            hostname: &*HOSTNAME,
-            service: *SERVICE_NAME
-                .get()
-                .unwrap_or(&"service name not initialized"),
-            uptime: START_TIME
-                .get()
-                .map(|start| start.elapsed().as_secs_f64())
-                .unwrap_or(f64::NEG_INFINITY),
-            ping: PING_RESPONSE.to_string(),
-        };
-
-        Body::from_json(&status)
-    });
+    server
+        .at("/monitor/status")
+        .get(|req: Request<Arc<State>>| async move {
+            let downstream = HEALTH_CHECKS.run().await;
+
+            let status = Status {
+                git: config::var("GIT_COMMIT")
+                    .unwrap_or_else(|_| "No GIT_COMMIT environment variable.".to_string()),
+                hostname: &*HOSTNAME,
+                service: *SERVICE_NAME
+                    .get()
+                    .unwrap_or(&"service name not initialized"),
+                uptime: START_TIME
+                    .get()
+                    .map(|start| start.elapsed().as_secs_f64())
+                    .unwrap_or(f64::NEG_INFINITY),
+                ping: PING_RESPONSE.to_string(),
+                downstream: &downstream,
+                stats: stats_snapshot(req.ext::<ScopedStats>()),
+                deprecations: deprecations(),
+                api_versions: crate::versions::version_stats(),
+                cache: crate::cache::cache_stats(),
+                upstreams: crate::client::registry::upstream_stats(),
+                concurrency: crate::middleware::concurrency::concurrency_stats(),
+                shedding: crate::middleware::priority::shedding_stats(),
+                scheduling: crate::builtins::scheduling::scheduling_stats(),
+                warmers: crate::warmers::warmer_stats(),
+                webhooks: crate::webhooks::webhook_stats(),
+                #[cfg(feature = "pg-jobs")]
+                jobs: crate::jobs::job_stats(),
+                #[cfg(feature = "postgres")]
+                leaders: crate::leader::leader_stats(),
+                #[cfg(feature = "postgres")]
+                postgres_pool: crate::builtins::pool_stats::pool_stats(),
+            };
+
+            Body::from_json(&status)
+        });
+
+    server
+        .at("/monitor/info")
+        .get(|_| async { Body::from_json(&build_info()) });
+
+    server
+        .at("/monitor/slo")
+        .get(|_| async { Body::from_json(&crate::slo::reports()) });
+
+    server
+        .at("/monitor/reload")
+        .post(|req: Request<Arc<State>>| async move {
+            authorize(&req)?;
+            match reload().await {
+                Ok(reloaded) => Body::from_json(&json!({ "reloaded": reloaded })),
+                Err(error) => Err(tide::Error::from_str(500, format!("{}", error))),
+            }
+        });
+
+    server
+        .at("/monitor/loglevel")
+        .get(|_| async { log_level_body() })
+        .put(set_log_level);
+}
+
+/// Require `MONITOR_TOKEN` as a bearer token, e.g. for routes which change the service's behavior.
+///
+/// Without `MONITOR_TOKEN`, these routes are open in development, but refused if `ENVIRONMENT` starts with `prod`.
+fn authorize<State>(req: &Request<State>) -> tide::Result<()> {
+    let token = match config::var("MONITOR_TOKEN") {
+        Ok(token) => token,
+        Err(_) => {
+            let production = config::var("ENVIRONMENT")
+                .map(|environment| environment.starts_with("prod"))
+                .unwrap_or(false);
+            if production {
+                return Err(tide::Error::from_str(
+                    StatusCode::Forbidden,
+                    "MONITOR_TOKEN must be set to use this route in production",
+                ));
+            }
+            return Ok(());
+        }
+    };
+
+    let authorized = req
+        .header("Authorization")
+        .and_then(|header| header.last().as_str().strip_prefix("Bearer "))
+        .map(|bearer| utils::constant_time_eq(bearer.as_bytes(), token.as_bytes()))
+        .unwrap_or(false);
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(tide::Error::from_str(
+            StatusCode::Unauthorized,
+            "A valid MONITOR_TOKEN bearer token is required",
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct LogLevelUpdate {
+    loglevel: String,
+}
+
+fn log_level_body() -> tide::Result<Body> {
+    let log_level = LogLevel::from_config(log::LevelFilter::Info)?;
+    Body::from_json(&json!({ "loglevel": log_level.to_string() }))
+}
+
+/// Replace the active `LOGLEVEL` until the process exits, or it is set again.
+async fn set_log_level<State>(mut req: Request<Arc<State>>) -> tide::Result<Body>
+where
+    State: Send + Sync + 'static,
+{
+    authorize(&req)?;
+
+    let update: LogLevelUpdate = req.body_json().await?;
+    let log_level: LogLevel = update
+        .loglevel
+        .parse()
+        .map_err(|error| tide::Error::from_str(StatusCode::BadRequest, format!("{}", error)))?;
+
+    let previous = Config::current();
+    previous
+        .clone()
+        .set("LOGLEVEL", log_level.to_string())
+        .install();
+
+    if let Err(error) = reload_setting("LOGLEVEL") {
+        previous.install();
+        return Err(tide::Error::from_str(500, format!("{}", error)));
+    }
+
+    log::warn!(
+        "Log level changed to \"{}\" via /monitor/loglevel",
+        log_level
+    );
+
+    log_level_body()
 }
 
 #[derive(Serialize)]
-struct Status<'host> {
+struct Status<'a> {
     git: String,
-    hostname: &'host str,
+    hostname: &'a str,
     service: &'static str,
     uptime: f64,
     ping: String,
+    downstream: &'a Downstream,
+    stats: StatsSnapshot,
+    deprecations: Vec<Deprecation>,
+    #[serde(rename = "apiVersions", skip_serializing_if = "Option::is_none")]
+    api_versions: Option<BTreeMap<String, crate::versions::VersionStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<crate::cache::CacheStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstreams: Option<BTreeMap<&'static str, crate::client::registry::UpstreamStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    concurrency: Option<BTreeMap<&'static str, crate::middleware::concurrency::ConcurrencyStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shedding: Option<crate::middleware::priority::SheddingStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduling: Option<crate::builtins::scheduling::SchedulingStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmers: Option<BTreeMap<&'static str, crate::warmers::WarmerStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhooks: Option<crate::webhooks::WebhookStats>,
+    #[cfg(feature = "pg-jobs")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jobs: Option<crate::jobs::JobStats>,
+    #[cfg(feature = "postgres")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leaders: Option<BTreeMap<&'static str, crate::leader::LeaderStats>>,
+    #[cfg(feature = "postgres")]
+    #[serde(rename = "postgresPool", skip_serializing_if = "Option::is_none")]
+    postgres_pool: Option<crate::builtins::pool_stats::PoolStats>,
+}
+
+type HealthCheckFn =
+    dyn Fn() -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync;
+
+type Downstream = BTreeMap<&'static str, HealthStatus>;
+
+#[derive(Clone, Debug, Serialize)]
+struct HealthStatus {
+    error: Option<String>,
+    latency: f64,
+    status: &'static str,
+}
+
+static HEALTH_CHECKS: Lazy<HealthCheckRunner> = Lazy::new(HealthCheckRunner::new);
+
+/// Runs registered downstream health checks for `/monitor/status`.
+///
+/// Concurrent callers are coalesced into a single run (single-flight), and results are cached for
+/// `HEALTH_CHECK_CACHE_MS` (default `1000`), so that probe storms do not stampede downstream dependencies.
+struct HealthCheckRunner {
+    checks: RwLock<Vec<(&'static str, Arc<HealthCheckFn>)>>,
+    last_run: Mutex<Option<(Instant, Arc<Downstream>)>>,
+    cache_ttl: Duration,
+    check_timeout: Duration,
+}
+
+impl HealthCheckRunner {
+    fn new() -> Self {
+        let cache_ttl = config::var("HEALTH_CHECK_CACHE_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000);
+        let check_timeout = config::var("HEALTH_CHECK_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5000);
+
+        Self {
+            checks: RwLock::new(Vec::new()),
+            last_run: Mutex::new(None),
+            cache_ttl: Duration::from_millis(cache_ttl),
+            check_timeout: Duration::from_millis(check_timeout),
+        }
+    }
+
+    fn register(&self, name: &'static str, check: Arc<HealthCheckFn>) {
+        let mut checks = self.checks.write().expect("health check registry poisoned");
+        checks.retain(|(existing, _)| *existing != name);
+        checks.push((name, check));
+    }
+
+    async fn run(&self) -> Arc<Downstream> {
+        // Holding the lock for the duration of the run is what makes this single-flight:
+        // any callers which arrive during a run wait for it, and then hit the fresh cache.
+        let mut last_run = self.last_run.lock().await;
+
+        if let Some((ran_at, downstream)) = &*last_run {
+            if ran_at.elapsed() < self.cache_ttl {
+                return downstream.clone();
+            }
+        }
+
+        let downstream = Arc::new(self.run_uncached().await);
+        *last_run = Some((Instant::now(), downstream.clone()));
+        downstream
+    }
+
+    /// Run every check concurrently, ignoring the cache.
+    async fn run_uncached(&self) -> Downstream {
+        let checks = self
+            .checks
+            .read()
+            .expect("health check registry poisoned")
+            .clone();
+
+        let handles: Vec<_> = checks
+            .into_iter()
+            .map(|(name, check)| {
+                let check_timeout = self.check_timeout;
+                let handle = async_std::task::spawn(async move {
+                    let start = Instant::now();
+                    let result = timeout(check_timeout, check()).await;
+                    let latency = start.elapsed().as_secs_f64() * 1000.0;
+
+                    let error = match result {
+                        Ok(Ok(())) => None,
+                        Ok(Err(error)) => Some(format!("{:?}", error)),
+                        Err(_) => Some(format!("Timed out after {:?}", check_timeout)),
+                    };
+
+                    if let Some(error) = &error {
+                        log::warn!("Health check \"{}\" failed: {}", name, error);
+                    }
+
+                    HealthStatus {
+                        status: if error.is_none() {
+                            "healthy"
+                        } else {
+                            "unhealthy"
+                        },
+                        error,
+                        latency,
+                    }
+                });
+                (name, handle)
+            })
+            .collect();
+
+        let mut downstream = Downstream::new();
+        for (name, handle) in handles {
+            downstream.insert(name, handle.await);
+        }
+
+        downstream
+    }
+}
+
+/// Register a downstream dependency health check, reported under `"downstream"` in `/monitor/status`.
+///
+/// Checks are run concurrently, at most once per `HEALTH_CHECK_CACHE_MS` (default `1000`) regardless of
+/// how many requests are made to `/monitor/status`, and each is limited to `HEALTH_CHECK_TIMEOUT_MS` (default `5000`).
+///
+/// Registering a check with the same name as an existing check replaces it.
+///
+/// ## Example:
+///
+/// ```
+/// preroll::register_health_check("exampleReachability", || async {
+///     // e.g. make a request to a downstream service.
+///     Ok(())
+/// });
+/// ```
+pub fn register_health_check<CheckFn, CheckFut>(name: &'static str, check: CheckFn)
+where
+    CheckFn: Fn() -> CheckFut + Send + Sync + 'static,
+    CheckFut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    HEALTH_CHECKS.register(name, Arc::new(move || Box::pin(check())));
+}
+
+/// If `STARTUP_CHECKS` is enabled, wait until every registered health check passes, before the listener binds.
+///
+/// Failing checks are retried with backoff, for at most `STARTUP_CHECK_MAX_WAIT_MS` (default `30000`),
+/// after which setup fails with the errors of the checks which are still failing.
+pub(crate) async fn check_dependencies_at_startup() -> color_eyre::eyre::Result<()> {
+    let enabled = config::var("STARTUP_CHECKS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(());
+    }
+
+    let max_wait: u64 = config::var("STARTUP_CHECK_MAX_WAIT_MS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(30_000))?;
+    let max_wait = Duration::from_millis(max_wait);
+
+    let start = Instant::now();
+    let mut retry_interval = Duration::from_millis(250);
+    loop {
+        let failures: Vec<String> = HEALTH_CHECKS
+            .run_uncached()
+            .await
+            .into_iter()
+            .filter_map(|(name, status)| status.error.map(|error| format!("{}: {}", name, error)))
+            .collect();
+
+        if failures.is_empty() {
+            log::info!("Startup dependency checks passed in {:?}", start.elapsed());
+            return Ok(());
+        }
+
+        if start.elapsed() + retry_interval > max_wait {
+            return Err(color_eyre::eyre::eyre!(
+                "Startup dependency checks still failing after {:?}:\n  - {}",
+                start.elapsed(),
+                failures.join("\n  - ")
+            ));
+        }
+
+        log::warn!(
+            "Startup dependency checks failed, retrying in {:?}: {}",
+            retry_interval,
+            failures.join(", ")
+        );
+        async_std::task::sleep(retry_interval).await;
+        retry_interval = (retry_interval * 2).min(Duration::from_secs(5));
+    }
 }
 
 // TODO(Jeremiah):
@@ -61,28 +411,7 @@ struct Status<'host> {
 // Add more status fields, similar to Boltzmann.js:
 //
 // {
-//     "downstream": {
-//         "postgresReachability": {
-//             "error": null,
-//             "latency": 2,
-//             "status": "healthy"
-//         },
-//         "redisReachability": {
-//             "error": null,
-//             "latency": 2,
-//             "status": "healthy"
-//         }
-//     },
 //     "memory": {
 //         "rss": 87212032
 //     },
-//     "stats": {
-//         "requestCount": 63425,
-//         "statuses": {
-//             "200": 50024,
-//             "202": 7963,
-//             "204": 5404,
-//             "500": 34
-//         }
-//     },
 // }
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgPool, Postgres};
+
+use crate::config;
+use crate::logging::log_kv;
+
+static POOL: OnceCell<(PgPool, u32)> = OnceCell::new();
+static ACQUIRE_WAITS: Lazy<AcquireWaits> = Lazy::new(AcquireWaits::default);
+
+/// The `PG_ACQUIRE_WARN_MS` threshold, above which a connection acquisition is logged at `WARN`.
+static ACQUIRE_WARN_THRESHOLD: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_millis(
+        config::var("PG_ACQUIRE_WARN_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000),
+    )
+});
+
+#[derive(Debug, Default)]
+struct AcquireWaits {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+    last_micros: AtomicU64,
+}
+
+/// Postgres connection pool statistics, reported under `"postgresPool"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStats {
+    size: u32,
+    idle: usize,
+    in_use: u32,
+    max_connections: u32,
+    acquire_count: u64,
+    acquire_wait_ms_last: f64,
+    acquire_wait_ms_max: f64,
+    acquire_wait_ms_mean: f64,
+}
+
+/// Set the pool which is reported in `/monitor/status`.
+pub fn register(pool: &PgPool, max_connections: u32) {
+    POOL.set((pool.clone(), max_connections)).ok();
+}
+
+/// The pool registered during setup, if any.
+pub fn pool() -> Option<PgPool> {
+    POOL.get().map(|(pool, _)| pool.clone())
+}
+
+/// Acquire a connection, recording how long was spent waiting for it.
+pub async fn acquire(pool: &PgPool) -> sqlx::Result<PoolConnection<Postgres>> {
+    let start = Instant::now();
+    let conn = pool.acquire().await;
+    record_acquire(start.elapsed());
+    conn
+}
+
+fn record_acquire(elapsed: Duration) {
+    let micros = elapsed.as_micros() as u64;
+    ACQUIRE_WAITS.count.fetch_add(1, Ordering::Relaxed);
+    ACQUIRE_WAITS
+        .total_micros
+        .fetch_add(micros, Ordering::Relaxed);
+    ACQUIRE_WAITS
+        .max_micros
+        .fetch_max(micros, Ordering::Relaxed);
+    ACQUIRE_WAITS.last_micros.store(micros, Ordering::Relaxed);
+
+    if elapsed > *ACQUIRE_WARN_THRESHOLD {
+        let (size, idle) = POOL
+            .get()
+            .map(|(pool, _)| (pool.size(), pool.num_idle()))
+            .unwrap_or_default();
+        log_kv!(
+            warn,
+            {
+                elapsed: format!("{:?}", elapsed),
+                pool_size: size,
+                pool_idle: idle,
+            },
+            "Slow postgres connection acquisition"
+        );
+    }
+}
+
+/// The registered pool's current statistics, if there is a registered pool.
+pub fn pool_stats() -> Option<PoolStats> {
+    let (pool, max_connections) = POOL.get()?;
+    let size = pool.size();
+    let idle = pool.num_idle();
+
+    let count = ACQUIRE_WAITS.count.load(Ordering::Relaxed);
+    let total_micros = ACQUIRE_WAITS.total_micros.load(Ordering::Relaxed);
+
+    Some(PoolStats {
+        size,
+        idle,
+        in_use: size.saturating_sub(idle as u32),
+        max_connections: *max_connections,
+        acquire_count: count,
+        acquire_wait_ms_last: micros_to_ms(ACQUIRE_WAITS.last_micros.load(Ordering::Relaxed)),
+        acquire_wait_ms_max: micros_to_ms(ACQUIRE_WAITS.max_micros.load(Ordering::Relaxed)),
+        acquire_wait_ms_mean: if count == 0 {
+            0.0
+        } else {
+            micros_to_ms(total_micros) / count as f64
+        },
+    })
+}
+
+fn micros_to_ms(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
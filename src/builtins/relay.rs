@@ -0,0 +1,63 @@
+//! The background relays of preroll's queues: the outbox, notifications, jobs, and webhook deliveries.
+//!
+//! A relay polls its queue until the server is [shut down][crate::setup::shutdown_handle]. Each poll claims a batch
+//! of due rows with `FOR UPDATE SKIP LOCKED`, so that they are locked until the poll's transaction commits,
+//! and concurrent relays, e.g. of other instances, skip them rather than attempt them twice at once.
+//! Failed attempts are retried after a [`backoff`][].
+
+use std::future::Future;
+use std::time::Duration;
+
+use async_std::task;
+use color_eyre::eyre::Result;
+use futures_lite::future;
+
+use crate::builtins::shutdown::shutdown_handle;
+
+/// The longest wait between attempts, unless a queue sets its own.
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The wait after `attempts` failed attempts: one second, doubling after each attempt, up to `max`.
+pub(crate) fn backoff(attempts: u32, max: Duration) -> Duration {
+    Duration::from_secs(2_u64.saturating_pow(attempts.saturating_sub(1))).min(max)
+}
+
+/// Run `poll` in the background every `interval`, until the server is shut down.
+///
+/// `poll` returns whether there may be more ready, in which case it is run again at once.
+/// Its errors are logged at `WARN`, as failures of `name`, e.g. `"Outbox relay"`.
+pub(crate) fn spawn<F, Fut>(name: &'static str, interval: Duration, poll: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<bool>> + Send,
+{
+    task::spawn(async move {
+        let shutdown = shutdown_handle();
+        while !shutdown.is_stopped() {
+            match poll().await {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(error) => log::warn!("{} failed: {:?}", name, error),
+            }
+            future::or(task::sleep(interval), shutdown.stopped()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(1, MAX_BACKOFF), Duration::from_secs(1));
+        assert_eq!(backoff(2, MAX_BACKOFF), Duration::from_secs(2));
+        assert_eq!(backoff(5, MAX_BACKOFF), Duration::from_secs(16));
+        assert_eq!(backoff(20, MAX_BACKOFF), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX, MAX_BACKOFF), MAX_BACKOFF);
+        assert_eq!(
+            backoff(12, Duration::from_secs(3600)),
+            Duration::from_secs(2048)
+        );
+    }
+}
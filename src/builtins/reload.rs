@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+
+use color_eyre::eyre::{eyre, Result};
+use once_cell::sync::Lazy;
+
+use crate::config;
+use crate::logging::log_kv;
+
+type ReloadHook = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+static RELOAD_HOOKS: Lazy<Mutex<Vec<(&'static str, ReloadHook)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register a hook which re-reads a setting when configuration is reloaded.
+///
+/// Registering a hook for the same setting as an existing hook replaces it.
+pub fn on_reload(setting: &'static str, hook: impl Fn() -> Result<()> + Send + Sync + 'static) {
+    if let Ok(mut hooks) = RELOAD_HOOKS.lock() {
+        hooks.retain(|(existing, _)| *existing != setting);
+        hooks.push((setting, Box::new(hook)));
+    }
+}
+
+/// Re-read the config file (and secrets), then re-apply every reloadable setting.
+///
+/// Every hook is run even if some fail. Returns the settings which were reloaded.
+pub async fn reload() -> Result<Vec<&'static str>> {
+    config::load_file()?;
+
+    #[cfg(feature = "aws-secrets")]
+    crate::secrets::resolve_all().await?;
+
+    let mut reloaded = Vec::new();
+    let mut failed = Vec::new();
+    if let Ok(hooks) = RELOAD_HOOKS.lock() {
+        for (setting, hook) in hooks.iter() {
+            match hook() {
+                Ok(()) => reloaded.push(*setting),
+                Err(error) => failed.push(format!("{}: {}", setting, error)),
+            }
+        }
+    }
+
+    log_kv!(
+        info,
+        {
+            reloaded: reloaded.join(","),
+            failed: failed.join(","),
+        },
+        "Configuration reloaded"
+    );
+
+    if failed.is_empty() {
+        Ok(reloaded)
+    } else {
+        Err(eyre!("Failed to reload {}", failed.join(", ")))
+    }
+}
+
+/// Re-apply a single setting, returning `false` if it is not reloadable.
+pub(crate) fn reload_setting(setting: &str) -> Result<bool> {
+    let hooks = RELOAD_HOOKS
+        .lock()
+        .map_err(|_| eyre!("reload hooks poisoned"))?;
+
+    match hooks.iter().find(|(existing, _)| *existing == setting) {
+        Some((_, hook)) => hook().map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Reload configuration whenever the process receives `SIGHUP`.
+#[cfg(unix)]
+pub fn listen_for_sighup() -> Result<()> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            log::info!("SIGHUP received, reloading configuration");
+            if let Err(error) = async_std::task::block_on(reload()) {
+                log::error!("Configuration reload failed: {:?}", error);
+            }
+        }
+    });
+
+    Ok(())
+}
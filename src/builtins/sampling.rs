@@ -0,0 +1,356 @@
+//! Rule-based sampling of honeycomb traces, decided once each request's root span has completed.
+//!
+//! Spans and events are buffered per trace until the root span from `TraceMiddleware` is reported,
+//! which carries the response status and request path. Errors and slow requests are always kept,
+//! and other requests are kept at the rate of the first matching rule, or the default rate.
+
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Result};
+use serde_json::Value;
+
+use crate::config;
+
+/// The span field on which `TraceMiddleware` records the response status.
+pub const STATUS_FIELD: &str = "http.status";
+/// The span field on which `TraceMiddleware` records the request path.
+pub const PATH_FIELD: &str = "http.path";
+/// The span field on which `TraceMiddleware` records whether the request was slow.
+pub const SLOW_FIELD: &str = "slow_request";
+
+/// The most traces buffered, or remembered once decided, before the oldest are evicted.
+const MAX_TRACES: usize = 1000;
+
+/// Report data, as passed to a `tracing_honeycomb::Reporter`.
+pub type Data = HashMap<String, Value>;
+
+/// Report data which has been sampled, to be sent with its timestamp and sample rate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sampled {
+    pub data: Data,
+    pub timestamp: DateTime<Utc>,
+    /// Keep 1 in `rate`, which honeycomb weights counts by.
+    pub rate: u32,
+}
+
+/// A path pattern, with its sample rate (keep 1 in `rate`).
+#[derive(Clone, Debug, PartialEq)]
+struct SampleRule {
+    /// Matches paths starting with `path` if true, or equal to `path` otherwise.
+    prefix: bool,
+    path: String,
+    rate: u32,
+}
+
+impl SampleRule {
+    fn matches(&self, path: &str) -> bool {
+        if self.prefix {
+            path.starts_with(&self.path)
+        } else {
+            path == self.path
+        }
+    }
+}
+
+/// The sampling rules from `HONEYCOMB_SAMPLE_RULES`, and the default rate from `HONEYCOMB_SAMPLE_RATE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleRules {
+    rules: Vec<SampleRule>,
+    default_rate: u32,
+}
+
+impl SampleRules {
+    /// Read the rules, if `HONEYCOMB_SAMPLE_RULES` is set.
+    ///
+    /// Rules are comma separated `pattern=rate` pairs, where a pattern ending in `*` matches any path with that prefix,
+    /// e.g. `/api/v1/products*=20,/api/v1/health=100`.
+    pub fn from_config() -> Result<Option<Self>> {
+        let rules = match config::var("HONEYCOMB_SAMPLE_RULES") {
+            Ok(rules) if !rules.trim().is_empty() => rules,
+            _ => return Ok(None),
+        };
+
+        let default_rate = match config::var("HONEYCOMB_SAMPLE_RATE") {
+            Ok(rate) => parse_rate(&rate)?,
+            Err(_) => 1,
+        };
+
+        Self::parse(&rules, default_rate).map(Some)
+    }
+
+    fn parse(rules: &str, default_rate: u32) -> Result<Self> {
+        let rules = rules
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (pattern, rate) = rule.rsplit_once('=').ok_or_else(|| {
+                    eyre!(
+                        "HONEYCOMB_SAMPLE_RULES must be pattern=rate pairs, got \"{}\"",
+                        rule
+                    )
+                })?;
+                let pattern = pattern.trim();
+                let path = pattern.trim_end_matches('*');
+
+                Ok(SampleRule {
+                    prefix: path.len() != pattern.len(),
+                    path: path.to_string(),
+                    rate: parse_rate(rate)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            rules,
+            default_rate,
+        })
+    }
+
+    /// The sample rate for a trace, from its root span's data.
+    fn rate_for(&self, root: &Data) -> u32 {
+        let status = root.get(STATUS_FIELD).and_then(Value::as_u64).unwrap_or(0);
+        let slow = root
+            .get(SLOW_FIELD)
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if status >= 500 || slow {
+            return 1;
+        }
+
+        let path = root.get(PATH_FIELD).and_then(Value::as_str).unwrap_or("");
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map_or(self.default_rate, |rule| rule.rate)
+    }
+}
+
+impl std::fmt::Display for SampleRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rule in self.rules.iter() {
+            let wildcard = if rule.prefix { "*" } else { "" };
+            write!(f, "{}{}={},", rule.path, wildcard, rule.rate)?;
+        }
+        write!(f, "default={}", self.default_rate)
+    }
+}
+
+fn parse_rate(rate: &str) -> Result<u32> {
+    match rate.trim().parse() {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(eyre!(
+            "Honeycomb sample rates must be positive integers, got \"{}\"",
+            rate
+        )),
+    }
+}
+
+#[derive(Debug)]
+enum TraceState {
+    /// Waiting for the root span.
+    Buffered(Vec<(Data, DateTime<Utc>)>),
+    /// Kept at the sample rate, or dropped if `None`.
+    Decided(Option<u32>),
+}
+
+/// Buffers report data per trace until a sampling decision can be made.
+#[derive(Debug)]
+pub struct Sampler {
+    rules: SampleRules,
+    traces: HashMap<String, TraceState>,
+    order: VecDeque<String>,
+}
+
+impl Sampler {
+    pub fn new(rules: SampleRules) -> Self {
+        Self {
+            rules,
+            traces: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Replace the rules, e.g. when configuration is reloaded. Buffered traces are decided by the new rules.
+    pub fn set_rules(&mut self, rules: SampleRules) {
+        self.rules = rules;
+    }
+
+    /// Accept report data, returning any which should now be sent, with its sample rate.
+    pub fn sample(&mut self, data: Data, timestamp: DateTime<Utc>) -> Vec<Sampled> {
+        let trace_id = match data.get("trace.trace_id") {
+            Some(Value::String(trace_id)) => trace_id.clone(),
+            Some(trace_id) => trace_id.to_string(),
+            None => {
+                return vec![Sampled {
+                    data,
+                    timestamp,
+                    rate: 1,
+                }]
+            }
+        };
+
+        if data.contains_key(STATUS_FIELD) {
+            let rate = self.rules.rate_for(&data);
+            let decision = keep(&trace_id, rate).then_some(rate);
+
+            let mut batch = match self
+                .traces
+                .insert(trace_id.clone(), TraceState::Decided(decision))
+            {
+                Some(TraceState::Buffered(buffered)) => buffered,
+                Some(TraceState::Decided(_)) => Vec::new(),
+                None => {
+                    self.order.push_back(trace_id);
+                    Vec::new()
+                }
+            };
+            batch.push((data, timestamp));
+
+            let mut sent = match decision {
+                Some(rate) => sampled(batch, rate).collect(),
+                None => Vec::new(),
+            };
+            sent.extend(self.evict());
+            return sent;
+        }
+
+        match self.traces.get_mut(&trace_id) {
+            Some(TraceState::Decided(Some(rate))) => vec![Sampled {
+                data,
+                timestamp,
+                rate: *rate,
+            }],
+            Some(TraceState::Decided(None)) => Vec::new(),
+            Some(TraceState::Buffered(buffered)) => {
+                buffered.push((data, timestamp));
+                Vec::new()
+            }
+            None => {
+                self.traces.insert(
+                    trace_id.clone(),
+                    TraceState::Buffered(vec![(data, timestamp)]),
+                );
+                self.order.push_back(trace_id);
+                self.evict()
+            }
+        }
+    }
+
+    /// Forget the oldest traces beyond `MAX_TRACES`, sending any still buffered at the default rate.
+    fn evict(&mut self) -> Vec<Sampled> {
+        let mut sent = Vec::new();
+        while self.order.len() > MAX_TRACES {
+            let trace_id = match self.order.pop_front() {
+                Some(trace_id) => trace_id,
+                None => break,
+            };
+
+            if let Some(TraceState::Buffered(buffered)) = self.traces.remove(&trace_id) {
+                let rate = self.rules.default_rate;
+                if keep(&trace_id, rate) {
+                    sent.extend(sampled(buffered, rate));
+                }
+            }
+        }
+        sent
+    }
+}
+
+fn sampled(batch: Vec<(Data, DateTime<Utc>)>, rate: u32) -> impl Iterator<Item = Sampled> {
+    batch.into_iter().map(move |(data, timestamp)| Sampled {
+        data,
+        timestamp,
+        rate,
+    })
+}
+
+/// Whether to keep a trace sampled at `rate`, consistently for the same trace id.
+fn keep(trace_id: &str, rate: u32) -> bool {
+    // FNV-1a, which unlike the std hasher is the same across processes.
+    let hash = trace_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    hash % u64::from(rate) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(trace_id: &str, fields: &[(&str, Value)]) -> Data {
+        let mut data = Data::new();
+        data.insert("trace.trace_id".to_string(), trace_id.into());
+        for (key, value) in fields {
+            data.insert(key.to_string(), value.clone());
+        }
+        data
+    }
+
+    #[test]
+    fn parses_rules() -> Result<()> {
+        let rules = SampleRules::parse("/api/v1/products*=20, /api/v1/health=100", 5)?;
+        assert_eq!(
+            rules.to_string(),
+            "/api/v1/products*=20,/api/v1/health=100,default=5"
+        );
+        assert!(SampleRules::parse("/api/v1/products", 1).is_err());
+        assert!(SampleRules::parse("/api/v1/products=0", 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rates_by_status_and_path() -> Result<()> {
+        let rules = SampleRules::parse("/api/v1/products*=20,/api/v1/health=100", 5)?;
+        let root = |status: u64, path: &str| {
+            data(
+                "t",
+                &[(STATUS_FIELD, status.into()), (PATH_FIELD, path.into())],
+            )
+        };
+
+        assert_eq!(rules.rate_for(&root(200, "/api/v1/products/1")), 20);
+        assert_eq!(rules.rate_for(&root(200, "/api/v1/health")), 100);
+        assert_eq!(rules.rate_for(&root(200, "/api/v1/health/deep")), 5);
+        assert_eq!(rules.rate_for(&root(503, "/api/v1/products/1")), 1);
+
+        let mut slow = root(200, "/api/v1/products/1");
+        slow.insert(SLOW_FIELD.to_string(), true.into());
+        assert_eq!(rules.rate_for(&slow), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn buffers_until_root() -> Result<()> {
+        let mut sampler = Sampler::new(SampleRules::parse("/hot*=1000000", 1)?);
+
+        // Dropped along with its root, unless the trace happens to be kept.
+        let trace_id = (0..)
+            .map(|n| n.to_string())
+            .find(|trace_id| !keep(trace_id, 1_000_000))
+            .unwrap_or_default();
+        assert!(sampler.sample(data(&trace_id, &[]), Utc::now()).is_empty());
+        let root = data(
+            &trace_id,
+            &[(STATUS_FIELD, 200.into()), (PATH_FIELD, "/hot/path".into())],
+        );
+        assert!(sampler.sample(root, Utc::now()).is_empty());
+        assert!(sampler.sample(data(&trace_id, &[]), Utc::now()).is_empty());
+
+        // Always kept, as an error.
+        assert!(sampler.sample(data("error", &[]), Utc::now()).is_empty());
+        let root = data(
+            "error",
+            &[(STATUS_FIELD, 500.into()), (PATH_FIELD, "/hot/path".into())],
+        );
+        assert_eq!(sampler.sample(root, Utc::now()).len(), 2);
+        assert_eq!(sampler.sample(data("error", &[]), Utc::now()).len(), 1);
+
+        // Not part of a trace.
+        assert_eq!(sampler.sample(Data::new(), Utc::now()).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Scheduling delay: how long requests wait to be handled, and how late the executor runs tasks,
+//! which rise as a service saturates, before its response times do.
+//!
+//! - Request queue delay is the time between a load balancer accepting a request and its handler starting,
+//!   from the `X-Request-Start` header set by the load balancer, as `t=<unix time>` or a bare unix time, in seconds,
+//!   milliseconds, or microseconds. Requests without the header are not measured.
+//! - Event loop lag is how much later than due a task sleeping for 100ms is woken, measured while the server is running.
+//!   Lag over `EVENT_LOOP_LAG_WARN_MS` (default `100`) is logged at `WARN`.
+//!
+//! Both are reported under `"scheduling"` in `/monitor/status`. Queue delay is also logged with each response,
+//! and recorded on the honeycomb span, as `queue_ms`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_std::task;
+use futures_lite::future;
+use serde::Serialize;
+use tide::Request;
+
+use crate::builtins::shutdown::shutdown_handle;
+use crate::config;
+use crate::logging::log_kv;
+
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+static PROBE_STARTED: AtomicBool = AtomicBool::new(false);
+static EVENT_LOOP_LAG: Gauge = Gauge::new();
+static REQUEST_QUEUE: Gauge = Gauge::new();
+
+/// The last, largest, and total of a delay's samples, in microseconds.
+#[derive(Debug)]
+struct Gauge {
+    last: AtomicU64,
+    max: AtomicU64,
+    total: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl Gauge {
+    const fn new() -> Self {
+        Self {
+            last: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, delay: Duration) {
+        let micros = delay.as_micros() as u64;
+        self.last.store(micros, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+        self.total.fetch_add(micros, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> Option<DelayStats> {
+        let samples = self.samples.load(Ordering::Relaxed);
+        if samples == 0 {
+            return None;
+        }
+
+        let millis = |micros: u64| micros as f64 / 1000.0;
+        Some(DelayStats {
+            last_ms: millis(self.last.load(Ordering::Relaxed)),
+            max_ms: millis(self.max.load(Ordering::Relaxed)),
+            mean_ms: millis(self.total.load(Ordering::Relaxed) / samples),
+            samples,
+        })
+    }
+}
+
+/// Start probing the event loop's lag, until the server is shut down. Only the first call starts a probe.
+pub(crate) fn start_lag_probe() {
+    if PROBE_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let warn_threshold = Duration::from_millis(
+        config::var("EVENT_LOOP_LAG_WARN_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(100),
+    );
+
+    task::spawn(async move {
+        let shutdown = shutdown_handle();
+        while !shutdown.is_stopped() {
+            let start = Instant::now();
+            future::or(task::sleep(PROBE_INTERVAL), shutdown.stopped()).await;
+            if shutdown.is_stopped() {
+                break;
+            }
+
+            let lag = start.elapsed().saturating_sub(PROBE_INTERVAL);
+            EVENT_LOOP_LAG.record(lag);
+            if lag > warn_threshold {
+                let lag_ms = lag.as_millis() as u64;
+                log_kv!(warn, { event_loop_lag_ms: lag_ms }, "Event loop lag of {}ms", lag_ms);
+            }
+        }
+    });
+}
+
+/// How long `req` waited between its `X-Request-Start` time and now, which is recorded, if it has the header.
+pub(crate) fn record_queue_delay<State>(req: &Request<State>) -> Option<Duration> {
+    let delay = queue_delay(req)?;
+    REQUEST_QUEUE.record(delay);
+    Some(delay)
+}
+
+/// How long `req` waited between its `X-Request-Start` time and now, if it has the header.
+pub(crate) fn queue_delay<State>(req: &Request<State>) -> Option<Duration> {
+    let header = req.header("X-Request-Start")?.last().as_str();
+    let start = parse_request_start(header)?;
+    SystemTime::now().duration_since(start).ok()
+}
+
+/// Parse `t=1650000000.123`, `1650000000123`, or `1650000000123456`, guessing the unit from the magnitude.
+fn parse_request_start(header: &str) -> Option<SystemTime> {
+    let value: f64 = header.trim().trim_start_matches("t=").parse().ok()?;
+    let seconds = if value > 1e15 {
+        value / 1e6
+    } else if value > 1e12 {
+        value / 1e3
+    } else {
+        value
+    };
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs_f64(seconds))
+}
+
+/// A delay's samples since startup.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DelayStats {
+    last_ms: f64,
+    max_ms: f64,
+    mean_ms: f64,
+    samples: u64,
+}
+
+/// Scheduling delay, reported under `"scheduling"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchedulingStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_loop_lag: Option<DelayStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_queue: Option<DelayStats>,
+}
+
+/// Scheduling delay, if any has been measured.
+pub(crate) fn scheduling_stats() -> Option<SchedulingStats> {
+    let stats = SchedulingStats {
+        event_loop_lag: EVENT_LOOP_LAG.stats(),
+        request_queue: REQUEST_QUEUE.stats(),
+    };
+    if stats.event_loop_lag.is_none() && stats.request_queue.is_none() {
+        return None;
+    }
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_start_in_any_unit() {
+        let expected = UNIX_EPOCH + Duration::from_millis(1_650_000_000_123);
+        for header in [
+            "t=1650000000.123",
+            "1650000000.123",
+            "t=1650000000123",
+            "t=1650000000123000",
+        ] {
+            let parsed = parse_request_start(header).map(|start| {
+                start
+                    .duration_since(expected)
+                    .unwrap_or_else(|error| error.duration())
+            });
+            assert!(
+                parsed < Some(Duration::from_millis(1)),
+                "{} parsed as {:?}",
+                header,
+                parsed
+            );
+        }
+
+        assert_eq!(parse_request_start("t=soon"), None);
+        assert_eq!(parse_request_start("t=-1"), None);
+    }
+
+    #[async_std::test]
+    async fn probes_event_loop_lag() {
+        start_lag_probe();
+        task::sleep(PROBE_INTERVAL * 3).await;
+
+        let stats = EVENT_LOOP_LAG.stats();
+        assert!(stats.map(|stats| stats.samples) >= Some(1));
+    }
+}
@@ -0,0 +1,110 @@
+//! preroll's own postgres tables, such as `events_outbox` and `jobs`, created and changed by versioned migrations
+//! when the features which use them are set up.
+//!
+//! Each migration is applied at most once, in order, and recorded in the `preroll_migrations` table, separately from
+//! the service's own migrations of `RUN_MIGRATIONS`. They are applied under the same advisory lock, so that
+//! only one instance of a multi-instance deploy applies them, and the others wait for it.
+//!
+//! The first migration of each table creates it only if it does not exist, as earlier versions created them at startup.
+
+use color_eyre::eyre::{Result, WrapErr};
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::{Connection, Executor};
+
+use crate::builtins::pool_stats;
+
+/// The advisory lock key held while migrating, so that only one instance of a multi-instance deploy migrates at once.
+///
+/// Arbitrary, but fixed: `0x7072_6572_6f6c_6c00` is `"preroll\0"`.
+pub(crate) const MIGRATION_LOCK_KEY: i64 = 0x7072_6572_6f6c_6c00;
+
+/// A migration of one of preroll's tables, applied once.
+#[derive(Debug)]
+pub(crate) struct Migration {
+    /// Unique across every table, e.g. `jobs_1`.
+    name: &'static str,
+    sql: &'static str,
+}
+
+macro_rules! migrations {
+    ($($name:literal),+ $(,)?) => {
+        &[$(Migration {
+            name: $name,
+            sql: include_str!(concat!("schema/", $name, ".sql")),
+        }),+]
+    };
+}
+
+pub(crate) const EVENTS_OUTBOX: &[Migration] = migrations!["events_outbox_1"];
+pub(crate) const NOTIFICATIONS: &[Migration] = migrations!["preroll_notifications_1"];
+pub(crate) const WEBHOOK_DELIVERIES: &[Migration] = migrations!["webhook_deliveries_1"];
+#[cfg(feature = "pg-jobs")]
+pub(crate) const JOBS: &[Migration] = migrations!["jobs_1"];
+#[cfg(feature = "idempotency")]
+pub(crate) const IDEMPOTENCY_KEYS: &[Migration] = migrations!["idempotency_keys_1"];
+#[cfg(feature = "sessions")]
+pub(crate) const SESSIONS: &[Migration] = migrations!["sessions_1"];
+
+const CREATE_MIGRATIONS_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS preroll_migrations (
+    name text PRIMARY KEY,
+    applied_at timestamptz NOT NULL DEFAULT now()
+);
+"#;
+
+/// Apply those of `migrations` which have not been applied yet.
+pub(crate) async fn migrate(pool: &PgPool, migrations: &[Migration]) -> Result<()> {
+    let mut conn = pool_stats::acquire(pool).await?;
+
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut conn)
+        .await?;
+
+    let result = apply(&mut conn, migrations).await;
+
+    // Advisory locks are held by the session, so must be released before the connection returns to the pool,
+    // or else the connection closed, which ends the session.
+    let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut conn)
+        .await;
+    if unlocked.is_err() {
+        drop(conn.detach());
+    }
+
+    result?;
+    unlocked?;
+    Ok(())
+}
+
+async fn apply(conn: &mut PgConnection, migrations: &[Migration]) -> Result<()> {
+    conn.execute(CREATE_MIGRATIONS_TABLE).await?;
+
+    for migration in migrations {
+        let mut tx = conn.begin().await?;
+
+        let applied: Option<(String,)> =
+            sqlx::query_as("SELECT name FROM preroll_migrations WHERE name = $1")
+                .bind(migration.name)
+                .fetch_optional(&mut tx)
+                .await?;
+        if applied.is_some() {
+            continue;
+        }
+
+        // Executed as a simple query, which allows multiple statements.
+        tx.execute(migration.sql)
+            .await
+            .wrap_err_with(|| format!("preroll migration {} failed", migration.name))?;
+        sqlx::query("INSERT INTO preroll_migrations (name) VALUES ($1)")
+            .bind(migration.name)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        log::info!("Applied preroll migration {}", migration.name);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS events_outbox (
+    id uuid PRIMARY KEY,
+    seq bigserial NOT NULL,
+    topic text NOT NULL,
+    key text,
+    payload jsonb NOT NULL,
+    request_id text,
+    attempts integer NOT NULL DEFAULT 0,
+    last_error text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    next_attempt_at timestamptz NOT NULL DEFAULT now(),
+    published_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS events_outbox_pending
+    ON events_outbox (seq)
+    WHERE published_at IS NULL;
+CREATE INDEX IF NOT EXISTS events_outbox_pending_keys
+    ON events_outbox (key, seq)
+    WHERE published_at IS NULL;
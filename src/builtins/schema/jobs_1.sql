@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    id uuid PRIMARY KEY,
+    job_type text NOT NULL,
+    payload jsonb NOT NULL,
+    request_id text,
+    attempts integer NOT NULL DEFAULT 0,
+    last_error text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    run_at timestamptz NOT NULL DEFAULT now(),
+    completed_at timestamptz,
+    failed_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS jobs_pending
+    ON jobs (run_at)
+    WHERE completed_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS preroll_notifications (
+    id uuid PRIMARY KEY,
+    channel text NOT NULL,
+    payload jsonb NOT NULL,
+    request_id text,
+    attempts integer NOT NULL DEFAULT 0,
+    last_error text,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    next_attempt_at timestamptz NOT NULL DEFAULT now(),
+    delivered_at timestamptz
+);
+CREATE INDEX IF NOT EXISTS preroll_notifications_pending
+    ON preroll_notifications (next_attempt_at)
+    WHERE delivered_at IS NULL;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id text PRIMARY KEY,
+    data jsonb NOT NULL,
+    expires_at timestamptz NOT NULL
+);
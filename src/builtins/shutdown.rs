@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::task;
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+static SHUTDOWN: Lazy<ShutdownHandle> = Lazy::new(ShutdownHandle::new);
+
+/// Requests being handled, which shutdown waits for.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// A handle which stops a preroll server from accepting connections, for orchestration code and integration tests.
+///
+/// Stopping is permanent, and wakes everything waiting on [`stopped`][ShutdownHandle::stopped].
+/// Requests already being handled run to completion on their own tasks, which `preroll::main!` waits for,
+/// for at most `SHUTDOWN_GRACE_MS`. `SIGTERM` also stops the server.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl ShutdownHandle {
+    fn new() -> Self {
+        // Nothing is ever sent: closing the channel is the signal, which wakes every receiver.
+        let (sender, receiver) = channel::bounded(1);
+        Self { sender, receiver }
+    }
+
+    /// Stop the server. `preroll::main!`, or [`start_server`][crate::setup::start_server], then returns `Ok(())`.
+    pub fn stop(&self) {
+        self.sender.close();
+    }
+
+    /// Whether [`stop`][ShutdownHandle::stop] has been called.
+    #[must_use]
+    pub fn is_stopped(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Wait until [`stop`][ShutdownHandle::stop] is called.
+    pub async fn stopped(&self) {
+        self.receiver.recv().await.ok();
+    }
+}
+
+/// The process-wide shutdown handle of the server started by `preroll::main!`.
+///
+/// It may be obtained at any time, e.g. in `custom_setup` to hand to orchestration code,
+/// and before or after the server is started.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// # #[allow(dead_code)]
+/// async fn custom_setup(server: tide::Server<Arc<()>>) -> preroll::SetupResult<tide::Server<Arc<()>>> {
+///     let shutdown = preroll::setup::shutdown_handle();
+///     async_std::task::spawn(async move {
+///         async_std::task::sleep(Duration::from_secs(60)).await;
+///         shutdown.stop();
+///     });
+///     Ok(server)
+/// }
+/// ```
+#[must_use]
+pub fn shutdown_handle() -> ShutdownHandle {
+    SHUTDOWN.clone()
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight(());
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Count a request as in flight, so that shutdown waits for it, until the returned guard is dropped.
+pub(crate) fn track_request() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    InFlight(())
+}
+
+/// Stop the server when the process receives `SIGTERM`.
+#[cfg(all(unix, not(feature = "lambda-http")))]
+pub(crate) fn listen_for_sigterm() -> color_eyre::eyre::Result<()> {
+    use signal_hook::consts::SIGTERM;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM])?;
+    std::thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            log::info!("SIGTERM received, shutting down");
+            SHUTDOWN.stop();
+        }
+    });
+
+    Ok(())
+}
+
+/// Wait for the requests in flight to finish, for at most `SHUTDOWN_GRACE_MS` (default `30000`).
+pub(crate) async fn drain() {
+    let grace = Duration::from_millis(
+        config::var("SHUTDOWN_GRACE_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30_000),
+    );
+    let deadline = Instant::now() + grace;
+
+    let in_flight = IN_FLIGHT.load(Ordering::SeqCst);
+    if in_flight > 0 {
+        log::info!("Waiting for {} request(s) to finish", in_flight);
+    }
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            log::warn!(
+                "Shutting down with {} request(s) unfinished after {:?}",
+                IN_FLIGHT.load(Ordering::SeqCst),
+                grace
+            );
+            return;
+        }
+        task::sleep(Duration::from_millis(50)).await;
+    }
+}
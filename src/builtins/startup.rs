@@ -0,0 +1,86 @@
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::logging::log_kv;
+
+static STARTUP_SUMMARY: Lazy<Mutex<StartupSummary>> =
+    Lazy::new(|| Mutex::new(StartupSummary::default()));
+
+/// What a service was actually started with, collected during setup and logged as a single record once listening.
+#[derive(Debug, Default)]
+pub struct StartupSummary {
+    pub service: &'static str,
+    pub environment: String,
+    pub middleware: Vec<&'static str>,
+    pub route_middleware: Vec<String>,
+    pub listeners: Vec<String>,
+    pub postgres_pool: Option<String>,
+    pub tracing: Option<String>,
+    pub config_file: Option<String>,
+    pub migrations: Option<String>,
+}
+
+/// Record some part of the startup configuration.
+pub fn record_startup(update: impl FnOnce(&mut StartupSummary)) {
+    if let Ok(mut summary) = STARTUP_SUMMARY.lock() {
+        update(&mut summary);
+    }
+}
+
+/// The preroll features this crate was compiled with.
+pub fn enabled_features() -> Vec<&'static str> {
+    let features = [
+        ("grpc-web", cfg!(feature = "grpc-web")),
+        ("honeycomb", cfg!(feature = "honeycomb")),
+        ("idempotency", cfg!(feature = "idempotency")),
+        ("launchdarkly", cfg!(feature = "launchdarkly")),
+        ("pg-jobs", cfg!(feature = "pg-jobs")),
+        ("postgres", cfg!(feature = "postgres")),
+        ("postgres-migrate", cfg!(feature = "postgres-migrate")),
+        ("redis", cfg!(feature = "redis")),
+        ("sessions", cfg!(feature = "sessions")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("unleash", cfg!(feature = "unleash")),
+        ("aws-secrets", cfg!(feature = "aws-secrets")),
+        ("lambda-http", cfg!(feature = "lambda-http")),
+        ("tracing-logs", cfg!(feature = "tracing-logs")),
+        ("custom_middleware", cfg!(feature = "custom_middleware")),
+        ("panic-on-error", cfg!(feature = "panic-on-error")),
+        ("fail-on-error", cfg!(feature = "fail-on-error")),
+        ("simd-json", cfg!(feature = "simd-json")),
+        ("test", cfg!(feature = "test")),
+    ];
+
+    features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Log the startup summary as one structured record.
+pub fn log_startup_summary() {
+    let summary = match STARTUP_SUMMARY.lock() {
+        Ok(summary) => summary,
+        Err(_) => return,
+    };
+
+    log_kv!(
+        info,
+        {
+            service: summary.service,
+            environment: summary.environment,
+            preroll_version: env!("CARGO_PKG_VERSION"),
+            features: enabled_features().join(","),
+            middleware: summary.middleware.join(","),
+            route_middleware: summary.route_middleware.join(","),
+            listeners: summary.listeners.join(","),
+            postgres_pool: summary.postgres_pool.as_deref().unwrap_or("disabled"),
+            tracing: summary.tracing.as_deref().unwrap_or("disabled"),
+            config_file: summary.config_file.as_deref().unwrap_or("none"),
+            migrations: summary.migrations.as_deref().unwrap_or("not run"),
+        },
+        "Preroll startup summary"
+    );
+}
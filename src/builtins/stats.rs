@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tide::StatusCode;
+
+static REQUEST_STATS: Lazy<RequestStats> = Lazy::new(RequestStats::default);
+
+/// Counts of responses served by the application server, reported under `"stats"` in `/monitor/status`.
+#[derive(Debug, Default)]
+struct RequestStats {
+    request_count: AtomicU64,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    routes: Mutex<BTreeMap<String, u64>>,
+}
+
+impl RequestStats {
+    fn record(&self, route: Option<&str>, status: StatusCode) {
+        self.request_count.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut statuses) = self.statuses.lock() {
+            *statuses.entry(status as u16).or_default() += 1;
+        }
+        if let Some(route) = route {
+            if let Ok(mut routes) = self.routes.lock() {
+                *routes.entry(route.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            request_count: self.request_count.load(Ordering::Relaxed),
+            statuses: self
+                .statuses
+                .lock()
+                .map(|statuses| statuses.clone())
+                .unwrap_or_default(),
+            routes: self
+                .routes
+                .lock()
+                .map(|routes| routes.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Request stats for a single server, rather than the whole process, such as for each `test_utils` server.
+///
+/// Set as a request extension, before `LogMiddleware`, these stats are recorded into and reported by `/monitor/status`.
+#[derive(Clone, Debug, Default)]
+pub struct ScopedStats(Arc<RequestStats>);
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsSnapshot {
+    pub request_count: u64,
+    pub statuses: BTreeMap<u16, u64>,
+    /// Counts of responses from routes with a [template][crate::routes], by template.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub routes: BTreeMap<String, u64>,
+}
+
+impl StatsSnapshot {
+    /// The number of responses recorded with `status`.
+    #[must_use]
+    pub fn status_count(&self, status: u16) -> u64 {
+        self.statuses.get(&status).copied().unwrap_or_default()
+    }
+}
+
+/// Record a response in the request stats registry, and in any matching SLO objectives.
+///
+/// `/monitor/` routes are not recorded, even if served through `LogMiddleware`.
+pub fn record_response(
+    scoped: Option<&ScopedStats>,
+    path: &str,
+    route: Option<&str>,
+    status: StatusCode,
+    elapsed: Duration,
+) {
+    if path.starts_with("/monitor/") {
+        return;
+    }
+
+    match scoped {
+        Some(scoped) => scoped.0.record(route, status),
+        None => REQUEST_STATS.record(route, status),
+    }
+
+    crate::slo::record(path, status, elapsed);
+}
+
+pub fn stats_snapshot(scoped: Option<&ScopedStats>) -> StatsSnapshot {
+    match scoped {
+        Some(scoped) => scoped.0.snapshot(),
+        None => REQUEST_STATS.snapshot(),
+    }
+}
@@ -0,0 +1,440 @@
+//! Response caching for read-mostly routes, with [`CacheMiddleware`][].
+//!
+//! Successful `GET` responses are stored in a [`CacheBackend`][] for a per-route TTL, and served from it until they expire.
+//! Every response through the middleware has a [`Cache-Status`](https://www.rfc-editor.org/rfc/rfc9211) header,
+//! e.g. `preroll; hit` or `preroll; fwd=miss; stored`, and hits, misses, stores, and backend errors are reported
+//! under `"cache"` in `/monitor/status`.
+//!
+//! The default backend is an in-memory LRU of at most `CACHE_MAX_ENTRIES` responses (default `1000`), per instance.
+//! With the `"redis"` feature, and `REDISURL` set, responses are instead cached in redis, shared by all instances.
+//! Other backends can be used by implementing [`CacheBackend`][] and calling [`set_backend`][] during setup,
+//! or per route with [`CacheMiddleware::backend`][].
+//!
+//! Backend failures are logged, and the request is handled as if it were not cached.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use preroll::cache::CacheMiddleware;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("products")
+//!         .with(CacheMiddleware::new(Duration::from_secs(30)).vary("Accept-Language"))
+//!         .get(|_| async { Ok("an expensive product list") });
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tide::http::headers::HeaderName;
+use tide::{Body, Response, StatusCode};
+
+use crate::config;
+
+pub use crate::middleware::cache::CacheMiddleware;
+
+static BACKEND: OnceCell<Arc<dyn CacheBackend>> = OnceCell::new();
+static CACHE_STATS: Lazy<CacheCounters> = Lazy::new(CacheCounters::default);
+
+/// A cached response.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    /// Rebuild the response.
+    pub(crate) fn to_response(&self) -> Response {
+        let status = StatusCode::try_from(self.status).unwrap_or(StatusCode::Ok);
+        let mut res = Response::new(status);
+        for (name, value) in self.headers.iter() {
+            if let Ok(name) = name.parse::<HeaderName>() {
+                res.append_header(name, value.as_str());
+            }
+        }
+
+        let mime = res.content_type();
+        let mut body = Body::from_bytes(self.body.clone());
+        if let Some(mime) = mime {
+            body.set_mime(mime);
+        }
+        res.set_body(body);
+        res
+    }
+}
+
+/// Stores cached responses.
+#[tide::utils::async_trait]
+pub trait CacheBackend: Send + Sync + 'static {
+    /// The response cached under `key`, if any, and unexpired.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<CachedResponse>>;
+
+    /// Cache `response` under `key`, for `ttl`.
+    async fn set(&self, key: &str, response: &CachedResponse, ttl: Duration) -> anyhow::Result<()>;
+}
+
+/// Set the backend for every [`CacheMiddleware`][] without its own.
+///
+/// Only the first backend set is used.
+pub fn set_backend(backend: impl CacheBackend) {
+    if BACKEND.set(Arc::new(backend)).is_err() {
+        log::warn!("A cache backend is already set, ignoring another.");
+    }
+}
+
+/// The backend which has been set, or else an in-memory LRU.
+pub(crate) fn backend() -> Arc<dyn CacheBackend> {
+    BACKEND
+        .get_or_init(|| {
+            let capacity = config::var("CACHE_MAX_ENTRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000);
+            Arc::new(MemoryCache::new(capacity))
+        })
+        .clone()
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: CachedResponse,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// Keys by when they were last used, least recent first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.last_used);
+            entry.last_used = self.tick;
+            self.order.insert(self.tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
+
+/// Caches responses in memory, evicting the least recently used beyond its capacity.
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl MemoryCache {
+    /// Create a new `MemoryCache` of at most `capacity` responses.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<CachedResponse>> {
+        let mut lru = self
+            .lru
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Memory cache lock poisoned"))?;
+
+        let expired = match lru.entries.get(key) {
+            Some(entry) => entry.expires_at <= Instant::now(),
+            None => return Ok(None),
+        };
+        if expired {
+            lru.remove(key);
+            return Ok(None);
+        }
+
+        lru.touch(key);
+        Ok(lru.entries.get(key).map(|entry| entry.response.clone()))
+    }
+
+    async fn set(&self, key: &str, response: &CachedResponse, ttl: Duration) -> anyhow::Result<()> {
+        let mut lru = self
+            .lru
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Memory cache lock poisoned"))?;
+
+        lru.remove(key);
+        lru.entries.insert(
+            key.to_string(),
+            Entry {
+                response: response.clone(),
+                expires_at: Instant::now() + ttl,
+                last_used: 0,
+            },
+        );
+        lru.touch(key);
+
+        while lru.entries.len() > self.capacity {
+            let oldest = match lru.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            lru.remove(&oldest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_backend::RedisCache;
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_std::sync::Mutex;
+    use redis::aio::MultiplexedConnection;
+    use redis::Client;
+
+    use super::{CacheBackend, CachedResponse};
+
+    /// The prefix of every key cached in redis.
+    const KEY_PREFIX: &str = "preroll:cache:";
+
+    /// Caches responses in redis, as hashes which expire after their TTL.
+    pub struct RedisCache {
+        client: Client,
+        conn: Mutex<Option<MultiplexedConnection>>,
+    }
+
+    impl std::fmt::Debug for RedisCache {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisCache").finish()
+        }
+    }
+
+    impl RedisCache {
+        /// Create a new `RedisCache` for a `redis://` url, connecting once first used.
+        pub fn new(url: &str) -> redis::RedisResult<Self> {
+            Ok(Self {
+                client: Client::open(url)?,
+                conn: Mutex::new(None),
+            })
+        }
+
+        /// A shared connection, connecting if there is none.
+        async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+            let mut conn = self.conn.lock().await;
+            if let Some(conn) = conn.as_ref() {
+                return Ok(conn.clone());
+            }
+
+            let connected = self.client.get_multiplexed_async_std_connection().await?;
+            *conn = Some(connected.clone());
+            Ok(connected)
+        }
+
+        /// Drop the shared connection after an error, so that the next use reconnects.
+        async fn reset(&self) {
+            *self.conn.lock().await = None;
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl CacheBackend for RedisCache {
+        async fn get(&self, key: &str) -> anyhow::Result<Option<CachedResponse>> {
+            let mut conn = self.connection().await?;
+
+            let fields: HashMap<String, Vec<u8>> = match redis::cmd("HGETALL")
+                .arg(format!("{}{}", KEY_PREFIX, key))
+                .query_async(&mut conn)
+                .await
+            {
+                Ok(fields) => fields,
+                Err(error) => {
+                    self.reset().await;
+                    return Err(error.into());
+                }
+            };
+
+            let status = match fields.get("status") {
+                Some(status) => String::from_utf8_lossy(status).parse()?,
+                None => return Ok(None),
+            };
+            let headers = match fields.get("headers") {
+                Some(headers) => serde_json::from_slice(headers)?,
+                None => Vec::new(),
+            };
+
+            Ok(Some(CachedResponse {
+                status,
+                headers,
+                body: fields.get("body").cloned().unwrap_or_default(),
+            }))
+        }
+
+        async fn set(
+            &self,
+            key: &str,
+            response: &CachedResponse,
+            ttl: Duration,
+        ) -> anyhow::Result<()> {
+            let mut conn = self.connection().await?;
+            let key = format!("{}{}", KEY_PREFIX, key);
+
+            let result: redis::RedisResult<()> = redis::pipe()
+                .atomic()
+                .cmd("DEL")
+                .arg(&key)
+                .ignore()
+                .cmd("HSET")
+                .arg(&key)
+                .arg("status")
+                .arg(response.status)
+                .arg("headers")
+                .arg(serde_json::to_vec(&response.headers)?)
+                .arg("body")
+                .arg(response.body.as_slice())
+                .ignore()
+                .cmd("PEXPIRE")
+                .arg(&key)
+                .arg(ttl.as_millis() as u64)
+                .ignore()
+                .query_async(&mut conn)
+                .await;
+
+            if result.is_err() {
+                self.reset().await;
+            }
+            Ok(result?)
+        }
+    }
+}
+
+/// If `REDISURL` is set, and no other backend has been, cache responses in redis.
+#[cfg(feature = "redis")]
+pub(crate) fn setup() -> color_eyre::eyre::Result<()> {
+    if BACKEND.get().is_some() {
+        return Ok(());
+    }
+
+    if let Ok(url) = config::var("REDISURL") {
+        BACKEND.set(Arc::new(RedisCache::new(&url)?)).ok();
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct CacheCounters {
+    used: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stores: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Response cache statistics, reported under `"cache"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    stores: u64,
+    errors: u64,
+}
+
+/// Record that a `CacheMiddleware` exists, so that stats are reported.
+pub(crate) fn record_used() {
+    CACHE_STATS.used.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn record_hit() {
+    CACHE_STATS.hits.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_miss() {
+    CACHE_STATS.misses.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_store() {
+    CACHE_STATS.stores.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_error() {
+    CACHE_STATS.errors.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The response cache statistics, if any `CacheMiddleware` has been created.
+pub(crate) fn cache_stats() -> Option<CacheStats> {
+    if !CACHE_STATS.used.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    Some(CacheStats {
+        hits: CACHE_STATS.hits.load(Ordering::Relaxed),
+        misses: CACHE_STATS.misses.load(Ordering::Relaxed),
+        stores: CACHE_STATS.stores.load(Ordering::Relaxed),
+        errors: CACHE_STATS.errors.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &str) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[async_std::test]
+    async fn memory_cache_evicts_least_recently_used() -> anyhow::Result<()> {
+        let cache = MemoryCache::new(2);
+        let ttl = Duration::from_secs(60);
+
+        cache.set("a", &response("a"), ttl).await?;
+        cache.set("b", &response("b"), ttl).await?;
+        assert_eq!(cache.get("a").await?, Some(response("a")));
+
+        cache.set("c", &response("c"), ttl).await?;
+        assert_eq!(cache.get("b").await?, None);
+        assert_eq!(cache.get("a").await?, Some(response("a")));
+        assert_eq!(cache.get("c").await?, Some(response("c")));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn memory_cache_expires() -> anyhow::Result<()> {
+        let cache = MemoryCache::new(2);
+
+        cache.set("a", &response("a"), Duration::ZERO).await?;
+        assert_eq!(cache.get("a").await?, None);
+        Ok(())
+    }
+}
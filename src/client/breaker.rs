@@ -0,0 +1,373 @@
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, Response, StatusCode};
+
+use crate::logging::log_kv;
+
+/// The thresholds of a [`CircuitBreaker`][].
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    failure_threshold: u32,
+    open_for: Duration,
+    half_open_probes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+impl BreakerConfig {
+    /// Open after 5 consecutive failures, for 30 seconds, and then close after 1 successful probe.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many consecutive failures open the circuit. At least 1.
+    #[must_use]
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// How long the circuit stays open, rejecting requests, before probing the upstream again.
+    #[must_use]
+    pub fn open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    /// How many probe requests are let through at once while half-open, all of which must succeed to close the circuit.
+    /// At least 1.
+    #[must_use]
+    pub fn half_open_probes(mut self, probes: u32) -> Self {
+        self.half_open_probes = probes.max(1);
+        self
+    }
+}
+
+/// The state of a [`CircuitBreaker`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent, and consecutive failures are counted.
+    Closed,
+    /// Requests are rejected without being sent, until the open period has passed.
+    Open,
+    /// A limited number of probe requests are sent, to find out whether the upstream has recovered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// The state's name, as logged and reported in `/monitor/status`.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "halfOpen",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probes: u32, successes: u32 },
+}
+
+impl State {
+    fn circuit_state(&self) -> CircuitState {
+        match self {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// A surf middleware which stops sending requests to a failing upstream, so that its failures do not cascade.
+///
+/// - Closed: requests are sent. Once `failure_threshold` consecutive requests fail, with a connection error, timeout,
+///   or server error response, the circuit opens.
+/// - Open: requests are rejected immediately with a `503 Service Unavailable` error which names the upstream,
+///   rendered as a [`JsonError`][crate::JsonError] if returned from a handler. After `open_for`, the circuit is half-open.
+/// - Half-open: up to `half_open_probes` requests are sent at once, and the rest rejected. If they all succeed, the circuit
+///   closes, or if any fails, it opens again.
+///
+/// State changes are logged, and recorded as trace events with the `"honeycomb"` feature.
+///
+/// Breakers are usually configured for a [registered upstream][crate::client::registry],
+/// but can be added to any `surf::Client` with `client.with(breaker)`. Clones share the same circuit.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    upstream: String,
+    config: BreakerConfig,
+    state: Mutex<State>,
+    rejected: AtomicU64,
+}
+
+impl Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("upstream", &self.inner.upstream)
+            .field("config", &self.inner.config)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl CircuitBreaker {
+    /// Create a new, closed, `CircuitBreaker` for the upstream named `upstream`.
+    #[must_use]
+    pub fn new(upstream: impl Into<String>, config: BreakerConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                upstream: upstream.into(),
+                config,
+                state: Mutex::new(State::Closed { failures: 0 }),
+                rejected: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The current state of the circuit.
+    #[must_use]
+    pub fn state(&self) -> CircuitState {
+        self.lock().circuit_state()
+    }
+
+    /// How many requests have been rejected without being sent.
+    #[must_use]
+    pub fn rejected(&self) -> u64 {
+        self.inner.rejected.load(Ordering::Relaxed)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether a request may be sent, and if so, whether it is a half-open probe.
+    fn admit(&self) -> Option<bool> {
+        let mut state = self.lock();
+        match &mut *state {
+            State::Closed { .. } => Some(false),
+            State::Open { until } if Instant::now() < *until => None,
+            State::Open { .. } => {
+                self.transition(
+                    &mut state,
+                    State::HalfOpen {
+                        probes: 1,
+                        successes: 0,
+                    },
+                );
+                Some(true)
+            }
+            State::HalfOpen { probes, .. } if *probes < self.inner.config.half_open_probes => {
+                *probes += 1;
+                Some(true)
+            }
+            State::HalfOpen { .. } => None,
+        }
+    }
+
+    /// Record the outcome of a sent request. `success` is `None` if the request was cancelled.
+    fn record(&self, probe: bool, success: Option<bool>) {
+        let config = &self.inner.config;
+        let mut state = self.lock();
+        match (&mut *state, probe) {
+            (State::Closed { failures }, false) => match success {
+                Some(true) => *failures = 0,
+                Some(false) => {
+                    *failures += 1;
+                    if *failures >= config.failure_threshold {
+                        let until = Instant::now() + config.open_for;
+                        self.transition(&mut state, State::Open { until });
+                    }
+                }
+                None => (),
+            },
+            (State::HalfOpen { probes, successes }, true) => {
+                *probes -= 1;
+                match success {
+                    Some(true) => {
+                        *successes += 1;
+                        if *successes >= config.half_open_probes {
+                            self.transition(&mut state, State::Closed { failures: 0 });
+                        }
+                    }
+                    Some(false) => {
+                        let until = Instant::now() + config.open_for;
+                        self.transition(&mut state, State::Open { until });
+                    }
+                    None => (),
+                }
+            }
+            // Requests sent before the circuit last changed state do not affect it.
+            _ => (),
+        }
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        let from = state.circuit_state().as_str();
+        let to = next.circuit_state().as_str();
+        *state = next;
+
+        let upstream = self.inner.upstream.as_str();
+        if to == "open" {
+            log_kv!(
+                warn,
+                { upstream: upstream, circuit_from: from, circuit_to: to },
+                "Circuit breaker for {} opened", upstream
+            );
+        } else {
+            log_kv!(
+                info,
+                { upstream: upstream, circuit_from: from, circuit_to: to },
+                "Circuit breaker for {} is {}", upstream, to
+            );
+        }
+
+        #[cfg(feature = "honeycomb")]
+        tracing::info!(
+            upstream = upstream,
+            circuit_from = from,
+            circuit_to = to,
+            "Circuit breaker state change"
+        );
+    }
+}
+
+/// Records a sent request as cancelled unless its outcome is recorded first.
+struct Outcome<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Outcome<'_> {
+    fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(self.probe, Some(success));
+    }
+}
+
+impl Drop for Outcome<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.record(self.probe, None);
+        }
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for CircuitBreaker {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        let probe = match self.admit() {
+            Some(probe) => probe,
+            None => {
+                self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(surf::Error::from_str(
+                    StatusCode::ServiceUnavailable,
+                    format!(
+                        "Upstream {} is unavailable (circuit breaker open)",
+                        self.inner.upstream
+                    ),
+                ));
+            }
+        };
+
+        let outcome = Outcome {
+            breaker: self,
+            probe,
+            recorded: false,
+        };
+        let res = next.run(req, client).await;
+        outcome.record(matches!(&res, Ok(res) if !res.status().is_server_error()));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn opens_and_recovers() -> surf::Result<()> {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let upstream_healthy = healthy.clone();
+        let (addr, _handle) =
+            test_utils::spawn_server((), move |mut server: tide::Route<'_, Arc<()>>| {
+                let healthy = upstream_healthy.clone();
+                server.at("status").get(move |_| {
+                    let healthy = healthy.load(Ordering::SeqCst);
+                    async move {
+                        match healthy {
+                            true => Ok("up"),
+                            false => Err(tide::Error::from_str(500, "down")),
+                        }
+                    }
+                });
+            })
+            .await?;
+
+        let breaker = CircuitBreaker::new(
+            "status",
+            BreakerConfig::new()
+                .failure_threshold(2)
+                .open_for(Duration::from_millis(50)),
+        );
+        // Without keep-alive, so that no request reuses a connection the test server has closed.
+        let client: Client = surf::Config::new().set_http_keep_alive(false).try_into()?;
+        let client = client.with(breaker.clone());
+        let url = format!("http://{}/api/v1/status", addr);
+
+        for _ in 0..2 {
+            assert_eq!(client.get(&url).await?.status(), 500);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let error = client.get(&url).await.err();
+        assert_eq!(
+            error.as_ref().map(|error| error.status()),
+            Some(StatusCode::ServiceUnavailable)
+        );
+        assert_eq!(
+            error.map(|error| error.to_string()),
+            Some("Upstream status is unavailable (circuit breaker open)".to_string())
+        );
+        assert_eq!(breaker.rejected(), 1);
+
+        // A failed probe opens the circuit again.
+        async_std::task::sleep(Duration::from_millis(60)).await;
+        assert_eq!(client.get(&url).await?.status(), 500);
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        healthy.store(true, Ordering::SeqCst);
+        async_std::task::sleep(Duration::from_millis(60)).await;
+        assert_eq!(client.get(&url).recv_string().await?, "up");
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+//! Utilities for outbound http clients.
+//!
+//! - [`TokenProvider`][]: OAuth2 client-credentials tokens, cached and refreshed before they expire,
+//!   and [`TokenMiddleware`][], which sends them on every request of a `surf::Client`.
+//! - [`registry`][]: Shared clients for named upstreams, with their base urls, timeouts, and statistics.
+//! - [`CircuitBreaker`][]: Stops sending requests to a failing upstream, so that its failures do not cascade.
+
+mod breaker;
+pub mod registry;
+mod token;
+
+pub use breaker::{BreakerConfig, CircuitBreaker, CircuitState};
+pub use token::{TokenMiddleware, TokenProvider};
@@ -0,0 +1,487 @@
+//! A registry of outbound http clients, by upstream name.
+//!
+//! Each registered upstream has one `surf::Client`, with its base url, timeout, and connection limit, which
+//! [`client`][] clones for every caller. Clones share the upstream's connection pool, so connections are reused across
+//! requests, rather than each handler, or each piece of state, configuring a client of its own.
+//!
+//! Every upstream's request counts, errors, and in-flight requests are reported under `"upstreams"` in `/monitor/status`.
+//! An upstream whose `peakInFlight` reaches its `maxConnections` has waited for connections to be freed for reuse.
+//!
+//! Requests which fail to get a response from an upstream, and are returned by a handler, are a `504 Gateway Timeout`
+//! if they timed out, or else a `502 Bad Gateway`, rather than a `500`. The upstream which failed is logged and traced as
+//! `upstream`, and its `JsonError` has a `code` of `upstream_timeout:{name}` or `upstream_error:{name}`.
+//!
+//! Upstreams can be configured from the environment, e.g. for an upstream named `orders`:
+//! - `UPSTREAM_ORDERS_URL` (required): The base url, such as `http://orders.internal/api/v1/`.
+//! - `UPSTREAM_ORDERS_TIMEOUT_MS`: The request timeout. Defaults to `30000`.
+//! - `UPSTREAM_ORDERS_MAX_CONNECTIONS`: The most connections kept open at once. Defaults to `50`.
+//! - `UPSTREAM_ORDERS_BREAKER_FAILURES`: If set, a [circuit breaker][CircuitBreaker] opens after this many
+//!   consecutive failures.
+//!     - `UPSTREAM_ORDERS_BREAKER_OPEN_MS`: How long the circuit stays open. Defaults to `30000`.
+//!     - `UPSTREAM_ORDERS_BREAKER_PROBES`: How many probe requests are sent at once while half-open. Defaults to `1`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::client::registry::{self, UpstreamConfig};
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     registry::register("orders", UpstreamConfig::from_env("orders")?)?;
+//!     Ok(())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("orders")
+//!         .get(|_req: Request<Arc<()>>| async move {
+//!             let orders = registry::client("orders")?.get("orders").recv_string().await?;
+//!             Ok(orders)
+//!         });
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, Response, StatusCode, Url};
+
+use super::{BreakerConfig, CircuitBreaker};
+use crate::config;
+
+static UPSTREAMS: Lazy<RwLock<BTreeMap<&'static str, Upstream>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// The configuration of a registered upstream.
+#[derive(Clone, Debug)]
+pub struct UpstreamConfig {
+    base_url: Url,
+    timeout: Duration,
+    max_connections: usize,
+    breaker: Option<BreakerConfig>,
+}
+
+impl UpstreamConfig {
+    /// Configure an upstream at `base_url`, with a timeout of 30 seconds and at most 50 connections.
+    pub fn new(base_url: &str) -> Result<Self> {
+        Ok(Self {
+            base_url: Url::parse(base_url)?,
+            timeout: Duration::from_secs(30),
+            max_connections: 50,
+            breaker: None,
+        })
+    }
+
+    /// Configure the upstream `name` from the `UPSTREAM_{NAME}_*` settings.
+    pub fn from_env(name: &str) -> Result<Self> {
+        let prefix = format!("UPSTREAM_{}", name.to_uppercase().replace('-', "_"));
+
+        let url_var = format!("{}_URL", prefix);
+        let base_url = config::var(&url_var).map_err(|_| eyre!("{} is not set", url_var))?;
+        let mut upstream = Self::new(&base_url)?;
+
+        if let Ok(timeout) = config::var(&format!("{}_TIMEOUT_MS", prefix)) {
+            upstream = upstream.timeout(Duration::from_millis(timeout.parse()?));
+        }
+        if let Ok(max_connections) = config::var(&format!("{}_MAX_CONNECTIONS", prefix)) {
+            upstream = upstream.max_connections(max_connections.parse()?);
+        }
+
+        if let Ok(failures) = config::var(&format!("{}_BREAKER_FAILURES", prefix)) {
+            let mut breaker = BreakerConfig::new().failure_threshold(failures.parse()?);
+            if let Ok(open_ms) = config::var(&format!("{}_BREAKER_OPEN_MS", prefix)) {
+                breaker = breaker.open_for(Duration::from_millis(open_ms.parse()?));
+            }
+            if let Ok(probes) = config::var(&format!("{}_BREAKER_PROBES", prefix)) {
+                breaker = breaker.half_open_probes(probes.parse()?);
+            }
+            upstream = upstream.circuit_breaker(breaker);
+        }
+        Ok(upstream)
+    }
+
+    /// The request timeout, including connecting.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most connections kept open to the upstream at once, after which requests wait for a connection.
+    /// At least 1.
+    #[must_use]
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Stop sending requests to the upstream while it is failing, with a [`CircuitBreaker`][].
+    #[must_use]
+    pub fn circuit_breaker(mut self, breaker: BreakerConfig) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Upstream {
+    config: UpstreamConfig,
+    client: Client,
+    counters: Arc<UpstreamCounters>,
+    breaker: Option<CircuitBreaker>,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    in_flight: AtomicU64,
+    peak_in_flight: AtomicU64,
+}
+
+/// Register the upstream `name`, replacing any previous upstream of that name.
+pub fn register(name: &'static str, upstream: UpstreamConfig) -> Result<()> {
+    let counters = Arc::new(UpstreamCounters::default());
+
+    let client: Client = surf::Config::new()
+        .set_base_url(upstream.base_url.clone())
+        .set_timeout(Some(upstream.timeout))
+        .set_max_connections_per_host(upstream.max_connections)
+        .try_into()
+        .map_err(|error| eyre!("Invalid upstream {}: {}", name, error))?;
+
+    // Outermost, so that errors of the other middleware, such as an open circuit, are classified too.
+    let client = client.with(UpstreamErrorMiddleware {
+        name,
+        timeout: upstream.timeout,
+    });
+
+    // Before the counters, so that rejected requests are not counted as sent.
+    let breaker = upstream
+        .breaker
+        .clone()
+        .map(|config| CircuitBreaker::new(name, config));
+    let client = match &breaker {
+        Some(breaker) => client.with(breaker.clone()),
+        None => client,
+    };
+    let client = client.with(CountersMiddleware(counters.clone()));
+
+    UPSTREAMS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(
+            name,
+            Upstream {
+                config: upstream,
+                client,
+                counters,
+                breaker,
+            },
+        );
+    Ok(())
+}
+
+/// A client for the upstream `name`, which shares its connection pool with every other client for that upstream.
+///
+/// Errors with a `500 Internal Server Error` if no upstream of that name has been registered.
+pub fn client(name: &str) -> tide::Result<Client> {
+    UPSTREAMS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(name)
+        .map(|upstream| upstream.client.clone())
+        .ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("No upstream registered as {}", name),
+            )
+        })
+}
+
+/// Counts an upstream's requests.
+#[derive(Debug)]
+struct CountersMiddleware(Arc<UpstreamCounters>);
+
+#[surf::utils::async_trait]
+impl Middleware for CountersMiddleware {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        let counters = &self.0;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        let in_flight = counters.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        counters
+            .peak_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+
+        // Decremented on drop, so that cancelled requests are no longer counted.
+        let guard = InFlightGuard(&counters.in_flight);
+        let res = next.run(req, client).await;
+        drop(guard);
+
+        match &res {
+            Ok(res) if !res.status().is_server_error() => (),
+            _ => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+/// Classifies an upstream's failed requests as a `502 Bad Gateway`, or a `504 Gateway Timeout` if they timed out.
+#[derive(Debug)]
+struct UpstreamErrorMiddleware {
+    name: &'static str,
+    timeout: Duration,
+}
+
+#[surf::utils::async_trait]
+impl Middleware for UpstreamErrorMiddleware {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        let start = Instant::now();
+        next.run(req, client).await.map_err(|error| {
+            // Http client backends report timeouts with errors of their own, such as curl's.
+            let timed_out = start.elapsed() >= self.timeout || is_timeout(&error);
+            upstream_error(self.name, error, timed_out)
+        })
+    }
+}
+
+fn is_timeout(error: &surf::Error) -> bool {
+    let error: &anyhow::Error = error.as_ref();
+    error.chain().any(|error| {
+        error.is::<async_std::future::TimeoutError>()
+            || error
+                .downcast_ref::<io::Error>()
+                .is_some_and(|error| error.kind() == io::ErrorKind::TimedOut)
+    })
+}
+
+fn upstream_error(upstream: &'static str, error: surf::Error, timed_out: bool) -> surf::Error {
+    if error.downcast_ref::<UpstreamError>().is_some() {
+        return error;
+    }
+
+    // Errors with a server error status of their own, such as an open circuit's `503`, keep it. Others, such as
+    // connection errors, have arbitrary statuses, and are not the client's.
+    let status = match error.status() {
+        _ if timed_out => StatusCode::GatewayTimeout,
+        StatusCode::InternalServerError => StatusCode::BadGateway,
+        status if status.is_server_error() => status,
+        _ => StatusCode::BadGateway,
+    };
+
+    surf::Error::new(
+        status,
+        UpstreamError {
+            upstream,
+            timed_out,
+            source: error.into_inner(),
+        },
+    )
+}
+
+/// A request to an upstream which failed to get a response.
+#[derive(Debug)]
+struct UpstreamError {
+    upstream: &'static str,
+    timed_out: bool,
+    source: anyhow::Error,
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timed_out {
+            write!(f, "Upstream {} timed out", self.upstream)
+        } else {
+            write!(f, "Upstream {} failed: {}", self.upstream, self.source)
+        }
+    }
+}
+
+impl Error for UpstreamError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// The upstream which `error` is from, if it is from a registered upstream.
+pub(crate) fn failed_upstream(error: &tide::Error) -> Option<&'static str> {
+    error
+        .downcast_ref::<UpstreamError>()
+        .map(|error| error.upstream)
+}
+
+/// The `JsonError` code of `error`, if it is from a registered upstream, e.g. `upstream_timeout:orders`.
+pub(crate) fn upstream_error_code(error: &tide::Error) -> Option<String> {
+    let error = error.downcast_ref::<UpstreamError>()?;
+    let kind = if error.timed_out {
+        "upstream_timeout"
+    } else {
+        "upstream_error"
+    };
+    Some(format!("{}:{}", kind, error.upstream))
+}
+
+struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An upstream's client statistics, reported under `"upstreams"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStats {
+    base_url: String,
+    timeout_ms: u64,
+    max_connections: usize,
+    requests: u64,
+    /// Requests which failed to connect, timed out, or received a server error.
+    errors: u64,
+    in_flight: u64,
+    peak_in_flight: u64,
+    /// The circuit breaker's state, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<&'static str>,
+    /// Requests rejected by an open circuit breaker, without being sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected: Option<u64>,
+}
+
+/// The statistics of every registered upstream, if there are any.
+pub(crate) fn upstream_stats() -> Option<BTreeMap<&'static str, UpstreamStats>> {
+    let upstreams = UPSTREAMS.read().unwrap_or_else(PoisonError::into_inner);
+    if upstreams.is_empty() {
+        return None;
+    }
+
+    let stats = upstreams
+        .iter()
+        .map(|(name, upstream)| {
+            let counters = &upstream.counters;
+            let stats = UpstreamStats {
+                base_url: upstream.config.base_url.to_string(),
+                timeout_ms: upstream.config.timeout.as_millis() as u64,
+                max_connections: upstream.config.max_connections,
+                requests: counters.requests.load(Ordering::Relaxed),
+                errors: counters.errors.load(Ordering::Relaxed),
+                in_flight: counters.in_flight.load(Ordering::Relaxed),
+                peak_in_flight: counters.peak_in_flight.load(Ordering::Relaxed),
+                circuit: upstream
+                    .breaker
+                    .as_ref()
+                    .map(|breaker| breaker.state().as_str()),
+                rejected: upstream.breaker.as_ref().map(CircuitBreaker::rejected),
+            };
+            (*name, stats)
+        })
+        .collect();
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn shares_clients_and_counts_requests() -> surf::Result<()> {
+        let (addr, _handle) =
+            test_utils::spawn_server((), |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("ok").get(|_| async { Ok("ok") });
+                server
+                    .at("fail")
+                    .get(|_| async { Err::<&str, _>(tide::Error::from_str(503, "Down")) });
+            })
+            .await?;
+
+        let upstream = UpstreamConfig::new(&format!("http://{}/api/v1/", addr))
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?
+            .max_connections(2);
+        register("registry-test", upstream)
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+        let body = client("registry-test")?.get("ok").recv_string().await?;
+        assert_eq!(body, "ok");
+        let res = client("registry-test")?.get("fail").await?;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert!(client("unregistered").is_err());
+
+        let stats = upstream_stats().unwrap_or_default();
+        let stats = serde_json::to_value(&stats["registry-test"])?;
+        assert_eq!(stats["requests"], 2);
+        assert_eq!(stats["errors"], 1);
+        assert_eq!(stats["inFlight"], 0);
+        assert_eq!(stats["maxConnections"], 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn classifies_upstream_failures() -> surf::Result<()> {
+        use tide::http::{self, Method};
+
+        use crate::middleware::{JsonErrorMiddleware, RequestIdMiddleware};
+        use crate::JsonError;
+
+        let (addr, _handle) =
+            test_utils::spawn_server((), |mut server: tide::Route<'_, Arc<()>>| {
+                server.at("slow").get(|_| async {
+                    async_std::task::sleep(Duration::from_millis(500)).await;
+                    Ok("slow")
+                });
+            })
+            .await?;
+        let upstream = UpstreamConfig::new(&format!("http://{}/api/v1/", addr))
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?
+            .timeout(Duration::from_millis(50));
+        register("timeout-test", upstream)
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+        // Nothing listens on a port which was just freed.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let upstream = UpstreamConfig::new(&format!("http://{}/", closed))
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+        register("refused-test", upstream)
+            .map_err(|error| surf::Error::from_str(500, error.to_string()))?;
+
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.with(JsonErrorMiddleware::new());
+        server
+            .at("/:upstream")
+            .get(|req: tide::Request<()>| async move {
+                let upstream = req.param("upstream")?;
+                client(upstream)?.get("slow").recv_string().await
+            });
+
+        for (upstream, status, code) in [
+            ("timeout-test", 504, "upstream_timeout:timeout-test"),
+            ("refused-test", 502, "upstream_error:refused-test"),
+        ] {
+            let url = Url::parse("http://localhost/")?.join(upstream)?;
+            let mut res: http::Response =
+                server.respond(http::Request::new(Method::Get, url)).await?;
+            assert_eq!(res.status(), status);
+            let error: JsonError = res.body_json().await?;
+            assert_eq!(error.code.as_deref(), Some(code));
+        }
+        Ok(())
+    }
+}
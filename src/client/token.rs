@@ -0,0 +1,359 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_std::task;
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use surf::http::headers::AUTHORIZATION;
+use surf::middleware::{Middleware, Next};
+use surf::{Body, Client, Request, Response, StatusCode};
+
+use crate::config;
+use crate::logging::log_kv;
+
+/// How many times a token request is attempted before failing.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Fetches OAuth2 client-credentials tokens for service-to-service requests, and caches them until shortly before
+/// they expire.
+///
+/// Clones share the same cached token. Concurrent callers wait on a single refresh, rather than each fetching a token,
+/// and failed token requests are retried with jittered exponential backoff. If a refresh fails while the cached token
+/// has not yet expired, the cached token is used.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::client::TokenProvider;
+///
+/// # #[allow(dead_code)]
+/// async fn list_orders() -> color_eyre::eyre::Result<String> {
+///     // From OAUTH_TOKEN_URL, OAUTH_CLIENT_ID, OAUTH_CLIENT_SECRET, and optionally OAUTH_SCOPE and OAUTH_AUDIENCE.
+///     let tokens = TokenProvider::from_env()?;
+///
+///     let client = surf::client().with(tokens.middleware());
+///     let orders = client.get("https://orders.internal/api/v1/orders").recv_string().await;
+///     orders.map_err(|error| color_eyre::eyre::eyre!(error))
+/// }
+/// ```
+#[derive(Clone)]
+pub struct TokenProvider {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    audience: Option<String>,
+    refresh_before: Duration,
+    http: Client,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+#[derive(Clone)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl Debug for TokenProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenProvider")
+            .field("token_url", &self.inner.token_url)
+            .field("client_id", &self.inner.client_id)
+            .field("scope", &self.inner.scope)
+            .field("audience", &self.inner.audience)
+            .finish()
+    }
+}
+
+impl TokenProvider {
+    /// Create a new `TokenProvider`, which requests tokens from `token_url` with the client credentials.
+    #[must_use]
+    pub fn new(
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                token_url: token_url.into(),
+                client_id: client_id.into(),
+                client_secret: client_secret.into(),
+                scope: None,
+                audience: None,
+                refresh_before: Duration::from_secs(60),
+                http: Client::new(),
+                cached: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Create a new `TokenProvider` from the `OAUTH_TOKEN_URL`, `OAUTH_CLIENT_ID`, and `OAUTH_CLIENT_SECRET` settings,
+    /// and optionally `OAUTH_SCOPE` and `OAUTH_AUDIENCE`.
+    pub fn from_env() -> Result<Self> {
+        let required = |name: &str| config::var(name).map_err(|_| eyre!("{} is not set", name));
+
+        let mut provider = Self::new(
+            required("OAUTH_TOKEN_URL")?,
+            required("OAUTH_CLIENT_ID")?,
+            required("OAUTH_CLIENT_SECRET")?,
+        );
+        if let Ok(scope) = config::var("OAUTH_SCOPE") {
+            provider = provider.scope(scope);
+        }
+        if let Ok(audience) = config::var("OAUTH_AUDIENCE") {
+            provider = provider.audience(audience);
+        }
+        Ok(provider)
+    }
+
+    /// The `scope` to request tokens for.
+    #[must_use]
+    pub fn scope(self, scope: impl Into<String>) -> Self {
+        self.configure(|inner| inner.scope = Some(scope.into()))
+    }
+
+    /// The `audience` to request tokens for, as some providers require.
+    #[must_use]
+    pub fn audience(self, audience: impl Into<String>) -> Self {
+        self.configure(|inner| inner.audience = Some(audience.into()))
+    }
+
+    /// How long before a token expires that it is refreshed. Defaults to 60 seconds.
+    #[must_use]
+    pub fn refresh_before(self, refresh_before: Duration) -> Self {
+        self.configure(|inner| inner.refresh_before = refresh_before)
+    }
+
+    fn configure(self, configure: impl FnOnce(&mut Inner)) -> Self {
+        let mut inner = match Arc::try_unwrap(self.inner) {
+            Ok(inner) => inner,
+            Err(shared) => Inner {
+                token_url: shared.token_url.clone(),
+                client_id: shared.client_id.clone(),
+                client_secret: shared.client_secret.clone(),
+                scope: shared.scope.clone(),
+                audience: shared.audience.clone(),
+                refresh_before: shared.refresh_before,
+                http: shared.http.clone(),
+                cached: Mutex::new(None),
+            },
+        };
+        configure(&mut inner);
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// A surf middleware which sends this provider's token as an `Authorization: Bearer` header on every request.
+    #[must_use]
+    pub fn middleware(&self) -> TokenMiddleware {
+        TokenMiddleware {
+            provider: self.clone(),
+        }
+    }
+
+    /// A current access token, fetching a new one if none is cached or the cached one is about to expire.
+    pub async fn token(&self) -> surf::Result<String> {
+        let mut cached = self.inner.cached.lock().await;
+
+        let now = Instant::now();
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > now + self.inner.refresh_before {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        match self.fetch().await {
+            Ok(token) => {
+                let access_token = token.access_token.clone();
+                *cached = Some(token);
+                Ok(access_token)
+            }
+            Err(error) => match cached.as_ref() {
+                Some(token) if token.expires_at > Instant::now() => {
+                    log_kv!(
+                        warn,
+                        { token_url: self.inner.token_url },
+                        "OAuth token refresh failed, using the cached token until it expires: {}", error
+                    );
+                    Ok(token.access_token.clone())
+                }
+                _ => Err(error),
+            },
+        }
+    }
+
+    /// Discard the cached token, such as after an upstream rejects it, so that the next request fetches a new one.
+    pub async fn invalidate(&self) {
+        *self.inner.cached.lock().await = None;
+    }
+
+    /// Request a token, retrying failures with jittered exponential backoff.
+    async fn fetch(&self) -> surf::Result<CachedToken> {
+        let mut attempt = 1;
+        loop {
+            match self.request_token().await {
+                Ok(token) => return Ok(token),
+                Err(error) if attempt < MAX_ATTEMPTS => {
+                    let backoff = jittered(Duration::from_millis(100) * 2_u32.pow(attempt - 1));
+                    log_kv!(
+                        warn,
+                        {
+                            token_url: self.inner.token_url,
+                            attempt: attempt,
+                            retry_ms: backoff.as_millis() as u64,
+                        },
+                        "OAuth token request failed: {}", error
+                    );
+                    task::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    async fn request_token(&self) -> surf::Result<CachedToken> {
+        let inner = &self.inner;
+
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &inner.scope {
+            form.push(("scope", scope));
+        }
+        if let Some(audience) = &inner.audience {
+            form.push(("audience", audience));
+        }
+
+        let credentials = base64::encode(format!("{}:{}", inner.client_id, inner.client_secret));
+        let requested_at = Instant::now();
+        let mut res = inner
+            .http
+            .post(&inner.token_url)
+            .header(AUTHORIZATION, format!("Basic {}", credentials))
+            .body(Body::from_form(&form)?)
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(surf::Error::from_str(
+                StatusCode::BadGateway,
+                format!("Token endpoint responded with {}", res.status()),
+            ));
+        }
+
+        let token: TokenResponse = res.body_json().await?;
+        Ok(CachedToken {
+            access_token: token.access_token,
+            // Tokens without an expiry are refreshed every 5 minutes.
+            expires_at: requested_at + Duration::from_secs(token.expires_in.unwrap_or(300)),
+        })
+    }
+}
+
+/// Sends a [`TokenProvider`][]'s token on every request of a `surf::Client`, from [`TokenProvider::middleware`][].
+///
+/// If a response is `401 Unauthorized`, the cached token is discarded, so that the next request fetches a new one.
+#[derive(Clone, Debug)]
+pub struct TokenMiddleware {
+    provider: TokenProvider,
+}
+
+#[surf::utils::async_trait]
+impl Middleware for TokenMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> surf::Result<Response> {
+        let token = self.provider.token().await?;
+        req.insert_header(AUTHORIZATION, format!("Bearer {}", token));
+
+        let res = next.run(req, client).await?;
+        if res.status() == StatusCode::Unauthorized {
+            self.provider.invalidate().await;
+        }
+        Ok(res)
+    }
+}
+
+/// `backoff`, plus up to as much again at random, so that clients which failed together do not retry together.
+fn jittered(backoff: Duration) -> Duration {
+    let random = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+    backoff + backoff * random / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+    use tide::Request as TideRequest;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn caches_and_sends_tokens() -> surf::Result<()> {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let (addr, _handle) =
+            test_utils::spawn_server((), move |mut server: tide::Route<'_, Arc<()>>| {
+                let counter = counter.clone();
+                server.at("token").post(move |mut req: TideRequest<Arc<()>>| {
+                let counter = counter.clone();
+                async move {
+                    let form = req.body_string().await?;
+                    let authorized = req.header(AUTHORIZATION).map(|v| v.as_str())
+                        == Some("Basic aWQ6c2VjcmV0");
+                    if !authorized || !form.contains("grant_type=client_credentials") {
+                        return Err(tide::Error::from_str(401, "Bad client credentials"));
+                    }
+                    let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                    Ok(json!({ "access_token": format!("token-{}", n), "expires_in": 3600 }))
+                }
+            });
+                server
+                    .at("whoami")
+                    .get(|req: TideRequest<Arc<()>>| async move {
+                        Ok(req
+                            .header(AUTHORIZATION)
+                            .map(|v| v.as_str().to_string())
+                            .unwrap_or_default())
+                    });
+            })
+            .await?;
+
+        let provider = TokenProvider::new(format!("http://{}/api/v1/token", addr), "id", "secret");
+        assert_eq!(provider.token().await?, "token-1");
+        assert_eq!(provider.token().await?, "token-1");
+
+        let client = surf::client().with(provider.middleware());
+        let whoami = client
+            .get(format!("http://{}/api/v1/whoami", addr))
+            .recv_string()
+            .await?;
+        assert_eq!(whoami, "Bearer token-1");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        // Tokens which expire within `refresh_before` are refreshed.
+        let provider = provider.refresh_before(Duration::from_secs(7200));
+        assert_eq!(provider.token().await?, "token-2");
+        assert_eq!(provider.token().await?, "token-3");
+
+        let provider = TokenProvider::new(format!("http://{}/api/v1/token", addr), "id", "wrong");
+        assert!(provider.token().await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use color_eyre::eyre::WrapErr;
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+
+use crate::setup::Result;
+
+/// The file names checked in the working directory if `PREROLL_CONFIG` is not set.
+const DEFAULT_PATHS: [&str; 3] = ["preroll.toml", "preroll.yaml", "preroll.yml"];
+
+static FILE: Lazy<RwLock<Arc<Map<String, Value>>>> = Lazy::new(|| RwLock::new(Arc::default()));
+
+/// Load the config file, from `PREROLL_CONFIG` or else the first of `DEFAULT_PATHS` which exists.
+///
+/// Returns the path which was loaded, if any.
+pub(crate) fn load() -> Result<Option<PathBuf>> {
+    let path = match super::var("PREROLL_CONFIG") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => match DEFAULT_PATHS.iter().map(Path::new).find(|p| p.exists()) {
+            Some(path) => path.to_path_buf(),
+            None => return Ok(None),
+        },
+    };
+
+    let contents = fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Unable to read config file {}", path.display()))?;
+    let values = parse(&path, &contents)
+        .wrap_err_with(|| format!("Invalid config file {}", path.display()))?;
+
+    if let Ok(mut file) = FILE.write() {
+        *file = Arc::new(values);
+    }
+
+    Ok(Some(path))
+}
+
+/// Parse a config file as YAML if it has a `.yaml` / `.yml` extension, otherwise as TOML.
+fn parse(path: &Path, contents: &str) -> Result<Map<String, Value>> {
+    let is_yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml") | Some("yml")
+    );
+
+    let values = if is_yaml {
+        serde_yaml::from_str(contents)?
+    } else {
+        toml::from_str(contents)?
+    };
+
+    Ok(values)
+}
+
+/// Look up a value by key, ignoring case. Nested values can be looked up by dotted path, e.g. `app.greeting`.
+pub(crate) fn get(key: &str) -> Option<Value> {
+    get_in(&current(), key)
+}
+
+/// The currently loaded config file values, which are replaced rather than mutated on reload.
+pub(crate) fn current() -> Arc<Map<String, Value>> {
+    FILE.read().map(|file| file.clone()).unwrap_or_default()
+}
+
+/// Look up a value by key in `file`, as with [`get`][].
+pub(crate) fn get_in(file: &Map<String, Value>, key: &str) -> Option<Value> {
+    let mut parts = key.split('.');
+    let mut value = find(file, parts.next()?)?;
+    for part in parts {
+        value = find(value.as_object()?, part)?;
+    }
+
+    Some(value.clone())
+}
+
+/// Every top-level string value in the config file.
+#[cfg(feature = "aws-secrets")]
+pub(crate) fn string_values() -> Vec<String> {
+    FILE.read()
+        .map(|file| {
+            file.values()
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn find<'a>(map: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    map.get(key).or_else(|| {
+        map.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toml_and_yaml() -> Result<()> {
+        let toml = parse(
+            Path::new("preroll.toml"),
+            "port = 8081\n\n[app]\ngreeting = \"hello\"\n",
+        )?;
+        let yaml = parse(
+            Path::new("preroll.yml"),
+            "port: 8081\napp:\n  greeting: hello\n",
+        )?;
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml.get("port"), Some(&Value::from(8081)));
+        assert_eq!(
+            find(&toml, "APP").and_then(|app| app.get("greeting")),
+            Some(&Value::from("hello"))
+        );
+        Ok(())
+    }
+}
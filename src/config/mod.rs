@@ -0,0 +1,746 @@
+//! Typed configuration, as an alternative to configuring a service only through environment variables.
+//!
+//! Settings are layered, highest precedence first:
+//! 1. Values set on an installed [`Config`][].
+//! 2. Environment variables.
+//! 3. The config file: `PREROLL_CONFIG`, or else `preroll.toml` / `preroll.yaml` / `preroll.yml` in the working directory.
+//! 4. preroll's defaults.
+//!
+//! Config file keys are the environment variable names, in any case, e.g. `port = 8081` or `loglevel = "info"`.
+//! The config file may also contain arbitrary application settings, which can be read with [`ConfigExt`][],
+//! for example from within `setup_state`:
+//!
+//! ```toml
+//! port = 8081
+//! loglevel = "info,sqlx=warn"
+//!
+//! [app]
+//! greeting = "Hello World!"
+//! ```
+//!
+//! ```no_run
+//! use preroll::config::{Config, ConfigExt};
+//!
+//! # #[allow(dead_code)]
+//! #[derive(serde::Deserialize)]
+//! struct AppConfig {
+//!     greeting: String,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! struct AppState {
+//!     greeting: String,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_app_state() -> preroll::SetupResult<AppState> {
+//!     let app: AppConfig = Config::current()
+//!         .config_value("app")?
+//!         .ok_or_else(|| color_eyre::eyre::eyre!("Missing [app] config"))?;
+//!
+//!     Ok(AppState {
+//!         greeting: app.greeting,
+//!     })
+//! }
+//! ```
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use preroll::config::Config;
+//! use tide::Route;
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, std::sync::Arc<()>>) {
+//!     server.at("hello-world").get(|_| async { Ok("Hello World!") });
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn config() -> Config {
+//!     Config::new()
+//!         .port(8081)
+//!         .log_level("info,sqlx=warn")
+//!         .slow_request_threshold(Duration::from_millis(500))
+//! }
+//!
+//! preroll::main!("hello-world", config = config(), setup_routes);
+//! ```
+
+use std::collections::BTreeMap;
+use std::env::{self, VarError};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::builtins::deprecations;
+use crate::snapshot::Snapshot;
+use crate::IdFormat;
+
+mod file;
+mod validate;
+
+pub(crate) use file::load as load_file;
+pub(crate) use validate::validate;
+
+static CONFIG: Lazy<RwLock<BTreeMap<&'static str, String>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// Settings which the service requires, checked by [`validate`][].
+static REQUIRED: Lazy<RwLock<Vec<&'static str>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// A typed configuration builder, layered over environment variables.
+///
+/// Each setter overrides the environment variable of the same meaning, as documented at the crate root.
+/// Any other setting can be overridden by name with [`Config::set`][].
+///
+/// A `Config` has no effect until it is [installed][Config::install], which `preroll::main!` does automatically
+/// when given a `config = ...` argument.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    vars: BTreeMap<&'static str, String>,
+    /// Config file values frozen by [`Config::snapshot`][], rather than read live.
+    file: Option<Arc<Map<String, Value>>>,
+    required: Vec<&'static str>,
+}
+
+impl Config {
+    /// Create a new, empty configuration, where every setting falls back to the environment.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of the currently installed configuration.
+    #[must_use]
+    pub fn current() -> Self {
+        Self {
+            vars: CONFIG
+                .read()
+                .map(|config| config.clone())
+                .unwrap_or_default(),
+            file: None,
+            required: REQUIRED
+                .read()
+                .map(|required| required.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// A copy of the currently installed configuration, together with the current config file values,
+    /// such that [`ConfigExt`][] reads from it are unaffected by a later [reload][crate::on_reload].
+    #[must_use]
+    pub fn snapshot() -> Self {
+        Self {
+            file: Some(file::current()),
+            ..Self::current()
+        }
+    }
+
+    /// Override any setting by its environment variable name, e.g. `"HONEYCOMB_DATASET"`.
+    #[must_use]
+    pub fn set(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.vars.insert(name, value.to_string());
+        self
+    }
+
+    /// Require a setting, e.g. an application API key, to be set in some layer.
+    ///
+    /// All required settings, and all of preroll's own settings, are checked at startup,
+    /// which fails with a single error listing every missing or invalid value.
+    #[must_use]
+    pub fn require(mut self, name: &'static str) -> Self {
+        self.required.push(name);
+        self
+    }
+
+    /// The hostname to listen on. (`HOST`)
+    #[must_use]
+    pub fn host(self, host: impl Into<String>) -> Self {
+        self.set("HOST", host.into())
+    }
+
+    /// The port to listen on. (`PORT`)
+    #[must_use]
+    pub fn port(self, port: u16) -> Self {
+        self.set("PORT", port)
+    }
+
+    /// If `PORT` is in use, how many of the following ports to try, e.g. for local development. (`PORT_FALLBACK_RANGE`)
+    #[must_use]
+    pub fn port_fallback_range(self, ports: u16) -> Self {
+        self.set("PORT_FALLBACK_RANGE", ports)
+    }
+
+    /// How long to wait for requests in flight to finish once the server is stopped. (`SHUTDOWN_GRACE_MS`)
+    #[must_use]
+    pub fn shutdown_grace(self, grace: Duration) -> Self {
+        self.set("SHUTDOWN_GRACE_MS", grace.as_millis())
+    }
+
+    /// How many tasks accept connections, for many-core hosts. (`ACCEPT_WORKERS`)
+    #[must_use]
+    pub fn accept_workers(self, workers: usize) -> Self {
+        self.set("ACCEPT_WORKERS", workers)
+    }
+
+    /// Whether each accepting task listens on its own `SO_REUSEPORT` socket, on unix. (`REUSE_PORT`)
+    #[must_use]
+    pub fn reuse_port(self, reuse_port: bool) -> Self {
+        self.set("REUSE_PORT", reuse_port)
+    }
+
+    /// The deployment environment, e.g. `"production"`. (`ENVIRONMENT`)
+    #[must_use]
+    pub fn environment(self, environment: impl Into<String>) -> Self {
+        self.set("ENVIRONMENT", environment.into())
+    }
+
+    /// Force-load environment variables from `.env`, even in production-mode. (`FORCE_DOTENV`)
+    #[must_use]
+    pub fn force_dotenv(self) -> Self {
+        self.set("FORCE_DOTENV", "1")
+    }
+
+    /// The logger's level filter directives, e.g. `"info,sqlx=warn"`. (`LOGLEVEL`)
+    #[must_use]
+    pub fn log_level(self, directives: impl Into<String>) -> Self {
+        self.set("LOGLEVEL", directives.into())
+    }
+
+    /// A file to also write production-mode JSON logs to. (`LOGFILE`)
+    #[must_use]
+    pub fn log_file(self, path: impl Into<String>) -> Self {
+        self.set("LOGFILE", path.into())
+    }
+
+    /// The schema of JSON log records: `preroll`, `ecs`, or `datadog`. (`LOG_FORMAT`)
+    #[must_use]
+    pub fn log_format(self, format: impl Into<String>) -> Self {
+        self.set("LOG_FORMAT", format.into())
+    }
+
+    /// Where to write access logs rather than with application logs: `stdout`, `stderr`, `fd:{n}`, or a file path. (`ACCESS_LOG`)
+    #[must_use]
+    pub fn access_log(self, destination: impl Into<String>) -> Self {
+        self.set("ACCESS_LOG", destination.into())
+    }
+
+    /// Event loop lag over this is logged at `WARN`. (`EVENT_LOOP_LAG_WARN_MS`)
+    #[must_use]
+    pub fn event_loop_lag_warning(self, threshold: Duration) -> Self {
+        self.set("EVENT_LOOP_LAG_WARN_MS", threshold.as_millis())
+    }
+
+    /// Successful responses slower than this are logged at `WARN`. (`SLOW_REQUEST_MS`)
+    #[must_use]
+    pub fn slow_request_threshold(self, threshold: Duration) -> Self {
+        self.set("SLOW_REQUEST_MS", threshold.as_millis())
+    }
+
+    /// The load balancers and other proxies, as addresses or CIDR ranges such as `10.0.0.0/8`, whose `Forwarded` or
+    /// `X-Forwarded-For` headers are believed. (`TRUSTED_PROXIES`)
+    #[must_use]
+    pub fn trusted_proxies<I: IntoIterator<Item = S>, S: AsRef<str>>(self, proxies: I) -> Self {
+        let proxies: Vec<String> = proxies
+            .into_iter()
+            .map(|proxy| proxy.as_ref().to_string())
+            .collect();
+        self.set("TRUSTED_PROXIES", proxies.join(","))
+    }
+
+    /// Paths whose successful responses are logged at `TRACE` rather than `INFO`, and which are not traced,
+    /// e.g. `["/", "/probes/*"]`, where a trailing `*` matches any path with that prefix. (`QUIET_PATHS`)
+    #[must_use]
+    pub fn quiet_paths<I: IntoIterator<Item = S>, S: AsRef<str>>(self, paths: I) -> Self {
+        let paths: Vec<String> = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_string())
+            .collect();
+        self.set("QUIET_PATHS", paths.join(","))
+    }
+
+    /// Route requests by the API version of the vendor media type in their `Accept` header, e.g. `myservice` for
+    /// `application/vnd.myservice.v2+json`. (`ACCEPT_VERSION_VENDOR`)
+    #[must_use]
+    pub fn accept_version_vendor(self, vendor: impl Into<String>) -> Self {
+        self.set("ACCEPT_VERSION_VENDOR", vendor.into())
+    }
+
+    /// The locales negotiated from requests' `Accept-Language`, e.g. `["en", "fr"]`. Defaults to the locales of the
+    /// [message catalog][crate::i18n::Catalog]. (`SUPPORTED_LOCALES`)
+    #[must_use]
+    pub fn supported_locales<I: IntoIterator<Item = S>, S: AsRef<str>>(self, locales: I) -> Self {
+        let locales: Vec<String> = locales
+            .into_iter()
+            .map(|locale| locale.as_ref().to_string())
+            .collect();
+        self.set("SUPPORTED_LOCALES", locales.join(","))
+    }
+
+    /// The locale of requests which accept none of the supported locales. (`DEFAULT_LOCALE`)
+    #[must_use]
+    pub fn default_locale(self, locale: impl Into<String>) -> Self {
+        self.set("DEFAULT_LOCALE", locale.into())
+    }
+
+    /// The most attempts at delivering a [webhook][crate::webhooks::dispatch] before it is dead-lettered. (`WEBHOOK_MAX_ATTEMPTS`)
+    #[must_use]
+    pub fn webhook_max_attempts(self, attempts: u32) -> Self {
+        self.set("WEBHOOK_MAX_ATTEMPTS", attempts)
+    }
+
+    /// How often pending webhook deliveries are attempted. (`WEBHOOK_POLL_MS`)
+    #[must_use]
+    pub fn webhook_poll_interval(self, interval: Duration) -> Self {
+        self.set("WEBHOOK_POLL_MS", interval.as_millis())
+    }
+
+    /// The maximum time a single webhook delivery may take. (`WEBHOOK_TIMEOUT_MS`)
+    #[must_use]
+    pub fn webhook_timeout(self, timeout: Duration) -> Self {
+        self.set("WEBHOOK_TIMEOUT_MS", timeout.as_millis())
+    }
+
+    /// Whether pending webhook deliveries are stored in postgres, rather than in memory. (`WEBHOOK_PERSIST`)
+    #[cfg(feature = "postgres")]
+    #[must_use]
+    pub fn webhook_persist(self, persist: bool) -> Self {
+        self.set("WEBHOOK_PERSIST", persist)
+    }
+
+    /// How often instances which are not the [leader][crate::leader] of an election retry,
+    /// and the leader checks its lock. (`LEADER_CHECK_MS`)
+    #[cfg(feature = "postgres")]
+    #[must_use]
+    pub fn leader_check_interval(self, interval: Duration) -> Self {
+        self.set("LEADER_CHECK_MS", interval.as_millis())
+    }
+
+    /// How many in-process workers run [jobs][crate::jobs]. (`JOBS_WORKERS`)
+    #[cfg(feature = "pg-jobs")]
+    #[must_use]
+    pub fn jobs_workers(self, workers: usize) -> Self {
+        self.set("JOBS_WORKERS", workers)
+    }
+
+    /// How often idle job workers check for due jobs. (`JOBS_POLL_MS`)
+    #[cfg(feature = "pg-jobs")]
+    #[must_use]
+    pub fn jobs_poll_interval(self, interval: Duration) -> Self {
+        self.set("JOBS_POLL_MS", interval.as_millis())
+    }
+
+    /// The most attempts at running a job before it is marked as failed. (`JOBS_MAX_ATTEMPTS`)
+    #[cfg(feature = "pg-jobs")]
+    #[must_use]
+    pub fn jobs_max_attempts(self, attempts: i32) -> Self {
+        self.set("JOBS_MAX_ATTEMPTS", attempts)
+    }
+
+    /// The format of generated request and correlation ids. (`ID_FORMAT`)
+    #[must_use]
+    pub fn id_format(self, format: IdFormat) -> Self {
+        self.set("ID_FORMAT", format)
+    }
+
+    /// The maximum time a single downstream health check may take. (`HEALTH_CHECK_TIMEOUT_MS`)
+    #[must_use]
+    pub fn health_check_timeout(self, timeout: Duration) -> Self {
+        self.set("HEALTH_CHECK_TIMEOUT_MS", timeout.as_millis())
+    }
+
+    /// How long downstream health check results are reused for. (`HEALTH_CHECK_CACHE_MS`)
+    #[must_use]
+    pub fn health_check_cache(self, ttl: Duration) -> Self {
+        self.set("HEALTH_CHECK_CACHE_MS", ttl.as_millis())
+    }
+
+    /// Wait for every health check to pass before listening, retrying for at most `max_wait`.
+    /// (`STARTUP_CHECKS`, `STARTUP_CHECK_MAX_WAIT_MS`)
+    #[must_use]
+    pub fn startup_checks(self, max_wait: Duration) -> Self {
+        self.set("STARTUP_CHECKS", "true")
+            .set("STARTUP_CHECK_MAX_WAIT_MS", max_wait.as_millis())
+    }
+
+    /// Requests with more headers than this are rejected. (`MAX_REQUEST_HEADERS`)
+    #[must_use]
+    pub fn max_request_headers(self, max: usize) -> Self {
+        self.set("MAX_REQUEST_HEADERS", max)
+    }
+
+    /// Requests with larger headers than this are rejected. (`MAX_REQUEST_HEADER_BYTES`)
+    #[must_use]
+    pub fn max_request_header_bytes(self, max: usize) -> Self {
+        self.set("MAX_REQUEST_HEADER_BYTES", max)
+    }
+
+    /// The most requests handled at once, after which requests are queued briefly, and then shed. (`MAX_INFLIGHT`)
+    #[must_use]
+    pub fn max_inflight(self, max: usize) -> Self {
+        self.set("MAX_INFLIGHT", max)
+    }
+
+    /// How long a request over a concurrency limit waits before it is shed. (`MAX_INFLIGHT_QUEUE_MS`)
+    #[must_use]
+    pub fn max_inflight_queue(self, queue_timeout: Duration) -> Self {
+        self.set("MAX_INFLIGHT_QUEUE_MS", queue_timeout.as_millis())
+    }
+
+    /// The percentages of `MAX_INFLIGHT` in use by other requests at which low, normal, and high priority requests are shed.
+    /// (`SHED_LOW_PERCENT`, `SHED_NORMAL_PERCENT`, `SHED_HIGH_PERCENT`)
+    #[must_use]
+    pub fn shed_percent(self, low: u32, normal: u32, high: u32) -> Self {
+        self.set("SHED_LOW_PERCENT", low)
+            .set("SHED_NORMAL_PERCENT", normal)
+            .set("SHED_HIGH_PERCENT", high)
+    }
+
+    /// The most responses cached in memory by `CacheMiddleware`, when there is no other backend. (`CACHE_MAX_ENTRIES`)
+    #[must_use]
+    pub fn cache_max_entries(self, max: usize) -> Self {
+        self.set("CACHE_MAX_ENTRIES", max)
+    }
+
+    /// The OAuth2 client credentials, and token endpoint, of [`TokenProvider::from_env`][crate::client::TokenProvider::from_env].
+    /// (`OAUTH_TOKEN_URL`, `OAUTH_CLIENT_ID`, `OAUTH_CLIENT_SECRET`)
+    #[must_use]
+    pub fn oauth_client(
+        self,
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        self.set("OAUTH_TOKEN_URL", token_url.into())
+            .set("OAUTH_CLIENT_ID", client_id.into())
+            .set("OAUTH_CLIENT_SECRET", client_secret.into())
+    }
+
+    /// How often flags are refreshed from a flag service. (`FLAGS_REFRESH_SECS`)
+    #[must_use]
+    pub fn flags_refresh(self, interval: Duration) -> Self {
+        self.set("FLAGS_REFRESH_SECS", interval.as_secs())
+    }
+
+    /// The Unleash server, and client token, of [`UnleashProvider`][crate::flags::UnleashProvider].
+    /// (`UNLEASH_URL`, `UNLEASH_API_TOKEN`)
+    #[cfg(feature = "unleash")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "unleash")))]
+    #[must_use]
+    pub fn unleash(self, url: impl Into<String>, api_token: impl Into<String>) -> Self {
+        self.set("UNLEASH_URL", url.into())
+            .set("UNLEASH_API_TOKEN", api_token.into())
+    }
+
+    /// The SDK key of [`LaunchDarklyProvider`][crate::flags::LaunchDarklyProvider]. (`LAUNCHDARKLY_SDK_KEY`)
+    #[cfg(feature = "launchdarkly")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "launchdarkly")))]
+    #[must_use]
+    pub fn launchdarkly_sdk_key(self, sdk_key: impl Into<String>) -> Self {
+        self.set("LAUNCHDARKLY_SDK_KEY", sdk_key.into())
+    }
+
+    /// The honeycomb API key, which enables tracing export. (`HONEYCOMB_WRITEKEY`)
+    #[cfg(feature = "honeycomb")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+    #[must_use]
+    pub fn honeycomb_write_key(self, write_key: impl Into<String>) -> Self {
+        self.set("HONEYCOMB_WRITEKEY", write_key.into())
+    }
+
+    /// Log honeycomb setup failures and connect the exporter in the background, rather than failing setup.
+    /// (`HONEYCOMB_RESILIENT`)
+    #[cfg(feature = "honeycomb")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+    #[must_use]
+    pub fn honeycomb_resilient(self) -> Self {
+        self.set("HONEYCOMB_RESILIENT", "true")
+    }
+
+    /// The tracing level filter. (`TRACELEVEL`)
+    #[cfg(feature = "honeycomb")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+    #[must_use]
+    pub fn trace_level(self, level: impl Into<String>) -> Self {
+        self.set("TRACELEVEL", level.into())
+    }
+
+    /// How long responses to requests with an `Idempotency-Key` are stored. (`IDEMPOTENCY_TTL_SECS`)
+    #[cfg(feature = "idempotency")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "idempotency")))]
+    #[must_use]
+    pub fn idempotency_ttl(self, ttl: Duration) -> Self {
+        self.set("IDEMPOTENCY_TTL_SECS", ttl.as_secs())
+    }
+
+    /// How long an `Idempotency-Key` stays claimed by a request which never completes, such as if the service stops.
+    /// (`IDEMPOTENCY_LEASE_SECS`)
+    #[cfg(feature = "idempotency")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "idempotency")))]
+    #[must_use]
+    pub fn idempotency_lease(self, lease: Duration) -> Self {
+        self.set("IDEMPOTENCY_LEASE_SECS", lease.as_secs())
+    }
+
+    /// The name of the session cookie. (`SESSION_COOKIE`)
+    #[cfg(feature = "sessions")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+    #[must_use]
+    pub fn session_cookie(self, name: impl Into<String>) -> Self {
+        self.set("SESSION_COOKIE", name.into())
+    }
+
+    /// The secret session cookies are signed with, which must be the same for every instance. (`SESSION_SECRET`)
+    #[cfg(feature = "sessions")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+    #[must_use]
+    pub fn session_secret(self, secret: impl Into<String>) -> Self {
+        self.set("SESSION_SECRET", secret.into())
+    }
+
+    /// How long sessions are kept after they were last changed. (`SESSION_TTL_SECS`)
+    #[cfg(feature = "sessions")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+    #[must_use]
+    pub fn session_ttl(self, ttl: Duration) -> Self {
+        self.set("SESSION_TTL_SECS", ttl.as_secs())
+    }
+
+    /// The postgres database url. (`PGURL`)
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn pg_url(self, url: impl Into<String>) -> Self {
+        self.set("PGURL", url.into())
+    }
+
+    /// The maximum number of pooled postgres connections. (`PGMAXCONNECTIONS`)
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn pg_max_connections(self, max_connections: u32) -> Self {
+        self.set("PGMAXCONNECTIONS", max_connections)
+    }
+
+    /// The maximum lifetime of a pooled postgres connection, at minute granularity. (`PGMAXLIFETIME`)
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn pg_max_lifetime(self, max_lifetime: Duration) -> Self {
+        self.set("PGMAXLIFETIME", max_lifetime.as_secs() / 60)
+    }
+
+    /// Run migrations at startup, before listening. (`RUN_MIGRATIONS`)
+    #[cfg(feature = "postgres-migrate")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres-migrate")))]
+    #[must_use]
+    pub fn run_migrations(self) -> Self {
+        self.set("RUN_MIGRATIONS", "true")
+    }
+
+    /// The directory of migrations to run at startup. (`MIGRATIONS_DIR`)
+    #[cfg(feature = "postgres-migrate")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres-migrate")))]
+    #[must_use]
+    pub fn migrations_dir(self, dir: impl Into<String>) -> Self {
+        self.set("MIGRATIONS_DIR", dir.into())
+    }
+
+    /// The redis url, which caches `CacheMiddleware` responses in redis rather than in memory. (`REDISURL`)
+    #[cfg(feature = "redis")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "redis")))]
+    #[must_use]
+    pub fn redis_url(self, url: impl Into<String>) -> Self {
+        self.set("REDISURL", url.into())
+    }
+
+    /// Make this the active configuration, replacing any previously installed configuration.
+    ///
+    /// Must be called before setup for settings to take effect.
+    pub fn install(self) {
+        if let Ok(mut config) = CONFIG.write() {
+            *config = self.vars;
+        }
+        if let Ok(mut required) = REQUIRED.write() {
+            *required = self.required;
+        }
+    }
+}
+
+/// Read application settings from the layered configuration.
+///
+/// Keys are looked up in a [`Config`][] by name, in the environment by uppercased name,
+/// and in the config file ignoring case, with nested values by dotted path, e.g. `app.greeting`.
+pub trait ConfigExt {
+    /// Deserialize the value for `key`, if it is set in any layer.
+    ///
+    /// String values (e.g. from environment variables) are parsed as JSON if possible, so that `PORT=8081`
+    /// can be read as a number.
+    fn config_value<T: DeserializeOwned>(&self, key: &str) -> serde_json::Result<Option<T>>;
+}
+
+impl ConfigExt for Config {
+    fn config_value<T: DeserializeOwned>(&self, key: &str) -> serde_json::Result<Option<T>> {
+        let value = self
+            .vars
+            .get(key)
+            .or_else(|| self.vars.get(key.to_uppercase().as_str()))
+            .map(|value| Value::String(value.clone()))
+            .or_else(|| value(key, self.file.as_deref()));
+
+        value.map(deserialize).transpose()
+    }
+}
+
+/// Reads from the request's [`Snapshot`][crate::snapshot::Snapshot] if it has one, otherwise from the current configuration.
+impl<State> ConfigExt for tide::Request<State> {
+    fn config_value<T: DeserializeOwned>(&self, key: &str) -> serde_json::Result<Option<T>> {
+        match self.ext::<Snapshot>() {
+            Some(snapshot) => snapshot.config().config_value(key),
+            None => Config::current().config_value(key),
+        }
+    }
+}
+
+fn deserialize<T: DeserializeOwned>(value: Value) -> serde_json::Result<T> {
+    match value {
+        Value::String(string) => {
+            serde_json::from_str(&string).or_else(|_| serde_json::from_value(Value::String(string)))
+        }
+        value => serde_json::from_value(value),
+    }
+}
+
+/// Look up a value below the installed [`Config`][]: the environment, then the config file, or `file` if frozen.
+fn value(key: &str, file: Option<&Map<String, Value>>) -> Option<Value> {
+    let value = env::var(key.to_uppercase())
+        .ok()
+        .map(Value::String)
+        .or_else(|| match file {
+            Some(file) => file::get_in(file, key),
+            None => file::get(key),
+        });
+
+    #[cfg(feature = "aws-secrets")]
+    if let Some(Value::String(reference)) = &value {
+        if let Some(secret) = crate::secrets::resolved(reference) {
+            return Some(Value::String(secret));
+        }
+    }
+
+    value
+}
+
+/// Look up a setting: the installed [`Config`][] first, then the environment, then the config file.
+///
+/// With the `"aws-secrets"` feature, secret references are replaced by their resolved values.
+///
+/// A drop-in replacement for [`std::env::var`][].
+pub(crate) fn var(name: &str) -> Result<String, VarError> {
+    let value = raw_var(name)?;
+
+    #[cfg(feature = "aws-secrets")]
+    if let Some(secret) = crate::secrets::resolved(&value) {
+        return Ok(secret);
+    }
+
+    Ok(value)
+}
+
+fn raw_var(name: &str) -> Result<String, VarError> {
+    let value = CONFIG
+        .read()
+        .ok()
+        .and_then(|config| config.get(name).cloned());
+
+    if let Some(value) = value {
+        return Ok(value);
+    }
+
+    let value = env::var(name).or_else(|err| match file::get(name) {
+        Some(Value::String(string)) => Ok(string),
+        Some(Value::Number(number)) => Ok(number.to_string()),
+        Some(Value::Bool(boolean)) => Ok(boolean.to_string()),
+        _ => Err(err),
+    });
+
+    // Fall back to the deprecated name of a renamed setting.
+    match (value, deprecations::renamed_from(name)) {
+        (Err(err), Some(deprecated)) => {
+            let value = raw_var(deprecated).map_err(|_| err)?;
+            deprecations::record_renamed_var(deprecated);
+            Ok(value)
+        }
+        (value, _) => value,
+    }
+}
+
+/// Every top-level string value in any layer, such as to find secret references.
+#[cfg(feature = "aws-secrets")]
+pub(crate) fn values() -> Vec<String> {
+    let mut values: Vec<String> = CONFIG
+        .read()
+        .map(|config| config.values().cloned().collect())
+        .unwrap_or_default();
+    values.extend(env::vars().map(|(_, value)| value));
+    values.extend(file::string_values());
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_overrides_env() {
+        env::set_var("PREROLL_CONFIG_TEST_ENV", "env");
+        env::set_var("PREROLL_CONFIG_TEST_BOTH", "env");
+
+        let previous = Config::current();
+        previous
+            .clone()
+            .set("PREROLL_CONFIG_TEST_BOTH", "config")
+            .set("PREROLL_CONFIG_TEST_CONFIG", 5)
+            .install();
+
+        assert_eq!(var("PREROLL_CONFIG_TEST_ENV"), Ok("env".to_string()));
+        assert_eq!(var("PREROLL_CONFIG_TEST_BOTH"), Ok("config".to_string()));
+        assert_eq!(var("PREROLL_CONFIG_TEST_CONFIG"), Ok("5".to_string()));
+        assert_eq!(var("PREROLL_CONFIG_TEST_UNSET"), Err(VarError::NotPresent));
+
+        let config = Config::current();
+        assert_eq!(
+            config.config_value("preroll_config_test_config").ok(),
+            Some(Some(5))
+        );
+        assert_eq!(
+            config
+                .config_value::<String>("PREROLL_CONFIG_TEST_ENV")
+                .ok(),
+            Some(Some("env".to_string()))
+        );
+
+        previous.install();
+    }
+
+    #[test]
+    fn renamed_vars_fall_back_to_deprecated_names() {
+        let previous = Config::current();
+        previous
+            .clone()
+            .set("HONEYCOMBIO_WRITE_KEY", "legacy")
+            .install();
+
+        assert_eq!(var("HONEYCOMB_WRITEKEY"), Ok("legacy".to_string()));
+        assert!(deprecations::deprecations()
+            .iter()
+            .any(|deprecation| deprecation.name == "HONEYCOMBIO_WRITE_KEY"));
+
+        previous.install();
+    }
+}
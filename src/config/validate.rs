@@ -0,0 +1,162 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Result};
+
+use super::{var, REQUIRED};
+use crate::logging::{AccessLog, LogFormat};
+use crate::middleware::forwarded::TrustedProxies;
+use crate::IdFormat;
+
+/// Check every setting preroll reads during setup, and every [required][super::Config::require] setting, up front,
+/// returning a single error which lists all missing or invalid values.
+pub(crate) fn validate() -> Result<()> {
+    let mut problems = Vec::new();
+
+    let required = REQUIRED
+        .read()
+        .map(|required| required.clone())
+        .unwrap_or_default();
+    for name in required {
+        require(&mut problems, name);
+    }
+
+    check::<u16>(&mut problems, "PORT");
+    check::<AccessLog>(&mut problems, "ACCESS_LOG");
+    check::<LogFormat>(&mut problems, "LOG_FORMAT");
+    check::<u16>(&mut problems, "PORT_FALLBACK_RANGE");
+    check::<usize>(&mut problems, "ACCEPT_WORKERS");
+    check::<bool>(&mut problems, "REUSE_PORT");
+    check::<u64>(&mut problems, "SHUTDOWN_GRACE_MS");
+    check::<u64>(&mut problems, "SLOW_REQUEST_MS");
+    check::<u64>(&mut problems, "EVENT_LOOP_LAG_WARN_MS");
+    check::<TrustedProxies>(&mut problems, "TRUSTED_PROXIES");
+    check::<IdFormat>(&mut problems, "ID_FORMAT");
+    check::<u64>(&mut problems, "HEALTH_CHECK_TIMEOUT_MS");
+    check::<u64>(&mut problems, "HEALTH_CHECK_CACHE_MS");
+    check::<usize>(&mut problems, "MAX_REQUEST_HEADERS");
+    check::<usize>(&mut problems, "MAX_REQUEST_HEADER_BYTES");
+    check::<usize>(&mut problems, "MAX_INFLIGHT");
+    check::<u64>(&mut problems, "MAX_INFLIGHT_QUEUE_MS");
+    check::<u32>(&mut problems, "SHED_LOW_PERCENT");
+    check::<u32>(&mut problems, "SHED_NORMAL_PERCENT");
+    check::<u32>(&mut problems, "SHED_HIGH_PERCENT");
+    check::<u64>(&mut problems, "STARTUP_CHECK_MAX_WAIT_MS");
+    check::<usize>(&mut problems, "CACHE_MAX_ENTRIES");
+    check::<u64>(&mut problems, "SSE_KEEP_ALIVE_SECS");
+    check::<surf::Url>(&mut problems, "OAUTH_TOKEN_URL");
+    check::<u64>(&mut problems, "FLAGS_REFRESH_SECS");
+    check::<u32>(&mut problems, "WEBHOOK_MAX_ATTEMPTS");
+    check::<u64>(&mut problems, "WEBHOOK_POLL_MS");
+    check::<u64>(&mut problems, "WEBHOOK_TIMEOUT_MS");
+
+    #[cfg(feature = "unleash")]
+    check::<surf::Url>(&mut problems, "UNLEASH_URL");
+
+    #[cfg(feature = "launchdarkly")]
+    check::<surf::Url>(&mut problems, "LAUNCHDARKLY_BASE_URL");
+
+    #[cfg(feature = "idempotency")]
+    {
+        check::<u64>(&mut problems, "IDEMPOTENCY_TTL_SECS");
+        check::<u64>(&mut problems, "IDEMPOTENCY_LEASE_SECS");
+    }
+
+    #[cfg(feature = "sessions")]
+    {
+        check::<u64>(&mut problems, "SESSION_TTL_SECS");
+        let production = var("ENVIRONMENT")
+            .map(|environment| environment.starts_with("prod"))
+            .unwrap_or(false);
+        if production {
+            require(&mut problems, "SESSION_SECRET");
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    {
+        check::<sqlx::postgres::PgConnectOptions>(&mut problems, "PGURL");
+        check::<u32>(&mut problems, "PGMAXCONNECTIONS");
+        check::<u64>(&mut problems, "PGMAXLIFETIME");
+        check::<u64>(&mut problems, "PG_ACQUIRE_WARN_MS");
+        check::<u64>(&mut problems, "NOTIFICATION_RELAY_INTERVAL_MS");
+        check::<i32>(&mut problems, "NOTIFICATION_MAX_ATTEMPTS");
+        check::<u64>(&mut problems, "OUTBOX_RELAY_INTERVAL_MS");
+        check::<bool>(&mut problems, "WEBHOOK_PERSIST");
+        check::<u64>(&mut problems, "LEADER_CHECK_MS");
+    }
+
+    #[cfg(feature = "pg-jobs")]
+    {
+        check::<usize>(&mut problems, "JOBS_WORKERS");
+        check::<u64>(&mut problems, "JOBS_POLL_MS");
+        check::<i32>(&mut problems, "JOBS_MAX_ATTEMPTS");
+    }
+
+    #[cfg(feature = "sqlite")]
+    check::<sqlx::sqlite::SqliteConnectOptions>(&mut problems, "SQLITEURL");
+
+    #[cfg(all(feature = "honeycomb", not(feature = "lambda-http")))]
+    if let Err(error) = crate::builtins::sampling::SampleRules::from_config() {
+        problems.push(error.to_string());
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(eyre!(
+        "Invalid configuration, {} problem(s):\n  - {}",
+        problems.len(),
+        problems.join("\n  - ")
+    ))
+}
+
+/// Record a problem if the setting `name` is not set, or is empty.
+fn require(problems: &mut Vec<String>, name: &str) {
+    if var(name).map_or(true, |value| value.trim().is_empty()) {
+        problems.push(format!("{}: required, but not set", name));
+    }
+}
+
+/// Record a problem if the setting `name` is set, but cannot be parsed as a `T`.
+fn check<T>(problems: &mut Vec<String>, name: &str)
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(value) = var(name) {
+        if let Err(error) = value.parse::<T>() {
+            problems.push(format!("{}: invalid value \"{}\": {}", name, value, error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn lists_every_problem() {
+        env::set_var("PREROLL_VALIDATE_TEST_PORT", "http");
+        env::set_var("PREROLL_VALIDATE_TEST_VALID", "8080");
+
+        let mut problems = Vec::new();
+        require(&mut problems, "PREROLL_VALIDATE_TEST_REQUIRED");
+        require(&mut problems, "PREROLL_VALIDATE_TEST_VALID");
+        check::<u16>(&mut problems, "PREROLL_VALIDATE_TEST_PORT");
+        check::<u16>(&mut problems, "PREROLL_VALIDATE_TEST_VALID");
+        check::<u16>(&mut problems, "PREROLL_VALIDATE_TEST_UNSET");
+
+        assert_eq!(
+            problems,
+            vec![
+                "PREROLL_VALIDATE_TEST_REQUIRED: required, but not set".to_string(),
+                "PREROLL_VALIDATE_TEST_PORT: invalid value \"http\": invalid digit found in string"
+                    .to_string(),
+            ]
+        );
+    }
+}
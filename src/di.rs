@@ -0,0 +1,267 @@
+//! Request-scoped dependency injection.
+//!
+//! Constructors for service dependencies, such as upstream clients or repositories, are [registered][register] by type
+//! during setup, and handlers resolve them with [`req.inject::<T>()`][InjectRequestExt::inject], from the prelude.
+//! Each request constructs its own instance of a type the first time it is injected, and shares it for the rest of the
+//! request.
+//!
+//! Tests can swap real dependencies for mocks, without restructuring the application's state, by passing
+//! [`Overrides`][] to [`test_utils::create_client_with_overrides`][crate::test_utils::create_client_with_overrides].
+//! Overrides only apply to that test's server, so tests running in parallel do not see each other's mocks.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::di;
+//! use preroll::prelude::*;
+//! use tide::{Request, Route};
+//!
+//! pub trait Inventory: Send + Sync {
+//!     fn stock(&self, sku: &str) -> u32;
+//! }
+//!
+//! struct WarehouseInventory;
+//!
+//! impl Inventory for WarehouseInventory {
+//!     fn stock(&self, _sku: &str) -> u32 {
+//!         // ... (an upstream request) ...
+//!         7
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     di::register::<Box<dyn Inventory>, _>(|| Box::new(WarehouseInventory));
+//!     Ok(())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("stock/:sku")
+//!         .get(|req: Request<Arc<()>>| async move {
+//!             let inventory = req.inject::<Box<dyn Inventory>>()?;
+//!             Ok(inventory.stock(req.param("sku")?).to_string())
+//!         });
+//! }
+//! ```
+
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use once_cell::sync::Lazy;
+use tide::{Request, StatusCode};
+
+type Instance = Arc<dyn Any + Send + Sync>;
+type Constructor = Arc<dyn Fn() -> Instance + Send + Sync>;
+
+static REGISTRY: Lazy<RwLock<HashMap<TypeId, Constructor>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn constructor<T, F>(constructor: F) -> Constructor
+where
+    T: Send + Sync + 'static,
+    F: Fn() -> T + Send + Sync + 'static,
+{
+    Arc::new(move || {
+        let instance: Instance = Arc::new(constructor());
+        instance
+    })
+}
+
+/// Register the constructor of `T`, replacing any previously registered constructor of `T`.
+///
+/// The constructor is called once per request which injects a `T`.
+/// Dependencies which should be shared between requests, such as a connection pool, can be cloned by the constructor.
+pub fn register<T, F>(constructor_fn: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn() -> T + Send + Sync + 'static,
+{
+    REGISTRY
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(TypeId::of::<T>(), constructor(constructor_fn));
+}
+
+/// Constructors which take precedence over [registered][register] ones, for a single test server.
+///
+/// See [`test_utils::create_client_with_overrides`][crate::test_utils::create_client_with_overrides].
+#[derive(Clone, Default)]
+pub struct Overrides {
+    constructors: HashMap<TypeId, Constructor>,
+}
+
+impl Debug for Overrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Overrides")
+            .field("count", &self.constructors.len())
+            .finish()
+    }
+}
+
+impl Overrides {
+    /// Create an empty set of overrides.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct `T` with `constructor_fn`, rather than with its registered constructor.
+    #[must_use]
+    pub fn with<T, F>(mut self, constructor_fn: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        self.constructors
+            .insert(TypeId::of::<T>(), constructor(constructor_fn));
+        self
+    }
+}
+
+/// The instances constructed for a single request, set by `InjectMiddleware`.
+#[derive(Clone, Default)]
+pub(crate) struct Scope {
+    overrides: Arc<Overrides>,
+    instances: Arc<Mutex<HashMap<TypeId, Instance>>>,
+}
+
+impl Scope {
+    pub(crate) fn new(overrides: Arc<Overrides>) -> Self {
+        Self {
+            overrides,
+            instances: Arc::default(),
+        }
+    }
+
+    pub(crate) fn resolve<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let type_id = TypeId::of::<T>();
+
+        let mut instances = self
+            .instances
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let instance = match instances.get(&type_id) {
+            Some(instance) => instance.clone(),
+            None => {
+                let instance = construct(&self.overrides, type_id)?;
+                instances.insert(type_id, instance.clone());
+                instance
+            }
+        };
+        instance.downcast().ok()
+    }
+}
+
+fn construct(overrides: &Overrides, type_id: TypeId) -> Option<Instance> {
+    let constructor = match overrides.constructors.get(&type_id) {
+        Some(constructor) => constructor.clone(),
+        None => REGISTRY
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&type_id)?
+            .clone(),
+    };
+    // Called without holding the registry lock, so that constructors may themselves register.
+    Some(constructor())
+}
+
+/// An extension trait for resolving registered dependencies.
+pub trait InjectRequestExt {
+    /// The request's instance of `T`, constructed on first use by its [registered][register] constructor,
+    /// or by a test's [`Overrides`][].
+    ///
+    /// Errors with a `500 Internal Server Error` if no constructor of `T` has been registered.
+    fn inject<T: Send + Sync + 'static>(&self) -> tide::Result<Arc<T>>;
+}
+
+impl<State> InjectRequestExt for Request<State> {
+    fn inject<T: Send + Sync + 'static>(&self) -> tide::Result<Arc<T>> {
+        let resolved = match self.ext::<Scope>() {
+            Some(scope) => scope.resolve::<T>(),
+            // Without preroll's middleware, e.g. in a bare tide server, instances are not shared within the request.
+            None => construct(&Overrides::default(), TypeId::of::<T>())
+                .and_then(|instance| instance.downcast().ok()),
+        };
+
+        resolved.ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("No constructor registered for {}", type_name::<T>()),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tide::http::Method;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(usize);
+
+    #[test]
+    fn resolves_one_instance_per_scope() {
+        static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+        register(|| Counter(CONSTRUCTED.fetch_add(1, Ordering::SeqCst)));
+
+        let scope = Scope::new(Arc::default());
+        let first = scope.resolve::<Counter>();
+        assert_eq!(first.as_deref(), Some(&Counter(0)));
+        assert_eq!(scope.resolve::<Counter>().as_deref(), Some(&Counter(0)));
+
+        let other_scope = Scope::new(Arc::default());
+        assert_eq!(
+            other_scope.resolve::<Counter>().as_deref(),
+            Some(&Counter(1))
+        );
+        assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn prefers_overrides() {
+        struct Greeting(&'static str);
+        register(|| Greeting("registered"));
+
+        let overrides = Overrides::new().with(|| Greeting("overridden"));
+        let scope = Scope::new(Arc::new(overrides));
+        assert_eq!(
+            scope.resolve::<Greeting>().map(|greeting| greeting.0),
+            Some("overridden")
+        );
+
+        let scope = Scope::new(Arc::default());
+        assert_eq!(
+            scope.resolve::<Greeting>().map(|greeting| greeting.0),
+            Some("registered")
+        );
+    }
+
+    #[test]
+    fn errors_on_missing_dependencies() {
+        struct Unregistered;
+
+        let scope = Scope::new(Arc::default());
+        assert!(scope.resolve::<Unregistered>().is_none());
+
+        let mut req: Request<()> = test_utils::request(Method::Get, "/").into();
+        req.set_ext(scope);
+        match req.inject::<Unregistered>() {
+            Ok(_) => panic!("Unregistered was injected"),
+            Err(error) => {
+                assert_eq!(error.status(), StatusCode::InternalServerError);
+                assert!(error.to_string().contains("No constructor registered for"));
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use serde::de::DeserializeOwned;
+use serde_path_to_error::{Path, Segment};
+use tide::{Request, StatusCode};
+
+use crate::body::{BodyLimits, BodyStreamExt};
+use crate::middleware::json_error::{DetailedError, JsonErrorDetail};
+
+/// A JSON request body, deserialized into `T`.
+///
+/// The body is read within [`BodyLimits`][], failing with a `413 Payload Too Large` if it is too large.
+/// Bodies which are not valid JSON, or not a valid `T`, fail with a `422 Unprocessable Entity`, whose
+/// [`JsonError`][crate::JsonError] `details` have the pointer to the offending field and the type expected there,
+/// e.g. `{ "pointer": "/items/0/quantity", "message": "invalid type: string \"two\", expected u32", "expected": "u32" }`.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::body::BodyLimits;
+/// use preroll::extract::Json;
+/// use serde::Deserialize;
+/// use tide::Request;
+///
+/// #[derive(Deserialize)]
+/// struct Order {
+///     items: Vec<Item>,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct Item {
+///     sku: String,
+///     quantity: u32,
+/// }
+///
+/// # #[allow(dead_code)]
+/// async fn create_order(mut req: Request<()>) -> tide::Result {
+///     let Json(order) = Json::<Order>::from_request(&mut req, BodyLimits::new()).await?;
+///     let quantity: u32 = order.items.iter().map(|item| item.quantity).sum();
+///     Ok(format!("{} items, starting with {}", quantity, order.items[0].sku).into())
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    /// Read and deserialize the request's body.
+    pub async fn from_request<State>(
+        req: &mut Request<State>,
+        limits: BodyLimits,
+    ) -> tide::Result<Self> {
+        let mut body = req.body_stream(limits)?;
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next_chunk().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+        from_slice(&bytes).map(Self)
+    }
+}
+
+impl<T> Json<T> {
+    /// The deserialized body.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// Deserialize `bytes`, with the path to the offending field of any error.
+fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> tide::Result<T> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(bytes);
+    serde_path_to_error::deserialize(deserializer).map_err(|error| {
+        let pointer = json_pointer(error.path());
+        let inner = error.inner().to_string();
+        // e.g. `invalid type: string "two", expected u32 at line 1 column 31`
+        let message = match inner.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_string(),
+            None => inner,
+        };
+        let expected = message
+            .rsplit_once(", expected ")
+            .map(|(_, expected)| expected.to_string());
+
+        let summary = if pointer.is_empty() {
+            format!("Invalid JSON body: {}", message)
+        } else {
+            format!("Invalid JSON body at {}: {}", error.path(), message)
+        };
+        let detail = JsonErrorDetail {
+            pointer,
+            message,
+            expected,
+        };
+        DetailedError::error(StatusCode::UnprocessableEntity, summary, vec![detail])
+    })
+}
+
+/// The [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901) of a deserialization path, e.g. `/items/0/quantity`.
+fn json_pointer(path: &Path) -> String {
+    path.iter()
+        .filter_map(|segment| match segment {
+            Segment::Seq { index } => Some(index.to_string()),
+            Segment::Map { key } => Some(key.clone()),
+            Segment::Enum { variant } => Some(variant.clone()),
+            Segment::Unknown => None,
+        })
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Order {
+        items: Vec<Item>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Item {
+        sku: String,
+        quantity: u32,
+    }
+
+    fn details(body: &str) -> Option<JsonErrorDetail> {
+        let error = from_slice::<Order>(body.as_bytes()).err()?;
+        assert_eq!(error.status(), StatusCode::UnprocessableEntity);
+        let detailed = error.downcast_ref::<DetailedError>()?;
+        assert!(detailed.to_string().starts_with("Invalid JSON body"));
+        detailed.details().first().cloned()
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn points_to_the_offending_field() {
+        let detail = details(r#"{"items": [{"sku": "a", "quantity": "two"}]}"#).unwrap();
+        assert_eq!(detail.pointer, "/items/0/quantity");
+        assert_eq!(detail.message, "invalid type: string \"two\", expected u32");
+        assert_eq!(detail.expected.as_deref(), Some("u32"));
+
+        let detail = details(r#"{"items": [{"sku": "a/b"}]}"#).unwrap();
+        assert_eq!(detail.pointer, "/items/0");
+        assert_eq!(detail.message, "missing field `quantity`");
+        assert_eq!(detail.expected, None);
+
+        let detail = details(r#"{"items": "#).unwrap();
+        assert_eq!(detail.pointer, "/items");
+
+        assert!(details(r#"{"items": []}"#).is_none());
+    }
+}
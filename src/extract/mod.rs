@@ -0,0 +1,10 @@
+//! Extractors which parse request bodies, within limits, into typed values.
+//!
+//! Violations are returned as errors with a fitting status, such as `413 Payload Too Large` or `422 Unprocessable Entity`,
+//! which preroll's error handling middleware turns into [`JsonError`][crate::JsonError] responses.
+
+mod json;
+mod multipart;
+
+pub use json::Json;
+pub use multipart::{Multipart, MultipartLimits, Part};
@@ -0,0 +1,638 @@
+use std::path::{Path, PathBuf};
+
+use async_std::fs::{self, File};
+use async_std::io::WriteExt;
+use tide::{Body, Request, StatusCode};
+use uuid::Uuid;
+
+use crate::body::{BodyLimits, BodyStream};
+
+/// The largest part headers accepted.
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Limits on a multipart upload.
+#[derive(Clone, Debug)]
+pub struct MultipartLimits {
+    max_total_bytes: u64,
+    max_part_bytes: u64,
+    max_parts: usize,
+    memory_threshold: u64,
+    temp_dir: Option<PathBuf>,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: 512 * 1024 * 1024,
+            max_part_bytes: 100 * 1024 * 1024,
+            max_parts: 100,
+            memory_threshold: 1024 * 1024,
+            temp_dir: None,
+        }
+    }
+}
+
+impl MultipartLimits {
+    /// The default limits: 512 MiB in total, 100 MiB and 100 parts at most, with parts over 1 MiB spilled to temp files.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The most bytes the whole request body may have before failing with a `413 Payload Too Large`.
+    #[must_use]
+    pub fn max_total_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_total_bytes = max_bytes;
+        self
+    }
+
+    /// The most bytes any one part may have before failing with a `413 Payload Too Large`.
+    #[must_use]
+    pub fn max_part_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_part_bytes = max_bytes;
+        self
+    }
+
+    /// The most parts which may be uploaded before failing with a `413 Payload Too Large`.
+    #[must_use]
+    pub fn max_parts(mut self, max_parts: usize) -> Self {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Parts larger than this are written to a temp file rather than kept in memory.
+    #[must_use]
+    pub fn memory_threshold(mut self, max_bytes: u64) -> Self {
+        self.memory_threshold = max_bytes;
+        self
+    }
+
+    /// The directory for temp files. Defaults to the system temp directory.
+    #[must_use]
+    pub fn temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = Some(dir.into());
+        self
+    }
+}
+
+/// A temp file which is deleted when dropped, unless persisted.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[derive(Debug)]
+enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+/// One part of a multipart upload, such as a form field or a file.
+#[derive(Debug)]
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: u64,
+    data: PartData,
+}
+
+impl Part {
+    /// The form field name.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The uploaded file's name, if the part is a file.
+    #[must_use]
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The part's `Content-Type`, if it has one.
+    #[must_use]
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// The part's size in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The part's contents, if they were kept in memory.
+    #[must_use]
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            PartData::Memory(bytes) => Some(bytes),
+            PartData::File(_) => None,
+        }
+    }
+
+    /// The part's contents as UTF-8 text, if they were kept in memory and are valid UTF-8, such as for a form field.
+    #[must_use]
+    pub fn text(&self) -> Option<&str> {
+        self.bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    /// The temp file holding the part's contents, if they were spilled to disk. It is deleted when the part is dropped.
+    #[must_use]
+    pub fn temp_path(&self) -> Option<&Path> {
+        match &self.data {
+            PartData::Memory(_) => None,
+            PartData::File(file) => Some(&file.path),
+        }
+    }
+
+    /// Read the part's contents into memory, wherever they are.
+    pub async fn read_bytes(&self) -> std::io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(bytes) => Ok(bytes.clone()),
+            PartData::File(file) => fs::read(&file.path).await,
+        }
+    }
+
+    /// Write the part's contents to `path`, moving its temp file there if it has one.
+    pub async fn persist(self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        match self.data {
+            PartData::Memory(bytes) => fs::write(path, bytes).await,
+            PartData::File(file) => {
+                // Falls back to copying if the temp file is on another filesystem.
+                if fs::rename(&file.path, path).await.is_err() {
+                    fs::copy(&file.path, path).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A parsed `multipart/form-data` upload.
+///
+/// Parts are parsed as the body is read, within [`MultipartLimits`][], with large parts written to temp files
+/// rather than kept in memory. Uploads which exceed a limit fail with a `413 Payload Too Large`,
+/// and malformed uploads, or requests which are not `multipart/form-data`, fail with a `422 Unprocessable Entity`.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::extract::{Multipart, MultipartLimits};
+/// use tide::Request;
+///
+/// # #[allow(dead_code)]
+/// async fn upload_avatar(mut req: Request<()>) -> tide::Result {
+///     let limits = MultipartLimits::new().max_part_bytes(5 * 1024 * 1024);
+///     let upload = Multipart::from_request(&mut req, limits).await?;
+///
+///     let user = upload.text("user").unwrap_or("anonymous");
+///     if let Some(avatar) = upload.file("avatar") {
+///         let name = avatar.filename().unwrap_or("avatar");
+///         avatar.read_bytes().await?;
+///         return Ok(format!("{} uploaded {} ({} bytes)", user, name, avatar.size()).into());
+///     }
+///     Ok("no avatar".into())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Parse the request's body as `multipart/form-data`.
+    pub async fn from_request<State>(
+        req: &mut Request<State>,
+        limits: MultipartLimits,
+    ) -> tide::Result<Self> {
+        let content_type = req
+            .header("Content-Type")
+            .map(|values| values.last().as_str().to_string())
+            .unwrap_or_default();
+
+        if let Some(len) = req.len() {
+            if len as u64 > limits.max_total_bytes {
+                return Err(too_large(format!(
+                    "Request body exceeds {} bytes",
+                    limits.max_total_bytes
+                )));
+            }
+        }
+
+        Self::parse(req.take_body(), &content_type, limits).await
+    }
+
+    /// All parts, in the order they were uploaded.
+    #[must_use]
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// Take all parts, such as to persist them.
+    #[must_use]
+    pub fn into_parts(self) -> Vec<Part> {
+        self.parts
+    }
+
+    /// The first part named `name`.
+    #[must_use]
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    /// The text of the first form field named `name`.
+    #[must_use]
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.parts
+            .iter()
+            .find(|part| part.name == name && part.filename.is_none())
+            .and_then(Part::text)
+    }
+
+    /// The first file part named `name`.
+    #[must_use]
+    pub fn file(&self, name: &str) -> Option<&Part> {
+        self.parts
+            .iter()
+            .find(|part| part.name == name && part.filename.is_some())
+    }
+
+    async fn parse(body: Body, content_type: &str, limits: MultipartLimits) -> tide::Result<Self> {
+        let boundary = boundary(content_type).ok_or_else(|| {
+            unprocessable("Expected a multipart/form-data request with a boundary")
+        })?;
+        // Every delimiter after the first is preceded by a line break.
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+        let mut stream = BodyStream::new(body, BodyLimits::new().max_bytes(limits.max_total_bytes));
+        let mut parser = Parser {
+            limits,
+            parts: Vec::new(),
+            current: None,
+        };
+
+        // Treated as if preceded by a line break, so that the first delimiter matches like the rest.
+        let mut buf = b"\r\n".to_vec();
+        let mut state = State::Preamble;
+        loop {
+            let progressed = match state {
+                State::Preamble => match find(&buf, &delimiter) {
+                    Some(at) => {
+                        buf.drain(..at + delimiter.len());
+                        state = State::AfterDelimiter;
+                        true
+                    }
+                    None => {
+                        let keep = buf.len().min(delimiter.len() - 1);
+                        buf.drain(..buf.len() - keep);
+                        false
+                    }
+                },
+                State::AfterDelimiter if buf.len() >= 2 => {
+                    if buf.starts_with(b"--") {
+                        state = State::End;
+                    } else if buf.starts_with(b"\r\n") {
+                        buf.drain(..2);
+                        state = State::Headers;
+                    } else {
+                        return Err(unprocessable("Malformed multipart delimiter"));
+                    }
+                    true
+                }
+                State::AfterDelimiter => false,
+                State::Headers => match find(&buf, b"\r\n\r\n") {
+                    Some(at) => {
+                        let headers = String::from_utf8_lossy(&buf[..at]).to_string();
+                        buf.drain(..at + 4);
+                        parser.start_part(&headers)?;
+                        state = State::Body;
+                        true
+                    }
+                    None if buf.len() > MAX_HEADER_BYTES => {
+                        return Err(unprocessable("Multipart part headers are too large"));
+                    }
+                    None => false,
+                },
+                State::Body => match find(&buf, &delimiter) {
+                    Some(at) => {
+                        parser.write(&buf[..at]).await?;
+                        parser.finish_part().await?;
+                        buf.drain(..at + delimiter.len());
+                        state = State::AfterDelimiter;
+                        true
+                    }
+                    None => {
+                        // The end of the buffer may be the start of a delimiter.
+                        let keep = buf.len().min(delimiter.len() - 1);
+                        let write = buf.len() - keep;
+                        parser.write(&buf[..write]).await?;
+                        buf.drain(..write);
+                        false
+                    }
+                },
+                State::End => {
+                    return Ok(Self {
+                        parts: parser.parts,
+                    })
+                }
+            };
+
+            if !progressed {
+                match stream.next_chunk().await? {
+                    Some(chunk) => buf.extend_from_slice(&chunk),
+                    None => {
+                        return Err(unprocessable(
+                            "Multipart body ended before its final delimiter",
+                        ))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Preamble,
+    AfterDelimiter,
+    Headers,
+    Body,
+    End,
+}
+
+/// Accumulates parts, within limits.
+#[derive(Debug)]
+struct Parser {
+    limits: MultipartLimits,
+    parts: Vec<Part>,
+    current: Option<(Part, Option<File>)>,
+}
+
+impl Parser {
+    fn start_part(&mut self, headers: &str) -> tide::Result<()> {
+        if self.parts.len() >= self.limits.max_parts {
+            return Err(too_large(format!(
+                "Multipart upload has more than {} parts",
+                self.limits.max_parts
+            )));
+        }
+
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in headers.split("\r\n") {
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-disposition" => disposition = Some(value.trim().to_string()),
+                    "content-type" => content_type = Some(value.trim().to_string()),
+                    _ => (),
+                }
+            }
+        }
+
+        let disposition = disposition
+            .ok_or_else(|| unprocessable("Multipart part without a Content-Disposition header"))?;
+        let params = parameters(&disposition);
+        let param = |key: &str| {
+            params
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.clone())
+        };
+        let name = param("name").ok_or_else(|| unprocessable("Multipart part without a name"))?;
+
+        self.current = Some((
+            Part {
+                name,
+                filename: param("filename"),
+                content_type,
+                size: 0,
+                data: PartData::Memory(Vec::new()),
+            },
+            None,
+        ));
+        Ok(())
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> tide::Result<()> {
+        let (part, file) = match self.current.as_mut() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        part.size += bytes.len() as u64;
+        if part.size > self.limits.max_part_bytes {
+            return Err(too_large(format!(
+                "Multipart part \"{}\" exceeds {} bytes",
+                part.name, self.limits.max_part_bytes
+            )));
+        }
+
+        if let PartData::Memory(memory) = &mut part.data {
+            if part.size <= self.limits.memory_threshold {
+                memory.extend_from_slice(bytes);
+                return Ok(());
+            }
+
+            // Spill to a temp file, registered first so that it is deleted even if writing fails.
+            let dir = self
+                .limits
+                .temp_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir);
+            let path = dir.join(format!("preroll-upload-{}", Uuid::new_v4()));
+            let buffered = std::mem::take(memory);
+            part.data = PartData::File(TempFile { path: path.clone() });
+
+            let mut spilled = File::create(&path).await?;
+            spilled.write_all(&buffered).await?;
+            *file = Some(spilled);
+        }
+
+        if let Some(file) = file.as_mut() {
+            file.write_all(bytes).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish_part(&mut self) -> tide::Result<()> {
+        if let Some((part, file)) = self.current.take() {
+            if let Some(mut file) = file {
+                file.flush().await?;
+            }
+            self.parts.push(part);
+        }
+        Ok(())
+    }
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type.
+fn boundary(content_type: &str) -> Option<String> {
+    let (essence, _) = content_type.split_once(';')?;
+    if !essence.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    parameters(content_type)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// The `key=value` parameters of a header value, after its first `;`, unquoting quoted values.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = match value.split_once(';') {
+        Some((_, rest)) => rest,
+        None => return params,
+    };
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+        let (key, after_key) = match rest.split_once('=') {
+            Some(split) => split,
+            None => return params,
+        };
+
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some((_, escaped)) = chars.next() {
+                                value.push(escaped);
+                            }
+                        }
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after_key.find(';').unwrap_or(after_key.len());
+                (after_key[..end].trim().to_string(), &after_key[end..])
+            }
+        };
+
+        params.push((key.trim().to_string(), value));
+        rest = after_value;
+    }
+}
+
+/// The position of `needle` in `haystack`, if present.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn too_large(message: String) -> tide::Error {
+    tide::Error::from_str(StatusCode::PayloadTooLarge, message)
+}
+
+fn unprocessable(message: &'static str) -> tide::Error {
+    tide::Error::from_str(StatusCode::UnprocessableEntity, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"XyZ\"";
+
+    fn upload(file: &str) -> Body {
+        Body::from_string(format!(
+            "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"user\"\r\n\r\n\
+            ada\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"avatar\"; filename=\"a;b.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            {}\r\n--XyZ--\r\n",
+            file
+        ))
+    }
+
+    #[async_std::test]
+    async fn parses_fields_and_files() -> tide::Result<()> {
+        let upload = Multipart::parse(
+            upload("png\r\n--Xy bytes"),
+            CONTENT_TYPE,
+            MultipartLimits::new(),
+        )
+        .await?;
+
+        assert_eq!(upload.parts().len(), 2);
+        assert_eq!(upload.text("user"), Some("ada"));
+
+        let avatar = upload
+            .file("avatar")
+            .ok_or_else(|| tide::Error::from_str(500, "no avatar"))?;
+        assert_eq!(avatar.filename(), Some("a;b.png"));
+        assert_eq!(avatar.content_type(), Some("image/png"));
+        assert_eq!(avatar.bytes(), Some(&b"png\r\n--Xy bytes"[..]));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn spills_large_parts_to_temp_files() -> tide::Result<()> {
+        let file = "x".repeat(100);
+        let limits = MultipartLimits::new().memory_threshold(10);
+        let upload = Multipart::parse(upload(&file), CONTENT_TYPE, limits).await?;
+
+        let avatar = upload
+            .file("avatar")
+            .ok_or_else(|| tide::Error::from_str(500, "no avatar"))?;
+        let path = avatar
+            .temp_path()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| tide::Error::from_str(500, "not spilled"))?;
+        assert_eq!(avatar.read_bytes().await?, file.as_bytes());
+        assert_eq!(upload.text("user"), Some("ada"));
+
+        drop(upload);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn enforces_limits() {
+        let status = |result: tide::Result<Multipart>| result.err().map(|error| error.status());
+
+        let limits = MultipartLimits::new().max_part_bytes(10);
+        let result = Multipart::parse(upload(&"x".repeat(100)), CONTENT_TYPE, limits).await;
+        assert_eq!(status(result), Some(StatusCode::PayloadTooLarge));
+
+        let limits = MultipartLimits::new().max_parts(1);
+        let result = Multipart::parse(upload("x"), CONTENT_TYPE, limits).await;
+        assert_eq!(status(result), Some(StatusCode::PayloadTooLarge));
+
+        let result =
+            Multipart::parse(upload("x"), "application/json", MultipartLimits::new()).await;
+        assert_eq!(status(result), Some(StatusCode::UnprocessableEntity));
+
+        let truncated = Body::from_string(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nabc".to_string(),
+        );
+        let result = Multipart::parse(truncated, CONTENT_TYPE, MultipartLimits::new()).await;
+        assert_eq!(status(result), Some(StatusCode::UnprocessableEntity));
+    }
+}
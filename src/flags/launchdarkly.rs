@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use surf::Client;
+
+use super::{bucket, http_client, spawn_refresh, FlagContext, FlagProvider, FlagValue};
+use crate::config;
+use crate::logging::log_kv;
+
+/// Evaluates flags from [LaunchDarkly](https://launchdarkly.com/)'s server-side SDK API.
+///
+/// Set up automatically with the `"launchdarkly"` feature if `LAUNCHDARKLY_SDK_KEY` is set. Flags are fetched from
+/// `LAUNCHDARKLY_BASE_URL` (default `https://sdk.launchdarkly.com/`), such as a relay proxy, at startup,
+/// and every `FLAGS_REFRESH_SECS`.
+///
+/// Flags which are off evaluate to their off variation. Flags which are on evaluate to the variation targeting the
+/// context's key, if any, or else their fallthrough variation or percentage rollout, bucketed by the context's key.
+/// Targeting rules and segments are not evaluated.
+///
+/// Boolean variations are on or off, string variations are variants, and other variations are variants of their JSON.
+#[derive(Clone)]
+pub struct LaunchDarklyProvider {
+    client: Client,
+    sdk_key: String,
+    flags: Arc<RwLock<HashMap<String, Flag>>>,
+}
+
+impl Debug for LaunchDarklyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LaunchDarklyProvider")
+            .field("base_url", &self.client.config().base_url)
+            .field("flags", &self.flags.read().map(|flags| flags.len()).ok())
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AllData {
+    flags: HashMap<String, Flag>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Flag {
+    on: bool,
+    #[serde(default)]
+    targets: Vec<Target>,
+    #[serde(default)]
+    fallthrough: Fallthrough,
+    off_variation: Option<usize>,
+    #[serde(default)]
+    variations: Vec<Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Target {
+    values: Vec<String>,
+    variation: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Fallthrough {
+    variation: Option<usize>,
+    rollout: Option<Rollout>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Rollout {
+    variations: Vec<WeightedVariation>,
+}
+
+/// A variation's share of a rollout, out of `100000`.
+#[derive(Clone, Debug, Deserialize)]
+struct WeightedVariation {
+    variation: usize,
+    weight: u32,
+}
+
+impl Flag {
+    fn evaluate(&self, flag: &str, context: &FlagContext) -> Option<FlagValue> {
+        let variation = if !self.on {
+            self.off_variation?
+        } else if let Some(target) = context.key().and_then(|key| {
+            self.targets
+                .iter()
+                .find(|target| target.values.iter().any(|value| value == key))
+        }) {
+            target.variation
+        } else {
+            self.fallthrough_variation(flag, context)?
+        };
+
+        let value = match self.variations.get(variation)? {
+            Value::Bool(enabled) => FlagValue::Bool(*enabled),
+            Value::String(variant) => FlagValue::Variant(variant.clone()),
+            value => FlagValue::Variant(value.to_string()),
+        };
+        Some(value)
+    }
+
+    fn fallthrough_variation(&self, flag: &str, context: &FlagContext) -> Option<usize> {
+        let rollout = match &self.fallthrough.rollout {
+            Some(rollout) => rollout,
+            None => return self.fallthrough.variation,
+        };
+
+        // Anonymous contexts get the first variation of a rollout.
+        let mut position = context
+            .key()
+            .map_or(0.0, |key| bucket(flag, key) * 100_000.0);
+        for weighted in &rollout.variations {
+            position -= f64::from(weighted.weight);
+            if position < 0.0 {
+                return Some(weighted.variation);
+            }
+        }
+        rollout.variations.last().map(|weighted| weighted.variation)
+    }
+}
+
+impl LaunchDarklyProvider {
+    /// Create a new `LaunchDarklyProvider` with `LAUNCHDARKLY_SDK_KEY`, without fetching any flags yet.
+    pub fn from_env() -> Result<Self> {
+        let sdk_key = config::var("LAUNCHDARKLY_SDK_KEY")
+            .map_err(|_| eyre!("LAUNCHDARKLY_SDK_KEY is not set"))?;
+        let base_url = config::var("LAUNCHDARKLY_BASE_URL")
+            .unwrap_or_else(|_| "https://sdk.launchdarkly.com/".to_string());
+        Ok(Self {
+            client: http_client(&base_url)?,
+            sdk_key,
+            flags: Arc::default(),
+        })
+    }
+
+    /// Fetch every flag, replacing the previous flags, and return how many there are.
+    pub async fn refresh(&self) -> surf::Result<usize> {
+        let AllData { flags } = self
+            .client
+            .get("sdk/latest-all")
+            .header("Authorization", self.sdk_key.as_str())
+            .recv_json()
+            .await?;
+
+        let count = flags.len();
+        *self.flags.write().unwrap_or_else(PoisonError::into_inner) = flags;
+        Ok(count)
+    }
+
+    /// Fetch flags, and then keep refreshing them in the background.
+    pub(crate) async fn start(&self) {
+        match self.refresh().await {
+            Ok(count) => log::info!("LaunchDarkly flags fetched: {}", count),
+            Err(error) => {
+                log_kv!(
+                    warn,
+                    { flag_service: "launchdarkly" },
+                    "Failed to fetch LaunchDarkly flags, retrying in the background: {}", error
+                );
+            }
+        }
+
+        let provider = self.clone();
+        spawn_refresh("launchdarkly", move || {
+            let provider = provider.clone();
+            async move { provider.refresh().await }
+        });
+    }
+}
+
+impl FlagProvider for LaunchDarklyProvider {
+    fn evaluate(&self, flag: &str, context: &FlagContext) -> Option<FlagValue> {
+        self.flags
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(flag)?
+            .evaluate(flag, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_targets_and_fallthrough() -> serde_json::Result<()> {
+        let AllData { flags } = serde_json::from_value(serde_json::json!({
+            "flags": {
+                "new-pricing": {
+                    "on": true,
+                    "targets": [{ "values": ["user-1"], "variation": 0 }],
+                    "fallthrough": { "variation": 1 },
+                    "offVariation": 1,
+                    "variations": [true, false]
+                },
+                "checkout": {
+                    "on": false,
+                    "fallthrough": { "variation": 0 },
+                    "offVariation": 1,
+                    "variations": ["one-page", "classic"]
+                }
+            }
+        }))?;
+        let evaluate = |flag: &str, context: &FlagContext| flags.get(flag)?.evaluate(flag, context);
+
+        let user = FlagContext::new("user-1");
+        assert_eq!(evaluate("new-pricing", &user), Some(FlagValue::Bool(true)));
+        assert_eq!(
+            evaluate("new-pricing", &FlagContext::new("user-2")),
+            Some(FlagValue::Bool(false))
+        );
+        assert_eq!(
+            evaluate("checkout", &user),
+            Some(FlagValue::Variant("classic".to_string()))
+        );
+        assert_eq!(evaluate("unknown", &user), None);
+        Ok(())
+    }
+}
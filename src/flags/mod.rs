@@ -0,0 +1,366 @@
+//! Feature flags, checked in handlers with [`FlagsRequestExt`][] from the prelude.
+//!
+//! Flags are evaluated by a [`FlagProvider`][] for the request's [`FlagContext`][], if one has been set as a request
+//! extension, such as by an authentication middleware, or else for an anonymous context.
+//! Flags which the provider does not know of are disabled, and have no variant.
+//!
+//! The default provider, [`StaticProvider`][], reads `FLAG_{NAME}` settings from the environment or the config file,
+//! e.g. `FLAG_NEW_PRICING=true`, or `FLAG_CHECKOUT=one-page` for a variant.
+//!
+//! With the `"unleash"` feature and `UNLEASH_URL` set, or the `"launchdarkly"` feature and `LAUNCHDARKLY_SDK_KEY` set,
+//! flags are instead fetched from that service at startup, and refreshed every `FLAGS_REFRESH_SECS` (default `30`).
+//! Other providers can be used by implementing [`FlagProvider`][] and calling [`set_provider`][] during setup.
+//!
+//! Tests can set flags for a single test server, without affecting tests running in parallel, with
+//! [`test_utils::create_client_with_flags`][crate::test_utils::create_client_with_flags].
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::flags::FlagContext;
+//! use preroll::prelude::*;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("checkout")
+//!         .get(|mut req: Request<Arc<()>>| async move {
+//!             req.set_ext(FlagContext::new("user-1").attribute("country", "CA"));
+//!
+//!             let pricing = if req.flag_enabled("new-pricing") { "new" } else { "old" };
+//!             let layout = req.flag_variant("checkout").unwrap_or_else(|| "classic".to_string());
+//!             Ok(format!("{} pricing, {} layout", pricing, layout))
+//!         });
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::task;
+use once_cell::sync::{Lazy, OnceCell};
+use sha2::{Digest, Sha256};
+use tide::Request;
+
+use crate::config;
+use crate::di::Scope;
+use crate::logging::log_kv;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "launchdarkly")] {
+        mod launchdarkly;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "launchdarkly")))]
+        pub use launchdarkly::LaunchDarklyProvider;
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "unleash")] {
+        mod unleash;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "unleash")))]
+        pub use unleash::UnleashProvider;
+    }
+}
+
+static PROVIDER: OnceCell<Arc<dyn FlagProvider>> = OnceCell::new();
+static ANONYMOUS: Lazy<FlagContext> = Lazy::new(FlagContext::default);
+
+/// The value of a flag: on or off, or a named variant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlagValue {
+    Bool(bool),
+    /// A variant, which counts as enabled.
+    Variant(String),
+}
+
+impl FlagValue {
+    /// Whether the flag is on, or has a variant.
+    #[must_use]
+    pub fn enabled(&self) -> bool {
+        match self {
+            FlagValue::Bool(enabled) => *enabled,
+            FlagValue::Variant(_) => true,
+        }
+    }
+
+    /// The flag's variant, if it has one.
+    #[must_use]
+    pub fn variant(&self) -> Option<&str> {
+        match self {
+            FlagValue::Bool(_) => None,
+            FlagValue::Variant(variant) => Some(variant),
+        }
+    }
+}
+
+impl From<bool> for FlagValue {
+    fn from(enabled: bool) -> Self {
+        FlagValue::Bool(enabled)
+    }
+}
+
+impl From<&str> for FlagValue {
+    fn from(variant: &str) -> Self {
+        FlagValue::Variant(variant.to_string())
+    }
+}
+
+impl From<String> for FlagValue {
+    fn from(variant: String) -> Self {
+        FlagValue::Variant(variant)
+    }
+}
+
+/// Who flags are evaluated for, such as a user, with any attributes used for targeting.
+///
+/// Set as a request extension with `req.set_ext(context)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlagContext {
+    key: Option<String>,
+    attributes: BTreeMap<String, String>,
+}
+
+impl FlagContext {
+    /// A context identified by `key`, such as a user id, which percentage rollouts are bucketed by.
+    #[must_use]
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: Some(key.into()),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// A context without a key, which is excluded from targeting and percentage rollouts.
+    #[must_use]
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    /// Add an attribute, such as a country or plan, for providers which target by attribute.
+    #[must_use]
+    pub fn attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    /// The context's key, unless it is anonymous.
+    #[must_use]
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// The value of the attribute `name`, if it is set.
+    #[must_use]
+    pub fn attribute_value(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+}
+
+/// A source of flag values.
+///
+/// Evaluation is synchronous, so providers backed by a remote service should evaluate from a local copy of its flags,
+/// refreshed in the background.
+pub trait FlagProvider: Send + Sync + 'static {
+    /// The value of `flag` for `context`, or `None` if the flag is not known.
+    fn evaluate(&self, flag: &str, context: &FlagContext) -> Option<FlagValue>;
+}
+
+/// Set the provider of every flag.
+///
+/// Only the first provider set is used.
+pub fn set_provider(provider: impl FlagProvider) {
+    if PROVIDER.set(Arc::new(provider)).is_err() {
+        log::warn!("A flag provider is already set, ignoring another.");
+    }
+}
+
+/// The provider which has been set, or else a [`StaticProvider`][].
+fn provider() -> &'static Arc<dyn FlagProvider> {
+    PROVIDER.get_or_init(|| Arc::new(StaticProvider))
+}
+
+/// Reads flags from `FLAG_{NAME}` settings, in the environment or config file, as of each check.
+///
+/// The name is uppercased, with `-` and `.` replaced by `_`, e.g. `new-pricing` is read from `FLAG_NEW_PRICING`.
+/// `true`, `on`, and `1` are enabled, `false`, `off`, and `0` are disabled, and any other value is a variant.
+/// Every context gets the same values.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaticProvider;
+
+impl FlagProvider for StaticProvider {
+    fn evaluate(&self, flag: &str, _context: &FlagContext) -> Option<FlagValue> {
+        let var = format!("FLAG_{}", flag.to_uppercase().replace(['-', '.'], "_"));
+        let value = config::var(&var).ok()?;
+        let value = match value.to_lowercase().as_str() {
+            "true" | "on" | "1" => FlagValue::Bool(true),
+            "false" | "off" | "0" => FlagValue::Bool(false),
+            _ => FlagValue::Variant(value),
+        };
+        Some(value)
+    }
+}
+
+/// Flag values which take precedence over the provider's, for a single test server.
+///
+/// See [`test_utils::create_client_with_flags`][crate::test_utils::create_client_with_flags].
+#[derive(Clone, Debug, Default)]
+pub struct FlagOverrides {
+    flags: HashMap<String, FlagValue>,
+}
+
+impl FlagOverrides {
+    /// Create an empty set of flag overrides.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluate `flag` as `value` for every context.
+    #[must_use]
+    pub fn set(mut self, flag: impl Into<String>, value: impl Into<FlagValue>) -> Self {
+        self.flags.insert(flag.into(), value.into());
+        self
+    }
+}
+
+/// An extension trait for checking feature flags.
+pub trait FlagsRequestExt {
+    /// The value of `flag` for the request's [`FlagContext`][], or `None` if it is not known.
+    fn flag(&self, flag: &str) -> Option<FlagValue>;
+
+    /// Whether `flag` is on, or has a variant, for the request's [`FlagContext`][].
+    fn flag_enabled(&self, flag: &str) -> bool {
+        self.flag(flag).is_some_and(|value| value.enabled())
+    }
+
+    /// The variant of `flag` for the request's [`FlagContext`][], if it has one.
+    fn flag_variant(&self, flag: &str) -> Option<String> {
+        self.flag(flag)
+            .and_then(|value| value.variant().map(ToString::to_string))
+    }
+}
+
+impl<State> FlagsRequestExt for Request<State> {
+    fn flag(&self, flag: &str) -> Option<FlagValue> {
+        let overridden = self
+            .ext::<Scope>()
+            .and_then(Scope::resolve::<FlagOverrides>)
+            .and_then(|overrides| overrides.flags.get(flag).cloned());
+        if overridden.is_some() {
+            return overridden;
+        }
+
+        let context = self.ext::<FlagContext>().unwrap_or(&ANONYMOUS);
+        provider().evaluate(flag, context)
+    }
+}
+
+/// A stable position in `[0, 1)` for `key` within `seed`, such as a flag name, for percentage rollouts.
+#[cfg_attr(
+    not(any(feature = "launchdarkly", feature = "unleash")),
+    allow(dead_code)
+)]
+pub(crate) fn bucket(seed: &str, key: &str) -> f64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.as_bytes());
+    hasher.update(b":");
+    hasher.update(key.as_bytes());
+    let digest = hasher.finalize();
+    let mut prefix = [0; 4];
+    prefix.copy_from_slice(&digest[..4]);
+    f64::from(u32::from_be_bytes(prefix)) / (f64::from(u32::MAX) + 1.0)
+}
+
+/// A client for a flag service, with a timeout of 10 seconds.
+#[cfg_attr(
+    not(any(feature = "launchdarkly", feature = "unleash")),
+    allow(dead_code)
+)]
+pub(crate) fn http_client(base_url: &str) -> color_eyre::eyre::Result<surf::Client> {
+    let client: surf::Client = surf::Config::new()
+        .set_base_url(surf::Url::parse(base_url)?)
+        .set_timeout(Some(Duration::from_secs(10)))
+        .try_into()?;
+    Ok(client)
+}
+
+/// Refresh a provider's flags with `refresh` every `FLAGS_REFRESH_SECS`, keeping its previous flags on failure.
+#[cfg_attr(
+    not(any(feature = "launchdarkly", feature = "unleash")),
+    allow(dead_code)
+)]
+pub(crate) fn spawn_refresh<RefreshFn, RefreshFut>(service: &'static str, refresh: RefreshFn)
+where
+    RefreshFn: Fn() -> RefreshFut + Send + 'static,
+    RefreshFut: Future<Output = surf::Result<usize>> + Send,
+{
+    let refresh_secs = config::var("FLAGS_REFRESH_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30);
+    let interval = Duration::from_secs(refresh_secs);
+
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            if let Err(error) = refresh().await {
+                log_kv!(
+                    warn,
+                    { flag_service: service, refresh_secs: refresh_secs },
+                    "Failed to refresh flags from {}, keeping previous flags: {}", service, error
+                );
+            }
+        }
+    });
+}
+
+/// Set up the provider of the configured flag service, if any.
+///
+/// A service which cannot be reached at startup does not fail setup: flags are unknown until a refresh succeeds.
+pub(crate) async fn setup() -> color_eyre::eyre::Result<()> {
+    if PROVIDER.get().is_some() {
+        return Ok(());
+    }
+
+    #[cfg(feature = "unleash")]
+    if config::var("UNLEASH_URL").is_ok() {
+        let provider = UnleashProvider::from_env()?;
+        provider.start().await;
+        set_provider(provider);
+        return Ok(());
+    }
+
+    #[cfg(feature = "launchdarkly")]
+    if config::var("LAUNCHDARKLY_SDK_KEY").is_ok() {
+        let provider = LaunchDarklyProvider::from_env()?;
+        provider.start().await;
+        set_provider(provider);
+        return Ok(());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_stable_and_spread() {
+        assert_eq!(bucket("rollout", "user-1"), bucket("rollout", "user-1"));
+        assert_ne!(bucket("rollout", "user-1"), bucket("other", "user-1"));
+
+        let enabled = (0..1000)
+            .filter(|user| bucket("rollout", &user.to_string()) < 0.25)
+            .count();
+        assert!((200..300).contains(&enabled), "{} of 1000", enabled);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use surf::Client;
+
+use super::{bucket, http_client, spawn_refresh, FlagContext, FlagProvider, FlagValue};
+use crate::config;
+use crate::logging::log_kv;
+
+/// Evaluates flags from an [Unleash](https://www.getunleash.io/) server's client API.
+///
+/// Set up automatically with the `"unleash"` feature if `UNLEASH_URL` is set, e.g. `https://unleash.internal/api/`,
+/// with `UNLEASH_API_TOKEN` as the client token. Features are fetched at startup, and every `FLAGS_REFRESH_SECS`.
+///
+/// The `default`, `userWithId`, `flexibleRollout`, and `gradualRolloutUserId` strategies are evaluated, with rollouts
+/// bucketed by the context's key. Other strategies do not match. Enabled features with variants evaluate to a variant,
+/// chosen by weight for the context's key.
+#[derive(Clone)]
+pub struct UnleashProvider {
+    client: Client,
+    token: Option<String>,
+    app_name: String,
+    features: Arc<RwLock<HashMap<String, Feature>>>,
+}
+
+impl Debug for UnleashProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnleashProvider")
+            .field("base_url", &self.client.config().base_url)
+            .field("app_name", &self.app_name)
+            .field(
+                "features",
+                &self.features.read().map(|features| features.len()).ok(),
+            )
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Features {
+    features: Vec<Feature>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Feature {
+    name: String,
+    enabled: bool,
+    #[serde(default)]
+    strategies: Vec<Strategy>,
+    #[serde(default)]
+    variants: Vec<Variant>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Strategy {
+    name: String,
+    #[serde(default)]
+    parameters: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Variant {
+    name: String,
+    weight: u32,
+}
+
+impl Strategy {
+    fn parameter(&self, name: &str) -> Option<String> {
+        match self.parameters.get(name)? {
+            serde_json::Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        }
+    }
+
+    fn matches(&self, feature: &str, context: &FlagContext) -> bool {
+        match self.name.as_str() {
+            "default" => true,
+            "userWithId" => match (context.key(), self.parameter("userIds")) {
+                (Some(key), Some(user_ids)) => user_ids.split(',').any(|id| id.trim() == key),
+                _ => false,
+            },
+            "flexibleRollout" | "gradualRolloutUserId" => {
+                let rollout: f64 = self
+                    .parameter("rollout")
+                    .or_else(|| self.parameter("percentage"))
+                    .and_then(|rollout| rollout.parse().ok())
+                    .unwrap_or(0.0);
+                let group = self
+                    .parameter("groupId")
+                    .unwrap_or_else(|| feature.to_string());
+                context
+                    .key()
+                    .is_some_and(|key| bucket(&group, key) * 100.0 < rollout)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Feature {
+    fn evaluate(&self, context: &FlagContext) -> FlagValue {
+        let enabled = self.enabled
+            && (self.strategies.is_empty()
+                || self
+                    .strategies
+                    .iter()
+                    .any(|strategy| strategy.matches(&self.name, context)));
+        if !enabled {
+            return FlagValue::Bool(false);
+        }
+
+        let total: u32 = self.variants.iter().map(|variant| variant.weight).sum();
+        let key = match context.key() {
+            Some(key) if total > 0 => key,
+            _ => return FlagValue::Bool(true),
+        };
+
+        let mut position = bucket(&format!("{}:variant", self.name), key) * f64::from(total);
+        for variant in &self.variants {
+            position -= f64::from(variant.weight);
+            if position < 0.0 {
+                return FlagValue::Variant(variant.name.clone());
+            }
+        }
+        FlagValue::Bool(true)
+    }
+}
+
+impl UnleashProvider {
+    /// Create a new `UnleashProvider` for `UNLEASH_URL`, with `UNLEASH_API_TOKEN` and `UNLEASH_APP_NAME`
+    /// (default `preroll`), without fetching any features yet.
+    pub fn from_env() -> Result<Self> {
+        let url = config::var("UNLEASH_URL").map_err(|_| eyre!("UNLEASH_URL is not set"))?;
+        Ok(Self {
+            client: http_client(&url)?,
+            token: config::var("UNLEASH_API_TOKEN").ok(),
+            app_name: config::var("UNLEASH_APP_NAME").unwrap_or_else(|_| "preroll".to_string()),
+            features: Arc::default(),
+        })
+    }
+
+    /// Fetch every feature, replacing the previous features, and return how many there are.
+    pub async fn refresh(&self) -> surf::Result<usize> {
+        let mut req = self
+            .client
+            .get("client/features")
+            .header("UNLEASH-APPNAME", self.app_name.as_str());
+        if let Some(token) = &self.token {
+            req = req.header("Authorization", token.as_str());
+        }
+        let Features { features } = req.recv_json().await?;
+
+        let count = features.len();
+        *self
+            .features
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = features
+            .into_iter()
+            .map(|feature| (feature.name.clone(), feature))
+            .collect();
+        Ok(count)
+    }
+
+    /// Fetch features, and then keep refreshing them in the background.
+    pub(crate) async fn start(&self) {
+        match self.refresh().await {
+            Ok(count) => log::info!("Unleash features fetched: {}", count),
+            Err(error) => {
+                log_kv!(
+                    warn,
+                    { flag_service: "unleash" },
+                    "Failed to fetch Unleash features, retrying in the background: {}", error
+                );
+            }
+        }
+
+        let provider = self.clone();
+        spawn_refresh("unleash", move || {
+            let provider = provider.clone();
+            async move { provider.refresh().await }
+        });
+    }
+}
+
+impl FlagProvider for UnleashProvider {
+    fn evaluate(&self, flag: &str, context: &FlagContext) -> Option<FlagValue> {
+        self.features
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(flag)
+            .map(|feature| feature.evaluate(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_strategies_and_variants() -> serde_json::Result<()> {
+        let Features { features } = serde_json::from_value(serde_json::json!({
+            "features": [
+                {
+                    "name": "new-pricing",
+                    "enabled": true,
+                    "strategies": [{ "name": "userWithId", "parameters": { "userIds": "user-1, user-2" } }]
+                },
+                {
+                    "name": "checkout",
+                    "enabled": true,
+                    "strategies": [{ "name": "flexibleRollout", "parameters": { "rollout": "100" } }],
+                    "variants": [{ "name": "one-page", "weight": 1000 }]
+                },
+                { "name": "disabled", "enabled": false }
+            ]
+        }))?;
+        let [pricing, checkout, disabled] = match <[Feature; 3]>::try_from(features) {
+            Ok(features) => features,
+            Err(features) => panic!("unexpected features: {:?}", features),
+        };
+
+        let user = FlagContext::new("user-2");
+        assert_eq!(pricing.evaluate(&user), FlagValue::Bool(true));
+        assert_eq!(
+            pricing.evaluate(&FlagContext::new("user-3")),
+            FlagValue::Bool(false)
+        );
+        assert_eq!(
+            checkout.evaluate(&user),
+            FlagValue::Variant("one-page".to_string())
+        );
+        assert_eq!(
+            checkout.evaluate(&FlagContext::anonymous()),
+            FlagValue::Bool(false)
+        );
+        assert_eq!(disabled.evaluate(&user), FlagValue::Bool(false));
+        Ok(())
+    }
+}
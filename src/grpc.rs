@@ -0,0 +1,619 @@
+//! Serve gRPC services over HTTP with JSON messages, via JSON transcoding or grpc-web's JSON codec.
+//!
+//! Each method of a [`GrpcService`][] is mounted at `POST {package.Service}/{Method}` under the route it is mounted on,
+//! so under `/api/v{N}` when mounted from `setup_routes`. Clients should use that prefix as their base URL,
+//! e.g. `https://example.com/api/v1`.
+//!
+//! Each method is called with a request message deserialized from JSON. Message types are plain serde types,
+//! such as those generated from protobuf definitions with serde derives (e.g. by `prost-build` with `type_attribute`,
+//! or `pbjson`). Only JSON messages are supported: not binary protobuf, so not the default codec of grpc-web
+//! or gRPC clients.
+//!
+//! Requests are served according to their `Content-Type`:
+//! - `application/json` (or none): JSON transcoding. The body is the request message, the response is the response message,
+//!   and errors are `JsonError`s with the HTTP status which the [`Code`][] maps to.
+//! - `application/grpc-web+json`: grpc-web, as sent by grpc-web clients configured for the JSON codec. The body is
+//!   length-prefixed frames, and errors are reported in the `grpc-status` and `grpc-message` trailers, with a `200 OK` status.
+//! - Any other grpc-web content type, such as `application/grpc-web+proto` or `application/grpc-web-text`,
+//!   is rejected with a `415 Unsupported Media Type`.
+//!
+//! Request bodies larger than [`max_bytes`][GrpcService::max_bytes], 4 MiB by default, are rejected with a `413`.
+//!
+//! Methods are served from preroll's application server, so requests are logged, traced, and have request ids like any other.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::grpc::{GrpcService, Status};
+//! use serde::{Deserialize, Serialize};
+//! use tide::{Request, Route};
+//!
+//! #[derive(Deserialize)]
+//! struct GetProductRequest {
+//!     id: u64,
+//! }
+//!
+//! #[derive(Serialize)]
+//! struct Product {
+//!     id: u64,
+//!     name: String,
+//! }
+//!
+//! async fn get_product(_req: Request<Arc<()>>, message: GetProductRequest) -> Result<Product, Status> {
+//!     match message.id {
+//!         1 => Ok(Product { id: 1, name: "Tea".to_string() }),
+//!         id => Err(Status::not_found(format!("No product {}", id))),
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     GrpcService::new("shop.v1.Products")
+//!         .unary("GetProduct", get_product)
+//!         .mount(&mut server);
+//! }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tide::http::{mime, Mime};
+use tide::{Body, Request, Response, Route, StatusCode};
+
+use crate::body::{BodyLimits, BodyStreamExt};
+use crate::logging::log_kv;
+
+/// The content type of grpc-web requests and responses using the JSON codec.
+const GRPC_WEB_JSON: &str = "application/grpc-web+json";
+
+/// The largest request body, by default, as for gRPC's default maximum message size.
+const DEFAULT_MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+/// The frame flag of a trailers frame, rather than a message frame.
+const TRAILERS_FLAG: u8 = 0x80;
+
+/// The frame flag of a compressed message, which is not supported.
+const COMPRESSED_FLAG: u8 = 0x01;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
+type Handler<State> =
+    dyn Fn(Request<State>, Vec<u8>) -> BoxFuture<Result<Vec<u8>, Status>> + Send + Sync;
+
+/// A gRPC status code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Code {
+    /// Not an error.
+    Ok = 0,
+    /// The operation was cancelled, typically by the caller.
+    Cancelled = 1,
+    /// An unknown error.
+    Unknown = 2,
+    /// The client specified an invalid argument.
+    InvalidArgument = 3,
+    /// The deadline expired before the operation could complete.
+    DeadlineExceeded = 4,
+    /// Some requested entity was not found.
+    NotFound = 5,
+    /// Some entity the client attempted to create already exists.
+    AlreadyExists = 6,
+    /// The caller does not have permission to execute the operation.
+    PermissionDenied = 7,
+    /// Some resource has been exhausted, such as a rate limit.
+    ResourceExhausted = 8,
+    /// The system is not in a state required for the operation.
+    FailedPrecondition = 9,
+    /// The operation was aborted, typically due to a concurrency conflict.
+    Aborted = 10,
+    /// The operation was attempted past the valid range.
+    OutOfRange = 11,
+    /// The operation is not implemented or supported.
+    Unimplemented = 12,
+    /// An internal error.
+    Internal = 13,
+    /// The service is currently unavailable.
+    Unavailable = 14,
+    /// Unrecoverable data loss or corruption.
+    DataLoss = 15,
+    /// The request does not have valid authentication credentials.
+    Unauthenticated = 16,
+}
+
+impl Code {
+    /// The HTTP status which JSON transcoding responds with, per the `google.rpc.Code` mapping.
+    #[must_use]
+    pub fn http_status(self) -> StatusCode {
+        match self {
+            Code::Ok => StatusCode::Ok,
+            // `499 Client Closed Request` is not a standard status.
+            Code::Cancelled => StatusCode::RequestTimeout,
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                StatusCode::BadRequest
+            }
+            Code::DeadlineExceeded => StatusCode::GatewayTimeout,
+            Code::NotFound => StatusCode::NotFound,
+            Code::AlreadyExists | Code::Aborted => StatusCode::Conflict,
+            Code::PermissionDenied => StatusCode::Forbidden,
+            Code::ResourceExhausted => StatusCode::TooManyRequests,
+            Code::Unimplemented => StatusCode::NotImplemented,
+            Code::Unavailable => StatusCode::ServiceUnavailable,
+            Code::Unauthenticated => StatusCode::Unauthorized,
+            Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::InternalServerError,
+        }
+    }
+
+    fn from_http_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BadRequest | StatusCode::UnprocessableEntity => Code::InvalidArgument,
+            StatusCode::Unauthorized => Code::Unauthenticated,
+            StatusCode::Forbidden => Code::PermissionDenied,
+            StatusCode::NotFound => Code::NotFound,
+            StatusCode::Conflict => Code::Aborted,
+            StatusCode::TooManyRequests => Code::ResourceExhausted,
+            StatusCode::NotImplemented => Code::Unimplemented,
+            StatusCode::ServiceUnavailable => Code::Unavailable,
+            StatusCode::GatewayTimeout => Code::DeadlineExceeded,
+            status if status.is_client_error() => Code::FailedPrecondition,
+            _ => Code::Internal,
+        }
+    }
+}
+
+/// The error of a gRPC method: a [`Code`][] and a message for the client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    code: Code,
+    message: String,
+}
+
+impl Status {
+    /// A status with `code` and `message`.
+    #[must_use]
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// An `INVALID_ARGUMENT` status.
+    #[must_use]
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::new(Code::InvalidArgument, message)
+    }
+
+    /// A `NOT_FOUND` status.
+    #[must_use]
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(Code::NotFound, message)
+    }
+
+    /// A `PERMISSION_DENIED` status.
+    #[must_use]
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(Code::PermissionDenied, message)
+    }
+
+    /// An `UNAUTHENTICATED` status.
+    #[must_use]
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::new(Code::Unauthenticated, message)
+    }
+
+    /// An `INTERNAL` status.
+    #[must_use]
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(Code::Internal, message)
+    }
+
+    /// The status code.
+    #[must_use]
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    /// The message for the client.
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gRPC status {:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Status {}
+
+/// Allows `?` on `tide::Result`s in methods, mapping the error's HTTP status to a gRPC code.
+///
+/// Server errors are logged, and reported to the client only as `INTERNAL`, as `JsonError`s are.
+impl From<tide::Error> for Status {
+    fn from(error: tide::Error) -> Self {
+        let status = error.status();
+        if status.is_server_error() {
+            log_kv!(error, {}, "gRPC method error: {:?}", error);
+            return Self::new(Code::from_http_status(status), "Internal Server Error");
+        }
+        Self::new(Code::from_http_status(status), error.to_string())
+    }
+}
+
+/// A gRPC service, whose methods are mounted as routes with [`mount`][GrpcService::mount].
+///
+/// See [the module documentation][self] for an example.
+pub struct GrpcService<State> {
+    name: String,
+    methods: Vec<(String, Arc<Handler<State>>)>,
+    limits: BodyLimits,
+}
+
+impl<State> fmt::Debug for GrpcService<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcService")
+            .field("name", &self.name)
+            .field("limits", &self.limits)
+            .field(
+                "methods",
+                &self
+                    .methods
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> GrpcService<State> {
+    /// A service with the fully qualified name `name`, e.g. `"shop.v1.Products"`.
+    #[must_use]
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            methods: Vec::new(),
+            limits: BodyLimits::new().max_bytes(DEFAULT_MAX_BYTES),
+        }
+    }
+
+    /// The largest request body which is read, rejecting larger ones with a `413`. Defaults to 4 MiB.
+    #[must_use]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.limits = self.limits.max_bytes(max_bytes);
+        self
+    }
+
+    /// Add a unary method named `method`, e.g. `"GetProduct"`, handled by `handler`.
+    ///
+    /// Request messages which cannot be deserialized are rejected with `INVALID_ARGUMENT`.
+    #[must_use]
+    pub fn unary<F, Fut, Req, Res>(mut self, method: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Request<State>, Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Res, Status>> + Send + 'static,
+        Req: DeserializeOwned + Send + 'static,
+        Res: Serialize + 'static,
+    {
+        let handler = Arc::new(handler);
+        let method_handler: Arc<Handler<State>> = Arc::new(move |req, message: Vec<u8>| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                // Clients may omit the body of an empty message.
+                let message = if message.is_empty() {
+                    b"{}".to_vec()
+                } else {
+                    message
+                };
+                let message = serde_json::from_slice(&message).map_err(|error| {
+                    Status::invalid_argument(format!("Invalid request message: {}", error))
+                })?;
+
+                let response = handler(req, message).await?;
+                serde_json::to_vec(&response).map_err(|error| {
+                    Status::internal(format!("Could not serialize response message: {}", error))
+                })
+            })
+        });
+
+        self.methods.push((method.into(), method_handler));
+        self
+    }
+
+    /// Mount every method at `{name}/{method}` under `server`.
+    ///
+    /// The paths are relative to `server`, such as the `/api/v{N}` route given to `setup_routes`.
+    pub fn mount(self, server: &mut Route<'_, State>) {
+        let limits = self.limits;
+        for (method, handler) in self.methods {
+            let service = self.name.clone();
+            let path = format!("{}/{}", service, method);
+            server.at(&path).post(move |req: Request<State>| {
+                let handler = handler.clone();
+                let service = service.clone();
+                let method = method.clone();
+                async move { call(req, handler, limits, &service, &method).await }
+            });
+        }
+    }
+}
+
+/// Call a method, decoding and encoding messages according to the request's content type.
+async fn call<State>(
+    mut req: Request<State>,
+    handler: Arc<Handler<State>>,
+    limits: BodyLimits,
+    service: &str,
+    method: &str,
+) -> tide::Result {
+    let essence = req.content_type().map(|mime| mime.essence().to_string());
+    let grpc_web = match essence.as_deref() {
+        Some(GRPC_WEB_JSON) => true,
+        Some(essence) if essence.starts_with("application/grpc") => {
+            return Err(tide::Error::from_str(
+                StatusCode::UnsupportedMediaType,
+                format!(
+                    "Unsupported gRPC content type {}: only JSON messages are supported, with {}",
+                    essence, GRPC_WEB_JSON
+                ),
+            ));
+        }
+        _ => false,
+    };
+    let body = req.body_stream(limits)?.into_bytes().await?;
+
+    if !grpc_web {
+        let message = handler(req, body)
+            .await
+            .map_err(|status| tide::Error::from_str(status.code.http_status(), status.message))?;
+
+        let mut body = Body::from_bytes(message);
+        body.set_mime(mime::JSON);
+        let mut res = Response::new(StatusCode::Ok);
+        res.set_body(body);
+        return Ok(res);
+    }
+
+    let result = match decode_frame(&body) {
+        Ok(message) => handler(req, message.to_vec()).await,
+        Err(status) => Err(status),
+    };
+
+    let mut frames = Vec::new();
+    let status = match result {
+        Ok(message) => {
+            encode_frame(&mut frames, 0, &message);
+            Status::new(Code::Ok, "")
+        }
+        Err(status) => {
+            log_kv!(
+                warn,
+                {
+                    grpc_service: service,
+                    grpc_method: method,
+                    grpc_status: status.code as i32,
+                },
+                "gRPC method failed: {}", status.message
+            );
+            status
+        }
+    };
+    let trailers = format!(
+        "grpc-status:{}\r\ngrpc-message:{}\r\n",
+        status.code as i32,
+        percent_encode(&status.message)
+    );
+    encode_frame(&mut frames, TRAILERS_FLAG, trailers.as_bytes());
+
+    let mut body = Body::from_bytes(frames);
+    body.set_mime(Mime::from(GRPC_WEB_JSON));
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(body);
+    Ok(res)
+}
+
+/// The message of the first frame of a grpc-web request body.
+fn decode_frame(body: &[u8]) -> Result<&[u8], Status> {
+    let invalid = || Status::invalid_argument("Invalid grpc-web request frame");
+    if body.is_empty() {
+        return Ok(&[]);
+    }
+
+    let header = body.get(..5).ok_or_else(invalid)?;
+    if header[0] & COMPRESSED_FLAG != 0 {
+        return Err(Status::new(
+            Code::Unimplemented,
+            "Compressed grpc-web messages are not supported",
+        ));
+    }
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    body.get(5..5 + len).ok_or_else(invalid)
+}
+
+fn encode_frame(frames: &mut Vec<u8>, flag: u8, data: &[u8]) {
+    frames.push(flag);
+    frames.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frames.extend_from_slice(data);
+}
+
+/// Percent-encode a `grpc-message`, as the gRPC protocol requires for bytes outside of printable ASCII.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::test_utils;
+
+    #[derive(Deserialize)]
+    struct Greet {
+        name: String,
+    }
+
+    #[derive(Serialize)]
+    struct Greeting {
+        message: String,
+    }
+
+    fn server() -> tide::Server<()> {
+        let mut server = tide::new();
+        server.at("/api/v1").nest({
+            let mut api = tide::new();
+            mount(&mut api.at("/"));
+            api
+        });
+        server
+    }
+
+    fn mount(route: &mut Route<'_, ()>) {
+        GrpcService::new("greeter.v1.Greeter")
+            .unary("Greet", |_req, greet: Greet| async move {
+                if greet.name.is_empty() {
+                    return Err(Status::invalid_argument("A name is required"));
+                }
+                Ok(Greeting {
+                    message: format!("Hello, {}!", greet.name),
+                })
+            })
+            .mount(route);
+    }
+
+    fn request(content_type: &str, body: Vec<u8>) -> http::Request {
+        request_at("/api/v1/greeter.v1.Greeter/Greet", content_type, body)
+    }
+
+    fn request_at(path: &str, content_type: &str, body: Vec<u8>) -> http::Request {
+        let mut req = test_utils::request(Method::Post, path);
+        req.insert_header("Content-Type", content_type);
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn mounts_methods_under_the_route() -> tide::Result<()> {
+        let server = server();
+
+        let body = br#"{"name":"Ada"}"#.to_vec();
+        let req = request_at(
+            "/api/v1/greeter.v1.Greeter/Greet",
+            "application/json",
+            body.clone(),
+        );
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let req = request_at("/greeter.v1.Greeter/Greet", "application/json", body);
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn transcodes_json() -> tide::Result<()> {
+        let server = server();
+
+        let req = request("application/json", br#"{"name":"Ada"}"#.to_vec());
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await?, r#"{"message":"Hello, Ada!"}"#);
+
+        let req = request("application/json", br#"{"name":""}"#.to_vec());
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn serves_grpc_web() -> tide::Result<()> {
+        let server = server();
+
+        let mut body = Vec::new();
+        encode_frame(&mut body, 0, br#"{"name":"Ada"}"#);
+        let mut res: http::Response = server.respond(request(GRPC_WEB_JSON, body)).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut expected = Vec::new();
+        encode_frame(&mut expected, 0, br#"{"message":"Hello, Ada!"}"#);
+        encode_frame(
+            &mut expected,
+            TRAILERS_FLAG,
+            b"grpc-status:0\r\ngrpc-message:\r\n",
+        );
+        assert_eq!(res.body_bytes().await?, expected);
+
+        let mut body = Vec::new();
+        encode_frame(&mut body, 0, br#"{"name":""}"#);
+        let mut res: http::Response = server.respond(request(GRPC_WEB_JSON, body)).await?;
+        let mut expected = Vec::new();
+        encode_frame(
+            &mut expected,
+            TRAILERS_FLAG,
+            b"grpc-status:3\r\ngrpc-message:A name is required\r\n",
+        );
+        assert_eq!(res.body_bytes().await?, expected);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_binary_and_text_grpc_web() -> tide::Result<()> {
+        let server = server();
+
+        let mut body = Vec::new();
+        encode_frame(&mut body, 0, b"\x0a\x03Ada");
+        for content_type in ["application/grpc-web+proto", "application/grpc-web-text"] {
+            let res: http::Response = server.respond(request(content_type, body.clone())).await?;
+            assert_eq!(res.status(), StatusCode::UnsupportedMediaType);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_request_bodies() -> tide::Result<()> {
+        let mut server = tide::new();
+        GrpcService::new("greeter.v1.Greeter")
+            .max_bytes(32)
+            .unary("Greet", |_req, greet: Greet| async move {
+                Ok(Greeting {
+                    message: format!("Hello, {}!", greet.name),
+                })
+            })
+            .mount(&mut server.at("/"));
+
+        let req = request_at(
+            "/greeter.v1.Greeter/Greet",
+            "application/json",
+            br#"{"name":"Ada"}"#.to_vec(),
+        );
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let name = "a".repeat(32);
+        let req = request_at(
+            "/greeter.v1.Greeter/Greet",
+            "application/json",
+            format!(r#"{{"name":"{}"}}"#, name).into_bytes(),
+        );
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        Ok(())
+    }
+}
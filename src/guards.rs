@@ -0,0 +1,151 @@
+//! Declarative authorization guards for the routes of an API version, through
+//! [`VariadicRoutes::guarded`][crate::VariadicRoutes::guarded].
+//!
+//! A [`Guard`][] checks each request to the guarded routes, and rejects those it does not allow with a
+//! `403 Forbidden` [`JsonError`][crate::JsonError], so that authorization policy is declared once per version rather
+//! than checked in each handler.
+//!
+//! Guards run after all server middleware, including any added in `custom_setup`, so the service's authentication
+//! middleware should set the [`Scopes`][] of the authenticated client as a request extension for guards to check.
+//! Requests without `Scopes` have none, and are rejected by [`RequireScope`][].
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::guards::{RequireScope, Scopes};
+//! use preroll::VariadicRoutes;
+//! use tide::{Middleware, Next, Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! struct Authenticate;
+//!
+//! #[tide::utils::async_trait]
+//! impl<State: Clone + Send + Sync + 'static> Middleware<State> for Authenticate {
+//!     async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+//!         // Verify the request's credentials, then:
+//!         req.set_ext(Scopes::new(["admin", "users:read"]));
+//!         Ok(next.run(req).await)
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn routes_v1(mut server: Route<'_, Arc<()>>) {
+//!     server.at("/users").get(|_| async { Ok("users") });
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn admin_routes() -> VariadicRoutes<()> {
+//!     VariadicRoutes::from(routes_v1).guarded(RequireScope("admin"))
+//! }
+//! ```
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use tide::{Middleware, Next, Request, StatusCode};
+
+/// The scopes, or roles, granted to the client of a request, set as a request extension by authentication middleware.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scopes(BTreeSet<String>);
+
+impl Scopes {
+    /// The scopes of a client, e.g. from the `scope` claim of its token.
+    #[must_use]
+    pub fn new<S: Into<String>>(scopes: impl IntoIterator<Item = S>) -> Self {
+        Self(scopes.into_iter().map(Into::into).collect())
+    }
+
+    /// Whether the client has been granted `scope`.
+    pub fn contains(&self, scope: &str) -> bool {
+        self.0.contains(scope)
+    }
+}
+
+/// A check which each request to guarded routes must pass. See [`preroll::guards`][crate::guards].
+pub trait Guard<State>: Send + Sync + 'static {
+    /// Allow the request, or reject it with the reason given to the client in a `403 Forbidden`.
+    fn check(&self, req: &Request<State>) -> Result<(), String>;
+}
+
+/// Allow only requests whose [`Scopes`][] contain the scope, e.g. `RequireScope("admin")`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequireScope(pub &'static str);
+
+impl<State> Guard<State> for RequireScope {
+    fn check(&self, req: &Request<State>) -> Result<(), String> {
+        let allowed = req
+            .ext::<Scopes>()
+            .map(|scopes| scopes.contains(self.0))
+            .unwrap_or(false);
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("The \"{}\" scope is required", self.0))
+        }
+    }
+}
+
+/// Rejects requests which any of its guards do not allow.
+pub(crate) struct GuardMiddleware<State> {
+    guards: Vec<Arc<dyn Guard<State>>>,
+}
+
+impl<State> GuardMiddleware<State> {
+    pub(crate) fn new(guards: Vec<Arc<dyn Guard<State>>>) -> Self {
+        Self { guards }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for GuardMiddleware<State> {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        for guard in &self.guards {
+            if let Err(reason) = guard.check(&req) {
+                return Err(tide::Error::from_str(StatusCode::Forbidden, reason));
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn rejects_requests_without_the_scope() -> tide::Result<()> {
+        let mut server = tide::new();
+        server
+            .at("/admin")
+            .with(GuardMiddleware::new(vec![Arc::new(RequireScope("admin"))]))
+            .get(|_| async { Ok("ok") });
+
+        let get = |scopes: Option<Scopes>| {
+            #[allow(clippy::unwrap_used)]
+            let mut req =
+                http::Request::new(Method::Get, Url::parse("http://example.com/admin").unwrap());
+            if let Some(scopes) = scopes {
+                req.ext_mut().insert(scopes);
+            }
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        assert_eq!(get(None).await?.status(), StatusCode::Forbidden);
+        assert_eq!(
+            get(Some(Scopes::new(["users:read"]))).await?.status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            get(Some(Scopes::new(["admin", "users:read"])))
+                .await?
+                .status(),
+            StatusCode::Ok
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Locale negotiation from `Accept-Language`, and localization of client-facing messages.
+//!
+//! [`LocaleMiddleware`][crate::middleware::LocaleMiddleware], which preroll installs by default, negotiates each request's
+//! [`Locale`][] from its `Accept-Language` header, among the `SUPPORTED_LOCALES`, or else the locales of the message
+//! [`Catalog`][]. Requests which accept none of them get the `DEFAULT_LOCALE`, which defaults to `en`.
+//!
+//! Handlers can read the locale with [`req.locale()`][LocaleRequestExt::locale], and translate messages with
+//! [`req.translate(..)`][LocaleRequestExt::translate], from the prelude.
+//!
+//! Once a catalog is [set][set_catalog], [`JsonError`][crate::JsonError] titles and messages are translated too,
+//! and error responses get a `Content-Language` header. Messages are looked up by their exact text, such as `Not Found`,
+//! or `Missing tenant id`, and are left as they are if there is no translation.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::i18n::{self, Catalog};
+//! use preroll::prelude::*;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     i18n::set_catalog(
+//!         Catalog::new()
+//!             .messages("fr", [("Not Found", "Introuvable"), ("Hello", "Bonjour")])
+//!             .messages("de", [("Not Found", "Nicht gefunden"), ("Hello", "Hallo")]),
+//!     );
+//!
+//!     server
+//!         .at("hello")
+//!         .get(|req: Request<Arc<()>>| async move { Ok(req.translate("Hello")) });
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result};
+use once_cell::sync::OnceCell;
+use tide::Request;
+
+static CATALOG: OnceCell<Catalog> = OnceCell::new();
+
+/// A negotiated locale, as a language tag such as `en` or `fr-CA`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn new(tag: impl Into<String>) -> Self {
+        Self(tag.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The primary language subtag, e.g. `fr` for `fr-CA`.
+    pub fn language(&self) -> &str {
+        language(&self.0)
+    }
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Translations of messages, by locale.
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// Create a new, empty `Catalog`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add translations of messages into `locale`, as `(message, translation)` pairs.
+    #[must_use]
+    pub fn messages<I, K, V>(mut self, locale: impl Into<String>, messages: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.locales.entry(locale.into()).or_default().extend(
+            messages
+                .into_iter()
+                .map(|(message, translation)| (message.into(), translation.into())),
+        );
+        self
+    }
+
+    /// Load a catalog from a directory of JSON files named by locale, such as `fr.json`,
+    /// each an object of messages and their translations.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let mut catalog = Self::new();
+        for entry in std::fs::read_dir(dir.as_ref())? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+
+            let locale = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| eyre!("Invalid catalog file name: {}", path.display()))?
+                .to_string();
+            let messages: HashMap<String, String> = serde_json::from_slice(&std::fs::read(&path)?)
+                .map_err(|e| eyre!("Invalid catalog file {}: {}", path.display(), e))?;
+            catalog = catalog.messages(locale, messages);
+        }
+        Ok(catalog)
+    }
+
+    /// The locales with translations.
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.locales.keys().map(String::as_str)
+    }
+
+    /// The translation of `message` into `locale`, or else into its language, e.g. `fr` for `fr-CA`.
+    pub fn translate(&self, locale: &str, message: &str) -> Option<&str> {
+        let lookup = |locale: &str| self.locales.get(locale)?.get(message).map(String::as_str);
+        lookup(locale).or_else(|| lookup(language(locale)))
+    }
+
+    /// Whether there are translations into `locale`, or its language.
+    fn has_locale(&self, locale: &str) -> bool {
+        self.locales.contains_key(locale) || self.locales.contains_key(language(locale))
+    }
+}
+
+/// Set the message catalog, once, during setup.
+pub fn set_catalog(catalog: Catalog) {
+    if CATALOG.set(catalog).is_err() {
+        log::warn!("A message catalog is already set, ignoring another.");
+    }
+}
+
+/// The message catalog, if one has been set.
+pub(crate) fn catalog() -> Option<&'static Catalog> {
+    CATALOG.get()
+}
+
+/// `message`, translated into `locale` if there is a translation.
+pub fn translate(locale: &Locale, message: &str) -> String {
+    translation(locale, message).unwrap_or(message).to_string()
+}
+
+/// The translation of `message` into `locale` in the message catalog, if there is one.
+pub(crate) fn translation(locale: &Locale, message: &str) -> Option<&'static str> {
+    catalog()?.translate(locale.as_str(), message)
+}
+
+/// Whether messages can be translated into `locale`.
+pub(crate) fn is_localized(locale: &Locale) -> bool {
+    catalog().is_some_and(|catalog| catalog.has_locale(locale.as_str()))
+}
+
+/// The language tags of an `Accept-Language` header, most preferred first, without any which are not acceptable (`q=0`)
+/// or wildcards.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then(|| (tag.to_string(), quality))
+        })
+        .collect();
+    // A stable sort, so that tags of the same quality keep their order.
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// The first of `supported` which matches an `accepted` tag, in order of preference, exactly or by language.
+pub fn negotiate<'s>(accepted: &[String], supported: &'s [String]) -> Option<&'s str> {
+    accepted.iter().find_map(|tag| {
+        supported
+            .iter()
+            .find(|locale| locale.eq_ignore_ascii_case(tag))
+            .or_else(|| {
+                supported
+                    .iter()
+                    .find(|locale| language(locale).eq_ignore_ascii_case(language(tag)))
+            })
+            .map(String::as_str)
+    })
+}
+
+fn language(tag: &str) -> &str {
+    tag.split(['-', '_']).next().unwrap_or(tag)
+}
+
+/// An extension trait for the current request's locale.
+pub trait LocaleRequestExt {
+    /// The locale negotiated by [`LocaleMiddleware`][crate::middleware::LocaleMiddleware], if it is installed.
+    fn locale(&self) -> Option<&Locale>;
+
+    /// `message`, translated into the request's locale if there is a translation in the message catalog.
+    fn translate(&self, message: &str) -> String;
+}
+
+impl<State> LocaleRequestExt for Request<State> {
+    fn locale(&self) -> Option<&Locale> {
+        self.ext::<Locale>()
+    }
+
+    fn translate(&self, message: &str) -> String {
+        match self.locale() {
+            Some(locale) => translate(locale, message),
+            None => message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method};
+    use tide::StatusCode;
+
+    use super::*;
+    use crate::middleware::{JsonErrorMiddleware, LocaleMiddleware, RequestIdMiddleware};
+    use crate::test_utils;
+    use crate::JsonError;
+
+    #[test]
+    fn negotiates_accepted_locales() {
+        let accepted = parse_accept_language("fr-CA;q=0.9, de;q=0, en-US;q=0.5, *;q=0.1, es");
+        assert_eq!(accepted, ["es", "fr-CA", "en-US"]);
+
+        let supported = ["en".to_string(), "fr".to_string(), "de".to_string()];
+        assert_eq!(negotiate(&accepted, &supported), Some("fr"));
+        assert_eq!(negotiate(&["de-AT".to_string()], &supported), Some("de"));
+        assert_eq!(negotiate(&["ja".to_string()], &supported), None);
+
+        let catalog = Catalog::new().messages("fr", [("Not Found", "Introuvable")]);
+        assert_eq!(catalog.translate("fr-CA", "Not Found"), Some("Introuvable"));
+        assert_eq!(catalog.translate("fr", "Bad Request"), None);
+        assert_eq!(catalog.translate("de", "Not Found"), None);
+    }
+
+    #[async_std::test]
+    async fn localizes_json_errors() -> tide::Result<()> {
+        set_catalog(Catalog::new().messages(
+            "fr",
+            [
+                ("Not Found", "Introuvable"),
+                ("No such greeting", "Salutation inconnue"),
+            ],
+        ));
+
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.with(LocaleMiddleware::new());
+        server.with(JsonErrorMiddleware::new());
+        server.at("/greeting").get(|_| async {
+            Err::<String, _>(tide::Error::from_str(
+                StatusCode::NotFound,
+                "No such greeting",
+            ))
+        });
+
+        let mut req = test_utils::request(Method::Get, "/greeting");
+        req.insert_header("Accept-Language", "fr-CA, en;q=0.5");
+        let mut res: http::Response = server.respond(req).await?;
+
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(
+            res.header("Content-Language").map(|values| values.as_str()),
+            Some("fr")
+        );
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.title, "Introuvable");
+        assert_eq!(error.message, "Salutation inconnue");
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+//! Safe retries of `POST` and `PUT` requests, with the `Idempotency-Key` header.
+//!
+//! The first response for each key is stored for `IDEMPOTENCY_TTL_SECS` (default `86400`, one day), and
+//! replayed for any retry with the same key, with an `Idempotent-Replayed: true` header, rather than handling
+//! the request again. A retry while the first request is still being handled gets a `409 Conflict`,
+//! and reusing a key for a different request (method, path, or body) gets a `422 Unprocessable Entity`.
+//! Server errors are not stored, so that the request can be retried.
+//!
+//! Keys are scoped to the caller: the request's [tenant][crate::TenantMiddleware], if any, and its `Authorization`
+//! header, or else its [client address][crate::ClientIp]. The same key sent by another caller is a different key,
+//! so that it never replays the first caller's response. Keys are stored as a digest of the key and the caller.
+//!
+//! While a request is handled its key is claimed for at most `IDEMPOTENCY_LEASE_SECS` (default `60`), after which
+//! the claim expires, such as if the service stopped, and a retry is handled again. It should be longer than
+//! requests take to handle.
+//!
+//! Keys are stored in redis if the `"redis"` feature is enabled and `REDISURL` is set, or else in the `idempotency_keys`
+//! postgres table, created if it does not exist, if the `"postgres"` feature is enabled, or else in memory,
+//! which is only suitable for a single instance. Other stores can be used by implementing [`IdempotencyStore`][]
+//! and calling [`set_store`][] during setup, e.g. in `setup_state`.
+//!
+//! ## Example:
+//!
+//! ```sh
+//! curl -X POST localhost:8080/api/v1/payments \
+//!     -H "Idempotency-Key: 4b0e6e62-4c47-4a4f-9a2a-1b6f5e4f1e0d" \
+//!     -d '{"amount": 100}'
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+static STORE: OnceCell<Arc<dyn IdempotencyStore>> = OnceCell::new();
+
+/// A response stored for replaying to retries.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The outcome of claiming an idempotency key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Claim {
+    /// The key was unused, or expired, and the request should be handled.
+    Claimed,
+    /// Another request with the key is still being handled.
+    InProgress,
+    /// A request with the key was already handled, with this response.
+    Completed(StoredResponse),
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Stores idempotency keys, and the responses to their requests.
+#[tide::utils::async_trait]
+pub trait IdempotencyStore: Send + Sync + 'static {
+    /// Claim `key` for a request with `fingerprint`, for at most `lease` while the request is handled.
+    async fn claim(&self, key: &str, fingerprint: &str, lease: Duration) -> anyhow::Result<Claim>;
+
+    /// Store the response to the request which claimed `key`, for `ttl`.
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        ttl: Duration,
+    ) -> anyhow::Result<()>;
+
+    /// Release a claim on `key` which has no response, so that the request can be retried.
+    async fn release(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Set the store for idempotency keys.
+///
+/// Only the first store set is used.
+pub fn set_store(store: impl IdempotencyStore) {
+    if STORE.set(Arc::new(store)).is_err() {
+        log::warn!("An idempotency store is already set, ignoring another.");
+    }
+}
+
+/// The store which has been set, or else an in-memory store.
+pub(crate) fn store() -> Arc<dyn IdempotencyStore> {
+    STORE
+        .get_or_init(|| Arc::new(MemoryStore::default()))
+        .clone()
+}
+
+#[derive(Debug)]
+struct Entry {
+    fingerprint: String,
+    expires_at: Instant,
+    response: Option<StoredResponse>,
+}
+
+/// Stores idempotency keys in memory, for a single instance.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[tide::utils::async_trait]
+impl IdempotencyStore for MemoryStore {
+    async fn claim(&self, key: &str, fingerprint: &str, lease: Duration) -> anyhow::Result<Claim> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Idempotency store lock poisoned"))?;
+
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at > now);
+
+        let claim = match entries.get(key) {
+            Some(entry) if entry.fingerprint != fingerprint => Claim::Mismatch,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => Claim::Completed(response.clone()),
+            Some(_) => Claim::InProgress,
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        fingerprint: fingerprint.to_string(),
+                        expires_at: now + lease,
+                        response: None,
+                    },
+                );
+                Claim::Claimed
+            }
+        };
+
+        Ok(claim)
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Idempotency store lock poisoned"))?;
+
+        if let Some(entry) = entries.get_mut(key) {
+            entry.expires_at = Instant::now() + ttl;
+            entry.response = Some(response.clone());
+        }
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> anyhow::Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Idempotency store lock poisoned"))?;
+
+        if entries
+            .get(key)
+            .is_some_and(|entry| entry.response.is_none())
+        {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub(crate) use self::postgres::setup;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use color_eyre::eyre::Result;
+    use sqlx::postgres::PgPool;
+    use sqlx::types::Json;
+
+    use super::{Claim, IdempotencyStore, StoredResponse, STORE};
+    use crate::builtins::schema;
+
+    /// A stored key's fingerprint, and its response, if completed.
+    type Row = (
+        String,
+        Option<i32>,
+        Option<Json<Vec<(String, String)>>>,
+        Option<Vec<u8>>,
+    );
+
+    /// Stores idempotency keys in the `idempotency_keys` postgres table, shared by all instances.
+    #[derive(Debug, Clone)]
+    pub struct PostgresStore {
+        pool: PgPool,
+    }
+
+    impl PostgresStore {
+        /// Create a new `PostgresStore`, which expects the `idempotency_keys` table to exist.
+        #[must_use]
+        pub fn new(pool: PgPool) -> Self {
+            Self { pool }
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl IdempotencyStore for PostgresStore {
+        async fn claim(
+            &self,
+            key: &str,
+            fingerprint: &str,
+            lease: Duration,
+        ) -> anyhow::Result<Claim> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            // Claims the key if it is unused, or has expired.
+            let claimed = sqlx::query(
+                "INSERT INTO idempotency_keys (key, fingerprint, expires_at) \
+                    VALUES ($1, $2, now() + make_interval(secs => $3)) \
+                    ON CONFLICT (key) DO UPDATE SET fingerprint = EXCLUDED.fingerprint, \
+                    status = NULL, headers = NULL, body = NULL, expires_at = EXCLUDED.expires_at \
+                    WHERE idempotency_keys.expires_at <= now()",
+            )
+            .bind(key)
+            .bind(fingerprint)
+            .bind(lease.as_secs_f64())
+            .execute(&mut conn)
+            .await?
+            .rows_affected()
+                == 1;
+            if claimed {
+                return Ok(Claim::Claimed);
+            }
+
+            let existing: Option<Row> = sqlx::query_as(
+                "SELECT fingerprint, status, headers, body FROM idempotency_keys WHERE key = $1",
+            )
+            .bind(key)
+            .fetch_optional(&mut conn)
+            .await?;
+
+            let claim = match existing {
+                // Released or expired since, so the client may simply retry.
+                None => Claim::InProgress,
+                Some((existing, ..)) if existing != fingerprint => Claim::Mismatch,
+                Some((_, Some(status), headers, body)) => Claim::Completed(StoredResponse {
+                    status: status as u16,
+                    headers: headers.map(|Json(headers)| headers).unwrap_or_default(),
+                    body: body.unwrap_or_default(),
+                }),
+                Some(_) => Claim::InProgress,
+            };
+
+            Ok(claim)
+        }
+
+        async fn complete(
+            &self,
+            key: &str,
+            response: &StoredResponse,
+            ttl: Duration,
+        ) -> anyhow::Result<()> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            sqlx::query(
+                "UPDATE idempotency_keys SET status = $2, headers = $3, body = $4, \
+                    expires_at = now() + make_interval(secs => $5) WHERE key = $1",
+            )
+            .bind(key)
+            .bind(i32::from(response.status))
+            .bind(Json(&response.headers))
+            .bind(&response.body)
+            .bind(ttl.as_secs_f64())
+            .execute(&mut conn)
+            .await?;
+
+            Ok(())
+        }
+
+        async fn release(&self, key: &str) -> anyhow::Result<()> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            sqlx::query("DELETE FROM idempotency_keys WHERE key = $1 AND status IS NULL")
+                .bind(key)
+                .execute(&mut conn)
+                .await?;
+
+            Ok(())
+        }
+    }
+
+    /// Unless another store has been set, create the `idempotency_keys` table and store keys there.
+    pub(crate) async fn setup(pool: &PgPool) -> Result<()> {
+        if STORE.get().is_some() {
+            return Ok(());
+        }
+
+        schema::migrate(pool, schema::IDEMPOTENCY_KEYS).await?;
+        STORE.set(Arc::new(PostgresStore::new(pool.clone()))).ok();
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_std::sync::Mutex;
+    use redis::aio::MultiplexedConnection;
+    use redis::Client;
+
+    use super::{Claim, IdempotencyStore, StoredResponse};
+
+    /// The prefix of every idempotency key in redis.
+    const KEY_PREFIX: &str = "preroll:idempotency:";
+
+    /// Claims the key if it is unused, or has expired, else returns the fields of its claim.
+    const CLAIM: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'fingerprint') == 1 then
+    return redis.call('HGETALL', KEYS[1])
+end
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[1], 'fingerprint', ARGV[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return {}
+"#;
+
+    /// Stores the response, unless the claim has expired.
+    const COMPLETE: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'fingerprint') == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'status', ARGV[1], 'headers', ARGV[2], 'body', ARGV[3])
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return 1
+"#;
+
+    /// Deletes the claim, unless it has a response.
+    const RELEASE: &str = r#"
+if redis.call('HEXISTS', KEYS[1], 'status') == 0 then
+    redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+    /// Stores idempotency keys in redis, as hashes which expire after their lease or TTL, shared by all instances.
+    pub struct RedisStore {
+        client: Client,
+        conn: Mutex<Option<MultiplexedConnection>>,
+    }
+
+    impl std::fmt::Debug for RedisStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisStore").finish()
+        }
+    }
+
+    impl RedisStore {
+        /// Create a new `RedisStore` for a `redis://` url, connecting once first used.
+        pub fn new(url: &str) -> redis::RedisResult<Self> {
+            Ok(Self {
+                client: Client::open(url)?,
+                conn: Mutex::new(None),
+            })
+        }
+
+        /// Run `cmd` on a shared connection, connecting if there is none, and reconnecting next time after an error.
+        async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+            let mut conn = self.conn.lock().await;
+            let mut connection = match conn.as_ref() {
+                Some(connection) => connection.clone(),
+                None => {
+                    let connected = self.client.get_multiplexed_async_std_connection().await?;
+                    *conn = Some(connected.clone());
+                    connected
+                }
+            };
+            drop(conn);
+
+            let result = cmd.query_async(&mut connection).await;
+            if result.is_err() {
+                *self.conn.lock().await = None;
+            }
+            result
+        }
+    }
+
+    /// An `EVAL` of `script` on `key`, to which the script's arguments are added.
+    fn eval(script: &str, key: &str) -> redis::Cmd {
+        let mut cmd = redis::cmd("EVAL");
+        cmd.arg(script).arg(1).arg(format!("{}{}", KEY_PREFIX, key));
+        cmd
+    }
+
+    #[tide::utils::async_trait]
+    impl IdempotencyStore for RedisStore {
+        async fn claim(
+            &self,
+            key: &str,
+            fingerprint: &str,
+            lease: Duration,
+        ) -> anyhow::Result<Claim> {
+            let mut fields: HashMap<String, Vec<u8>> = self
+                .query(
+                    eval(CLAIM, key)
+                        .arg(fingerprint)
+                        .arg(lease.as_millis() as u64),
+                )
+                .await?;
+            if fields.is_empty() {
+                return Ok(Claim::Claimed);
+            }
+
+            if fields.get("fingerprint").map(Vec::as_slice) != Some(fingerprint.as_bytes()) {
+                return Ok(Claim::Mismatch);
+            }
+
+            let status = match fields.get("status") {
+                Some(status) => String::from_utf8_lossy(status).parse()?,
+                None => return Ok(Claim::InProgress),
+            };
+            let headers = match fields.get("headers") {
+                Some(headers) => serde_json::from_slice(headers)?,
+                None => Vec::new(),
+            };
+
+            Ok(Claim::Completed(StoredResponse {
+                status,
+                headers,
+                body: fields.remove("body").unwrap_or_default(),
+            }))
+        }
+
+        async fn complete(
+            &self,
+            key: &str,
+            response: &StoredResponse,
+            ttl: Duration,
+        ) -> anyhow::Result<()> {
+            self.query::<()>(
+                eval(COMPLETE, key)
+                    .arg(response.status)
+                    .arg(serde_json::to_vec(&response.headers)?)
+                    .arg(response.body.as_slice())
+                    .arg(ttl.as_millis() as u64),
+            )
+            .await?;
+            Ok(())
+        }
+
+        async fn release(&self, key: &str) -> anyhow::Result<()> {
+            self.query::<()>(&eval(RELEASE, key)).await?;
+            Ok(())
+        }
+    }
+}
+
+/// If `REDISURL` is set, and no other store has been, store idempotency keys in redis.
+#[cfg(feature = "redis")]
+pub(crate) fn setup_redis() -> color_eyre::eyre::Result<()> {
+    if STORE.get().is_some() {
+        return Ok(());
+    }
+
+    if let Ok(url) = crate::config::var("REDISURL") {
+        STORE.set(Arc::new(RedisStore::new(&url)?)).ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &str) -> StoredResponse {
+        StoredResponse {
+            status: 201,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[async_std::test]
+    async fn memory_store_claims_and_replays() -> anyhow::Result<()> {
+        let store = MemoryStore::default();
+        let lease = Duration::from_secs(60);
+
+        assert_eq!(store.claim("key", "a", lease).await?, Claim::Claimed);
+        assert_eq!(store.claim("key", "a", lease).await?, Claim::InProgress);
+        assert_eq!(store.claim("key", "b", lease).await?, Claim::Mismatch);
+
+        store.complete("key", &response("{}"), lease).await?;
+        store.release("key").await?;
+        assert_eq!(
+            store.claim("key", "a", lease).await?,
+            Claim::Completed(response("{}"))
+        );
+        assert_eq!(store.claim("key", "b", lease).await?, Claim::Mismatch);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn memory_store_releases_and_expires() -> anyhow::Result<()> {
+        let store = MemoryStore::default();
+
+        assert_eq!(
+            store.claim("key", "a", Duration::from_secs(60)).await?,
+            Claim::Claimed
+        );
+        store.release("key").await?;
+        assert_eq!(
+            store.claim("key", "b", Duration::ZERO).await?,
+            Claim::Claimed
+        );
+        assert_eq!(
+            store.claim("key", "a", Duration::from_secs(60)).await?,
+            Claim::Claimed
+        );
+        Ok(())
+    }
+}
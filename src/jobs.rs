@@ -0,0 +1,420 @@
+//! A postgres-backed job queue, with in-process workers.
+//!
+//! Jobs are written into the request's own postgres transaction with [`enqueue_job`][JobRequestExt::enqueue_job],
+//! into the `jobs` table, and so are only run if, and once, that transaction commits.
+//!
+//! A pool of `JOBS_WORKERS` (default `4`) workers, started at setup, claims jobs with `FOR UPDATE SKIP LOCKED`,
+//! so that every instance of the service shares the queue without running a job twice at once, and runs them with
+//! the [handler registered][register_handler] for their type. Workers poll every `JOBS_POLL_MS` (default `1000`)
+//! while the queue is empty, and stop once the server is [shut down][crate::setup::shutdown_handle].
+//!
+//! Failed jobs, including those whose handler panics, are retried with exponential backoff, up to `JOBS_MAX_ATTEMPTS` (default `5`) attempts, after which
+//! they are marked as failed, with their last error, and logged at `ERROR`. A job is held locked while it runs,
+//! so a job which runs for longer than the transaction may stay open should instead enqueue smaller jobs.
+//! Jobs of types without a registered handler are left in the queue, for instances which handle them.
+//!
+//! Completed, retried, and failed jobs are counted under `"jobs"` in `/monitor/status`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::jobs::{self, Job};
+//! use preroll::prelude::*;
+//! use serde::{Deserialize, Serialize};
+//! use tide::Request;
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct Receipt {
+//!     order_id: i64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     jobs::register_handler("send_receipt", |job: Job| async move {
+//!         let receipt: Receipt = job.payload()?;
+//!         log::info!("Sending the receipt of order {}", receipt.order_id);
+//!         Ok(())
+//!     });
+//!     Ok(())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn create_order(req: Request<Arc<()>>) -> tide::Result {
+//!     let mut pg_conn = req.pg_conn().await;
+//!
+//!     // ... insert the order ...
+//!
+//!     req.enqueue_job(&mut **pg_conn, "send_receipt", &Receipt { order_id: 1 })
+//!         .await?;
+//!
+//!     Ok("created".into())
+//! }
+//! ```
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use futures_lite::FutureExt;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::types::Json;
+use sqlx::Connection;
+use tide::Request;
+use uuid::Uuid;
+
+use crate::builtins::{relay, schema};
+use crate::config;
+use crate::logging::log_kv;
+use crate::middleware::extension_types::RequestId;
+
+static HANDLERS: Lazy<RwLock<BTreeMap<String, Arc<JobHandlerFn>>>> = Lazy::new(Default::default);
+
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+static RETRIED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+type JobHandlerFn =
+    dyn Fn(Job) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync;
+
+/// `id, job_type, payload, attempts, request_id, created_at` of a claimed job.
+type JobRow = (
+    Uuid,
+    String,
+    Json<Value>,
+    i32,
+    Option<String>,
+    DateTime<Utc>,
+);
+
+/// A job claimed from the queue, as given to its handler.
+#[derive(Clone, Debug)]
+pub struct Job {
+    /// Unique per job, and the same across retries, so that handlers can deduplicate.
+    pub id: Uuid,
+    pub job_type: String,
+    /// The number of this attempt, starting at `1`.
+    pub attempt: u32,
+    /// The `X-Request-Id` of the request which enqueued the job.
+    pub request_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    payload: Value,
+}
+
+impl Job {
+    /// The job's payload, deserialized.
+    pub fn payload<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        T::deserialize(&self.payload)
+    }
+
+    /// The job's payload, as JSON.
+    pub fn payload_json(&self) -> &Value {
+        &self.payload
+    }
+}
+
+/// Register the handler of jobs of `job_type`, replacing any previous handler.
+///
+/// Handlers may be registered at any time, such as in `setup_state`. Errors are retried with backoff.
+pub fn register_handler<F, Fut>(job_type: impl Into<String>, handler: F)
+where
+    F: Fn(Job) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    HANDLERS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(job_type.into(), Arc::new(move |job| Box::pin(handler(job))));
+}
+
+/// An extension trait for enqueueing jobs within the request's transaction.
+#[tide::utils::async_trait]
+pub trait JobRequestExt {
+    /// Write a job of `job_type` with `payload` into `tx`, to be run once, and only if, the transaction commits.
+    ///
+    /// Returns the job's id.
+    async fn enqueue_job<T: Serialize + Sync>(
+        &self,
+        tx: &mut PgConnection,
+        job_type: &str,
+        payload: &T,
+    ) -> sqlx::Result<Uuid>;
+}
+
+#[tide::utils::async_trait]
+impl<State: Send + Sync + 'static> JobRequestExt for Request<State> {
+    async fn enqueue_job<T: Serialize + Sync>(
+        &self,
+        tx: &mut PgConnection,
+        job_type: &str,
+        payload: &T,
+    ) -> sqlx::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let request_id = self.ext::<RequestId>().map(|id| id.as_str().to_string());
+
+        sqlx::query("INSERT INTO jobs (id, job_type, payload, request_id) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind(job_type)
+            .bind(Json(payload))
+            .bind(request_id)
+            .execute(tx)
+            .await?;
+
+        Ok(id)
+    }
+}
+
+/// Create the jobs table and start the workers.
+pub(crate) async fn setup(pool: &PgPool) -> Result<()> {
+    schema::migrate(pool, schema::JOBS).await?;
+
+    let workers: usize = config::var("JOBS_WORKERS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(4))?;
+    let interval: u64 = config::var("JOBS_POLL_MS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(1000))?;
+    let max_attempts: i32 = config::var("JOBS_MAX_ATTEMPTS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(5))?;
+
+    for _ in 0..workers {
+        let pool = pool.clone();
+        // There may be more jobs ready to run after each one.
+        relay::spawn("Job worker", Duration::from_millis(interval), move || {
+            let pool = pool.clone();
+            async move { work(&pool, max_attempts).await }
+        });
+    }
+
+    Ok(())
+}
+
+/// Claim and run one due job, returning whether there was one.
+async fn work(pool: &PgPool, max_attempts: i32) -> Result<bool> {
+    let job_types: Vec<String> = HANDLERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .keys()
+        .cloned()
+        .collect();
+    if job_types.is_empty() {
+        return Ok(false);
+    }
+
+    let mut conn = crate::builtins::pool_stats::acquire(pool).await?;
+    let mut tx = conn.begin().await?;
+
+    let claimed: Option<JobRow> = sqlx::query_as(
+        "SELECT id, job_type, payload, attempts, request_id, created_at FROM jobs \
+            WHERE completed_at IS NULL AND failed_at IS NULL AND run_at <= now() AND job_type = ANY($1) \
+            ORDER BY run_at LIMIT 1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(&job_types)
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let (id, job_type, Json(payload), attempts, request_id, created_at) = match claimed {
+        Some(claimed) => claimed,
+        None => return Ok(false),
+    };
+
+    let handler = HANDLERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&job_type)
+        .cloned();
+    let job = Job {
+        id,
+        job_type,
+        attempt: attempts as u32 + 1,
+        request_id,
+        created_at,
+        payload,
+    };
+
+    match run(handler, job.clone()).await {
+        Ok(()) => {
+            sqlx::query(
+                "UPDATE jobs SET completed_at = now(), attempts = attempts + 1 WHERE id = $1",
+            )
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(error) => match retry_secs(attempts + 1, max_attempts) {
+            None => {
+                log_kv!(
+                    error,
+                    {
+                        job_id: id.to_string(),
+                        job_type: job.job_type,
+                        attempts: attempts + 1,
+                        request_id: job.request_id.as_deref().unwrap_or(""),
+                    },
+                    "Job failed, and will not be retried: {:?}", error
+                );
+
+                sqlx::query(
+                    "UPDATE jobs SET attempts = attempts + 1, last_error = $2, failed_at = now() WHERE id = $1",
+                )
+                .bind(id)
+                .bind(format!("{:?}", error))
+                .execute(&mut tx)
+                .await?;
+                FAILED.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(retry_secs) => {
+                log_kv!(
+                    warn,
+                    {
+                        job_id: id.to_string(),
+                        job_type: job.job_type,
+                        attempts: attempts + 1,
+                        request_id: job.request_id.as_deref().unwrap_or(""),
+                    },
+                    "Job failed, retrying in {}s: {:?}", retry_secs, error
+                );
+
+                sqlx::query(
+                    "UPDATE jobs SET attempts = attempts + 1, last_error = $2, \
+                    run_at = now() + make_interval(secs => $3) WHERE id = $1",
+                )
+                .bind(id)
+                .bind(format!("{:?}", error))
+                .bind(retry_secs)
+                .execute(&mut tx)
+                .await?;
+                RETRIED.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+    }
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Run `job` with its handler, failing if it has none, or if the handler panics, rather than the worker.
+async fn run(handler: Option<Arc<JobHandlerFn>>, job: Job) -> anyhow::Result<()> {
+    let handler = match handler {
+        Some(handler) => handler,
+        None => anyhow::bail!("No handler registered for {}", job.job_type),
+    };
+
+    AssertUnwindSafe(handler(job))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| Err(anyhow::anyhow!("Job panicked: {}", panic_message(&*panic))))
+}
+
+/// The message of a panic's payload, which is usually a `&str` or a `String`.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("(no message)"),
+    }
+}
+
+/// The seconds to wait before retrying a job which has failed `attempts` times,
+/// or `None` if it has used all of its `max_attempts`.
+fn retry_secs(attempts: i32, max_attempts: i32) -> Option<f64> {
+    if attempts >= max_attempts {
+        return None;
+    }
+    Some(relay::backoff(attempts as u32, relay::MAX_BACKOFF).as_secs_f64())
+}
+
+/// The jobs run by this instance's workers, reported under `"jobs"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStats {
+    handlers: usize,
+    completed: u64,
+    retried: u64,
+    failed: u64,
+}
+
+/// The job stats, if any handlers are registered.
+pub(crate) fn job_stats() -> Option<JobStats> {
+    let handlers = HANDLERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .len();
+    if handlers == 0 {
+        return None;
+    }
+
+    Some(JobStats {
+        handlers,
+        completed: COMPLETED.load(Ordering::Relaxed),
+        retried: RETRIED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn fails_attempts_which_panic() {
+        let job = Job {
+            id: Uuid::nil(),
+            job_type: "explode".to_string(),
+            attempt: 1,
+            request_id: None,
+            created_at: Utc::now(),
+            payload: Value::Null,
+        };
+
+        let handler: Arc<JobHandlerFn> = Arc::new(|job: Job| {
+            Box::pin(async move {
+                if job.attempt == 1 {
+                    panic!("boom on attempt {}", job.attempt);
+                }
+                Ok(())
+            })
+        });
+
+        let error = run(Some(handler.clone()), job.clone()).await.err();
+        assert_eq!(
+            error.map(|error| error.to_string()),
+            Some("Job panicked: boom on attempt 1".to_string())
+        );
+
+        assert!(run(
+            Some(handler),
+            Job {
+                attempt: 2,
+                ..job.clone()
+            }
+        )
+        .await
+        .is_ok());
+        assert!(run(None, job).await.is_err());
+    }
+
+    #[test]
+    fn stops_retrying_after_max_attempts() {
+        assert_eq!(retry_secs(5, 5), None);
+        assert_eq!(retry_secs(6, 5), None);
+        assert_eq!(retry_secs(1, 1), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! JSON serialization for response bodies.
+//!
+//! Serialization can dominate CPU time for large responses, such as long lists.
+//! With the `"simd-json"` feature, these functions use [`simd-json`](https://docs.rs/simd-json)'s serializer,
+//! otherwise `serde_json`. preroll's own JSON error bodies are serialized with these functions.
+//!
+//! The difference can be measured for a given machine with `cargo bench --bench json --features simd-json`.
+//!
+//! ## Example:
+//!
+//! ```
+//! use serde::Serialize;
+//! use tide::Request;
+//!
+//! #[derive(Serialize)]
+//! struct Item {
+//!     id: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn list(_req: Request<()>) -> tide::Result {
+//!     let items: Vec<Item> = (0..1000).map(|id| Item { id }).collect();
+//!     Ok(preroll::json::body(&items)?.into())
+//! }
+//! ```
+
+use serde::Serialize;
+use tide::http::mime;
+use tide::Body;
+
+/// Serialize `value` as a JSON byte vector.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> tide::Result<Vec<u8>> {
+    #[cfg(feature = "simd-json")]
+    let bytes = simd_json::serde::to_vec(value)?;
+    #[cfg(not(feature = "simd-json"))]
+    let bytes = serde_json::to_vec(value)?;
+
+    Ok(bytes)
+}
+
+/// Serialize `value` as a JSON response body, with an `application/json` content type.
+///
+/// Equivalent to [`Body::from_json`][], but with the configured serializer.
+pub fn body<T: Serialize + ?Sized>(value: &T) -> tide::Result<Body> {
+    let mut body = Body::from_bytes(to_vec(value)?);
+    body.set_mime(mime::JSON);
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn serializes_like_serde_json() -> tide::Result<()> {
+        let value = json!({ "items": [{ "id": 1, "name": "one\n" }, { "id": 2, "name": null }] });
+
+        let body = body(&value)?;
+        assert_eq!(body.mime(), &mime::JSON);
+        assert_eq!(body.into_bytes().await?, serde_json::to_vec(&value)?);
+        Ok(())
+    }
+}
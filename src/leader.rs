@@ -0,0 +1,323 @@
+//! Leader election between the instances of a service, with postgres advisory locks.
+//!
+//! [`elect`][] starts campaigning for the leadership of `name` in the background. At most one instance at a time holds
+//! the advisory lock of `name`, which it holds for as long as its session lives, on a connection taken from the
+//! [shared pool][crate::postgres::shared_pool], and so counted against `PGMAXCONNECTIONS`.
+//!
+//! Instances which are not the leader retry every `LEADER_CHECK_MS` (default `5000`), which is also how often the
+//! leader checks that its connection, and so its lock, is still alive. If the leader stops or loses its connection,
+//! another instance takes over within about that long. Leadership is released once the server is
+//! [shut down][crate::setup::shutdown_handle].
+//!
+//! A [`Warmer`][crate::warmers::Warmer] can be run only by the leader with
+//! [`leader_only`][crate::warmers::Warmer::leader_only]. The outbox relay and job workers instead share their work
+//! between every instance, with `FOR UPDATE SKIP LOCKED`.
+//!
+//! Every election, whether this instance is its leader, and its last error, are reported under `"leaders"`
+//! in `/monitor/status`.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use async_std::task;
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     let leadership = preroll::leader::elect("cart-cleanup");
+//!     task::spawn(async move {
+//!         loop {
+//!             task::sleep(Duration::from_secs(60)).await;
+//!             if !leadership.is_leader() {
+//!                 continue;
+//!             }
+//!             if let Ok(mut conn) = preroll::postgres::acquire().await {
+//!                 sqlx::query("DELETE FROM carts WHERE updated_at < now() - interval '30 days'")
+//!                     .execute(&mut conn)
+//!                     .await
+//!                     .ok();
+//!             }
+//!         }
+//!     });
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+use async_std::task;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Result};
+use futures_lite::future;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sqlx::pool::PoolConnection;
+use sqlx::Postgres;
+
+use crate::builtins::pool_stats;
+use crate::builtins::shutdown::shutdown_handle;
+use crate::config;
+use crate::logging::log_kv;
+
+static ELECTIONS: Lazy<RwLock<BTreeMap<&'static str, Leadership>>> = Lazy::new(Default::default);
+
+/// This instance's view of an election. See [`preroll::leader`][crate::leader].
+#[derive(Clone, Debug)]
+pub struct Leadership {
+    name: &'static str,
+    state: Arc<ElectionState>,
+}
+
+#[derive(Debug, Default)]
+struct ElectionState {
+    leader: AtomicBool,
+    since: Mutex<Option<DateTime<Utc>>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Leadership {
+    /// The election's name.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether this instance is currently the leader.
+    #[must_use]
+    pub fn is_leader(&self) -> bool {
+        self.state.leader.load(Ordering::SeqCst)
+    }
+
+    fn set_leader(&self, leader: bool) {
+        if self.state.leader.swap(leader, Ordering::SeqCst) == leader {
+            return;
+        }
+
+        *self
+            .state
+            .since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = leader.then(Utc::now);
+        if leader {
+            log_kv!(info, { election: self.name }, "Elected the leader of {}", self.name);
+        } else {
+            log_kv!(warn, { election: self.name }, "No longer the leader of {}", self.name);
+        }
+    }
+
+    fn set_error(&self, error: Option<String>) {
+        *self
+            .state
+            .last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = error;
+    }
+}
+
+/// Campaign for the leadership of `name`, in the background, until the server is shut down.
+///
+/// Electing the same name again returns the same `Leadership`.
+pub fn elect(name: &'static str) -> Leadership {
+    let mut elections = ELECTIONS.write().unwrap_or_else(PoisonError::into_inner);
+    if let Some(leadership) = elections.get(name) {
+        return leadership.clone();
+    }
+
+    let leadership = Leadership {
+        name,
+        state: Arc::new(ElectionState::default()),
+    };
+    elections.insert(name, leadership.clone());
+
+    let interval = Duration::from_millis(
+        config::var("LEADER_CHECK_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5000),
+    );
+    let campaigner = leadership.clone();
+    task::spawn(async move {
+        let shutdown = shutdown_handle();
+        while !shutdown.is_stopped() {
+            match campaign(&campaigner, interval).await {
+                Ok(()) => campaigner.set_error(None),
+                Err(error) => {
+                    log_kv!(warn, { election: name }, "Leader election of {} failed: {:?}", name, error);
+                    campaigner.set_error(Some(error.to_string()));
+                }
+            }
+            campaigner.set_leader(false);
+            future::or(task::sleep(interval), shutdown.stopped()).await;
+        }
+    });
+
+    leadership
+}
+
+/// Try to take the lock of the election, and if it is taken, hold it until the connection fails or the server stops.
+async fn campaign(leadership: &Leadership, interval: Duration) -> Result<()> {
+    let pool = pool_stats::pool().ok_or_else(|| eyre!("No postgres pool has been set up"))?;
+    let mut conn = pool_stats::acquire(&pool).await?;
+
+    let (acquired,): (bool,) =
+        sqlx::query_as("SELECT pg_try_advisory_lock(hashtext('preroll.leader'), hashtext($1))")
+            .bind(leadership.name)
+            .fetch_one(&mut conn)
+            .await?;
+    if !acquired {
+        return Ok(());
+    }
+    leadership.set_leader(true);
+
+    let shutdown = shutdown_handle();
+    loop {
+        future::or(task::sleep(interval), shutdown.stopped()).await;
+        if shutdown.is_stopped() {
+            break;
+        }
+
+        // Held by the session, so the lock is held for as long as the connection works.
+        if let Err(error) = sqlx::query("SELECT 1").execute(&mut conn).await {
+            leadership.set_leader(false);
+            close(conn);
+            return Err(error.into());
+        }
+    }
+
+    // Advisory locks are held by the session, so must be released before the connection returns to the pool.
+    leadership.set_leader(false);
+    let unlocked =
+        sqlx::query("SELECT pg_advisory_unlock(hashtext('preroll.leader'), hashtext($1))")
+            .bind(leadership.name)
+            .execute(&mut conn)
+            .await;
+    if unlocked.is_err() {
+        close(conn);
+    }
+    unlocked?;
+
+    Ok(())
+}
+
+/// Close a connection which may still hold the election's lock, rather than return it to the pool,
+/// where the lock would be held by whichever task used it next. Its session, and so the lock, ends with it.
+fn close(conn: PoolConnection<Postgres>) {
+    drop(conn.detach());
+}
+
+/// An election's status on this instance, reported under `"leaders"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderStats {
+    leader: bool,
+    /// When this instance became the leader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// The status of every election, if there are any.
+pub(crate) fn leader_stats() -> Option<BTreeMap<&'static str, LeaderStats>> {
+    let elections = ELECTIONS.read().unwrap_or_else(PoisonError::into_inner);
+    if elections.is_empty() {
+        return None;
+    }
+
+    let stats = elections
+        .iter()
+        .map(|(name, leadership)| {
+            let state = &leadership.state;
+            let stats = LeaderStats {
+                leader: state.leader.load(Ordering::SeqCst),
+                since: *state.since.lock().unwrap_or_else(PoisonError::into_inner),
+                last_error: state
+                    .last_error
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            };
+            (*name, stats)
+        })
+        .collect();
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leadership(name: &'static str) -> Leadership {
+        let leadership = Leadership {
+            name,
+            state: Arc::new(ElectionState::default()),
+        };
+        ELECTIONS
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, leadership.clone());
+        leadership
+    }
+
+    fn stats(name: &str) -> Option<serde_json::Value> {
+        let stats = leader_stats()?;
+        serde_json::to_value(stats.get(name)?).ok()
+    }
+
+    #[test]
+    fn tracks_leadership() {
+        let leadership = leadership("leader-test-transitions");
+        assert!(!leadership.is_leader());
+        assert_eq!(
+            stats("leader-test-transitions"),
+            Some(serde_json::json!({ "leader": false }))
+        );
+
+        leadership.set_leader(true);
+        assert!(leadership.is_leader());
+        let since = *leadership
+            .state
+            .since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        assert!(since.is_some());
+
+        // Staying the leader keeps the original election time.
+        leadership.set_leader(true);
+        assert_eq!(
+            *leadership
+                .state
+                .since
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            since
+        );
+
+        leadership.set_leader(false);
+        assert!(!leadership.is_leader());
+        assert_eq!(
+            stats("leader-test-transitions"),
+            Some(serde_json::json!({ "leader": false }))
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let leadership = leadership("leader-test-errors");
+
+        leadership.set_error(Some("connection refused".to_string()));
+        assert_eq!(
+            stats("leader-test-errors"),
+            Some(serde_json::json!({ "leader": false, "lastError": "connection refused" }))
+        );
+
+        leadership.set_error(None);
+        assert_eq!(
+            stats("leader-test-errors"),
+            Some(serde_json::json!({ "leader": false }))
+        );
+    }
+}
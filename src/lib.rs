@@ -44,8 +44,46 @@
 //!
 //! - Boilerplate `main` setup via [`preroll::main!`][], with optional features automatically configured.
 //! - A [`preroll::prelude::*;`][] with all extension traits.
-//! - Response logging with many details.
+//! - Response logging with many details, including the client's address from trusted proxies' `Forwarded` headers.
+//! - [`Pii`][] and [`Secret`][] log field wrappers, which are hashed or masked in production and in JSON logs,
+//!   and readable in development. Client addresses and referers are logged as `Pii`.
+//! - [Route templates][routes], e.g. `/api/v1/users/:id`, in logs, traces, and stats, rather than each unique path.
+//! - [Deprecation][versions] of old API versions, with `Deprecation` and `Sunset` headers, usage counts, and optional
+//!   `410 Gone` responses after their sunset date.
+//! - [Route middleware][routes#route-middleware], e.g. for authentication, which always runs within preroll's middleware.
+//! - Declarative [guards][guards], e.g. `RequireScope("admin")`, for the authorization of whole API versions.
 //! - Automatic JSON responses for errors in the form of [`JsonError`][].
+//! - [Consistent JSON envelopes][respond] for successful responses, with `Location` headers for `201 Created`.
+//! - Optional CSRF protection for cookie-authenticated routes, with [`CsrfMiddleware`][].
+//! - `Accept-Language` negotiation of each request's [locale][i18n], and localized error messages from a message catalog.
+//! - [Webhook][webhooks] signature verification, with Stripe and GitHub presets, replay protection, and signed test payloads.
+//! - Signed outbound [webhook][webhooks::dispatch] deliveries to registered endpoints, with retries, backoff, and dead-lettering.
+//! - Optional tenant resolution for multi-tenant services, with [`TenantMiddleware`][], from a header, subdomain, or JWT claim.
+//! - [`PrerollResources`][], preroll's database pools, redis client, and configuration, for reuse by `setup_state`.
+//! - Request-scoped [dependency injection][di], with per-test overrides for mocking services.
+//! - [Feature flags][flags], from settings by default, or from LaunchDarkly or Unleash, with per-test overrides.
+//! - Cron-scheduled [warmers][], which pre-populate caches or refresh tokens with the application's state,
+//!   without overlapping runs.
+//! - OAuth2 client-credentials [tokens][client::TokenProvider] for service-to-service requests, cached, refreshed before
+//!   they expire, and sent by a surf middleware.
+//! - A [registry][client::registry] of shared outbound clients by upstream name, with per-upstream base urls, timeouts,
+//!   and connection limits, and request statistics in `/monitor/status`.
+//! - [Circuit breakers][client::CircuitBreaker] for outbound clients, which reject requests to a failing upstream with a `503`.
+//! - Pre-routing [request rewrites][rewrite], such as legacy path aliases, recorded in the response log.
+//! - Optional ETags for JSON responses, and `304 Not Modified` replies to matching `If-None-Match` requests,
+//!   with [`ETagMiddleware`][].
+//! - Optional coalescing of concurrent identical `GET` requests, with [`SingleFlightMiddleware`][].
+//! - Optional per-route [response caching][cache], in memory or in redis, with `Cache-Status` headers and stats.
+//! - [Server-Sent Events][sse] endpoints, with keep-alives and disconnect detection.
+//! - [`multipart/form-data` uploads][extract::Multipart], with per-part and total size limits, and large parts spilled to temp files.
+//! - [JSON bodies][extract::Json] whose errors have the pointer to each offending field, and the type expected there.
+//! - A streaming [proxy][utils::proxy] to upstream services, for thin API facades, which propagates request ids and traces.
+//! - Cursor and offset [pagination][pagination], with validated query parameters, `Link` headers, and a consistent JSON envelope.
+//! - Global and per-route [concurrency limits][ConcurrencyLimitMiddleware], which queue requests briefly and then shed them
+//!   with a `503`, rather than exhausting memory under spikes.
+//! - Route [priorities][PriorityMiddleware], so that low-priority routes are shed first under load, while critical routes keep working.
+//! - Rejection of requests with conflicting `Content-Length` / `Transfer-Encoding`, duplicate critical headers,
+//!   or absurd header counts / sizes.
 //! - [Test utils][] with easy mock client setup.
 //!
 //! ## Optional features
@@ -58,17 +96,54 @@
 //! ```
 //!
 //! ### List of optional add-on features:
+//! - `"aws-secrets"`: Resolves setting values which reference AWS Secrets Manager or SSM Parameter Store at startup.
+//!     - `aws-sm://{secret-id}` resolves to the secret's string value, or `aws-sm://{secret-id}#{key}` to a single key
+//!       of a JSON secret, e.g. `PGURL=aws-sm://prod/my-service#pgurl`.
+//!     - `aws-ssm://{parameter-name}` resolves to the (decrypted) parameter value, e.g. `aws-ssm:///prod/my-service/pgurl`.
+//!     - Env variable `AWS_REGION` (required), and credentials from `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY` /
+//!       `AWS_SESSION_TOKEN`, or else the ECS container credentials endpoint.
+//!     - Env variable `AWS_SECRETS_REFRESH_SECS`, if set, re-resolves secrets on that interval.
+//!       Only settings read after a refresh, rather than at startup, see refreshed values.
+//!     - Startup fails if any reference cannot be resolved.
+//! - `"grpc-web"`: Serves gRPC services, with JSON messages, via JSON transcoding or grpc-web's JSON codec.
+//!     - Methods are mounted as routes at `{package.Service}/{Method}`, under `/api/v{N}` like other routes, with [`preroll::grpc`][]'s `GrpcService`.
+//!     - Errors are `JsonError`s when transcoding, or `grpc-status` trailers for grpc-web clients.
 //! - `"honeycomb"`: Enables tracing to [honeycomb.io].
 //!     - Env variable `HONEYCOMBIO_WRITE_KEY` (required).
 //!     - Env variable `TRACELEVEL`, sets the tracing level filter, defaults to `info`.
+//!     - Env variable `HONEYCOMB_RESILIENT=true`, if set, honeycomb setup failures never prevent the service from starting:
+//!       the write key is checked and the exporter connected in the background, retrying with backoff,
+//!       and telemetry is dropped (while trace ids are still assigned) until it succeeds.
+//!     - Env variable `HONEYCOMB_SAMPLE_RATE`, if set, keeps 1 in that many traces.
+//!     - Env variable `HONEYCOMB_SAMPLE_RULES`, if set, samples each trace once its request has completed instead:
+//!       server errors and slow requests are always kept, and other requests are kept at the rate of the first matching
+//!       `pattern=rate` rule, e.g. `/api/v1/products*=20,/api/v1/health=100`, or otherwise `HONEYCOMB_SAMPLE_RATE`.
+//!       Not supported with `"lambda-http"`.
 //!     - Writes to a dataset named `{service_name}-{environment}`.
 //!         - `service_name` is from `preroll::main!("service_name", ...)`.
 //!         - `environment` is from `ENVIRONMENT`, or defaults to `"development"`.
+//!     - Handlers can add custom fields to the request's span with `req.trace_field(name, value)`, from the prelude.
+//!     - Handlers and inner functions can be given their own child spans with `#[preroll::instrument]`.
+//! - `"idempotency"`: Replays the stored response to `POST` and `PUT` retries with the same `Idempotency-Key` header.
+//!     - Env variable `IDEMPOTENCY_TTL_SECS`, how long responses are stored, defaults to `86400` (one day).
+//!     - Env variable `IDEMPOTENCY_LEASE_SECS`, how long a key stays claimed by a request which never completes,
+//!       defaults to `60`. It should be longer than requests take to handle.
+//!     - Keys are stored in redis if the `"redis"` feature is enabled and `REDISURL` is set, or else in postgres
+//!       if the `"postgres"` feature is enabled, or else in memory.
+//!     - See [`preroll::idempotency`][] for details.
 //! - `"lambda-http"`: Changes the HTTP listener to connect to an AWS Lambda execution environment.
 //!     - Is no longer reachable as a regular http server, but accepts http lambda requests as if it were one.
 //!     - Some environment variables, such as `PORT`, are disregarded.
 //!     - If the `"honeycomb"` feature is enabled, trace events are written to stdout, and must be collected via
-//!         a layer provided by Honeycomb. See: https://docs.honeycomb.io/getting-data-in/integrations/aws/aws-lambda/
+//!       a layer provided by Honeycomb. See: https://docs.honeycomb.io/getting-data-in/integrations/aws/aws-lambda/
+//! - `"launchdarkly"`: Evaluates [feature flags][flags] with [`LaunchDarklyProvider`][flags::LaunchDarklyProvider].
+//!     - Env variable `LAUNCHDARKLY_SDK_KEY`, which enables the provider if set.
+//!     - Env variable `LAUNCHDARKLY_BASE_URL`, such as a relay proxy. Defaults to `https://sdk.launchdarkly.com/`.
+//! - `"pg-jobs"`: Adds `"postgres"`, and a postgres-backed [job queue][jobs], enqueued within request transactions
+//!   with [`req.enqueue_job(..)`][prelude::JobRequestExt::enqueue_job], and run by in-process workers.
+//!     - Env variable `JOBS_WORKERS`, default `4` workers, which poll every `JOBS_POLL_MS` (default `1000`) while idle.
+//!     - Env variable `JOBS_MAX_ATTEMPTS`, default `5`, after which failing jobs are marked as failed.
+//!     - Reports completed, retried, and failed jobs under `"jobs"` in `/monitor/status`.
 //! - `"postgres"`: Enables a postgres connection pool with transactions.
 //!     - Env variable `PGURL`, which should be a properly formatted `postgres://` database url.
 //!         - Defaults to `"postgres://localhost/{service_name}"` (default postgres port).
@@ -76,18 +151,172 @@
 //!     - Env variable `PGMAXCONNECTIONS`, default 5 connections.
 //!     - Env variable `PGMAXLIFETIME`, default `30` (minutes).
 //!     - Enables [`PostgresRequestExt`][prelude::PostgresRequestExt] and [`test_utils::create_client_and_postgres`][].
+//!     - Enables [`req.pg_transaction(|tx| ...)`][prelude::PostgresTransactionExt::pg_transaction],
+//!       which commits or rolls back based on the closure's result.
+//!     - Reports `postgresReachability` under `"downstream"` in `/monitor/status`.
+//!     - Reports pool size, idle and in-use connections, and connection acquisition wait times,
+//!       under `"postgresPool"` in `/monitor/status`.
+//!     - Env variable `PG_ACQUIRE_WARN_MS`, default `1000`: connection acquisitions slower than this are logged at `WARN`.
+//!     - SQLx statement logs (at `DEBUG`) include the `request_id`, and `honeycomb_trace_id`, of the request which ran them.
+//!     - Enables transactional [`notifications`][] (email, SMS, push), delivered by a background relay only once committed.
+//!     - Enables [`postgres::shared_pool()`][postgres::shared_pool], for queries outside of requests, such as in background tasks.
+//!     - Enables transactional event publishing via an [`outbox`][], to an HTTP webhook (`OUTBOX_WEBHOOK_URL`) or a custom sink.
+//!     - Enables [leader election][leader] between instances with advisory locks, e.g. for leader-only warmers.
+//!       Env variable `LEADER_CHECK_MS`, default `5000`, how often non-leaders retry, and the leader checks its lock.
+//!     - Env variable `WEBHOOK_PERSIST=true` stores pending outbound [webhook][webhooks] deliveries in postgres.
+//! - `"postgres-migrate"`: Adds `"postgres"`, and runs migrations at startup, before listening.
+//!     - Env variable `RUN_MIGRATIONS=true` enables running migrations.
+//!     - Env variable `MIGRATIONS_DIR`, the runtime directory of `sqlx` migrations. Defaults to `./migrations`.
+//!     - A postgres advisory lock is held while migrating, so that only one instance migrates at a time.
+//!     - Migration failures fail setup.
+//! - `"redis"`: Caches [`CacheMiddleware`][cache] responses in redis, shared by all instances, rather than in memory.
+//!     - Env variable `REDISURL`, a `redis://` url. If not set, responses are still cached in memory.
+//! - `"sessions"`: Cookie sessions, read and changed with [`req.session()`][prelude::SessionRequestExt::session].
+//!     - Env variable `SESSION_SECRET`, which signs session cookies, required in production.
+//!     - Env variable `SESSION_COOKIE`, the cookie name, defaults to `preroll_session`.
+//!     - Env variable `SESSION_TTL_SECS`, how long sessions are kept after they were last changed, defaults to `86400` (one day).
+//!     - Sessions are stored in redis if `REDISURL` is set, or else in postgres if the `"postgres"` feature is enabled,
+//!       or else in memory.
+//!     - See [`preroll::sessions`][] for details.
+//! - `"sqlite"`: Enables a SQLite connection pool with transactions, such as for local development without a postgres daemon.
+//!     - Env variable `SQLITEURL`, a `sqlite://` database url. Defaults to `"sqlite://{service_name}.db"`, created if missing.
+//!     - Enables [`SqliteRequestExt`][prelude::SqliteRequestExt], the same shape as `PostgresRequestExt`,
+//!       and [`test_utils::create_client_and_sqlite`][], which uses an in-memory database unless `TEST_SQLITEURL` is set.
+//!     - Reports `sqliteReachability` under `"downstream"` in `/monitor/status`.
+//! - `"tracing-logs"`: Emits logs as [`tracing`](https://docs.rs/tracing) events rather than via `env_logger`.
+//!     - Log output keeps the same JSON field names, and includes events from `tracing::instrument`-ed handlers.
+//!     - Records from the `log` crate are forwarded into `tracing`.
+//!     - If the `"honeycomb"` feature is enabled, logging and tracing share a single subscriber.
+//! - `"unleash"`: Evaluates [feature flags][flags] with [`UnleashProvider`][flags::UnleashProvider].
+//!     - Env variable `UNLEASH_URL`, the server's API url, e.g. `https://unleash.internal/api/`, which enables the provider if set.
+//!     - Env variables `UNLEASH_API_TOKEN`, a client token, and `UNLEASH_APP_NAME`, which defaults to `preroll`.
 //!
 //! ### List of other optional features:
+//! - `"fail-on-error"`: Like `"panic-on-error"`, but rather than panicking, fails the request of the in-process
+//!   [`test_utils`][] client, such as from [`test_utils::create_client`][], with the error's report.
+//!     - Requests over a listener, such as to [`test_utils::spawn_server`][], get the error response as usual.
+//!     - Do not use in production. Prevents `--release` compilation.
 //! - `"panic-on-error"`: Makes the response logger [panic][] on error rather than log.
+//!     - Prints the error's full [color-eyre](https://docs.rs/color-eyre) report, with any captured backtrace,
+//!       and the request's method, path, and request id, before panicking.
 //!     - Do not use in production. Prevents `--release` compilation.
+//! - `"simd-json"`: Serializes JSON error bodies, and bodies built with [`json::body`][], with `simd-json` rather than `serde_json`.
 //!
 //! ## General Environment Settings
 //! The following environment variables are read during `preroll::main!`:
-//! - `ENVIRONMENT`: If this starts with `prod`, load the production-mode JSON logger, avoid `.env`.
+//!
+//! Any of these can instead be set with a typed [`preroll::Config`][Config], which takes precedence over the environment,
+//! or in a config file, which the environment takes precedence over. See [`config`][mod@config].
+//! - `ACCEPT_VERSION_VENDOR`: If set, e.g. to `myservice`, requests with `Accept: application/vnd.myservice.v2+json`
+//!   are routed to `/api/v2` if their path is not already versioned. See [API versions][versions].
+//! - `ACCEPT_WORKERS`: How many tasks accept connections. Defaults to `1`. A single accept loop can limit the throughput
+//!   of small responses on many-core hosts.
+//!     - `REUSE_PORT=true`: On unix, each task listens on its own `SO_REUSEPORT` socket, between which the kernel balances
+//!       connections, rather than all accepting from one socket.
+//! - `ACCESS_LOG`: Where `LogMiddleware`'s request / response access logs are written, rather than with application logs,
+//!   so that log pipelines can retain and parse them separately: `stdout`, `stderr`, `fd:{n}` for an open file descriptor,
+//!   such as `fd:3`, or a file path, which is rotated as `LOG_ROTATION` says. Access logs have the target
+//!   `preroll::middleware::logger`, which `LOGLEVEL` directives also apply to.
+//! - `CACHE_MAX_ENTRIES`: The most responses cached in memory by [`CacheMiddleware`][cache]. Defaults to `1000`.
+//! - `DEFAULT_LOCALE`: The [locale][i18n] of requests which accept none of the supported locales. Defaults to `en`.
+//! - `ENVIRONMENT`: If this starts with `prod`, load the production-mode JSON logger, avoid `.env`, and omit the `causes` of
+//!   internal server errors from [`JsonError`][] responses.
+//! - `EVENT_LOOP_LAG_WARN_MS`: How late, in milliseconds, a task may be woken before the event loop's lag is logged at `WARN`.
+//!   Defaults to `100`. Event loop lag, and the delay of requests since their `X-Request-Start`, are reported under
+//!   `"scheduling"` in `/monitor/status`.
+//! - `FLAG_{NAME}`: The value of the [feature flag][flags] `name`, e.g. `FLAG_NEW_PRICING=true`, without a flag service.
+//! - `FLAGS_REFRESH_SECS`: How often flags are refreshed from a flag service. Defaults to `30`.
 //! - `FORCE_DOTENV`: Override production-mode, force-load environment from `.env`.
+//! - `HEALTH_CHECK_CACHE_MS`: How long `/monitor/status` downstream health check results are reused for. Defaults to `1000`.
+//! - `HEALTH_CHECK_TIMEOUT_MS`: The maximum time a single downstream health check may take. Defaults to `5000`.
+//! - `MAX_INFLIGHT`: If set, the most requests handled at once, after which requests wait up to `MAX_INFLIGHT_QUEUE_MS`
+//!   (default `100`) and are then shed with a `503` and `Retry-After`. See [`ConcurrencyLimitMiddleware`][].
+//! - `MAX_REQUEST_HEADERS`: Requests with more headers than this are rejected with a 400. Defaults to `100`.
+//! - `MAX_REQUEST_HEADER_BYTES`: Requests with larger headers than this are rejected with a 400. Defaults to `16384`.
+//! - `ID_FORMAT`: The format of generated request and correlation ids: `uuidv4` (default), `uuidv7`, or `ulid`.
+//!     - `uuidv7` and `ulid` are time-ordered, which makes them cheaper to scan in logs and to index.
+//!     - Incoming `X-Request-Id` headers are accepted as any UUID or ULID, regardless of this setting.
 //! - `HOST`: Sets the hostname that this service will listen on. Defaults to `"127.0.0.1"`.
-//! - `LOGLEVEL`: Set the logger's level filter, defaults to `info` in production-mode, `debug` in development-mode.
+//! - `LISTEN_FDS`: Set by a supervisor, such as systemd socket activation, which passes listening sockets on to the service.
+//!   They are listened on rather than `HOST` and `PORT`, so that the supervisor keeps the socket open, and queues
+//!   connections, while the service restarts.
+//! - `LOGFILE`: In production-mode, also write JSON logs to this file path.
+//! - `LOG_FORMAT`: The schema of JSON log records, so that log pipelines can ingest them without remapping:
+//!   `preroll` (default), `ecs` for the [Elastic Common Schema](https://www.elastic.co/guide/en/ecs/current/index.html),
+//!   or `datadog` for Datadog's standard attributes. `level`, `time`, `request_id`, `status`, `duration_ms`, and the other
+//!   `LogMiddleware` access log fields are renamed, with statuses as numbers and durations in nanoseconds.
+//!   Fields which the service logs itself are written as they are, unless they would overwrite one of the record's
+//!   own in `ecs` or `datadog`, e.g. `status` in `datadog`, which are prefixed with `app.`, e.g. `app.status`.
+//!   In every format, the access log's `duration_ms`, `body_size`, and `queue_ms` are numbers, rather than strings,
+//!   unless a value is not a number, which is kept as it is.
+//! - `LOG_ROTATION`: When to rotate `LOGFILE` and an `ACCESS_LOG` file: `never` (default), `hourly`, `daily`, or a size such as `100M`.
+//!     - Rotated files are renamed to `{LOGFILE}.{time}`.
+//! - `LOGLEVEL`: Set the logger's level filter, defaults to `info`.
+//!     - Per-module overrides are supported in `env_logger`'s directive syntax, e.g. `info,sqlx=warn,surf=debug`.
+//! - `MONITOR_TOKEN`: Required as a bearer token by monitor routes which change the service's behavior.
+//!   If unset, those routes are open, unless `ENVIRONMENT` starts with `prod`, where they are refused.
+//! - `OAUTH_TOKEN_URL`, `OAUTH_CLIENT_ID`, `OAUTH_CLIENT_SECRET`: The OAuth2 client credentials of
+//!   [`TokenProvider::from_env`][client::TokenProvider::from_env], and optionally `OAUTH_SCOPE` and `OAUTH_AUDIENCE`.
+//! - `PII_HASH_KEY`: The secret key of [`Pii`][] hashes, so that they correlate across instances and restarts.
+//!   If unset, a random key is used per process.
+//! - `PREROLL_CONFIG`: The path of a TOML or YAML config file. Defaults to `preroll.toml`, `preroll.yaml`, or `preroll.yml`,
+//!   if present in the working directory.
 //! - `PORT`: Sets the port that this service will listen on. Defaults to `8080`.
+//! - `PORT_FALLBACK_RANGE`: If `PORT` is in use, how many of the following ports to try, logging the one listened on,
+//!   such as for running several services in local development. Defaults to `0`, failing startup with the address in use.
+//! - `QUIET_PATHS`: Comma separated paths, e.g. `/,/probes/*`, whose successful responses are logged at `TRACE` rather than `INFO`,
+//!   and which are not traced. A trailing `*` matches any path with that prefix. Failed and slow responses are logged as usual.
+//! - `SHED_LOW_PERCENT`, `SHED_NORMAL_PERCENT`, `SHED_HIGH_PERCENT`: The percentages of `MAX_INFLIGHT` in use at which
+//!   requests to routes of each [`Priority`][] are shed. Default to `50`, `80`, and `100`.
+//! - `SHUTDOWN_GRACE_MS`: Once the server is stopped, such as by `SIGTERM`, how long to wait for requests in flight
+//!   to finish. Defaults to `30000`. With `REUSE_PORT=true`, a new process can start listening before the old one is
+//!   sent `SIGTERM`, for restarts without downtime.
+//! - `SLOW_REQUEST_MS`: If set, successful responses taking longer than this many milliseconds are logged at `WARN`
+//!   with a `slow_request=true` field, which is also recorded on the honeycomb span.
+//! - `SSE_KEEP_ALIVE_SECS`: How long an [SSE][sse] stream may be idle before a keep-alive comment is sent. Defaults to `15`.
+//! - `STARTUP_CHECKS=true`: If set, every registered health check must pass before the server starts listening.
+//!   Failing checks are retried with backoff for at most `STARTUP_CHECK_MAX_WAIT_MS` (default `30000`), after which startup fails.
+//! - `SUPPORTED_LOCALES`: Comma separated [locales][i18n], e.g. `en,fr`, negotiated from requests' `Accept-Language`.
+//!   Defaults to the locales of the message catalog.
+//! - `TRUSTED_PROXIES`: Comma separated addresses or CIDR ranges, e.g. `10.0.0.0/8`, of load balancers and other proxies
+//!   whose `Forwarded` or `X-Forwarded-For` headers are believed, for the [`ClientIp`][] of each request, and the scheme
+//!   and host of its URL.
+//! - `WEBHOOK_MAX_ATTEMPTS`: The most attempts at delivering an outbound [webhook][webhooks], after which it is dead-lettered.
+//!   Defaults to `10`. Deliveries are attempted every `WEBHOOK_POLL_MS` (default `1000`), each for at most
+//!   `WEBHOOK_TIMEOUT_MS` (default `10000`).
+//!
+//! ## Monitoring
+//!
+//! The following routes are served outside of the logging and tracing middleware:
+//! - `/monitor/ping`: Replies with a randomly generated name, fixed for the process duration.
+//! - `/monitor/status`: Service status, including downstream health checks, request stats, registered upstream
+//!   client stats, concurrency limit gauges, load shedding counts, and any deprecated preroll settings or APIs in use.
+//! - `/monitor/info`: Build information: the service's crate version, git commit, build time, rustc version, and enabled features.
+//!     - The git commit is `GIT_COMMIT` at compile time if it was set, otherwise at runtime.
+//! - `/monitor/slo`: Burn rates for any registered [SLO objectives][slo].
+//! - `POST /monitor/reload`: Reloads configuration, as on `SIGHUP`. See below.
+//! - `GET /monitor/loglevel`: The active `LOGLEVEL`, as `{"loglevel": "info,sqlx=warn"}`.
+//! - `PUT /monitor/loglevel`: Replaces the active `LOGLEVEL` with `{"loglevel": "debug"}`, until restart, without a redeploy.
+//!
+//! `POST` and `PUT` monitor routes require `MONITOR_TOKEN` as an `Authorization: Bearer` token.
+//! If it is unset, they are open in development, and refused in production.
+//!
+//! ## Configuration reload
+//!
+//! On `SIGHUP`, or `POST /monitor/reload`, the config file (and any AWS secrets) are re-read and the following settings
+//! are re-applied without a restart: `LOGLEVEL`, `TRACELEVEL`, `SLOW_REQUEST_MS`, `QUIET_PATHS`, the concurrency limits
+//! `MAX_INFLIGHT`, `MAX_INFLIGHT_{NAME}`, and `MAX_INFLIGHT_QUEUE_MS`, and, outside of lambda,
+//! `HONEYCOMB_SAMPLE_RATE` and `HONEYCOMB_SAMPLE_RULES`.
+//! Further settings can be made reloadable with [`on_reload`][].
+//!
+//! ## Startup summary
+//!
+//! Before anything is set up, every preroll setting which is set, and every setting marked as required with
+//! [`Config::require`][], is checked, and startup fails with a single error listing all missing or invalid values.
+//!
+//! Once listening, a single `"Preroll startup summary"` log record is written, with the service name, environment, enabled features,
+//! middleware order, listeners, postgres url (with any password elided) and pool settings, tracing exporter, and config file, so that it is possible to
+//! confirm what configuration a given deployment actually ran with.
 //!
 //! ## Note:
 //!
@@ -97,6 +326,9 @@
 //! [`preroll::main!`]: https://docs.rs/preroll/0.8.0/preroll/macro.main.html
 //! [`preroll::prelude::*;`]: https://docs.rs/preroll/0.8.0/preroll/prelude/index.html
 //! [`JsonError`]: https://docs.rs/preroll/0.8.0/preroll/struct.JsonError.html
+//! [`preroll::grpc`]: https://docs.rs/preroll/0.8.0/preroll/grpc/index.html
+//! [`preroll::idempotency`]: https://docs.rs/preroll/0.8.0/preroll/idempotency/index.html
+//! [`preroll::sessions`]: https://docs.rs/preroll/0.8.0/preroll/sessions/index.html
 //! [async-std]: https://async.rs/
 //! [honeycomb.io]: https://www.honeycomb.io/
 //! [SQLx]: https://github.com/launchbadge/sqlx#sqlx
@@ -144,25 +376,132 @@
 
 #[cfg(all(not(debug_assertions), feature = "panic-on-error"))]
 compile_error!("The \"panic-on-error\" feature must not be used in production, and is not available with `--release`.");
+#[cfg(all(not(debug_assertions), feature = "fail-on-error"))]
+compile_error!("The \"fail-on-error\" feature must not be used in production, and is not available with `--release`.");
 
 mod routes_variadic;
 
 pub(crate) mod builtins;
 pub(crate) mod logging;
 pub(crate) mod middleware;
+#[cfg(feature = "aws-secrets")]
+pub(crate) mod secrets;
 
 #[doc(hidden)]
 pub mod setup;
 
+pub mod body;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod di;
+pub mod extract;
+pub mod flags;
+#[cfg(feature = "grpc-web")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "grpc-web")))]
+pub mod grpc;
+pub mod guards;
+pub mod i18n;
+#[cfg(feature = "idempotency")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "idempotency")))]
+pub mod idempotency;
+#[cfg(feature = "pg-jobs")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "pg-jobs")))]
+pub mod jobs;
+pub mod json;
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub mod leader;
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub mod notifications;
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub mod outbox;
+pub mod pagination;
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub mod postgres;
 pub mod prelude;
+pub mod resources;
+pub mod respond;
+pub mod rewrite;
+pub mod routes;
+#[cfg(feature = "sessions")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+pub mod sessions;
+pub mod slo;
+pub mod snapshot;
+pub mod sse;
 pub mod test_utils;
 pub mod utils;
+pub mod versions;
+pub mod warmers;
+pub mod webhooks;
 
 /// The format of error responses from preroll's error handling middleware.
-pub use middleware::json_error::JsonError;
+pub use middleware::json_error::{Backoff, JsonError, JsonErrorDetail, RateLimit};
+
+/// Log field wrappers which redact personal data and secrets in production.
+pub use logging::{Pii, Secret};
 
 pub use routes_variadic::VariadicRoutes;
 
+pub use middleware::concurrency::ConcurrencyLimitMiddleware;
+pub use middleware::csrf::CsrfMiddleware;
+pub use middleware::etag::ETagMiddleware;
+pub use middleware::forwarded::ClientIp;
+pub use middleware::priority::{Priority, PriorityMiddleware};
+#[cfg(feature = "sessions")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+pub use middleware::sessions::SessionMiddleware;
+pub use middleware::single_flight::SingleFlightMiddleware;
+pub use middleware::tenant::{TenantId, TenantMiddleware};
+
+pub use builtins::monitor::register_health_check;
+pub use builtins::reload::on_reload;
+
+#[doc(hidden)]
+pub use builtins::deprecations::record_deprecated_api;
+
+pub use config::Config;
+
+pub use resources::PrerollResources;
+
+pub use middleware::extension_types::IdFormat;
+
+/// Creates a child span of the request's trace for a handler or inner function, re-exported from `tracing`.
+///
+/// The span is recorded in honeycomb with its duration, and with the function's arguments as fields,
+/// unless skipped, so that per-function latency shows up within each request's trace.
+/// The generated code refers to `tracing`, which must also be a dependency of the service.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use tide::Request;
+///
+/// # #[allow(dead_code)]
+/// #[preroll::instrument(skip(req), fields(path = req.url().path()))]
+/// async fn get_cart(req: Request<Arc<()>>) -> tide::Result {
+///     let cart_id: u64 = req.param("cart_id")?.parse()?;
+///     let total = cart_total(cart_id).await;
+///     Ok(total.to_string().into())
+/// }
+///
+/// # #[allow(dead_code)]
+/// #[preroll::instrument]
+/// async fn cart_total(cart_id: u64) -> u64 {
+///     // ... query the cart ...
+///     # cart_id
+/// }
+/// ```
+#[cfg(feature = "honeycomb")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+pub use tracing::instrument;
+
 /// The result type which is expected from functions passed to `preroll::main!`.
 ///
 /// This is a `color_eyre::eyre::Result<T>`.
@@ -179,6 +518,11 @@ pub type SetupResult<T> = setup::Result<T>;
 ///
 /// An [**`&'static str`**](https://doc.rust-lang.org/std/primitive.str.html), e.g. `"service-name"`.
 ///
+/// ## `config = ...` (optional)
+/// A typed [`preroll::Config`][crate::Config], written as `config = expression` directly after the service name.
+///
+/// Settings in the config take precedence over environment variables, which remain the fallback for anything not set.
+///
 /// ## `state_setup` (optional)
 /// This is where server state can be set.
 ///
@@ -190,6 +534,9 @@ pub type SetupResult<T> = setup::Result<T>;
 /// This function must be `async` and must return a `preroll::SetupResult`.
 /// It is expected that setup could be anything and may need to await or error.
 ///
+/// It may instead take a [`PrerollResources`][] argument, **`async fn setup_state(resources: PrerollResources)`**,
+/// to reuse the database pools, redis client, and configuration preroll has already set up.
+///
 /// See [`tide::Server::with_state()`][] for more on Tide server state.
 ///
 /// ## `custom_setup` (optional) (advanced)
@@ -213,6 +560,9 @@ pub type SetupResult<T> = setup::Result<T>;
 ///
 /// For example, `preroll::main!("my-service", my_routes)` will have `my_routes` mounted at `/api/v1`.
 ///
+/// Routes common to every version, such as `/whoami`, can be mounted under each `/api/v{N}` with
+/// [`VariadicRoutes::shared`][crate::VariadicRoutes::shared], e.g. `VariadicRoutes::from((routes_v1, routes_v2)).shared(routes_common)`.
+///
 /// See [`tide::Server::at()`][] for more on Tide server routing.
 ///
 /// # Basic Example
@@ -315,6 +665,30 @@ pub type SetupResult<T> = setup::Result<T>;
 /// # }
 /// ```
 ///
+/// # Config Example
+///
+/// ```no_run
+/// # #[cfg(not(feature = "custom_middleware"))]
+/// # {
+/// use std::time::Duration;
+///
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello-world").get(|_| async { Ok("Hello World!") });
+/// }
+///
+/// preroll::main!(
+///     "hello-world",
+///     config = preroll::Config::new()
+///         .port(8081)
+///         .slow_request_threshold(Duration::from_millis(500)),
+///     setup_routes
+/// );
+/// # }
+/// ```
+///
 /// [`tide::Server::at()`]: https://docs.rs/tide/0.15.0/tide/struct.Server.html#method.at
 /// [`tide::Server::with_state()`]: https://docs.rs/tide/0.15.0/tide/struct.Server.html#method.with_state
 /// [unit `()`]: https://doc.rust-lang.org/std/primitive.unit.html
@@ -323,13 +697,27 @@ pub type SetupResult<T> = setup::Result<T>;
 /// [Tuple]: https://doc.rust-lang.org/std/primitive.tuple.html
 #[macro_export]
 macro_rules! main {
-    // preroll::main!("service-name", routes_setup_function);
-    ($service_name:tt, $routes_fns:tt) => {
-        $crate::main!($service_name, async { Ok(()) }, routes_fns);
+    // Internal: the generated `main`, with `$config` as the body of a function returning `preroll::Config`.
+    (@config $config:tt, $service_name:tt, $state_setup:tt, $custom_setup:tt, $routes_fns:tt) => {
+        fn preroll_config() -> $crate::Config $config
+
+        fn main() -> preroll::setup::Result<()> {
+            preroll_config().install();
+            $crate::setup::set_service_build(
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                option_env!("GIT_COMMIT"),
+            );
+
+            let fut =
+                preroll::setup::setup($service_name, $state_setup, $custom_setup, $routes_fns);
+
+            preroll::setup::block_on(fut)
+        }
     };
 
-    // preroll::main!("service-name", state_setup_function, routes_setup_function);
-    ($service_name:tt, $state_setup:tt, $routes_fns:tt) => {
+    // Internal: no custom setup.
+    (@config $config:tt, $service_name:tt, $state_setup:tt, $routes_fns:tt) => {
         async fn setup_noop<State>(
             server: tide::Server<std::sync::Arc<State>>,
         ) -> preroll::SetupResult<tide::Server<std::sync::Arc<State>>>
@@ -339,16 +727,27 @@ macro_rules! main {
             Ok(server)
         }
 
-        $crate::main!($service_name, $state_setup, setup_noop, $routes_fns);
+        $crate::main!(@config $config, $service_name, $state_setup, setup_noop, $routes_fns);
     };
 
-    // preroll::main!("service-name", state_setup_function, custom_setup_function, routes_setup_function(s));
-    ($service_name:tt, $state_setup:tt, $custom_setup:tt, $routes_fns:tt) => {
-        fn main() -> preroll::setup::Result<()> {
-            let fut =
-                preroll::setup::setup($service_name, $state_setup, $custom_setup, $routes_fns);
-
-            preroll::setup::block_on(fut)
+    // Internal: no state setup.
+    (@config $config:tt, $service_name:tt, $routes_fns:tt) => {
+        async fn setup_unit_state() -> preroll::SetupResult<()> {
+            Ok(())
         }
+
+        $crate::main!(@config $config, $service_name, setup_unit_state, $routes_fns);
+    };
+
+    // preroll::main!("service-name", config = config_expression, ...);
+    ($service_name:tt, config = $config:expr, $($args:tt),+) => {
+        $crate::main!(@config { $config }, $service_name, $($args),+);
+    };
+
+    // preroll::main!("service-name", routes_setup_function);
+    // preroll::main!("service-name", state_setup_function, routes_setup_function);
+    // preroll::main!("service-name", state_setup_function, custom_setup_function, routes_setup_function(s));
+    ($service_name:tt, $($args:tt),+) => {
+        $crate::main!(@config { $crate::Config::new() }, $service_name, $($args),+);
     };
 }
@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::fmt::{self, Debug, Display};
+
+use log::kv::{ToValue, Value};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+
+use crate::config;
+use crate::utils::hmac_sha256_hex;
+
+/// Whether classified values are redacted wherever they are displayed, as `ENVIRONMENT` starts with `prod`.
+static PRODUCTION: Lazy<bool> = Lazy::new(|| {
+    config::var("ENVIRONMENT")
+        .map(|environment| environment.starts_with("prod"))
+        .unwrap_or(false)
+});
+
+/// The key of [`Pii`][] hashes, from `PII_HASH_KEY`, or else random, so that hashes cannot be reversed by guessing values.
+static PII_HASH_KEY: Lazy<Vec<u8>> = Lazy::new(|| match config::var("PII_HASH_KEY") {
+    Ok(key) => key.into_bytes(),
+    Err(_) => {
+        let mut key = vec![0_u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    }
+});
+
+thread_local! {
+    /// Whether a JSON log record is being formatted on this thread, which redacts classified values in any environment.
+    static FORMATTING_JSON: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Redacts classified values on this thread until dropped, while a JSON log record is formatted.
+pub(crate) struct JsonRedaction {
+    previous: bool,
+}
+
+impl JsonRedaction {
+    pub(crate) fn enter() -> Self {
+        Self {
+            previous: FORMATTING_JSON.with(|formatting| formatting.replace(true)),
+        }
+    }
+}
+
+impl Drop for JsonRedaction {
+    fn drop(&mut self) {
+        FORMATTING_JSON.with(|formatting| formatting.set(self.previous));
+    }
+}
+
+fn redacted() -> bool {
+    *PRODUCTION || FORMATTING_JSON.with(Cell::get)
+}
+
+/// Personally identifiable information, such as an email or IP address, as a log field or in a log message.
+///
+/// Displayed as-is in development, and as `pii:{hash}` in production and in JSON logs, where `{hash}` is the first 64 bits
+/// of the value's HMAC-SHA256, in hex. Records of the same value can still be correlated by its hash.
+///
+/// The HMAC is keyed with `PII_HASH_KEY`, so that values such as IP addresses cannot be recovered by hashing every
+/// candidate. If it is not set, the key is random, and hashes only correlate within a process.
+/// Set the same secret `PII_HASH_KEY` for every instance of a service to correlate across instances and restarts.
+///
+/// ## Example:
+/// ```
+/// use preroll::Pii;
+///
+/// # let email = "someone@example.com";
+/// kv_log_macro::info!("Signed up", { email: Pii(email) });
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Pii<T>(pub T);
+
+impl<T: Display> Display for Pii<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !redacted() {
+            return Display::fmt(&self.0, f);
+        }
+
+        let hash = hmac_sha256_hex(&PII_HASH_KEY, self.0.to_string().as_bytes());
+        write!(f, "pii:{}", &hash[..16])
+    }
+}
+
+impl<T: Display> Debug for Pii<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pii({})", self)
+    }
+}
+
+impl<T: Display> ToValue for Pii<T> {
+    fn to_value(&self) -> Value<'_> {
+        Value::from_display(self)
+    }
+}
+
+/// A secret, such as a token or password, as a log field or in a log message.
+///
+/// Displayed as-is in development, and as `***` in production and in JSON logs.
+///
+/// ## Example:
+/// ```
+/// use preroll::Secret;
+///
+/// # let api_key = "sk_test_123";
+/// kv_log_macro::debug!("Calling the payments API", { api_key: Secret(api_key) });
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Secret<T>(pub T);
+
+impl<T: Display> Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if redacted() {
+            f.write_str("***")
+        } else {
+            Display::fmt(&self.0, f)
+        }
+    }
+}
+
+impl<T: Display> Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", self)
+    }
+}
+
+impl<T: Display> ToValue for Secret<T> {
+    fn to_value(&self) -> Value<'_> {
+        Value::from_display(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_while_formatting_json() {
+        let email = Pii("someone@example.com");
+        let token = Secret("hunter2");
+
+        assert_eq!(email.to_string(), "someone@example.com");
+        assert_eq!(token.to_string(), "hunter2");
+
+        {
+            let _redaction = JsonRedaction::enter();
+            let hash = email.to_string();
+            assert!(hash.starts_with("pii:"));
+            assert_eq!(hash.len(), "pii:".len() + 16);
+            // Not the unkeyed SHA-256 of the email.
+            assert_ne!(hash, "pii:72497f475e4f76d0");
+            assert_eq!(Pii("someone@example.com").to_string(), hash);
+            assert_ne!(Pii("someone@example.org").to_string(), hash);
+            assert_eq!(token.to_string(), "***");
+            assert_eq!(format!("{:?}", token), "Secret(***)");
+        }
+
+        assert_eq!(email.to_value().to_string(), "someone@example.com");
+    }
+}
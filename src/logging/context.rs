@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::future::Future;
+
+/// Log record targets which are logged with the current request's context, as they have no access to the request.
+///
+/// Records of other targets are only logged with the request's tenant id, if there is one.
+const CONTEXT_TARGETS: [&str; 1] = ["sqlx"];
+
+async_std::task_local! {
+    static LOG_CONTEXT: RefCell<Option<LogContext>> = RefCell::new(None);
+}
+
+/// Identifiers of the request being handled by the current task.
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    pub request_id: String,
+    pub honeycomb_trace_id: Option<String>,
+    pub tenant_id: Option<String>,
+}
+
+impl LogContext {
+    /// Run `fut` with `context` as the log context of the current task, restoring the previous context afterwards.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        let previous = LOG_CONTEXT
+            .try_with(|current| current.replace(Some(self)))
+            .ok()
+            .flatten();
+
+        let output = fut.await;
+
+        LOG_CONTEXT
+            .try_with(|current| current.replace(previous))
+            .ok();
+
+        output
+    }
+
+    /// Record the honeycomb trace id in the current task's log context, if there is one.
+    #[cfg(feature = "honeycomb")]
+    pub fn set_honeycomb_trace_id(trace_id: String) {
+        LOG_CONTEXT
+            .try_with(|current| {
+                if let Some(context) = current.borrow_mut().as_mut() {
+                    context.honeycomb_trace_id = Some(trace_id);
+                }
+            })
+            .ok();
+    }
+
+    /// Record the tenant id in the current task's log context, if there is one.
+    pub fn set_tenant_id(tenant_id: String) {
+        LOG_CONTEXT
+            .try_with(|current| {
+                if let Some(context) = current.borrow_mut().as_mut() {
+                    context.tenant_id = Some(tenant_id);
+                }
+            })
+            .ok();
+    }
+
+    /// The context's fields, as key-value pairs. An empty `request_id` is left out.
+    pub fn pairs(&self) -> Vec<(&'static str, &str)> {
+        let mut pairs = Vec::new();
+        if !self.request_id.is_empty() {
+            pairs.push(("request_id", self.request_id.as_str()));
+        }
+        if let Some(trace_id) = &self.honeycomb_trace_id {
+            pairs.push(("honeycomb_trace_id", trace_id.as_str()));
+        }
+        if let Some(tenant_id) = &self.tenant_id {
+            pairs.push(("tenant_id", tenant_id.as_str()));
+        }
+        pairs
+    }
+
+    /// The current task's log context, or only its tenant id, if `target` is not logged with the full context.
+    pub fn for_target(target: &str) -> Option<Self> {
+        let context = LOG_CONTEXT
+            .try_with(|current| current.borrow().clone())
+            .ok()
+            .flatten()?;
+
+        if CONTEXT_TARGETS
+            .iter()
+            .any(|prefix| target.starts_with(prefix))
+        {
+            return Some(context);
+        }
+
+        context.tenant_id.map(|tenant_id| Self {
+            tenant_id: Some(tenant_id),
+            ..Self::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn scopes_context_to_the_task() {
+        assert!(LogContext::for_target("sqlx::query").is_none());
+
+        let context = LogContext {
+            request_id: "abc".to_string(),
+            honeycomb_trace_id: None,
+            tenant_id: None,
+        };
+        let (request_id, tenant_id) = context
+            .scope(async {
+                assert!(LogContext::for_target("preroll").is_none());
+                LogContext::set_tenant_id("acme".to_string());
+                let tenant_id = LogContext::for_target("preroll")
+                    .map(|context| (context.request_id, context.tenant_id));
+                (
+                    LogContext::for_target("sqlx::query").map(|context| context.request_id),
+                    tenant_id,
+                )
+            })
+            .await;
+
+        assert_eq!(request_id.as_deref(), Some("abc"));
+        assert_eq!(tenant_id, Some((String::new(), Some("acme".to_string()))));
+        assert!(LogContext::for_target("sqlx::query").is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use log::LevelFilter;
+
+use crate::config;
+
+/// A parsed `LOGLEVEL`, in `env_logger`'s directive syntax.
+///
+/// A default level, optionally followed by per-module overrides, e.g. `info,sqlx=warn,surf=debug`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLevel {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevel {
+    pub fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// The `LOGLEVEL` setting, or `default` if it is not set.
+    pub fn from_config(default: LevelFilter) -> Result<Self, log::ParseLevelError> {
+        config::var("LOGLEVEL")
+            .map(|v| v.parse())
+            .unwrap_or_else(|_| Ok(Self::new(default)))
+    }
+
+    /// The most verbose level of any directive.
+    #[cfg_attr(not(feature = "tracing-logs"), allow(dead_code))]
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+
+    /// The equivalent `tracing` filter.
+    #[cfg(feature = "tracing-logs")]
+    pub fn targets(&self) -> tracing_subscriber::filter::Targets {
+        use tracing_subscriber::filter::LevelFilter as TracingLevelFilter;
+
+        fn convert(level: LevelFilter) -> TracingLevelFilter {
+            match level {
+                LevelFilter::Off => TracingLevelFilter::OFF,
+                LevelFilter::Error => TracingLevelFilter::ERROR,
+                LevelFilter::Warn => TracingLevelFilter::WARN,
+                LevelFilter::Info => TracingLevelFilter::INFO,
+                LevelFilter::Debug => TracingLevelFilter::DEBUG,
+                LevelFilter::Trace => TracingLevelFilter::TRACE,
+            }
+        }
+
+        self.modules.iter().fold(
+            tracing_subscriber::filter::Targets::new().with_default(convert(self.default)),
+            |targets, (module, level)| targets.with_target(module.as_str(), convert(*level)),
+        )
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (module, level) in self.modules.iter() {
+            write!(f, ",{}={}", module, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = log::ParseLevelError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut log_level = Self::new(LevelFilter::Info);
+
+        for directive in string.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    log_level
+                        .modules
+                        .push((module.trim().to_string(), level.trim().parse()?));
+                }
+                None => log_level.default = directive.parse()?,
+            }
+        }
+
+        Ok(log_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_directives() -> Result<(), log::ParseLevelError> {
+        let log_level: LogLevel = "warn, sqlx=error,surf=debug".parse()?;
+
+        assert_eq!(log_level.default, LevelFilter::Warn);
+        assert_eq!(
+            log_level.modules,
+            vec![
+                ("sqlx".to_string(), LevelFilter::Error),
+                ("surf".to_string(), LevelFilter::Debug)
+            ]
+        );
+        assert_eq!(log_level.max_level(), LevelFilter::Debug);
+        assert_eq!(log_level.to_string(), "warn,sqlx=error,surf=debug");
+
+        assert_eq!(
+            "debug".parse::<LogLevel>()?,
+            LogLevel::new(LevelFilter::Debug)
+        );
+        assert!("sqlx=loud".parse::<LogLevel>().is_err());
+        Ok(())
+    }
+}
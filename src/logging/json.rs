@@ -1,12 +1,286 @@
+use std::borrow::Cow;
 use std::io::Write;
+use std::str::FromStr;
 use std::{io, process};
 
+use cfg_if::cfg_if;
 use log::kv;
+use once_cell::sync::Lazy;
 
+use super::{JsonRedaction, LogContext};
+use crate::config;
+use crate::middleware::logger::ACCESS_LOG_TARGET;
 use crate::utils::HOSTNAME;
 
+cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        use std::fmt;
+
+        use tracing::{Event, Subscriber};
+        use tracing_subscriber::fmt::format::Writer;
+        use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+        use tracing_subscriber::registry::LookupSpan;
+
+        use super::EventFields;
+    }
+}
+
+/// The schema of JSON log records, from `LOG_FORMAT`, or `preroll` if it is invalid.
+static LOG_FORMAT: Lazy<LogFormat> = Lazy::new(|| {
+    config::var("LOG_FORMAT")
+        .map(|format| format.parse())
+        .unwrap_or(Ok(LogFormat::Preroll))
+        .unwrap_or_else(|error| {
+            // Not logged via `log`, as this is first read while formatting a log record.
+            eprintln!("{}, defaulting to preroll", error);
+            LogFormat::Preroll
+        })
+});
+
+/// The schema of JSON log records, from `LOG_FORMAT`, so that log pipelines can ingest them without remapping.
+///
+/// - `preroll` (the default): preroll's own field names, e.g. `level`, `time`, `status`, and `duration_ms`.
+/// - `ecs`: the [Elastic Common Schema][ecs], e.g. `log.level`, `@timestamp`, and `http.response.status_code`.
+/// - `datadog`: Datadog's [standard attributes][datadog], e.g. `status` for the level, `timestamp`, and `http.status_code`.
+///
+/// In `ecs` and `datadog`, statuses are numbers, and durations are in nanoseconds rather than milliseconds.
+/// Fields without an equivalent in the schema keep preroll's names.
+///
+/// Only the fields of every record, and of `LogMiddleware`'s access logs, are mapped.
+/// Fields which applications log themselves are written as they are, except that in `ecs` and `datadog`,
+/// those named like one of the schema's fields which preroll writes, e.g. `status`, `timestamp`, or `host` in `datadog`,
+/// are prefixed with `app.`, e.g. `app.status`, rather than overwrite it.
+///
+/// [ecs]: https://www.elastic.co/guide/en/ecs/current/index.html
+/// [datadog]: https://docs.datadoghq.com/logs/log_configuration/attributes_naming_convention/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LogFormat {
+    Preroll,
+    Ecs,
+    Datadog,
+}
+
+impl FromStr for LogFormat {
+    type Err = io::Error;
+
+    fn from_str(string: &str) -> io::Result<Self> {
+        match string.trim().to_lowercase().as_str() {
+            "" | "preroll" => Ok(Self::Preroll),
+            "ecs" => Ok(Self::Ecs),
+            "datadog" => Ok(Self::Datadog),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "LOG_FORMAT must be preroll, ecs, or datadog, got \"{}\"",
+                    string
+                ),
+            )),
+        }
+    }
+}
+
+/// The fields which preroll writes in records other than access logs, whose names in a [`LogFormat`][]
+/// application fields must not reuse.
+const PREROLL_FIELDS: &[&str] = &[
+    "level",
+    "ecs.version",
+    "pid",
+    "message",
+    "error.message",
+    "target",
+    "hostname",
+    "time",
+    "request_id",
+    "honeycomb_trace_id",
+    "tenant_id",
+];
+
+/// How a field's value is written in a [`LogFormat`][].
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FieldKind {
+    Text,
+    /// A whole number, or `null` if the value is missing.
+    Number,
+    /// A number of milliseconds, with a fraction, or `null` if the value is missing.
+    Millis,
+    /// A number of milliseconds, written as a whole number of nanoseconds, or `null` if the value is missing.
+    Nanos,
+}
+
+impl FieldKind {
+    /// `value` as JSON, or as a string if it is not the number this kind expects.
+    fn json(self, value: &str) -> String {
+        let number = match self {
+            Self::Text => None,
+            _ if value.is_empty() || value == "None" => return "null".to_string(),
+            Self::Number => value.parse::<u64>().ok().map(|number| number.to_string()),
+            Self::Millis => value
+                .parse::<f64>()
+                .ok()
+                .filter(|millis| millis.is_finite())
+                .map(|millis| millis.to_string()),
+            Self::Nanos => value
+                .parse::<f64>()
+                .ok()
+                .filter(|millis| millis.is_finite())
+                .map(|millis| ((millis * 1e6).round() as u64).to_string()),
+        };
+        number.unwrap_or_else(|| json_str(value))
+    }
+}
+
+impl LogFormat {
+    /// The name of preroll's field `key` in this format, and how its value is written.
+    fn field(self, key: &str) -> (&str, FieldKind) {
+        let name = match self {
+            Self::Preroll => None,
+            Self::Ecs => match key {
+                "level" => Some("log.level"),
+                "time" => Some("@timestamp"),
+                "pid" => Some("process.pid"),
+                "hostname" => Some("host.hostname"),
+                "target" => Some("log.logger"),
+                "request_id" => Some("http.request.id"),
+                "status" => Some("http.response.status_code"),
+                "method" => Some("http.request.method"),
+                "path" => Some("url.path"),
+                // Not `client.ip`, which must be an address, and may be redacted.
+                "ip" => Some("client.address"),
+                "user_agent" => Some("user_agent.original"),
+                "referer" => Some("http.request.referrer"),
+                "duration_ms" => Some("event.duration"),
+                "honeycomb_trace_id" => Some("trace.id"),
+                "error_type" => Some("error.type"),
+                _ => None,
+            },
+            Self::Datadog => match key {
+                "level" => Some("status"),
+                "time" => Some("timestamp"),
+                "hostname" => Some("host"),
+                "target" => Some("logger.name"),
+                "request_id" => Some("http.request_id"),
+                "status" => Some("http.status_code"),
+                "method" => Some("http.method"),
+                "path" => Some("http.url_details.path"),
+                "ip" => Some("network.client.ip"),
+                "user_agent" => Some("http.useragent"),
+                "referer" => Some("http.referer"),
+                "duration_ms" => Some("duration"),
+                "error_type" => Some("error.kind"),
+                _ => None,
+            },
+        };
+
+        let kind = match key {
+            "pid" | "body_size" | "queue_ms" => FieldKind::Number,
+            "status" if self != Self::Preroll => FieldKind::Number,
+            "duration_ms" if self == Self::Preroll => FieldKind::Millis,
+            "duration_ms" => FieldKind::Nanos,
+            _ => FieldKind::Text,
+        };
+
+        (name.unwrap_or(key), kind)
+    }
+
+    /// The name of an application's field `key` in this format, prefixed if preroll writes a field of that name.
+    fn application_field(self, key: &str) -> Cow<'_, str> {
+        let reserved = self != Self::Preroll
+            && PREROLL_FIELDS
+                .iter()
+                .any(|field| self.field(field).0 == key);
+        if reserved {
+            Cow::Owned(format!("app.{}", key))
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+}
+
+/// A JSON log record, written from preroll's field names in a [`LogFormat`][]'s schema.
+struct JsonRecord<'t> {
+    format: LogFormat,
+    target: &'t str,
+    json: String,
+    /// Whether the record's own message has been written, after which a `message` field is the error's message.
+    has_message: bool,
+}
+
+impl<'t> JsonRecord<'t> {
+    fn new(format: LogFormat, level: &str, target: &'t str) -> Self {
+        let mut record = Self {
+            format,
+            target,
+            json: String::from("{"),
+            has_message: false,
+        };
+        record.preroll_field("level", &level.to_lowercase());
+        if format == LogFormat::Ecs {
+            record.preroll_field("ecs.version", ECS_VERSION);
+        }
+        record.preroll_field("pid", &process::id().to_string());
+        record
+    }
+
+    fn message(&mut self, message: &str) {
+        self.preroll_field("message", message);
+        self.has_message = true;
+    }
+
+    /// A field of the record's own key-values, which are only mapped in `LogMiddleware`'s access logs.
+    fn field(&mut self, key: &str, value: &str) {
+        if self.target == ACCESS_LOG_TARGET {
+            self.preroll_field(key, value);
+        } else {
+            let name = self.format.application_field(key);
+            self.write(&name, FieldKind::Text, value);
+        }
+    }
+
+    /// A field which preroll writes, and so maps into the record's format.
+    fn preroll_field(&mut self, key: &str, value: &str) {
+        let (name, kind) = match key {
+            // The message of an error, in addition to the record's message.
+            "message" if self.has_message && self.format != LogFormat::Preroll => {
+                ("error.message", FieldKind::Text)
+            }
+            _ => self.format.field(key),
+        };
+        self.write(name, kind, value);
+    }
+
+    fn write(&mut self, name: &str, kind: FieldKind, value: &str) {
+        if self.json.len() > 1 {
+            self.json.push(',');
+        }
+        self.json.push_str(&json_str(name));
+        self.json.push(':');
+        self.json.push_str(&kind.json(value));
+    }
+
+    fn finish(mut self) -> String {
+        self.preroll_field("target", self.target);
+        self.preroll_field("hostname", &HOSTNAME);
+        self.preroll_field(
+            "time",
+            &chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        );
+        self.json.push_str("}\n");
+        self.json
+    }
+}
+
+/// The version of the Elastic Common Schema which [`LogFormat::Ecs`][] records follow.
+const ECS_VERSION: &str = "1.12.0";
+
 // Modified from the json_env_logger crate
 pub fn log_format_json<F>(f: &mut F, record: &log::Record<'_>) -> io::Result<()>
+where
+    F: Write,
+{
+    write_json(f, record, *LOG_FORMAT)
+}
+
+fn write_json<F>(f: &mut F, record: &log::Record<'_>, format: LogFormat) -> io::Result<()>
 where
     F: Write,
 {
@@ -15,56 +289,85 @@ where
         // Ignore tracing spans.
         return Ok(());
     }
+    let _redaction = JsonRedaction::enter();
 
-    write!(f, "{{")?;
-    write!(
-        f,
-        "\"level\":\"{}\"",
-        format!("{}", record.level()).to_lowercase()
-    )?;
-    write!(f, ",\"pid\":{}", process::id())?;
-    write!(f, ",\"message\":")?;
-    write_json_str(f, &record.args().to_string())?;
+    let mut json = JsonRecord::new(format, record.level().as_str(), target);
+    json.message(&record.args().to_string());
 
-    let mut visitor = Visitor { writer: f };
+    let mut visitor = Visitor { json: &mut json };
     record
         .key_values()
         .visit(&mut visitor)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-    write!(f, ",\"target\":\"{}\"", target)?;
-    write!(f, ",\"hostname\":\"{}\"", *HOSTNAME)?;
-    write!(
-        f,
-        ",\"time\":\"{}\"",
-        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
-    )?;
+    if let Some(context) = LogContext::for_target(target) {
+        for (key, value) in context.pairs() {
+            json.preroll_field(key, value);
+        }
+    }
 
-    struct Visitor<'w, W: Write> {
-        writer: &'w mut W,
+    struct Visitor<'j, 't> {
+        json: &'j mut JsonRecord<'t>,
     }
 
-    impl<'kvs, 'w, W: Write> kv::Visitor<'kvs> for Visitor<'w, W> {
+    impl<'kvs, 'j, 't> kv::Visitor<'kvs> for Visitor<'j, 't> {
         fn visit_pair(
             &mut self,
             key: kv::Key<'kvs>,
             val: kv::Value<'kvs>,
         ) -> Result<(), kv::Error> {
-            write!(self.writer, ",\"{}\":\"{}\"", key, val)?;
+            self.json.field(key.as_str(), &val.to_string());
             Ok(())
         }
     }
 
-    writeln!(f, "}}")
+    f.write_all(json.finish().as_bytes())
+}
+
+/// Formats `tracing` events in the same JSON shape as [`log_format_json`].
+#[cfg(feature = "tracing-logs")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonEventFormat;
+
+#[cfg(feature = "tracing-logs")]
+impl<S, N> FormatEvent<S, N> for JsonEventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut f: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let _redaction = JsonRedaction::enter();
+
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let own_fields = fields.pairs.len();
+        fields.add_context(metadata.target());
+
+        let mut json = JsonRecord::new(*LOG_FORMAT, metadata.level().as_str(), metadata.target());
+        json.message(&fields.message);
+        let (own, context) = fields.pairs.split_at(own_fields);
+        for (key, value) in own.iter() {
+            json.field(key, value);
+        }
+        for (key, value) in context.iter() {
+            json.preroll_field(key, value);
+        }
+
+        f.write_str(&json.finish())
+    }
 }
 
 // until log kv Value impl serde::Serialize
-fn write_json_str<W: Write>(writer: &mut W, raw: &str) -> io::Result<()> {
-    serde_json::to_writer(writer, raw)?;
-    Ok(())
+fn json_str(raw: &str) -> String {
+    serde_json::Value::from(raw).to_string()
 }
 
-// Modified from the json_env_logger crate
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,14 +390,157 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "tracing-logs")]
+    #[test]
+    fn writes_tracing_events_as_json() -> Result<(), Box<dyn Error>> {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::prelude::*;
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().expect("test buffer poisoned").write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(JsonEventFormat)
+                .with_writer(move || writer.clone()),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(status = 200_u16, path = "/a\"b", "hello");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().expect("test buffer poisoned").clone())?;
+        let json: serde_json::Value = serde_json::from_str(&output)?;
+        assert_eq!(json["level"], "info");
+        assert_eq!(json["message"], "hello");
+        assert_eq!(json["status"], "200");
+        assert_eq!(json["path"], "/a\"b");
+        Ok(())
+    }
+
     #[test]
-    fn escapes_json_strings() -> Result<(), Box<dyn Error>> {
+    fn escapes_json_strings() {
+        assert_eq!(
+            "\"\\\"\\n\\t\"",
+            json_str(
+                r#""
+	"#
+            )
+        );
+    }
+
+    #[test]
+    fn maps_fields_onto_log_formats() -> Result<(), Box<dyn Error>> {
+        let mut kvs = std::collections::HashMap::new();
+        kvs.insert("status", "404");
+        kvs.insert("duration_ms", "1.5");
+        kvs.insert("body_size", "None");
+        kvs.insert("request_id", "abc");
+        kvs.insert("queue_ms", "unknown");
+        let record = log::Record::builder()
+            .args(format_args!("Handled Request"))
+            .key_values(&kvs)
+            .level(log::Level::Warn)
+            .target(ACCESS_LOG_TARGET)
+            .build();
+
+        let format = |format| -> Result<serde_json::Value, Box<dyn Error>> {
+            let mut buf = Vec::new();
+            write_json(&mut buf, &record, format)?;
+            Ok(serde_json::from_slice(&buf)?)
+        };
+
+        let json = format(LogFormat::Preroll)?;
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["status"], "404");
+        assert_eq!(json["duration_ms"], 1.5);
+        assert!(json["body_size"].is_null());
+        assert_eq!(json["queue_ms"], "unknown");
+
+        let json = format(LogFormat::Ecs)?;
+        assert_eq!(json["log.level"], "warn");
+        assert_eq!(json["message"], "Handled Request");
+        assert_eq!(json["http.request.id"], "abc");
+        assert_eq!(json["http.response.status_code"], 404);
+        assert_eq!(json["event.duration"], 1_500_000);
+        assert!(json["@timestamp"].is_string());
+
+        let json = format(LogFormat::Datadog)?;
+        assert_eq!(json["status"], "warn");
+        assert_eq!(json["http.request_id"], "abc");
+        assert_eq!(json["http.status_code"], 404);
+        assert_eq!(json["duration"], 1_500_000);
+        assert!(json["timestamp"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_application_fields_as_they_are() -> Result<(), Box<dyn Error>> {
+        let mut kvs = std::collections::HashMap::new();
+        kvs.insert("status", "shipped");
+        kvs.insert("duration_ms", "a while");
+        kvs.insert("body_size", "12");
+        let record = log::Record::builder()
+            .args(format_args!("Order updated"))
+            .key_values(&kvs)
+            .level(log::Level::Info)
+            .target("orders")
+            .build();
+
+        let mut buf = Vec::new();
+        write_json(&mut buf, &record, LogFormat::Ecs)?;
+        let json: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(json["log.level"], "info");
+        assert_eq!(json["log.logger"], "orders");
+        assert_eq!(json["status"], "shipped");
+        assert_eq!(json["duration_ms"], "a while");
+        assert_eq!(json["body_size"], "12");
+        assert!(json.get("http.response.status_code").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_application_fields_which_collide() -> Result<(), Box<dyn Error>> {
+        let mut kvs = std::collections::HashMap::new();
+        kvs.insert("status", "shipped");
+        kvs.insert("timestamp", "2021-06-01");
+        kvs.insert("host", "warehouse-3");
+        kvs.insert("level", "gold");
+        let record = log::Record::builder()
+            .args(format_args!("Order updated"))
+            .key_values(&kvs)
+            .level(log::Level::Info)
+            .target("orders")
+            .build();
+
+        let mut buf = Vec::new();
+        write_json(&mut buf, &record, LogFormat::Datadog)?;
+        let json: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(json["status"], "info");
+        assert!(json["timestamp"].as_str().is_some_and(|t| t.ends_with('Z')));
+        assert_eq!(json["host"], HOSTNAME.as_str());
+        assert_eq!(json["app.status"], "shipped");
+        assert_eq!(json["app.timestamp"], "2021-06-01");
+        assert_eq!(json["app.host"], "warehouse-3");
+        assert_eq!(json["level"], "gold");
+
         let mut buf = Vec::new();
-        write_json_str(
-            &mut buf, r#""
-	"#,
-        )?;
-        assert_eq!("\"\\\"\\n\\t\"", std::str::from_utf8(&buf)?);
+        write_json(&mut buf, &record, LogFormat::Preroll)?;
+        let json: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(json["host"], "warehouse-3");
+        assert!(json.get("app.host").is_none());
         Ok(())
     }
 }
@@ -1,5 +1,184 @@
+// The `log` formatters are unused when logging via `tracing`.
+#![cfg_attr(feature = "tracing-logs", allow(dead_code, unused_imports))]
+
+mod classified;
+mod context;
+mod filter;
 mod json;
 mod pretty;
+mod reloadable;
+mod sink;
+
+use std::time::Duration;
 
+pub(crate) use classified::JsonRedaction;
+pub use classified::{Pii, Secret};
+pub use context::LogContext;
+pub use filter::LogLevel;
 pub use json::log_format_json;
+pub(crate) use json::LogFormat;
 pub use pretty::log_format_pretty;
+pub use reloadable::{init_reloadable_logger, reload_access_logger, reload_logger};
+pub(crate) use sink::AccessLog;
+pub use sink::LogSink;
+
+/// Emit a log record with key-value fields, either via `log` (`kv_log_macro`) or, with the `"tracing-logs"` feature,
+/// as a `tracing` event with identically formatted fields.
+macro_rules! log_kv {
+    ($level:ident, { $($key:ident: $value:expr),* $(,)? }, $($msg:tt)+) => {
+        #[cfg(not(feature = "tracing-logs"))]
+        kv_log_macro::$level!($($msg)+, { $($key: $value),* });
+        #[cfg(feature = "tracing-logs")]
+        tracing::$level!(
+            $($key = %crate::logging::KvDisplay(&$value),)*
+            $($msg)+
+        );
+    };
+}
+
+pub(crate) use log_kv;
+
+/// A duration as a number of milliseconds, with a fraction, for the `duration_ms` log field.
+///
+/// JSON logs keep it a number, for aggregation, while pretty logs display it as a `Duration`.
+pub(crate) fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        use std::fmt;
+
+        use tracing::field::{Field, Visit};
+        use tracing::Metadata;
+        use tracing_subscriber::filter::Targets;
+        use tracing_subscriber::fmt::MakeWriter;
+        use tracing_subscriber::{reload, Layer, Registry};
+
+        use crate::middleware::logger::ACCESS_LOG_TARGET;
+
+        pub use json::JsonEventFormat;
+        pub use pretty::PrettyEventFormat;
+
+        /// A handle which can replace the level filter of a [`tracing_log_layer`].
+        pub type LogFilterHandle = reload::Handle<Targets, Registry>;
+
+        /// A `tracing` layer which writes events to stderr, formatted like preroll's `log` output.
+        ///
+        /// This is the `"tracing-logs"` replacement for the `env_logger` setup.
+        /// In production-mode, JSON output is written to the `sink`. Access logs are written to the `access_sink`, if any.
+        pub fn tracing_log_layer(
+            production: bool,
+            log_level: &LogLevel,
+            sink: LogSink,
+            access_sink: Option<LogSink>,
+        ) -> (Box<dyn Layer<Registry> + Send + Sync>, LogFilterHandle) {
+            let (filter, handle) = reload::Layer::new(log_level.targets());
+
+            let layer = if production {
+                tracing_subscriber::fmt::layer()
+                    .event_format(JsonEventFormat)
+                    .with_writer(SinkWriter { sink, access_sink })
+                    .with_filter(filter)
+                    .boxed()
+            } else {
+                tracing_subscriber::fmt::layer()
+                    .event_format(PrettyEventFormat)
+                    .with_writer(SinkWriter {
+                        sink: LogSink::default(),
+                        access_sink,
+                    })
+                    .with_filter(filter)
+                    .boxed()
+            };
+
+            (layer, handle)
+        }
+
+        /// Writes access logs to the `access_sink`, if there is one, and all other events to the `sink`.
+        struct SinkWriter {
+            sink: LogSink,
+            access_sink: Option<LogSink>,
+        }
+
+        impl<'a> MakeWriter<'a> for SinkWriter {
+            type Writer = LogSink;
+
+            fn make_writer(&'a self) -> LogSink {
+                self.sink.clone()
+            }
+
+            fn make_writer_for(&'a self, metadata: &Metadata<'_>) -> LogSink {
+                match &self.access_sink {
+                    Some(access_sink) if metadata.target() == ACCESS_LOG_TARGET => access_sink.clone(),
+                    _ => self.sink.clone(),
+                }
+            }
+        }
+
+        /// Collects the fields of a `tracing` event as display strings, separating out the message.
+        #[derive(Default)]
+        pub(crate) struct EventFields {
+            pub(crate) message: String,
+            pub(crate) pairs: Vec<(&'static str, String)>,
+        }
+
+        impl Visit for EventFields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.record(field.name(), value.to_string());
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.record(field.name(), format!("{:?}", value));
+            }
+        }
+
+        impl EventFields {
+            /// Add the current request's [`LogContext`][] fields, if `target` is logged with them.
+            ///
+            /// Events forwarded from `log` records are matched by their original `log.target`.
+            pub(crate) fn add_context(&mut self, target: &str) {
+                let context = LogContext::for_target(
+                    self.pairs
+                        .iter()
+                        .find(|(name, _)| *name == "log.target")
+                        .map_or(target, |(_, log_target)| log_target.as_str()),
+                );
+
+                if let Some(context) = context {
+                    for (key, value) in context.pairs() {
+                        self.pairs.push((key, value.to_string()));
+                    }
+                }
+            }
+
+            fn record(&mut self, name: &'static str, value: String) {
+                if name != "message" {
+                    self.pairs.push((name, value));
+                    return;
+                }
+
+                // Events from LogMiddleware may have a `message` field in addition to the event message,
+                // which is recorded last. Keep both, the same as a `log` record with a `message` kv pair.
+                let previous = std::mem::replace(&mut self.message, value);
+                if !previous.is_empty() {
+                    self.pairs.push((name, previous));
+                }
+            }
+        }
+
+        /// Displays any `log` key-value value as it would appear in a `log` record.
+        ///
+        /// Allows the same field values to be emitted via `tracing` with identical formatting.
+        pub struct KvDisplay<'v, T: ?Sized>(pub &'v T);
+
+        impl<'v, T> fmt::Display for KvDisplay<'v, T>
+        where
+            T: log::kv::ToValue + ?Sized,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0.to_value())
+            }
+        }
+    }
+}
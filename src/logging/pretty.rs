@@ -1,11 +1,25 @@
 use std::io::Write;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use std::{fmt, io};
 
 // Note: Rust-Analyzer sometimes has trouble with this use:: statement.
 use env_logger::fmt::{Color, Formatter, Style, StyledValue};
 use log::{kv, Level};
 
+use super::LogContext;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        use tracing::{Event, Subscriber};
+        use tracing_subscriber::fmt::format::Writer;
+        use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+        use tracing_subscriber::registry::LookupSpan;
+
+        use super::EventFields;
+    }
+}
+
 // Modified from the pretty_env_logger crate
 pub fn log_format_pretty(f: &mut Formatter, record: &log::Record<'_>) -> io::Result<()> {
     let target = record.target();
@@ -27,6 +41,15 @@ pub fn log_format_pretty(f: &mut Formatter, record: &log::Record<'_>) -> io::Res
 
     write!(f, "{} {} | {}", level, target, record.args(),)?;
     format_kv_pairs(f, record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    if let Some(context) = LogContext::for_target(record.target()) {
+        for (key, value) in context.pairs() {
+            let mut style = f.style();
+            let key = style.set_bold(true).value(key);
+            write!(f, "\n  {} {}", key, value)?;
+        }
+    }
+
     writeln!(f)
 }
 
@@ -75,6 +98,7 @@ fn format_kv_pairs(f: &mut Formatter, record: &log::Record<'_>) -> Result<(), kv
             key: kv::Key<'kvs>,
             val: kv::Value<'kvs>,
         ) -> Result<(), kv::Error> {
+            let (key, val) = human_readable(key.as_str(), val.to_string());
             let mut style = self.f.style();
             let key = style.set_bold(true).value(key);
 
@@ -87,3 +111,60 @@ fn format_kv_pairs(f: &mut Formatter, record: &log::Record<'_>) -> Result<(), kv
     record.key_values().visit(&mut visitor)?;
     Ok(())
 }
+
+/// A `duration_ms` field as its `elapsed` time, e.g. `elapsed 1.5ms`, for reading rather than aggregating.
+fn human_readable(key: &str, value: String) -> (&str, String) {
+    let millis = match key {
+        "duration_ms" => value.parse::<f64>().ok(),
+        _ => None,
+    };
+    match millis.filter(|millis| millis.is_finite() && *millis >= 0.0) {
+        Some(millis) => (
+            "elapsed",
+            format!("{:?}", Duration::from_nanos((millis * 1e6).round() as u64)),
+        ),
+        None => (key, value),
+    }
+}
+
+/// Formats `tracing` events similarly to [`log_format_pretty`], minus colors.
+#[cfg(feature = "tracing-logs")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrettyEventFormat;
+
+#[cfg(feature = "tracing-logs")]
+impl<S, N> FormatEvent<S, N> for PrettyEventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut f: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let target = metadata.target();
+
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        fields.add_context(target);
+
+        write!(
+            f,
+            "{: <5} {} | {}",
+            metadata.level().as_str(),
+            Padded {
+                value: target,
+                width: max_target_width(target),
+            },
+            fields.message
+        )?;
+        for (key, value) in fields.pairs {
+            let (key, value) = human_readable(key, value);
+            write!(f, "\n  {} {}", key, value)?;
+        }
+        writeln!(f)
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::RwLock;
+
+use log::{Log, Metadata, Record, SetLoggerError};
+use once_cell::sync::Lazy;
+
+use crate::middleware::logger::ACCESS_LOG_TARGET;
+
+static LOGGER: Lazy<RwLock<Option<env_logger::Logger>>> = Lazy::new(|| RwLock::new(None));
+
+/// The logger of access logs, if they are written apart from application logs.
+static ACCESS_LOGGER: Lazy<RwLock<Option<env_logger::Logger>>> = Lazy::new(|| RwLock::new(None));
+
+/// The global logger, which delegates to an `env_logger::Logger` which can be replaced at runtime.
+///
+/// Access logs are delegated to the access logger instead, if there is one.
+struct ReloadableLogger;
+
+static RELOADABLE_LOGGER: ReloadableLogger = ReloadableLogger;
+
+impl ReloadableLogger {
+    /// The logger which `target` is logged by.
+    fn logger_for(target: &str) -> &'static RwLock<Option<env_logger::Logger>> {
+        let has_access_logger = matches!(ACCESS_LOGGER.read(), Ok(logger) if logger.is_some());
+        if has_access_logger && target == ACCESS_LOG_TARGET {
+            &ACCESS_LOGGER
+        } else {
+            &LOGGER
+        }
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match Self::logger_for(metadata.target()).read() {
+            Ok(logger) => matches!(logger.as_ref(), Some(logger) if logger.enabled(metadata)),
+            Err(_) => false,
+        }
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if let Ok(logger) = Self::logger_for(record.target()).read() {
+            if let Some(logger) = logger.as_ref() {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for logger in [&LOGGER, &ACCESS_LOGGER] {
+            if let Ok(logger) = logger.read() {
+                if let Some(logger) = logger.as_ref() {
+                    logger.flush();
+                }
+            }
+        }
+    }
+}
+
+/// Install `logger` as the global logger, such that it can later be replaced with [`reload_logger`].
+pub fn init_reloadable_logger(logger: env_logger::Logger) -> Result<(), SetLoggerError> {
+    log::set_logger(&RELOADABLE_LOGGER)?;
+    reload_logger(logger);
+    Ok(())
+}
+
+/// Replace the global logger, e.g. with a new level filter.
+pub fn reload_logger(logger: env_logger::Logger) {
+    let max_level = logger.filter();
+    if let Ok(mut current) = LOGGER.write() {
+        *current = Some(logger);
+    }
+    log::set_max_level(max_level);
+}
+
+/// Replace the logger of access logs, which are otherwise logged by the global logger.
+///
+/// The access logger is expected to have the same level filter as the global logger.
+pub fn reload_access_logger(logger: env_logger::Logger) {
+    if let Ok(mut current) = ACCESS_LOGGER.write() {
+        *current = Some(logger);
+    }
+}
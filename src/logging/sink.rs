@@ -0,0 +1,359 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+
+use crate::config;
+
+/// Where log output is written.
+///
+/// Application logs are written to stderr, and additionally to a (possibly rotating) file if `LOGFILE` is set.
+#[derive(Clone, Debug)]
+pub struct LogSink {
+    console: Console,
+    file: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+/// Which standard stream, if any, a [`LogSink`][] writes to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Console {
+    Stderr,
+    Stdout,
+    None,
+}
+
+impl Default for LogSink {
+    fn default() -> Self {
+        Self {
+            console: Console::Stderr,
+            file: None,
+        }
+    }
+}
+
+impl LogSink {
+    /// Set up the log sink from `LOGFILE` and `LOG_ROTATION`.
+    pub fn from_env() -> io::Result<Self> {
+        let file = match config::var("LOGFILE") {
+            Ok(path) if !path.is_empty() => Some(Arc::new(Mutex::new(RotatingFile::open(
+                path,
+                rotation_from_env()?,
+            )?))),
+            _ => None,
+        };
+
+        Ok(Self {
+            console: Console::Stderr,
+            file,
+        })
+    }
+
+    /// Set up the sink of `LogMiddleware`'s access logs from `ACCESS_LOG`, if they are written apart from application logs.
+    pub fn access_from_env() -> io::Result<Option<Self>> {
+        let access_log = match config::var("ACCESS_LOG") {
+            Ok(access_log) if !access_log.trim().is_empty() => access_log.parse::<AccessLog>()?,
+            _ => return Ok(None),
+        };
+
+        let (path, rotation) = match access_log {
+            AccessLog::Stderr => return Ok(Some(Self::default())),
+            AccessLog::Stdout => {
+                return Ok(Some(Self {
+                    console: Console::Stdout,
+                    file: None,
+                }))
+            }
+            AccessLog::Fd(fd) => (PathBuf::from(format!("/dev/fd/{}", fd)), Rotation::Never),
+            AccessLog::File(path) => (path, rotation_from_env()?),
+        };
+
+        Ok(Some(Self {
+            console: Console::None,
+            file: Some(Arc::new(Mutex::new(RotatingFile::open(path, rotation)?))),
+        }))
+    }
+
+    /// The `env_logger` target for this sink.
+    pub fn env_logger_target(self) -> env_logger::Target {
+        if self.console == Console::Stderr && self.file.is_none() {
+            env_logger::Target::Stderr
+        } else {
+            env_logger::Target::Pipe(Box::new(self))
+        }
+    }
+}
+
+impl Write for LogSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.console {
+            Console::Stderr => io::stderr().write_all(buf)?,
+            Console::Stdout => io::stdout().write_all(buf)?,
+            Console::None => (),
+        }
+
+        if let Some(file) = &self.file {
+            let mut file = file
+                .lock()
+                .map_err(|_| io::Error::other("Log file lock poisoned"))?;
+
+            if let Err(error) = file.write_all(buf) {
+                writeln!(
+                    io::stderr(),
+                    "Unable to write to {}: {}",
+                    file.path.display(),
+                    error
+                )?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.console {
+            Console::Stderr => io::stderr().flush()?,
+            Console::Stdout => io::stdout().flush()?,
+            Console::None => (),
+        }
+
+        if let Some(file) = &self.file {
+            file.lock()
+                .map_err(|_| io::Error::other("Log file lock poisoned"))?
+                .flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where `LogMiddleware`'s access logs are written, from `ACCESS_LOG`, rather than with application logs.
+///
+/// One of `stdout`, `stderr`, `fd:{n}` for an open file descriptor, such as `fd:3`, or else a file path,
+/// which is rotated as `LOG_ROTATION` says.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum AccessLog {
+    Stderr,
+    Stdout,
+    Fd(u32),
+    File(PathBuf),
+}
+
+impl std::str::FromStr for AccessLog {
+    type Err = io::Error;
+
+    fn from_str(string: &str) -> io::Result<Self> {
+        let string = string.trim();
+        match string {
+            "stderr" => return Ok(Self::Stderr),
+            "stdout" => return Ok(Self::Stdout),
+            _ => (),
+        }
+
+        match string.strip_prefix("fd:") {
+            Some(fd) => fd.parse().map(Self::Fd).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "ACCESS_LOG must be stdout, stderr, fd:{{n}}, or a file path, got \"{}\"",
+                        string
+                    ),
+                )
+            }),
+            None => Ok(Self::File(PathBuf::from(string))),
+        }
+    }
+}
+
+fn rotation_from_env() -> io::Result<Rotation> {
+    config::var("LOG_ROTATION")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(Rotation::Never))
+}
+
+/// When to rotate the log file, from `LOG_ROTATION`.
+///
+/// One of `never` (the default), `hourly`, `daily`, or a size in bytes with an optional `K`, `M`, or `G` suffix, e.g. `100M`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+    Size(u64),
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = io::Error;
+
+    fn from_str(string: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "LOG_ROTATION must be never, hourly, daily, or a size such as 100M, got \"{}\"",
+                    string
+                ),
+            )
+        };
+
+        let lower = string.trim().to_lowercase();
+        match lower.as_str() {
+            "" | "never" => return Ok(Self::Never),
+            "hourly" => return Ok(Self::Hourly),
+            "daily" => return Ok(Self::Daily),
+            _ => (),
+        }
+
+        let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier = match &lower[digits.len()..] {
+            "" | "b" => 1,
+            "k" | "kb" => 1024,
+            "m" | "mb" => 1024 * 1024,
+            "g" | "gb" => 1024 * 1024 * 1024,
+            _ => return Err(invalid()),
+        };
+
+        match digits.trim().parse::<u64>() {
+            Ok(size) if size > 0 => Ok(Self::Size(size * multiplier)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Rotation {
+    /// A label for the current time period, which changes when a time-based rotation is due.
+    fn period(&self) -> Option<String> {
+        match self {
+            Self::Hourly => Some(Utc::now().format("%Y-%m-%dT%H").to_string()),
+            Self::Daily => Some(Utc::now().format("%Y-%m-%d").to_string()),
+            Self::Never | Self::Size(_) => None,
+        }
+    }
+}
+
+/// A log file which is renamed to `{path}.{suffix}` and re-created when its rotation is due.
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    period: Option<String>,
+}
+
+impl RotatingFile {
+    fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            period: rotation.period(),
+        })
+    }
+
+    fn rotate(&mut self, suffix: &str) -> io::Result<()> {
+        self.file.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".");
+        rotated.push(suffix);
+        fs::rename(&self.path, rotated)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.rotation {
+            Rotation::Size(max_size) => {
+                if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                    let suffix = Utc::now().format("%Y-%m-%dT%H-%M-%S%.3f").to_string();
+                    self.rotate(&suffix)?;
+                }
+            }
+            Rotation::Hourly | Rotation::Daily => {
+                let period = self.rotation.period();
+                if period != self.period {
+                    if let Some(previous) = std::mem::replace(&mut self.period, period) {
+                        self.rotate(&previous)?;
+                    }
+                }
+            }
+            Rotation::Never => (),
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn parses_rotation() -> Result<(), Box<dyn Error>> {
+        assert_eq!("never".parse::<Rotation>()?, Rotation::Never);
+        assert_eq!("Daily".parse::<Rotation>()?, Rotation::Daily);
+        assert_eq!("hourly".parse::<Rotation>()?, Rotation::Hourly);
+        assert_eq!("4096".parse::<Rotation>()?, Rotation::Size(4096));
+        assert_eq!("10K".parse::<Rotation>()?, Rotation::Size(10 * 1024));
+        assert_eq!(
+            "100mb".parse::<Rotation>()?,
+            Rotation::Size(100 * 1024 * 1024)
+        );
+        assert!("weekly".parse::<Rotation>().is_err());
+        assert!("0".parse::<Rotation>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_access_log() -> Result<(), Box<dyn Error>> {
+        assert_eq!("stdout".parse::<AccessLog>()?, AccessLog::Stdout);
+        assert_eq!(" stderr ".parse::<AccessLog>()?, AccessLog::Stderr);
+        assert_eq!("fd:3".parse::<AccessLog>()?, AccessLog::Fd(3));
+        assert_eq!(
+            "/var/log/access.log".parse::<AccessLog>()?,
+            AccessLog::File(PathBuf::from("/var/log/access.log"))
+        );
+        assert!("fd:three".parse::<AccessLog>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rotates_by_size() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("preroll-logfile-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("service.log");
+
+        let mut file = RotatingFile::open(&path, Rotation::Size(16))?;
+        file.write_all(b"0123456789\n")?;
+        file.write_all(b"0123456789\n")?;
+        file.flush()?;
+
+        assert_eq!(fs::read_to_string(&path)?, "0123456789\n");
+        assert_eq!(fs::read_dir(&dir)?.count(), 2);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
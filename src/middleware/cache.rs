@@ -0,0 +1,293 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tide::http::headers::{HeaderName, AUTHORIZATION, COOKIE, SET_COOKIE};
+use tide::http::{self, Method};
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+use crate::cache::{self, CacheBackend, CachedResponse};
+use crate::logging::log_kv;
+use crate::middleware::forwarded::url_host;
+
+/// The response header describing how the cache handled the request.
+const CACHE_STATUS: &str = "Cache-Status";
+
+type KeyFn = dyn Fn(&http::Request) -> Option<String> + Send + Sync;
+
+/// Cache successful `GET` responses for a TTL, per route.
+///
+/// By default responses are cached by host, path, and query, and by the values of any [`vary`][CacheMiddleware::vary]
+/// headers. Requests with `Authorization` or `Cookie` headers are not cached, unless those headers are varied on,
+/// and neither are responses which set cookies. Keys are SHA-256 digests, so that header values such as credentials
+/// are not stored in the backend's keys, nor logged.
+///
+/// See [`preroll::cache`][crate::cache] for details.
+#[derive(Clone)]
+pub struct CacheMiddleware {
+    ttl: Duration,
+    vary: Vec<HeaderName>,
+    key_fn: Option<Arc<KeyFn>>,
+    backend: Option<Arc<dyn CacheBackend>>,
+}
+
+impl Debug for CacheMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheMiddleware")
+            .field("ttl", &self.ttl)
+            .field("vary", &self.vary)
+            .field("key_fn", &self.key_fn.is_some())
+            .field("backend", &self.backend.is_some())
+            .finish()
+    }
+}
+
+impl CacheMiddleware {
+    /// Create a new instance of `CacheMiddleware`, caching responses for `ttl`.
+    #[must_use]
+    pub fn new(ttl: Duration) -> Self {
+        cache::record_used();
+        Self {
+            ttl,
+            vary: Vec::new(),
+            key_fn: None,
+            backend: None,
+        }
+    }
+
+    /// Cache responses separately for each value of the request header `name`, e.g. `Accept-Language`.
+    #[must_use]
+    pub fn vary(mut self, name: impl Into<HeaderName>) -> Self {
+        self.vary.push(name.into());
+        self
+    }
+
+    /// Compute cache keys with `key_fn` rather than from the host, path, and query, or skip the cache if it returns `None`.
+    ///
+    /// Vary headers are still added to the key.
+    #[must_use]
+    pub fn key_fn<F>(mut self, key_fn: F) -> Self
+    where
+        F: Fn(&http::Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key_fn = Some(Arc::new(key_fn));
+        self
+    }
+
+    /// Cache in `backend`, rather than the backend [set][crate::cache::set_backend] for all routes.
+    #[must_use]
+    pub fn backend(mut self, backend: impl CacheBackend) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// The cache key for a request, as a digest, if it may be cached.
+    fn key(&self, req: &http::Request) -> Option<String> {
+        let varies = |name: &HeaderName| self.vary.iter().any(|vary| vary == name);
+        for credentials in [AUTHORIZATION, COOKIE].iter() {
+            if req.header(credentials).is_some() && !varies(credentials) {
+                return None;
+            }
+        }
+
+        let key = match &self.key_fn {
+            Some(key_fn) => key_fn(req)?,
+            None => {
+                let url = req.url();
+                format!(
+                    "{}{}?{}",
+                    url_host(url),
+                    url.path(),
+                    url.query().unwrap_or("")
+                )
+            }
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        for name in self.vary.iter() {
+            let value = req
+                .header(name)
+                .map(|values| values.to_string())
+                .unwrap_or_default();
+            hasher.update(format!("\n{}: {}", name, value));
+        }
+
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    /// Serve the request from the cache, or handle it and cache the response.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Get {
+            return Ok(next.run(req).await);
+        }
+
+        let key = match self.key(req.as_ref()) {
+            Some(key) => key,
+            None => {
+                let mut res = next.run(req).await;
+                res.insert_header(CACHE_STATUS, "preroll; fwd=bypass");
+                return Ok(res);
+            }
+        };
+
+        let backend = self.backend.clone().unwrap_or_else(cache::backend);
+        match backend.get(&key).await {
+            Ok(Some(cached)) => {
+                cache::record_hit();
+                let mut res = cached.to_response();
+                res.insert_header(CACHE_STATUS, "preroll; hit");
+                return Ok(res);
+            }
+            Ok(None) => cache::record_miss(),
+            Err(error) => backend_failed(&key, "get", error),
+        }
+
+        let mut res = next.run(req).await;
+        let cacheable = res.status() == StatusCode::Ok
+            && res.error().is_none()
+            && res.header(SET_COOKIE).is_none();
+        if !cacheable {
+            res.insert_header(CACHE_STATUS, "preroll; fwd=miss");
+            return Ok(res);
+        }
+
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+
+        let cached = CachedResponse {
+            status: res.status() as u16,
+            headers: res
+                .iter()
+                .flat_map(|(name, values)| {
+                    values
+                        .iter()
+                        .map(move |value| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+            body: bytes,
+        };
+        let cache_status = match backend.set(&key, &cached, self.ttl).await {
+            Ok(()) => {
+                cache::record_store();
+                "preroll; fwd=miss; stored"
+            }
+            Err(error) => {
+                backend_failed(&key, "set", error);
+                "preroll; fwd=miss"
+            }
+        };
+
+        let mut body = Body::from_bytes(cached.body);
+        body.set_mime(mime);
+        res.set_body(body);
+        res.insert_header(CACHE_STATUS, cache_status);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CacheMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+fn backend_failed(key: &str, operation: &str, error: anyhow::Error) {
+    cache::record_error();
+    log_kv!(
+        warn,
+        {
+            cache_key: key,
+            operation: operation,
+        },
+        "Response cache backend failed: {:?}", error
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::cache::MemoryCache;
+    use crate::test_utils;
+
+    fn request(path: &str, language: &str) -> http::Request {
+        request_to("http://localhost", path, language)
+    }
+
+    fn request_to(origin: &str, path: &str, language: &str) -> http::Request {
+        let mut req = test_utils::request(Method::Get, &format!("{}{}", origin, path));
+        req.insert_header("Accept-Language", language);
+        req
+    }
+
+    #[async_std::test]
+    async fn caches_by_host_path_query_and_vary_headers() -> tide::Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let mut server = tide::new();
+        server.with(
+            CacheMiddleware::new(Duration::from_secs(60))
+                .vary("Accept-Language")
+                .backend(MemoryCache::new(10)),
+        );
+        let handler_count = count.clone();
+        server.at("/products").get(move |_| {
+            let count = handler_count.clone();
+            async move { Ok(format!("products {}", count.fetch_add(1, Ordering::SeqCst))) }
+        });
+
+        let mut res: http::Response = server.respond(request("/products?page=1", "en")).await?;
+        assert_eq!(
+            res.header(CACHE_STATUS).map(|v| v.as_str()),
+            Some("preroll; fwd=miss; stored")
+        );
+        assert_eq!(res.body_string().await?, "products 0");
+
+        let mut res: http::Response = server.respond(request("/products?page=1", "en")).await?;
+        assert_eq!(
+            res.header(CACHE_STATUS).map(|v| v.as_str()),
+            Some("preroll; hit")
+        );
+        assert_eq!(res.body_string().await?, "products 0");
+
+        let mut res: http::Response = server.respond(request("/products?page=2", "en")).await?;
+        assert_eq!(res.body_string().await?, "products 1");
+        let mut res: http::Response = server.respond(request("/products?page=1", "fr")).await?;
+        assert_eq!(res.body_string().await?, "products 2");
+        let mut res: http::Response = server
+            .respond(request_to("http://other.example", "/products?page=1", "en"))
+            .await?;
+        assert_eq!(res.body_string().await?, "products 3");
+
+        let mut req = request("/products?page=1", "en");
+        req.insert_header(AUTHORIZATION, "Bearer secret");
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(
+            res.header(CACHE_STATUS).map(|v| v.as_str()),
+            Some("preroll; fwd=bypass")
+        );
+        assert_eq!(res.body_string().await?, "products 4");
+        Ok(())
+    }
+
+    #[test]
+    fn digests_keys() {
+        let cache = CacheMiddleware::new(Duration::from_secs(60)).vary(AUTHORIZATION);
+        let mut req = request("/products", "en");
+        req.insert_header(AUTHORIZATION, "Bearer secret");
+
+        let key = cache.key(&req).unwrap_or_default();
+        assert_eq!(key.len(), 64);
+        assert!(!key.contains("secret"));
+        assert_ne!(Some(key), cache.key(&request("/products", "en")));
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::future::timeout;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tide::http::headers::RETRY_AFTER;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::config;
+
+static LIMITERS: Lazy<RwLock<BTreeMap<&'static str, Arc<Limiter>>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// The default routes' limits, before `MAX_INFLIGHT_{NAME}` overrides, so that a reload can fall back to them.
+static ROUTE_DEFAULTS: Lazy<RwLock<BTreeMap<&'static str, usize>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+static QUEUE_TIMEOUT: Lazy<RwLock<Duration>> = Lazy::new(|| RwLock::new(queue_timeout()));
+
+/// Limit how many requests are handled at once, so that a spike of expensive requests is shed rather than exhausting memory.
+///
+/// A request which arrives while the limit is reached waits up to `MAX_INFLIGHT_QUEUE_MS` (default `100`) for another
+/// request to finish, and is otherwise rejected with a `503 Service Unavailable` `JsonError` and a `Retry-After: 1` header.
+///
+/// preroll installs a global limit of `MAX_INFLIGHT` requests, if it is set. Expensive routes can have a limit of their
+/// own with [`ConcurrencyLimitMiddleware::route`][], which `MAX_INFLIGHT_{NAME}` overrides, e.g. `MAX_INFLIGHT_REPORTS`.
+///
+/// Each limit, and its in-flight, queued, and shed requests, is reported under `"concurrency"` in `/monitor/status`.
+///
+/// These settings are re-read when configuration is reloaded. Requests already in flight count towards the old limit.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::ConcurrencyLimitMiddleware;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server
+///         .at("reports")
+///         .with(ConcurrencyLimitMiddleware::route("reports", 4))
+///         .get(|_| async { Ok("an expensive report") });
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitMiddleware {
+    name: &'static str,
+    queue_timeout: Option<Duration>,
+}
+
+impl Default for ConcurrencyLimitMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConcurrencyLimitMiddleware {
+    /// Create a new instance of `ConcurrencyLimitMiddleware`, with the global limit of `MAX_INFLIGHT` requests,
+    /// or no limit if it is not set.
+    #[must_use]
+    pub fn new() -> Self {
+        if let Some(limit) = configured_limit("global") {
+            Limiter::register("global", limit);
+        }
+        Self {
+            name: "global",
+            queue_timeout: None,
+        }
+    }
+
+    /// Create a new instance of `ConcurrencyLimitMiddleware` for the routes named `name`, with a limit of `limit` requests,
+    /// or of `MAX_INFLIGHT_{NAME}` if it is set.
+    ///
+    /// Replaces any previous limit of the same name in `/monitor/status`, so names should be unique.
+    #[must_use]
+    pub fn route(name: &'static str, limit: usize) -> Self {
+        ROUTE_DEFAULTS
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, limit);
+        if let Some(limit) = configured_limit(name) {
+            Limiter::register(name, limit);
+        }
+        Self {
+            name,
+            queue_timeout: None,
+        }
+    }
+
+    /// How long a request may wait for another to finish before it is shed, rather than `MAX_INFLIGHT_QUEUE_MS`.
+    #[must_use]
+    pub fn queue_timeout(mut self, queue_timeout: Duration) -> Self {
+        self.queue_timeout = Some(queue_timeout);
+        self
+    }
+
+    /// Handle the request once fewer than the limit are in flight, or shed it.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        // Looked up per request, so that a reloaded limit applies to the next request.
+        let limiter = LIMITERS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(self.name)
+            .cloned();
+        let limiter = match limiter {
+            Some(limiter) => limiter,
+            None => return Ok(next.run(req).await),
+        };
+
+        let queue_timeout = self
+            .queue_timeout
+            .unwrap_or_else(|| *QUEUE_TIMEOUT.read().unwrap_or_else(PoisonError::into_inner));
+        let permit = match limiter.acquire(queue_timeout).await {
+            Some(permit) => permit,
+            None => {
+                return Ok(shed_response(format!(
+                    "Too many requests in flight ({} limit of {})",
+                    limiter.name, limiter.limit
+                )))
+            }
+        };
+
+        let res = next.run(req).await;
+        drop(permit);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ConcurrencyLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// A `503 Service Unavailable` for a shed request, which clients may retry after a second.
+pub(crate) fn shed_response(message: String) -> Response {
+    let mut res = Response::new(StatusCode::ServiceUnavailable);
+    res.insert_header(RETRY_AFTER, "1");
+    res.set_error(tide::Error::from_str(
+        StatusCode::ServiceUnavailable,
+        message,
+    ));
+    res
+}
+
+/// The fraction of the global `MAX_INFLIGHT` limit used by requests other than the current one, including those queued,
+/// or `0.0` if there is no global limit.
+pub(crate) fn pressure() -> f64 {
+    let limiters = LIMITERS.read().unwrap_or_else(PoisonError::into_inner);
+    let global = match limiters.get("global") {
+        Some(global) => global,
+        None => return 0.0,
+    };
+
+    let others = (global.in_flight.load(Ordering::Relaxed) + global.queued.load(Ordering::Relaxed))
+        .saturating_sub(1);
+    others as f64 / global.limit as f64
+}
+
+fn queue_timeout() -> Duration {
+    let queue_timeout = config::var("MAX_INFLIGHT_QUEUE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+    Duration::from_millis(queue_timeout)
+}
+
+/// The limit named `name`: `MAX_INFLIGHT` for the global limit, or else `MAX_INFLIGHT_{NAME}`
+/// or the route's default. `None` if there is no limit.
+fn configured_limit(name: &'static str) -> Option<usize> {
+    let var = match name {
+        "global" => "MAX_INFLIGHT".to_string(),
+        name => format!("MAX_INFLIGHT_{}", name.to_uppercase().replace('-', "_")),
+    };
+    config::var(&var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .or_else(|| {
+            ROUTE_DEFAULTS
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(name)
+                .copied()
+        })
+}
+
+/// Re-read `MAX_INFLIGHT`, `MAX_INFLIGHT_{NAME}`, and `MAX_INFLIGHT_QUEUE_MS`.
+///
+/// A changed limit replaces its limiter, whose in-flight requests finish without counting towards the new limit.
+pub(crate) fn reload_concurrency_limits() {
+    if let Ok(mut current) = QUEUE_TIMEOUT.write() {
+        *current = queue_timeout();
+    }
+
+    let mut names: Vec<&'static str> = ROUTE_DEFAULTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .keys()
+        .copied()
+        .collect();
+    names.push("global");
+
+    for name in names {
+        let current = LIMITERS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .map(|limiter| limiter.limit);
+        match configured_limit(name) {
+            Some(limit) if Some(limit.max(1)) != current => {
+                Limiter::register(name, limit);
+            }
+            Some(_) => {}
+            None => {
+                LIMITERS
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(name);
+            }
+        }
+    }
+}
+
+/// A named limit, whose channel holds one message per request in flight.
+#[derive(Debug)]
+struct Limiter {
+    name: &'static str,
+    limit: usize,
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+    in_flight: AtomicU64,
+    peak_in_flight: AtomicU64,
+    queued: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl Limiter {
+    /// Create a limit of `limit` requests, replacing any previous limit of the same name in `/monitor/status`.
+    fn register(name: &'static str, limit: usize) {
+        let limit = limit.max(1);
+        let (sender, receiver) = channel::bounded(limit);
+        let limiter = Arc::new(Self {
+            name,
+            limit,
+            sender,
+            receiver,
+            in_flight: AtomicU64::new(0),
+            peak_in_flight: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+        });
+        LIMITERS
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(limiter.name, limiter);
+    }
+
+    /// Wait up to the queue timeout for the request to be let through, or `None` if it is shed.
+    async fn acquire(&self, queue_timeout: Duration) -> Option<Permit<'_>> {
+        if self.sender.try_send(()).is_err() {
+            // Decremented on drop, so that cancelled requests are no longer counted.
+            let queued = Gauge::increment(&self.queued);
+            let sent = timeout(queue_timeout, self.sender.send(())).await;
+            drop(queued);
+
+            if !matches!(sent, Ok(Ok(()))) {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+
+        let in_flight = Gauge::increment(&self.in_flight);
+        self.peak_in_flight
+            .fetch_max(self.in_flight.load(Ordering::Relaxed), Ordering::Relaxed);
+        Some(Permit {
+            limiter: self,
+            _in_flight: in_flight,
+        })
+    }
+}
+
+/// Lets the next request through when dropped.
+struct Permit<'a> {
+    limiter: &'a Limiter,
+    _in_flight: Gauge<'a>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.receiver.try_recv().ok();
+    }
+}
+
+struct Gauge<'a>(&'a AtomicU64);
+
+impl<'a> Gauge<'a> {
+    fn increment(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A concurrency limit's gauges, reported under `"concurrency"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyStats {
+    limit: usize,
+    in_flight: u64,
+    peak_in_flight: u64,
+    /// Requests waiting for another to finish.
+    queued: u64,
+    /// Requests rejected with a `503`.
+    shed: u64,
+}
+
+/// The gauges of every concurrency limit, if there are any.
+pub(crate) fn concurrency_stats() -> Option<BTreeMap<&'static str, ConcurrencyStats>> {
+    let limiters = LIMITERS.read().unwrap_or_else(PoisonError::into_inner);
+    if limiters.is_empty() {
+        return None;
+    }
+
+    let stats = limiters
+        .iter()
+        .map(|(name, limiter)| {
+            let stats = ConcurrencyStats {
+                limit: limiter.limit,
+                in_flight: limiter.in_flight.load(Ordering::Relaxed),
+                peak_in_flight: limiter.peak_in_flight.load(Ordering::Relaxed),
+                queued: limiter.queued.load(Ordering::Relaxed),
+                shed: limiter.shed.load(Ordering::Relaxed),
+            };
+            (*name, stats)
+        })
+        .collect();
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn sheds_requests_over_the_limit() -> tide::Result<()> {
+        let mut server = tide::new();
+        server
+            .at("/")
+            .with(
+                ConcurrencyLimitMiddleware::route("concurrency-test", 1)
+                    .queue_timeout(Duration::from_millis(10)),
+            )
+            .get(|_| async {
+                async_std::task::sleep(Duration::from_millis(200)).await;
+                Ok("done")
+            });
+
+        let first = {
+            let server = server.clone();
+            async_std::task::spawn(async move {
+                server
+                    .respond::<_, http::Response>(test_utils::request(Method::Get, "/"))
+                    .await
+            })
+        };
+        async_std::task::sleep(Duration::from_millis(50)).await;
+
+        let res: http::Response = server
+            .respond(test_utils::request(Method::Get, "/"))
+            .await?;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(
+            res.header(RETRY_AFTER).map(|value| value.as_str()),
+            Some("1")
+        );
+
+        let stats = concurrency_stats().unwrap_or_default();
+        let stats = serde_json::to_value(&stats["concurrency-test"])?;
+        assert_eq!(stats["inFlight"], 1);
+        assert_eq!(stats["shed"], 1);
+
+        let mut res = first.await?;
+        assert_eq!(res.body_string().await?, "done");
+
+        let res: http::Response = server
+            .respond(test_utils::request(Method::Get, "/"))
+            .await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+        Ok(())
+    }
+
+    #[test]
+    fn reloads_changed_limits() {
+        let _limit = ConcurrencyLimitMiddleware::route("reload-test", 2);
+        let limit = || {
+            concurrency_stats().and_then(|stats| stats.get("reload-test").map(|stats| stats.limit))
+        };
+        assert_eq!(limit(), Some(2));
+
+        env::set_var("MAX_INFLIGHT_RELOAD_TEST", "5");
+        reload_concurrency_limits();
+        assert_eq!(limit(), Some(5));
+
+        env::remove_var("MAX_INFLIGHT_RELOAD_TEST");
+        reload_concurrency_limits();
+        assert_eq!(limit(), Some(2));
+    }
+}
@@ -0,0 +1,308 @@
+use std::fmt::{self, Debug};
+
+use tide::http::headers::{HeaderName, COOKIE, SET_COOKIE};
+use tide::http::Method;
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::config;
+use crate::utils::{constant_time_eq, hmac_sha256_hex, random_token, request_cookie};
+
+/// The CSRF token for the current request, set by [`CsrfMiddleware`][].
+#[derive(Clone, Debug)]
+struct CsrfToken(String);
+
+/// An extension trait for reading the current request's CSRF token.
+pub trait CsrfRequestExt {
+    /// The token which unsafe requests from this client must send, e.g. to embed in a rendered form or page.
+    ///
+    /// `None` if [`CsrfMiddleware`][] is not installed, or if it is bound to sessions and the request has no session.
+    fn csrf_token(&self) -> Option<&str>;
+}
+
+impl<State> CsrfRequestExt for Request<State> {
+    fn csrf_token(&self) -> Option<&str> {
+        self.ext::<CsrfToken>().map(|token| token.0.as_str())
+    }
+}
+
+/// Protect cookie-authenticated routes from cross-site request forgery.
+///
+/// Unsafe requests (other than `GET`, `HEAD`, `OPTIONS`, and `TRACE`) which carry cookies must send the client's CSRF token
+/// in the `X-CSRF-Token` header, or are rejected with a `403 Forbidden`. Requests without cookies, such as those
+/// authenticated with bearer tokens, cannot be forged by a browser and are not checked.
+///
+/// Tokens are checked with one of two patterns:
+/// - Double-submit cookie, by default: a random token is issued in a `csrf_token` cookie, which is not `HttpOnly`, so that
+///   the page's scripts can copy it into the header. Cross-site pages can neither read the cookie nor set the header.
+/// - Session-bound tokens, with [`bind_to_session`][CsrfMiddleware::bind_to_session]: the token is an HMAC of the
+///   session cookie, so no token needs to be stored, client- or server-side, and it changes with the session.
+///
+/// Safe requests are given the token in an `X-CSRF-Token` response header, and handlers can read it with
+/// [`req.csrf_token()`][CsrfRequestExt::csrf_token], from the prelude. Tests can fetch it with
+/// [`TestClient::csrf`][crate::test_utils::TestClient::csrf].
+///
+/// This is not installed by default, as most preroll services are not used by browsers directly.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::prelude::*;
+/// use preroll::CsrfMiddleware;
+/// use tide::{Request, Route};
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server.with(CsrfMiddleware::new());
+///
+///     server
+///         .at("profile")
+///         .get(|req: Request<Arc<()>>| async move {
+///             Ok(format!("<input type=hidden name=csrf value={}>", req.csrf_token().unwrap_or("")))
+///         })
+///         .post(|_req| async { Ok("saved") });
+/// }
+/// ```
+#[derive(Clone)]
+pub struct CsrfMiddleware {
+    cookie_name: String,
+    header_name: HeaderName,
+    secure: bool,
+    session: Option<(String, Vec<u8>)>,
+}
+
+impl Debug for CsrfMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CsrfMiddleware")
+            .field("cookie_name", &self.cookie_name)
+            .field("header_name", &self.header_name)
+            .field("secure", &self.secure)
+            .field(
+                "session_cookie",
+                &self.session.as_ref().map(|(name, _)| name),
+            )
+            .finish()
+    }
+}
+
+impl Default for CsrfMiddleware {
+    fn default() -> Self {
+        let production = config::var("ENVIRONMENT")
+            .map(|environment| environment.starts_with("prod"))
+            .unwrap_or(false);
+
+        Self {
+            cookie_name: "csrf_token".to_string(),
+            header_name: HeaderName::from("X-CSRF-Token"),
+            secure: production,
+            session: None,
+        }
+    }
+}
+
+impl CsrfMiddleware {
+    /// Create a new instance of `CsrfMiddleware`, using double-submit cookies.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The name of the double-submit cookie. Defaults to `csrf_token`.
+    #[must_use]
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// The request and response header of the token. Defaults to `X-CSRF-Token`.
+    #[must_use]
+    pub fn header_name(mut self, name: impl Into<HeaderName>) -> Self {
+        self.header_name = name.into();
+        self
+    }
+
+    /// Whether the double-submit cookie is `Secure`, i.e. only sent over https.
+    /// Defaults to `true` if `ENVIRONMENT` starts with `prod`.
+    #[must_use]
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Derive tokens from the `session_cookie` cookie, signed with `secret`, rather than issuing a double-submit cookie.
+    ///
+    /// `secret` must be kept private, and be the same for every instance of the service.
+    #[must_use]
+    pub fn bind_to_session(
+        mut self,
+        session_cookie: impl Into<String>,
+        secret: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.session = Some((session_cookie.into(), secret.into()));
+        self
+    }
+
+    /// Check unsafe requests' tokens, and issue tokens to safe requests.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let safe = matches!(
+            req.method(),
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        );
+
+        let (token, issued) = match &self.session {
+            Some((session_cookie, secret)) => {
+                let token = request_cookie(&req, session_cookie)
+                    .map(|session| session_token(secret, &session));
+                (token, false)
+            }
+            None => match request_cookie(&req, &self.cookie_name) {
+                Some(token) => (Some(token), false),
+                None if safe => (Some(random_token()), true),
+                None => (None, false),
+            },
+        };
+
+        if !safe && req.header(COOKIE).is_some() {
+            let sent = req
+                .header(&self.header_name)
+                .map(|values| values.last().as_str());
+            let valid = match (&token, sent) {
+                (Some(token), Some(sent)) => constant_time_eq(token.as_bytes(), sent.as_bytes()),
+                // Bound to sessions, a request without a session is not cookie-authenticated.
+                (None, _) => self.session.is_some(),
+                (Some(_), None) => false,
+            };
+            if !valid {
+                return Err(tide::Error::from_str(
+                    StatusCode::Forbidden,
+                    "Missing or invalid CSRF token",
+                ));
+            }
+        }
+
+        if let Some(token) = &token {
+            req.set_ext(CsrfToken(token.clone()));
+        }
+
+        let mut res = next.run(req).await;
+        if let (true, Some(token)) = (safe, token) {
+            if issued {
+                let mut cookie = format!("{}={}; Path=/; SameSite=Strict", self.cookie_name, token);
+                if self.secure {
+                    cookie.push_str("; Secure");
+                }
+                res.append_header(SET_COOKIE, cookie);
+            }
+            res.insert_header(&self.header_name, token);
+        }
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CsrfMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// The token of a session, as a hex HMAC-SHA256 of the session cookie.
+fn session_token(secret: &[u8], session: &str) -> String {
+    hmac_sha256_hex(secret, session.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn server(csrf: CsrfMiddleware) -> tide::Server<()> {
+        let mut server = tide::new();
+        server.with(csrf);
+        server
+            .at("/profile")
+            .get(|_| async { Ok("profile") })
+            .post(|_| async { Ok("saved") });
+        server
+    }
+
+    fn request(method: Method, cookie: Option<&str>, token: Option<&str>) -> http::Request {
+        let mut req = test_utils::request(method, "/profile");
+        if let Some(cookie) = cookie {
+            req.insert_header(COOKIE, cookie);
+        }
+        if let Some(token) = token {
+            req.insert_header("X-CSRF-Token", token);
+        }
+        req
+    }
+
+    #[async_std::test]
+    async fn checks_double_submit_cookies() -> tide::Result<()> {
+        let server = server(CsrfMiddleware::new());
+
+        let res: http::Response = server.respond(request(Method::Get, None, None)).await?;
+        let token = res
+            .header("X-CSRF-Token")
+            .map(|v| v.as_str().to_string())
+            .unwrap_or_default();
+        assert_eq!(token.len(), 64);
+        assert_eq!(
+            res.header(SET_COOKIE).map(|v| v.as_str().to_string()),
+            Some(format!("csrf_token={}; Path=/; SameSite=Strict", token))
+        );
+
+        let cookie = format!("session=abc; csrf_token={}", token);
+        let req = request(Method::Post, Some(&cookie), Some(&token));
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let req = request(Method::Post, Some(&cookie), Some("forged"));
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let req = request(Method::Post, Some("session=abc"), None);
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        // Not cookie-authenticated.
+        let res: http::Response = server.respond(request(Method::Post, None, None)).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn checks_session_bound_tokens() -> tide::Result<()> {
+        let server = server(CsrfMiddleware::new().bind_to_session("session", "secret"));
+
+        let req = request(Method::Get, Some("session=abc"), None);
+        let res: http::Response = server.respond(req).await?;
+        assert!(res.header(SET_COOKIE).is_none());
+        let token = res
+            .header("X-CSRF-Token")
+            .map(|v| v.as_str().to_string())
+            .unwrap_or_default();
+        assert_eq!(token, session_token(b"secret", "abc"));
+        // RFC 4231, test case 2.
+        assert_eq!(
+            session_token(b"Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let req = request(Method::Post, Some("session=abc"), Some(&token));
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let req = request(Method::Post, Some("session=other"), Some(&token));
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use sha2::{Digest, Sha256};
+use tide::http::headers::{ETAG, IF_NONE_MATCH};
+use tide::http::Method;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+/// The largest response body for which an ETag is computed.
+const MAX_ETAG_BODY_BYTES: usize = 1024 * 1024;
+
+/// Add ETags to JSON responses, and reply `304 Not Modified` to conditional requests which match them.
+///
+/// Successful `GET` and `HEAD` responses with an `application/json` body of a known length get a strong ETag
+/// computed from the body, unless the handler has already set an ETag, such as a weak version-based `W/"42"`.
+/// A request whose `If-None-Match` matches the response's ETag then receives an empty `304` response instead.
+///
+/// This is not installed by default. Add it to specific routes, or to the whole server in `custom_setup`.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::ETagMiddleware;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server
+///         .at("catalog")
+///         .with(ETagMiddleware::new())
+///         .get(|_| async { tide::Body::from_json(&serde_json::json!({ "products": [] })) });
+/// }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ETagMiddleware {
+    _priv: (),
+}
+
+impl ETagMiddleware {
+    /// Create a new instance of `ETagMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+
+    /// Tag the response, and handle `If-None-Match`.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            return Ok(next.run(req).await);
+        }
+
+        let if_none_match = req.header(IF_NONE_MATCH).map(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+
+        let mut res = next.run(req).await;
+        if res.status() != StatusCode::Ok {
+            return Ok(res);
+        }
+
+        if res.header(ETAG).is_none() {
+            let is_json = res
+                .content_type()
+                .is_some_and(|mime| mime.essence() == "application/json");
+
+            match res.len() {
+                Some(len) if is_json && len <= MAX_ETAG_BODY_BYTES => {
+                    let body = res.take_body();
+                    let mime = body.mime().clone();
+                    let bytes = body.into_bytes().await?;
+
+                    res.insert_header(ETAG, strong_etag(&bytes));
+
+                    let mut body = Body::from_bytes(bytes);
+                    body.set_mime(mime);
+                    res.set_body(body);
+                }
+                _ => return Ok(res),
+            }
+        }
+
+        let etag = res.header(ETAG).map(|values| values.last().as_str());
+        if let (Some(if_none_match), Some(etag)) = (if_none_match, etag) {
+            if none_match_fails(&if_none_match, etag) {
+                res.set_status(StatusCode::NotModified);
+                res.take_body();
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ETagMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// A strong ETag for `bytes`, from the first 128 bits of their SHA-256 digest.
+pub(crate) fn strong_etag(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    let hex: String = digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether an `If-None-Match` header matches `etag`, such that the condition fails, using weak comparison.
+fn none_match_fails(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn request(path: &str) -> http::Request {
+        test_utils::request(Method::Get, path)
+    }
+
+    #[async_std::test]
+    async fn tags_json_responses_of_its_routes() -> tide::Result<()> {
+        let mut server = tide::new();
+        server
+            .at("/tagged")
+            .with(ETagMiddleware::new())
+            .get(|_| async { Body::from_json(&serde_json::json!({ "hello": "world" })) });
+        server
+            .at("/untagged")
+            .get(|_| async { Body::from_json(&serde_json::json!({ "hello": "world" })) });
+
+        let res: http::Response = server.respond(request("/untagged")).await?;
+        assert!(res.header(ETAG).is_none());
+
+        let res: http::Response = server.respond(request("/tagged")).await?;
+        let etag = res.header(ETAG).map(|values| values.last().to_string());
+        assert_eq!(etag, Some(strong_etag(b"{\"hello\":\"world\"}")));
+
+        let mut req = request("/tagged");
+        req.insert_header(IF_NONE_MATCH, etag.unwrap_or_default());
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::NotModified);
+        Ok(())
+    }
+
+    #[test]
+    fn strong_etags_are_quoted_and_stable() {
+        let etag = strong_etag(b"{\"hello\":\"world\"}");
+        assert_eq!(etag.len(), 34);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(etag, strong_etag(b"{\"hello\":\"world\"}"));
+        assert_ne!(etag, strong_etag(b"{\"hello\":\"there\"}"));
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert!(none_match_fails("\"abc\"", "\"abc\""));
+        assert!(none_match_fails("W/\"abc\"", "\"abc\""));
+        assert!(none_match_fails("\"xyz\", W/\"abc\"", "W/\"abc\""));
+        assert!(none_match_fails("*", "\"abc\""));
+        assert!(!none_match_fails("\"xyz\"", "\"abc\""));
+    }
+}
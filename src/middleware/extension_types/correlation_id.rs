@@ -6,6 +6,10 @@ use serde::de::{Error as DeError, Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
+use super::id_format::hyphenated;
+#[cfg(not(feature = "test"))]
+use super::id_format::IdFormat;
+
 #[derive(Debug, Clone)]
 pub struct CorrelationId {
     id: String,
@@ -15,7 +19,9 @@ impl CorrelationId {
     #[allow(clippy::new_without_default)]
     #[cfg(not(feature = "test"))]
     pub fn new() -> Self {
-        Uuid::new_v4().into()
+        Self {
+            id: IdFormat::current().generate().1,
+        }
     }
 
     pub fn as_str(&self) -> &str {
@@ -31,10 +37,8 @@ impl Display for CorrelationId {
 
 impl From<Uuid> for CorrelationId {
     fn from(uuid: Uuid) -> Self {
-        let buf = &mut [0; 36];
-        let human_id = uuid.to_hyphenated().encode_lower(buf);
         Self {
-            id: human_id.to_string(),
+            id: hyphenated(uuid),
         }
     }
 }
@@ -0,0 +1,208 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Error};
+use once_cell::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::config;
+
+static ID_FORMAT: OnceCell<IdFormat> = OnceCell::new();
+
+/// Crockford's base32 alphabet, as used by ULIDs.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The format of generated request and correlation ids. (`ID_FORMAT`)
+///
+/// Incoming ids are accepted in any of these formats, regardless of which is generated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    /// Random UUIDs, e.g. `0d9b7e4c-...`. The default. (`uuidv4`)
+    #[default]
+    UuidV4,
+    /// Time-ordered UUIDs, which sort by creation time. (`uuidv7`)
+    UuidV7,
+    /// Time-ordered [ULIDs](https://github.com/ulid/spec), e.g. `01ARZ3NDEKTSV4RRFFQ69G5FAV`. (`ulid`)
+    Ulid,
+}
+
+impl FromStr for IdFormat {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self, Error> {
+        match string.to_lowercase().as_str() {
+            "uuidv4" | "uuid" => Ok(Self::UuidV4),
+            "uuidv7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            _ => Err(eyre!(
+                "Invalid ID_FORMAT \"{}\", expected one of uuidv4, uuidv7, ulid",
+                string
+            )),
+        }
+    }
+}
+
+impl Display for IdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UuidV4 => write!(f, "uuidv4"),
+            Self::UuidV7 => write!(f, "uuidv7"),
+            Self::Ulid => write!(f, "ulid"),
+        }
+    }
+}
+
+impl IdFormat {
+    /// Read `ID_FORMAT`, for the rest of the process duration.
+    pub(crate) fn init() -> color_eyre::Result<Self> {
+        let format = match config::var("ID_FORMAT") {
+            Ok(format) => format.parse()?,
+            Err(_) => Self::default(),
+        };
+        Ok(*ID_FORMAT.get_or_init(|| format))
+    }
+
+    /// The configured format, or the default if not yet initialized.
+    #[cfg_attr(feature = "test", allow(dead_code))]
+    pub(crate) fn current() -> Self {
+        ID_FORMAT.get().copied().unwrap_or_default()
+    }
+
+    /// Generate a new id, both as 128 bits and in its string form.
+    #[cfg_attr(feature = "test", allow(dead_code))]
+    pub(crate) fn generate(self) -> (Uuid, String) {
+        let uuid = match self {
+            Self::UuidV4 => Uuid::new_v4(),
+            Self::UuidV7 | Self::Ulid => time_ordered(self),
+        };
+
+        let string = match self {
+            Self::UuidV4 | Self::UuidV7 => hyphenated(uuid),
+            Self::Ulid => encode_ulid(uuid.as_u128()),
+        };
+
+        (uuid, string)
+    }
+}
+
+#[cfg(feature = "test")]
+thread_local! {
+    static TEST_REQUEST_IDS: std::cell::Cell<u128> = const { std::cell::Cell::new(0) };
+    static TEST_CORRELATION_IDS: std::cell::Cell<u128> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(feature = "test")]
+fn next_in(sequence: &'static std::thread::LocalKey<std::cell::Cell<u128>>) -> Uuid {
+    sequence.with(|next| {
+        next.set(next.get() + 1);
+        Uuid::from_u128(next.get())
+    })
+}
+
+/// The next sequential test request id: `00000000-0000-0000-0000-000000000001`, then `...0002`, etc.
+///
+/// Sequences are per thread, so that tests running in parallel, with in-process test clients, each get
+/// the same ids every run.
+#[cfg(feature = "test")]
+pub(crate) fn next_test_request_id() -> Uuid {
+    next_in(&TEST_REQUEST_IDS)
+}
+
+/// The next sequential test correlation id, which are sequenced separately from request ids.
+#[cfg(feature = "test")]
+pub(crate) fn next_test_correlation_id() -> Uuid {
+    next_in(&TEST_CORRELATION_IDS)
+}
+
+/// Restart this thread's test request and correlation id sequences from `1`.
+#[cfg(feature = "test")]
+pub(crate) fn reset_test_ids() {
+    TEST_REQUEST_IDS.with(|next| next.set(0));
+    TEST_CORRELATION_IDS.with(|next| next.set(0));
+}
+
+/// 48 bits of unix milliseconds, followed by random bits.
+#[cfg_attr(feature = "test", allow(dead_code))]
+fn time_ordered(format: IdFormat) -> Uuid {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+
+    let mut bytes = *Uuid::new_v4().as_bytes();
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+
+    if format == IdFormat::UuidV7 {
+        bytes[6] = (bytes[6] & 0x0f) | 0x70; // Version 7
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+    }
+
+    Uuid::from_bytes(bytes)
+}
+
+pub(crate) fn hyphenated(uuid: Uuid) -> String {
+    let buf = &mut [0; 36];
+    uuid.to_hyphenated().encode_lower(buf).to_string()
+}
+
+#[cfg_attr(feature = "test", allow(dead_code))]
+fn encode_ulid(value: u128) -> String {
+    (0..26)
+        .map(|i| ULID_ALPHABET[((value >> (125 - 5 * i)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Decode a ULID, ignoring case, and with Crockford's substitutions for ambiguous characters.
+pub(crate) fn decode_ulid(string: &str) -> Option<u128> {
+    if string.len() != 26 {
+        return None;
+    }
+
+    let mut value: u128 = 0;
+    for (i, byte) in string.bytes().enumerate() {
+        let digit = match byte.to_ascii_uppercase() {
+            b'I' | b'L' => 1,
+            b'O' => 0,
+            upper => ULID_ALPHABET.iter().position(|&c| c == upper)? as u128,
+        };
+        // The first character only holds 3 bits.
+        if i == 0 && digit > 7 {
+            return None;
+        }
+        value = (value << 5) | digit;
+    }
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_time_ordered_ids() {
+        let (first, _) = IdFormat::UuidV7.generate();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let (second, second_string) = IdFormat::UuidV7.generate();
+
+        assert!(first < second);
+        assert_eq!(second.get_version_num(), 7);
+        assert_eq!(Uuid::parse_str(&second_string).ok(), Some(second));
+    }
+
+    #[test]
+    fn round_trips_ulids() {
+        let (uuid, string) = IdFormat::Ulid.generate();
+
+        assert_eq!(string.len(), 26);
+        assert_eq!(decode_ulid(&string), Some(uuid.as_u128()));
+        assert_eq!(decode_ulid(&string.to_lowercase()), Some(uuid.as_u128()));
+        assert_eq!(
+            decode_ulid("01ARZ3NDEKTSV4RRFFQ69G5FAV").map(encode_ulid),
+            Some("01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string())
+        );
+        assert_eq!(decode_ulid("81ARZ3NDEKTSV4RRFFQ69G5FAV"), None);
+        assert_eq!(decode_ulid("not-a-ulid"), None);
+    }
+}
@@ -1,5 +1,10 @@
 mod correlation_id;
+mod id_format;
 mod request_id;
 
 pub use correlation_id::CorrelationId;
+pub(crate) use id_format::decode_ulid;
+pub use id_format::IdFormat;
+#[cfg(feature = "test")]
+pub(crate) use id_format::{next_test_correlation_id, next_test_request_id, reset_test_ids};
 pub use request_id::RequestId;
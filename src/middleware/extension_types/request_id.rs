@@ -6,8 +6,13 @@ use serde::de::{Error as DeError, Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
+#[cfg(not(feature = "test"))]
+use super::id_format::IdFormat;
+use super::id_format::{decode_ulid, hyphenated};
+
 #[derive(Debug, Clone)]
 pub struct RequestId {
+    #[cfg_attr(not(feature = "honeycomb"), allow(dead_code))]
     id: Uuid,
     string_id: String,
 }
@@ -16,7 +21,8 @@ impl RequestId {
     #[allow(clippy::new_without_default)]
     #[cfg(not(feature = "test"))]
     pub fn new() -> Self {
-        Uuid::new_v4().into()
+        let (id, string_id) = IdFormat::current().generate();
+        Self { id, string_id }
     }
 
     pub fn as_str(&self) -> &str {
@@ -32,27 +38,31 @@ impl RequestId {
 
 impl Display for RequestId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.id)
+        write!(f, "{}", self.string_id)
     }
 }
 
 impl From<Uuid> for RequestId {
     fn from(uuid: Uuid) -> Self {
-        let buf = &mut [0; 36];
-        let human_id = uuid.to_hyphenated().encode_lower(buf);
         Self {
             id: uuid,
-            string_id: human_id.to_string(),
+            string_id: hyphenated(uuid),
         }
     }
 }
 
+/// Accepts a UUID of any version, or a ULID, regardless of the configured [`IdFormat`][].
 impl FromStr for RequestId {
     type Err = uuid::Error;
 
     fn from_str(string: &str) -> Result<Self, uuid::Error> {
+        let id = match Uuid::parse_str(string) {
+            Ok(id) => id,
+            Err(error) => decode_ulid(string).map(Uuid::from_u128).ok_or(error)?,
+        };
+
         Ok(Self {
-            id: Uuid::parse_str(string)?,
+            id,
             string_id: string.to_string(),
         })
     }
@@ -64,7 +74,7 @@ impl<'de> Visitor<'de> for RequestIdVisitor {
     type Value = RequestId;
 
     fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(formatter, "a UUID &str")
+        write!(formatter, "a UUID or ULID &str")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -0,0 +1,437 @@
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use tide::http::proxies::Forwarded;
+use tide::http::Url;
+use tide::{Middleware, Next, Request};
+
+use crate::config;
+
+static TRUSTED_PROXIES: Lazy<TrustedProxies> = Lazy::new(|| {
+    config::var("TRUSTED_PROXIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+});
+
+/// The networks of `TRUSTED_PROXIES`, e.g. `10.0.0.0/8,192.168.1.10`, whose forwarded headers are believed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, String> {
+        string
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("\"{}\" is not an IP address or CIDR range", entry))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// The address of the client which made the current request, from the forwarded headers of `TRUSTED_PROXIES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(IpAddr);
+
+impl ClientIp {
+    pub fn ip(&self) -> IpAddr {
+        self.0
+    }
+}
+
+impl Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An extension trait for reading the address of the current request's client.
+pub trait ClientIpRequestExt {
+    /// The client's address, from the forwarded headers of `TRUSTED_PROXIES`, or else the request's peer.
+    /// `None` if the request has no peer address.
+    fn client_ip(&self) -> Option<IpAddr>;
+}
+
+impl<State> ClientIpRequestExt for Request<State> {
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.ext::<ClientIp>().map(ClientIp::ip)
+    }
+}
+
+/// The client address to log for `req`: its [`ClientIp`][], or else its peer address.
+pub(crate) fn client_ip_or_peer<State>(req: &Request<State>) -> String {
+    match req.ext::<ClientIp>() {
+        Some(client_ip) => client_ip.to_string(),
+        None => req.peer_addr().unwrap_or("(no Peer Address)").to_string(),
+    }
+}
+
+/// The host, and port if any, of a request's `url`: its `Host`, or that forwarded by a trusted proxy,
+/// as the [`ForwardedUrlMiddleware`][] has set it on the url.
+pub(crate) fn url_host(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Parse an address of `Forwarded: for=` or `X-Forwarded-For`, such as `192.0.2.43`, `[2001:db8::17]`,
+/// or either with a port. Obfuscated identifiers such as `unknown` or `_hidden` are `None`.
+pub(crate) fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+/// The request's `Forwarded` header, or else its `X-Forwarded-For`, `X-Forwarded-Proto`, and `X-Forwarded-By` headers,
+/// with every line of each joined in order, since proxies may add a line rather than append to the last.
+fn forwarded_headers<State>(req: &Request<State>) -> Option<Forwarded<'static>> {
+    let joined = |name: &str| {
+        req.header(name).map(|values| {
+            values
+                .iter()
+                .map(|value| value.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    };
+
+    if let Some(header) = joined("Forwarded") {
+        return match Forwarded::parse(&header) {
+            Ok(forwarded) => Some(forwarded.into_owned()),
+            Err(error) => {
+                log::debug!("Invalid forwarded header: {}", error);
+                None
+            }
+        };
+    }
+
+    let mut forwarded = Forwarded::new();
+    let forwarded_for = joined("X-Forwarded-For");
+    let proto = joined("X-Forwarded-Proto");
+    let by = joined("X-Forwarded-By");
+    if forwarded_for.is_none() && proto.is_none() && by.is_none() {
+        return None;
+    }
+
+    for hop in forwarded_for.iter().flat_map(|hops| hops.split(',')) {
+        forwarded.add_for(hop.trim().to_string());
+    }
+    if let Some(proto) = proto {
+        forwarded.set_proto(proto);
+    }
+    if let Some(by) = by {
+        forwarded.set_by(by);
+    }
+    Some(forwarded)
+}
+
+/// Resolve the client from the request's `peer` and its forwarded hops, believing only those added by trusted proxies.
+fn resolve(trusted: &TrustedProxies, peer: IpAddr, forwarded_for: &[&str]) -> IpAddr {
+    let mut client = peer;
+    if !trusted.contains(&client) {
+        return client;
+    }
+
+    // Each proxy appends the address it received the request from, so the nearest hops are last.
+    for hop in forwarded_for.iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+        if !trusted.contains(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Resolve the address of the client which made each request, when it is behind a load balancer or other proxies.
+///
+/// The `Forwarded` header, or else `X-Forwarded-For`, is only believed if the request's peer is one of the
+/// `TRUSTED_PROXIES`, a comma separated list of addresses or CIDR ranges such as `10.0.0.0/8`, and only as far back as
+/// the hops added by trusted proxies, so that clients cannot spoof their address. Without `TRUSTED_PROXIES`, the client
+/// is always the peer.
+///
+/// Handlers can read the client's address with [`req.client_ip()`][ClientIpRequestExt::client_ip], from the prelude,
+/// or as a [`ClientIp`][] request extension, e.g. to rate limit by client. It is logged as `ip` by
+/// [`LogMiddleware`][crate::middleware::LogMiddleware], and, with the `"honeycomb"` feature, traced as `client_ip`.
+///
+/// This is installed by default.
+#[derive(Debug, Default, Clone)]
+pub struct ClientIpMiddleware {
+    _priv: (),
+}
+
+impl ClientIpMiddleware {
+    /// Create a new instance of `ClientIpMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+
+    /// Resolve the client of every request.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if req.ext::<ClientIp>().is_some() {
+            return Ok(next.run(req).await);
+        }
+
+        let peer = req.peer_addr().and_then(parse_hop);
+        if let Some(peer) = peer {
+            let client = match forwarded_headers(&req) {
+                Some(forwarded) => resolve(&TRUSTED_PROXIES, peer, &forwarded.forwarded_for()),
+                None => peer,
+            };
+            req.set_ext(ClientIp(client));
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ClientIpMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// The URL a request was made to, from the scheme and host forwarded by the proxy in front of the service,
+/// as `Forwarded: proto=https;host=example.com`, or `X-Forwarded-Proto` and `X-Forwarded-Host`.
+fn forwarded_url(
+    url: &Url,
+    forwarded: Option<&Forwarded<'_>>,
+    x_forwarded_host: Option<&str>,
+) -> Url {
+    let mut forwarded_url = url.clone();
+
+    // Each proxy appends its value, so of a comma separated list, only the last was set by the nearest, trusted, proxy.
+    // Earlier values may have been sent by the client.
+    let last = |value: &str| {
+        value
+            .rsplit(',')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+
+    if let Some(proto) = forwarded.and_then(Forwarded::proto).map(last) {
+        let proto = proto.to_ascii_lowercase();
+        if proto == "http" || proto == "https" {
+            forwarded_url.set_scheme(&proto).ok();
+        }
+    }
+
+    let host = forwarded
+        .and_then(Forwarded::host)
+        .or(x_forwarded_host)
+        .map(last);
+    if let Some(host) = host.filter(|host| !host.is_empty()) {
+        if let Ok(authority) = Url::parse(&format!("{}://{}", forwarded_url.scheme(), host)) {
+            if forwarded_url.set_host(authority.host_str()).is_ok() {
+                forwarded_url.set_port(authority.port()).ok();
+            }
+        }
+    }
+
+    forwarded_url
+}
+
+/// Rewrite the scheme and host of each request's URL to those the client used, when it is behind a load balancer
+/// or other proxy which terminates TLS or serves the service under another host, so that absolute URLs made from
+/// [`req.url()`][Request::url], such as `Location` headers and [pagination][crate::pagination] links, are correct.
+///
+/// The scheme and host are read from the `Forwarded` header's `proto` and `host`, or else from `X-Forwarded-Proto` and
+/// `X-Forwarded-Host`, only if the request's peer is one of the `TRUSTED_PROXIES`. See [`ClientIpMiddleware`][].
+/// Of a list of values, only the last, added by the nearest proxy, is used.
+///
+/// This is installed by default.
+#[derive(Debug, Default, Clone)]
+pub struct ForwardedUrlMiddleware {
+    _priv: (),
+}
+
+impl ForwardedUrlMiddleware {
+    /// Create a new instance of `ForwardedUrlMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+
+    /// Rewrite the URL of every request from a trusted proxy.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let trusted = req
+            .peer_addr()
+            .and_then(parse_hop)
+            .map(|peer| TRUSTED_PROXIES.contains(&peer))
+            .unwrap_or(false);
+        if !trusted {
+            return Ok(next.run(req).await);
+        }
+
+        let forwarded = forwarded_headers(&req);
+        let x_forwarded_host = req
+            .header("X-Forwarded-Host")
+            .map(|header| header.last().as_str());
+        let url = forwarded_url(req.url(), forwarded.as_ref(), x_forwarded_host);
+        let http_req: &mut tide::http::Request = req.as_mut();
+        *http_req.url_mut() = url;
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ForwardedUrlMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn believes_only_trusted_hops() {
+        let trusted: TrustedProxies = "10.0.0.0/8, 192.168.1.10".parse().unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // An untrusted peer's headers are ignored.
+        assert_eq!(
+            resolve(&trusted, ip("203.0.113.9"), &["198.51.100.1"]),
+            ip("203.0.113.9")
+        );
+
+        // The client is the nearest hop not added by a trusted proxy, even if earlier hops are spoofed.
+        assert_eq!(
+            resolve(
+                &trusted,
+                ip("10.1.2.3"),
+                &["1.1.1.1", "198.51.100.1", "192.168.1.10"]
+            ),
+            ip("198.51.100.1")
+        );
+
+        assert_eq!(
+            resolve(&trusted, ip("10.1.2.3"), &["[2001:db8:cafe::17]:4711"]),
+            ip("2001:db8:cafe::17")
+        );
+
+        // Obfuscated hops can't be looked past.
+        assert_eq!(
+            resolve(&trusted, ip("10.1.2.3"), &["198.51.100.1", "unknown"]),
+            ip("10.1.2.3")
+        );
+
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+    }
+
+    #[async_std::test]
+    async fn sets_the_client_ip() -> tide::Result<()> {
+        use tide::http::{self, Method, Url};
+
+        let mut server = tide::new();
+        server.with(ClientIpMiddleware::new());
+        server.at("/").get(|req: Request<()>| async move {
+            Ok(req.client_ip().map(|ip| ip.to_string()).unwrap_or_default())
+        });
+
+        let mut req = http::Request::new(Method::Get, Url::parse("http://example.com/")?);
+        req.set_peer_addr(Some("203.0.113.9:54321"));
+        req.insert_header("X-Forwarded-For", "198.51.100.1");
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.body_string().await?, "203.0.113.9");
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn joins_every_header_line() {
+        use tide::http::{self, Method};
+
+        let mut req = http::Request::new(Method::Get, Url::parse("http://example.com/").unwrap());
+        req.append_header("X-Forwarded-For", "1.1.1.1, 198.51.100.1");
+        req.append_header("X-Forwarded-For", "10.0.0.2");
+        req.append_header("X-Forwarded-Proto", "http");
+        req.append_header("X-Forwarded-Proto", "https");
+        let req: Request<()> = req.into();
+
+        let forwarded = forwarded_headers(&req).unwrap();
+        assert_eq!(
+            forwarded.forwarded_for(),
+            vec!["1.1.1.1", "198.51.100.1", "10.0.0.2"]
+        );
+        assert_eq!(forwarded.proto(), Some("http, https"));
+
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(
+            resolve(
+                &trusted,
+                "10.1.2.3".parse().unwrap(),
+                &forwarded.forwarded_for()
+            ),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn rewrites_the_url_from_forwarded_headers() {
+        let url = Url::parse("http://10.1.2.3:8080/orders?page=2").unwrap();
+
+        let forwarded =
+            Forwarded::parse("for=198.51.100.1;proto=https;host=shop.example.com").unwrap();
+        assert_eq!(
+            forwarded_url(&url, Some(&forwarded), None).as_str(),
+            "https://shop.example.com/orders?page=2"
+        );
+
+        let mut forwarded = Forwarded::new();
+        forwarded.set_proto("http, https");
+        assert_eq!(
+            forwarded_url(&url, Some(&forwarded), Some("api.example.com:8443")).as_str(),
+            "https://api.example.com:8443/orders?page=2"
+        );
+
+        // Values before the nearest proxy's may be the client's own.
+        assert_eq!(
+            forwarded_url(&url, None, Some("evil.com, real.com")).as_str(),
+            "http://real.com/orders?page=2"
+        );
+
+        // Only HTTP schemes are accepted.
+        let mut forwarded = Forwarded::new();
+        forwarded.set_proto("gopher");
+        assert_eq!(forwarded_url(&url, Some(&forwarded), None), url);
+    }
+}
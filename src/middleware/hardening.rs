@@ -0,0 +1,203 @@
+use tide::http::headers::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HOST, TRANSFER_ENCODING};
+use tide::http::{self, headers::HeaderName};
+use tide::{Middleware, Next, Request};
+
+use super::extension_types::RequestId;
+use super::forwarded::client_ip_or_peer;
+use crate::config;
+use crate::logging::{log_kv, Pii};
+
+/// Headers which must never be sent more than once in a request.
+const SINGLE_VALUE_HEADERS: [HeaderName; 5] = [
+    HOST,
+    CONTENT_LENGTH,
+    TRANSFER_ENCODING,
+    CONTENT_TYPE,
+    AUTHORIZATION,
+];
+
+/// Reject requests which look like request smuggling attempts or are otherwise malformed,
+/// rather than relying on whatever the underlying http implementation happens to do.
+///
+/// Rejected requests get a 400 `JsonError` and a `WARN` security log event.
+///
+/// Rejects requests with:
+/// - Both `Content-Length` and `Transfer-Encoding`.
+/// - A `Transfer-Encoding` other than exactly `chunked`.
+/// - Duplicate `Host`, `Content-Length`, `Transfer-Encoding`, `Content-Type`, or `Authorization` headers.
+/// - More than `MAX_REQUEST_HEADERS` headers (default `100`).
+/// - More than `MAX_REQUEST_HEADER_BYTES` bytes of header names and values (default `16384`).
+#[derive(Debug, Clone)]
+pub struct HardeningMiddleware {
+    max_headers: usize,
+    max_header_bytes: usize,
+}
+
+impl Default for HardeningMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HardeningMiddleware {
+    /// Create a new instance of `HardeningMiddleware`, with limits from the environment.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            max_headers: config::var("MAX_REQUEST_HEADERS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+            max_header_bytes: config::var("MAX_REQUEST_HEADER_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(16 * 1024),
+        }
+    }
+
+    /// Find the first reason to reject a request, if any.
+    fn violation(&self, req: &http::Request) -> Option<String> {
+        let mut header_count = 0;
+        let mut header_bytes = 0;
+        for (name, values) in req.iter() {
+            header_count += values.iter().count();
+            header_bytes += values
+                .iter()
+                .map(|value| name.as_str().len() + value.as_str().len())
+                .sum::<usize>();
+        }
+
+        if header_count > self.max_headers {
+            return Some(format!(
+                "Too many headers: {} (maximum {})",
+                header_count, self.max_headers
+            ));
+        }
+
+        if header_bytes > self.max_header_bytes {
+            return Some(format!(
+                "Headers too large: {} bytes (maximum {})",
+                header_bytes, self.max_header_bytes
+            ));
+        }
+
+        for name in SINGLE_VALUE_HEADERS.iter() {
+            if let Some(values) = req.header(name) {
+                if values.iter().count() > 1 {
+                    return Some(format!("Duplicate {} header", name));
+                }
+            }
+        }
+
+        if let Some(transfer_encoding) = req.header(TRANSFER_ENCODING) {
+            if req.header(CONTENT_LENGTH).is_some() {
+                return Some("Both Content-Length and Transfer-Encoding headers".to_string());
+            }
+
+            if !transfer_encoding
+                .last()
+                .as_str()
+                .trim()
+                .eq_ignore_ascii_case("chunked")
+            {
+                return Some(format!(
+                    "Unsupported Transfer-Encoding: \"{}\"",
+                    transfer_encoding.last()
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Check every request before it reaches the application.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if let Some(reason) = self.violation(req.as_ref()) {
+            log_kv!(
+                warn,
+                {
+                    security_event: "request_rejected",
+                    reason: reason,
+                    method: req.method().as_ref(),
+                    path: req.url().path(),
+                    ip: Pii(client_ip_or_peer(&req)),
+                    request_id: req.ext::<RequestId>().map(|id| id.as_str()).unwrap_or(""),
+                },
+                "Rejected request: {}", reason
+            );
+
+            return Err(tide::Error::from_str(400, reason));
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HardeningMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::Method;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn request() -> http::Request {
+        test_utils::request(Method::Post, "/")
+    }
+
+    #[test]
+    fn allows_normal_requests() {
+        let mut req = request();
+        req.insert_header(CONTENT_LENGTH, "10");
+        req.insert_header(CONTENT_TYPE, "application/json");
+
+        assert_eq!(HardeningMiddleware::new().violation(&req), None);
+    }
+
+    #[test]
+    fn rejects_smuggling() {
+        let middleware = HardeningMiddleware::new();
+
+        let mut req = request();
+        req.insert_header(CONTENT_LENGTH, "10");
+        req.insert_header(TRANSFER_ENCODING, "chunked");
+        assert!(middleware.violation(&req).is_some());
+
+        let mut req = request();
+        req.insert_header(TRANSFER_ENCODING, "chunked, identity");
+        assert!(middleware.violation(&req).is_some());
+
+        let mut req = request();
+        req.append_header(CONTENT_LENGTH, "10");
+        req.append_header(CONTENT_LENGTH, "20");
+        assert!(middleware.violation(&req).is_some());
+    }
+
+    #[test]
+    fn rejects_absurd_headers() {
+        let middleware = HardeningMiddleware {
+            max_headers: 2,
+            max_header_bytes: 64,
+        };
+
+        let mut req = request();
+        req.insert_header("X-A", "a");
+        req.insert_header("X-B", "b");
+        req.insert_header("X-C", "c");
+        assert!(middleware.violation(&req).is_some());
+
+        let mut req = request();
+        req.insert_header("X-A", "a".repeat(100));
+        assert!(middleware.violation(&req).is_some());
+    }
+}
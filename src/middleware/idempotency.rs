@@ -0,0 +1,334 @@
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tide::http::headers::{HeaderName, AUTHORIZATION};
+use tide::http::{self, Method};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::config;
+use crate::idempotency::{self, Claim, StoredResponse};
+use crate::logging::log_kv;
+use crate::middleware::forwarded::{parse_hop, ClientIp};
+use crate::middleware::tenant::TenantId;
+
+/// The request header carrying the client's idempotency key.
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// The response header marking a replayed response.
+const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+/// The longest idempotency key accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// Replay the stored response for `POST` and `PUT` requests with a previously seen `Idempotency-Key`.
+///
+/// See [`preroll::idempotency`][crate::idempotency] for details.
+#[derive(Debug, Clone)]
+pub struct IdempotencyMiddleware {
+    ttl: Duration,
+    lease: Duration,
+}
+
+impl Default for IdempotencyMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdempotencyMiddleware {
+    /// Create a new instance of `IdempotencyMiddleware`, with the TTL and lease from the environment.
+    #[must_use]
+    pub fn new() -> Self {
+        let secs = |name: &str, default: u64| {
+            Duration::from_secs(
+                config::var(name)
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+
+        Self {
+            ttl: secs("IDEMPOTENCY_TTL_SECS", 24 * 60 * 60),
+            lease: secs("IDEMPOTENCY_LEASE_SECS", 60),
+        }
+    }
+
+    /// Claim the request's key, then handle the request and store the response, or replay the stored response.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if !matches!(req.method(), Method::Post | Method::Put) {
+            return Ok(next.run(req).await);
+        }
+
+        let client_key = match req.header(IDEMPOTENCY_KEY) {
+            Some(values) => values.last().as_str().trim().to_string(),
+            None => return Ok(next.run(req).await),
+        };
+        if client_key.is_empty() || client_key.len() > MAX_KEY_LEN {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                format!(
+                    "The {} header must be between 1 and {} characters",
+                    IDEMPOTENCY_KEY, MAX_KEY_LEN
+                ),
+            ));
+        }
+
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+        let fingerprint = fingerprint(req.method(), req.url().path(), &bytes);
+        let mut body = Body::from_bytes(bytes);
+        body.set_mime(mime);
+        req.set_body(body);
+
+        let key = scoped_key(req.as_ref(), &client_key);
+        let store = idempotency::store();
+        let claim = store
+            .claim(&key, &fingerprint, self.lease)
+            .await
+            .map_err(|error| unavailable(&client_key, "claim", error))?;
+
+        match claim {
+            Claim::Claimed => (),
+            Claim::Completed(stored) => return Ok(replay(stored)),
+            Claim::InProgress => {
+                return Err(tide::Error::from_str(
+                    StatusCode::Conflict,
+                    "A request with this Idempotency-Key is still being handled",
+                ))
+            }
+            Claim::Mismatch => {
+                return Err(tide::Error::from_str(
+                    StatusCode::UnprocessableEntity,
+                    "This Idempotency-Key was already used for a different request",
+                ))
+            }
+        }
+
+        let mut res = next.run(req).await;
+
+        // Errors are not stored, so that the request can be retried, nor are responses which cannot be replayed.
+        if res.status().is_server_error() || res.error().is_some() {
+            store
+                .release(&key)
+                .await
+                .map_err(|error| unavailable(&client_key, "release", error))?;
+            return Ok(res);
+        }
+
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let bytes = body.into_bytes().await?;
+
+        let stored = StoredResponse {
+            status: res.status() as u16,
+            headers: res
+                .iter()
+                .flat_map(|(name, values)| {
+                    values
+                        .iter()
+                        .map(move |value| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+            body: bytes,
+        };
+        store
+            .complete(&key, &stored, self.ttl)
+            .await
+            .map_err(|error| unavailable(&client_key, "complete", error))?;
+
+        let mut body = Body::from_bytes(stored.body);
+        body.set_mime(mime);
+        res.set_body(body);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for IdempotencyMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// A digest of what makes requests the same, so that a key reused for a different request is detected.
+fn fingerprint(method: Method, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_ref());
+    hasher.update(b" ");
+    hasher.update(path);
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The key a request's `Idempotency-Key` is stored under: a digest of it and of the caller, so that a key reused by
+/// another caller never replays the first caller's response, such as its cookies.
+///
+/// The caller is the request's tenant, if any, and its `Authorization` header, or else its client address.
+fn scoped_key(req: &http::Request, client_key: &str) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(tenant) = req.ext().get::<TenantId>() {
+        hasher.update(b"tenant:");
+        hasher.update(tenant.as_str());
+        hasher.update(b"\n");
+    }
+
+    match req.header(AUTHORIZATION) {
+        Some(authorization) => {
+            hasher.update(b"authorization:");
+            hasher.update(authorization.to_string());
+        }
+        None => {
+            // Without the peer's port, which differs between connections.
+            let client = match req.ext().get::<ClientIp>() {
+                Some(client_ip) => Some(client_ip.ip()),
+                None => req.peer_addr().and_then(parse_hop),
+            };
+            hasher.update(b"client:");
+            hasher.update(client.map(|ip| ip.to_string()).unwrap_or_default());
+        }
+    }
+    hasher.update(b"\n");
+    hasher.update(client_key);
+    hex::encode(hasher.finalize())
+}
+
+/// Rebuild a stored response.
+fn replay(stored: StoredResponse) -> Response {
+    let status = StatusCode::try_from(stored.status).unwrap_or(StatusCode::Ok);
+    let mut res = Response::new(status);
+    for (name, value) in stored.headers {
+        if let Ok(name) = name.parse::<HeaderName>() {
+            res.append_header(name, value);
+        }
+    }
+    res.insert_header(IDEMPOTENT_REPLAYED, "true");
+
+    let mime = res.content_type();
+    let mut body = Body::from_bytes(stored.body);
+    if let Some(mime) = mime {
+        body.set_mime(mime);
+    }
+    res.set_body(body);
+    res
+}
+
+/// Requests must not be handled without their key being recorded, so a store failure fails the request.
+fn unavailable(key: &str, operation: &str, error: anyhow::Error) -> tide::Error {
+    log_kv!(
+        error,
+        {
+            idempotency_key: key,
+            operation: operation,
+        },
+        "Idempotency store failed: {:?}", error
+    );
+
+    tide::Error::from_str(
+        StatusCode::ServiceUnavailable,
+        "Idempotency keys are unavailable",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tide::http;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn server() -> tide::Server<()> {
+        let mut server = tide::new();
+        server.with(IdempotencyMiddleware::new());
+
+        let count = Arc::new(AtomicUsize::new(0));
+        server.at("/payments").post(move |mut req: Request<()>| {
+            let count = count.clone();
+            async move {
+                let body = req.body_string().await?;
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                Ok(format!("payment {} for {}", n, body))
+            }
+        });
+        server
+    }
+
+    fn request(key: &str, body: &str) -> http::Request {
+        let mut req = test_utils::request(Method::Post, "/payments");
+        req.insert_header(IDEMPOTENCY_KEY, key);
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn replays_responses_by_key() -> tide::Result<()> {
+        let server = server();
+
+        let mut first: http::Response = server.respond(request("a", "100")).await?;
+        assert_eq!(first.status(), StatusCode::Ok);
+        assert_eq!(first.body_string().await?, "payment 0 for 100");
+
+        let mut retry: http::Response = server.respond(request("a", "100")).await?;
+        assert_eq!(retry.body_string().await?, "payment 0 for 100");
+        assert_eq!(
+            retry.header(IDEMPOTENT_REPLAYED).map(|v| v.as_str()),
+            Some("true")
+        );
+
+        let mut other: http::Response = server.respond(request("b", "100")).await?;
+        assert_eq!(other.body_string().await?, "payment 1 for 100");
+
+        let reused: http::Response = server.respond(request("a", "200")).await?;
+        assert_eq!(reused.status(), StatusCode::UnprocessableEntity);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn scopes_keys_by_caller() -> tide::Result<()> {
+        let server = server();
+
+        let mut alice = request("a", "100");
+        alice.insert_header(AUTHORIZATION, "Bearer alice");
+        let mut first: http::Response = server.respond(alice).await?;
+        assert_eq!(first.body_string().await?, "payment 0 for 100");
+
+        let mut bob = request("a", "100");
+        bob.insert_header(AUTHORIZATION, "Bearer bob");
+        let mut other: http::Response = server.respond(bob).await?;
+        assert_eq!(other.body_string().await?, "payment 1 for 100");
+        assert!(other.header(IDEMPOTENT_REPLAYED).is_none());
+
+        let mut anonymous = request("a", "100");
+        anonymous.set_peer_addr(Some("203.0.113.9:4711"));
+        let mut other: http::Response = server.respond(anonymous).await?;
+        assert_eq!(other.body_string().await?, "payment 2 for 100");
+
+        let mut reconnected = request("a", "100");
+        reconnected.set_peer_addr(Some("203.0.113.9:4712"));
+        let mut retry: http::Response = server.respond(reconnected).await?;
+        assert_eq!(retry.body_string().await?, "payment 2 for 100");
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprints_method_path_and_body() {
+        let post = fingerprint(Method::Post, "/payments", b"100");
+        assert_eq!(post, fingerprint(Method::Post, "/payments", b"100"));
+        assert_ne!(post, fingerprint(Method::Put, "/payments", b"100"));
+        assert_ne!(post, fingerprint(Method::Post, "/refunds", b"100"));
+        assert_ne!(post, fingerprint(Method::Post, "/payments", b"200"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use tide::{Middleware, Next, Request};
+
+use crate::di::{Overrides, Scope};
+
+/// Give each request its own scope of [injected][crate::di] dependencies.
+#[derive(Clone, Debug, Default)]
+pub struct InjectMiddleware {
+    overrides: Arc<Overrides>,
+}
+
+impl InjectMiddleware {
+    /// Create a new instance of `InjectMiddleware`, which uses registered constructors.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new instance of `InjectMiddleware`, which prefers `overrides` to registered constructors.
+    #[must_use]
+    pub fn with_overrides(overrides: Overrides) -> Self {
+        Self {
+            overrides: Arc::new(overrides),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InjectMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(Scope::new(self.overrides.clone()));
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::di::{self, InjectRequestExt};
+    use crate::test_utils;
+
+    struct Greeter(&'static str);
+
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted;
+
+    #[async_std::test]
+    async fn injects_request_scoped_instances() -> tide::Result<()> {
+        di::register(|| Greeter("hello"));
+        di::register(|| {
+            CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+            Counted
+        });
+
+        let routes = |server: &mut tide::Server<()>| {
+            server.at("/").get(|req: Request<()>| async move {
+                let first = req.inject::<Counted>()?;
+                let second = req.inject::<Counted>()?;
+                assert!(Arc::ptr_eq(&first, &second));
+                Ok(req.inject::<Greeter>()?.0)
+            });
+            server.at("/missing").get(|req: Request<()>| async move {
+                req.inject::<String>().map(|s| s.to_string())
+            });
+        };
+
+        let mut server = tide::new();
+        server.with(InjectMiddleware::new());
+        routes(&mut server);
+
+        let mut mocked = tide::new();
+        mocked.with(InjectMiddleware::with_overrides(
+            Overrides::new().with(|| Greeter("mocked")),
+        ));
+        routes(&mut mocked);
+
+        let mut res: http::Response = server
+            .respond(test_utils::request(Method::Get, "/"))
+            .await?;
+        assert_eq!(res.body_string().await?, "hello");
+        assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), 1);
+
+        let mut res: http::Response = mocked
+            .respond(test_utils::request(Method::Get, "/"))
+            .await?;
+        assert_eq!(res.body_string().await?, "mocked");
+        assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), 2);
+
+        let res: http::Response = server
+            .respond(test_utils::request(Method::Get, "/missing"))
+            .await?;
+        assert_eq!(res.status(), 500);
+        Ok(())
+    }
+}
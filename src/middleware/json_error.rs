@@ -1,16 +1,38 @@
 use super::extension_types::{CorrelationId, RequestId};
+use crate::client::registry::upstream_error_code;
+use crate::config;
+use crate::i18n::{self, Locale};
+use crate::json;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tide::{Body, Middleware, Next, Request, Result};
+use std::backtrace::BacktraceStatus;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::time::Duration;
+use tide::http::headers::RETRY_AFTER;
+use tide::{Middleware, Next, Request, Response, Result, StatusCode};
 
 #[cfg(feature = "honeycomb")]
 use tracing_honeycomb::TraceId;
 
 #[cfg(feature = "test")]
-use uuid::Uuid;
+use super::extension_types::next_test_correlation_id;
 
 /// Transfrom Errors (`Result::Err`) into JSON responses.
 ///
 /// Special care is taken when handling non-4XX errors to not expose internal error messages.
+///
+/// With the `"postgres"` or `"sqlite"` features, common database errors are not internal server errors:
+/// `RowNotFound` is a `404 Not Found`, a unique violation is a `409 Conflict` with the constraint's name,
+/// a foreign key violation is a `422 Unprocessable Entity`, and a serialization failure or deadlock is a retryable
+/// `503 Service Unavailable`.
+///
+/// `429 Too Many Requests` and `503 Service Unavailable` responses always have a `Retry-After` header, and
+/// `X-RateLimit-Limit`, `X-RateLimit-Remaining`, and `X-RateLimit-Reset` headers if a rate limit is known,
+/// from their [`Backoff`][], or else any `Retry-After` header they have, or else one second.
+/// These are also in the `retry_after_secs` and `rate_limit` fields of their [`JsonError`][].
+///
+/// Titles and messages are translated into the request's [locale][crate::i18n], if the message catalog has a translation.
 #[derive(Debug, Default, Clone)]
 pub struct JsonErrorMiddleware {
     _priv: (),
@@ -18,6 +40,13 @@ pub struct JsonErrorMiddleware {
 
 struct JsonErrorMiddlewareHasBeenRun;
 
+/// Whether 5XX errors include their `causes`, unless `ENVIRONMENT` starts with `prod`.
+static INCLUDE_CAUSES: Lazy<bool> = Lazy::new(|| {
+    !config::var("ENVIRONMENT")
+        .map(|environment| environment.starts_with("prod"))
+        .unwrap_or(false)
+});
+
 /// The structure of an error as formatted by preroll's error handling middleware.
 ///
 /// A service using preroll will always respond with a JSON body in this format if an internal or client error occurs.
@@ -30,6 +59,13 @@ struct JsonErrorMiddlewareHasBeenRun;
 ///   "message": "missing field \"address\"",
 ///   "request_id": "00000000-0000-0000-0000-000000000000"
 ///   "correlation_id": null,
+///   "details": [
+///     {
+///       "pointer": "/items/0/quantity",
+///       "message": "invalid type: string \"two\", expected u32",
+///       "expected": "u32"
+///     }
+///   ]
 /// }
 /// ```
 #[derive(Debug, Deserialize, Serialize)]
@@ -41,10 +77,17 @@ pub struct JsonError {
     pub title: String,
     /// The origin error message for 4XX client errors.
     ///
-    /// In case of an 5XX internal server error, this field will be `"Internal Server Error (correlation_id=00000000-0000-0000-0000-000000000000)"`.
+    /// In case of an 5XX internal server error, this field will be `"Internal Server Error (correlation_id=00000000-0000-0000-0000-000000000001)"`.
     ///
     /// If the original error context is missing, this field will be `"(no additional context)"`.
+    ///
+    /// Translated into the request's [locale][crate::i18n], if the message catalog has a translation.
     pub message: String,
+    /// A machine-readable code for the error, if it has one, such as `upstream_timeout:orders` for a request to the
+    /// [registered upstream][crate::client::registry] `orders` which timed out, or `upstream_error:orders` for one which
+    /// otherwise failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
     /// The UUID v4 assigned to the request, possibly from an incoming header.
     pub request_id: RequestId,
     /// The service-unique UUID v4 assigned to the error response for 5XX internal server errors.
@@ -53,8 +96,260 @@ pub struct JsonError {
     #[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
     /// If the `honeycomb` feature is enabled, this will be the honeycomb trace id associated with this request.
     pub honeycomb_trace_id: Option<String>,
+    /// The specific problems with the request, if known, such as each invalid field of a
+    /// [JSON body][crate::extract::Json]. Omitted if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<JsonErrorDetail>,
+    /// For 5XX internal server errors outside of production, the error and each of its causes,
+    /// followed by its backtrace if one was captured, e.g. with `RUST_LIB_BACKTRACE=1`.
+    ///
+    /// Omitted if empty, and always if `ENVIRONMENT` starts with `prod`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+    /// For `429 Too Many Requests` and `503 Service Unavailable`, the seconds to wait before retrying,
+    /// as in the `Retry-After` header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    /// For `429 Too Many Requests` and `503 Service Unavailable`, the rate limit which was exceeded, if known,
+    /// as in the `X-RateLimit-*` headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// A rate limit, sent as the `X-RateLimit-Limit`, `X-RateLimit-Remaining`, and `X-RateLimit-Reset` headers.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The most requests allowed in the current window.
+    pub limit: u64,
+    /// The requests still allowed in the current window.
+    pub remaining: u64,
+    /// The seconds until the current window resets.
+    pub reset_secs: u64,
+}
+
+/// How long a client should wait before retrying a `429 Too Many Requests` or `503 Service Unavailable`,
+/// and the rate limit it exceeded, if any.
+///
+/// Rate limiters, load shedding, and maintenance modes can return a backoff as an error, with
+/// [`too_many_requests`][Backoff::too_many_requests] or [`unavailable`][Backoff::unavailable],
+/// or insert it as a response extension. Preroll's error handling middleware sends it as headers, and as fields of
+/// the [`JsonError`][].
+///
+/// ## Example:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use preroll::Backoff;
+/// use tide::Request;
+///
+/// # #[allow(dead_code)]
+/// async fn search(_req: Request<()>) -> tide::Result<String> {
+///     let remaining = 0;
+///     if remaining == 0 {
+///         return Err(Backoff::new(Duration::from_secs(30))
+///             .rate_limit(100, remaining, Duration::from_secs(30))
+///             .too_many_requests("Search is limited to 100 requests a minute"));
+///     }
+///     Ok("results".to_string())
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    retry_after: Duration,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Backoff {
+    /// Retry after `retry_after`, which is rounded up to whole seconds.
+    #[must_use]
+    pub fn new(retry_after: Duration) -> Self {
+        Self {
+            retry_after,
+            rate_limit: None,
+        }
+    }
+
+    /// The rate limit which was exceeded: `limit` requests per window, of which `remaining` are left,
+    /// and the time until the window resets.
+    #[must_use]
+    pub fn rate_limit(mut self, limit: u64, remaining: u64, reset: Duration) -> Self {
+        self.rate_limit = Some(RateLimit {
+            limit,
+            remaining,
+            reset_secs: ceil_secs(reset),
+        });
+        self
+    }
+
+    /// A `429 Too Many Requests` error with this backoff.
+    pub fn too_many_requests(self, message: impl Into<String>) -> tide::Error {
+        self.error(StatusCode::TooManyRequests, message.into())
+    }
+
+    /// A `503 Service Unavailable` error with this backoff.
+    pub fn unavailable(self, message: impl Into<String>) -> tide::Error {
+        self.error(StatusCode::ServiceUnavailable, message.into())
+    }
+
+    fn error(self, status: StatusCode, message: String) -> tide::Error {
+        tide::Error::new(
+            status,
+            BackoffError {
+                message,
+                backoff: self,
+            },
+        )
+    }
+
+    fn retry_after_secs(&self) -> u64 {
+        ceil_secs(self.retry_after)
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// The status and message of a database error which is the client's, such as a unique violation,
+/// or is retryable, such as a serialization failure, rather than an internal server error.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn database_error(error: &tide::Error) -> Option<(StatusCode, String)> {
+    let error: &anyhow::Error = error.as_ref();
+    let error = error
+        .chain()
+        .find_map(|error| error.downcast_ref::<sqlx::Error>())?;
+
+    let error = match error {
+        sqlx::Error::RowNotFound => {
+            return Some((StatusCode::NotFound, "Not found".to_string()));
+        }
+        sqlx::Error::Database(error) => error,
+        _ => return None,
+    };
+    let constraint = error
+        .constraint()
+        .map(|constraint| format!(" `{}`", constraint))
+        .unwrap_or_default();
+
+    // Postgres SQLSTATE codes, and SQLite extended result codes.
+    match error.code().as_deref()? {
+        // unique_violation, SQLITE_CONSTRAINT_UNIQUE, and SQLITE_CONSTRAINT_PRIMARYKEY
+        "23505" | "2067" | "1555" => Some((
+            StatusCode::Conflict,
+            format!(
+                "Conflicts with an existing record, by unique constraint{}",
+                constraint
+            ),
+        )),
+        // foreign_key_violation, and SQLITE_CONSTRAINT_FOREIGNKEY
+        "23503" | "787" => Some((
+            StatusCode::UnprocessableEntity,
+            format!("Violates foreign key constraint{}", constraint),
+        )),
+        // serialization_failure, and deadlock_detected
+        "40001" | "40P01" => Some((
+            StatusCode::ServiceUnavailable,
+            "Transaction conflicted with another, and may be retried".to_string(),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+fn database_error(_error: &tide::Error) -> Option<(StatusCode, String)> {
+    None
+}
+
+/// An error with a [`Backoff`][].
+#[derive(Debug)]
+struct BackoffError {
+    message: String,
+    backoff: Backoff,
+}
+
+impl Display for BackoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
+impl Error for BackoffError {}
+
+/// The backoff of a `429` or `503` response: its [`Backoff`][], or else its `Retry-After` seconds, or else one second.
+/// The headers of the backoff are set on the response.
+fn backoff(res: &mut Response) -> Backoff {
+    let backoff = res
+        .ext::<Backoff>()
+        .copied()
+        .or_else(|| {
+            res.error()
+                .and_then(|error| error.downcast_ref::<BackoffError>())
+                .map(|error| error.backoff)
+        })
+        .or_else(|| {
+            res.header(RETRY_AFTER)
+                .and_then(|value| value.last().as_str().trim().parse().ok())
+                .map(|secs| Backoff::new(Duration::from_secs(secs)))
+        })
+        .unwrap_or_else(|| Backoff::new(Duration::from_secs(1)));
+
+    res.insert_header(RETRY_AFTER, backoff.retry_after_secs().to_string());
+    if let Some(rate_limit) = &backoff.rate_limit {
+        res.insert_header("X-RateLimit-Limit", rate_limit.limit.to_string());
+        res.insert_header("X-RateLimit-Remaining", rate_limit.remaining.to_string());
+        res.insert_header("X-RateLimit-Reset", rate_limit.reset_secs.to_string());
+    }
+    backoff
+}
+
+/// A problem with a specific part of a request, such as a field of its JSON body.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct JsonErrorDetail {
+    /// The [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901) to the field, e.g. `/items/0/quantity`,
+    /// or `""` for the whole document.
+    pub pointer: String,
+    /// What is wrong with the field, e.g. `invalid type: string "two", expected u32`.
+    pub message: String,
+    /// The type which was expected, if known, e.g. `u32`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+/// A client error with [details][JsonErrorDetail], which become the `details` of its [`JsonError`][].
+#[derive(Debug)]
+pub(crate) struct DetailedError {
+    message: String,
+    details: Vec<JsonErrorDetail>,
+}
+
+impl DetailedError {
+    /// An error with `status`, `message`, and `details`.
+    pub(crate) fn error(
+        status: StatusCode,
+        message: impl Into<String>,
+        details: Vec<JsonErrorDetail>,
+    ) -> tide::Error {
+        let error = Self {
+            message: message.into(),
+            details,
+        };
+        tide::Error::new(status, error)
+    }
+
+    pub(crate) fn details(&self) -> &[JsonErrorDetail] {
+        &self.details
+    }
+}
+
+impl Display for DetailedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for DetailedError {}
+
 impl JsonErrorMiddleware {
     /// Create a new instance of `JsonErrorMiddleware`.
     #[must_use]
@@ -81,25 +376,70 @@ impl JsonErrorMiddleware {
         #[cfg(feature = "honeycomb")]
         let honeycomb_trace_id = req.ext::<TraceId>().cloned();
 
+        let locale = req.ext::<Locale>().cloned();
+        let translation = |message: &str| {
+            locale
+                .as_ref()
+                .and_then(|locale| i18n::translation(locale, message))
+        };
+        let localize = |message: &str| translation(message).unwrap_or(message).to_string();
+
         let mut res = next.run(req).await;
+
+        // Database errors which are the client's, or retryable, are not internal server errors.
+        let database_message = match res.error().and_then(database_error) {
+            Some((status, message)) => {
+                res.set_status(status);
+                Some(message)
+            }
+            None => None,
+        };
         let status = res.status();
+        let code = res.error().and_then(upstream_error_code);
+
+        let backoff = match status {
+            StatusCode::TooManyRequests | StatusCode::ServiceUnavailable => Some(backoff(&mut res)),
+            _ => None,
+        };
+        let retry_after_secs = backoff.as_ref().map(Backoff::retry_after_secs);
+        let rate_limit = backoff.and_then(|backoff| backoff.rate_limit);
+
+        if status.is_client_error() || status.is_server_error() {
+            if let Some(locale) = locale.as_ref().filter(|locale| i18n::is_localized(locale)) {
+                res.insert_header("Content-Language", locale.as_str());
+            }
+        }
 
         if status.is_server_error() {
+            let causes = match res.error() {
+                Some(error) if *INCLUDE_CAUSES => causes(error),
+                _ => Vec::new(),
+            };
+
             #[cfg(not(feature = "test"))]
             let correlation_id = CorrelationId::new();
             #[cfg(feature = "test")]
-            let correlation_id: CorrelationId = Uuid::nil().into();
+            let correlation_id: CorrelationId = next_test_correlation_id().into();
 
             let body = JsonError {
-                title: status.canonical_reason().to_string(),
-                message: format!("Internal Server Error (correlation_id={})", correlation_id),
+                title: localize(status.canonical_reason()),
+                message: format!(
+                    "{} (correlation_id={})",
+                    localize("Internal Server Error"),
+                    correlation_id
+                ),
                 status: status as u16,
+                code,
                 request_id,
                 correlation_id: Some(correlation_id.to_string()),
                 #[cfg(feature = "honeycomb")]
                 honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                details: Vec::new(),
+                causes,
+                retry_after_secs,
+                rate_limit,
             };
-            res.set_body(Body::from_json(&body)?);
+            res.set_body(json::body(&body)?);
 
             res.insert_header("X-Correlation-Id", correlation_id.as_str());
 
@@ -124,27 +464,46 @@ impl JsonErrorMiddleware {
 
         if status.is_client_error() {
             if let Some(error) = res.error() {
+                let details = error
+                    .downcast_ref::<DetailedError>()
+                    .map(|error| error.details().to_vec())
+                    .unwrap_or_default();
+                let message = match database_message {
+                    Some(message) => message,
+                    None => translation(&error.to_string())
+                        .map_or_else(|| format!("{:?}", error), String::from),
+                };
                 let body = JsonError {
-                    title: status.canonical_reason().to_string(),
-                    message: format!("{:?}", error),
+                    title: localize(status.canonical_reason()),
+                    message,
                     status: status as u16,
+                    code,
                     request_id,
                     correlation_id: None,
                     #[cfg(feature = "honeycomb")]
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                    details,
+                    causes: Vec::new(),
+                    retry_after_secs,
+                    rate_limit,
                 };
-                res.set_body(Body::from_json(&body)?);
+                res.set_body(json::body(&body)?);
             } else {
                 let body = JsonError {
-                    title: status.canonical_reason().to_string(),
-                    message: "(no additional context)".to_string(),
+                    title: localize(status.canonical_reason()),
+                    message: localize("(no additional context)"),
                     status: status as u16,
+                    code,
                     request_id,
                     correlation_id: None,
                     #[cfg(feature = "honeycomb")]
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                    details: Vec::new(),
+                    causes: Vec::new(),
+                    retry_after_secs,
+                    rate_limit,
                 };
-                res.set_body(Body::from_json(&body)?);
+                res.set_body(json::body(&body)?);
             }
 
             return Ok(res);
@@ -154,9 +513,125 @@ impl JsonErrorMiddleware {
     }
 }
 
+/// The error and each of its causes, then each line of its backtrace, if one was captured.
+fn causes(error: &tide::Error) -> Vec<String> {
+    let error: &anyhow::Error = error.as_ref();
+    let mut causes: Vec<String> = error.chain().map(ToString::to_string).collect();
+
+    let backtrace = error.backtrace();
+    if let BacktraceStatus::Captured = backtrace.status() {
+        causes.extend(
+            backtrace
+                .to_string()
+                .lines()
+                .map(|line| line.trim().to_string()),
+        );
+    }
+    causes
+}
+
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for JsonErrorMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
         self.handle(req, next).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::middleware::RequestIdMiddleware;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn includes_causes_of_internal_errors() -> Result<()> {
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.with(JsonErrorMiddleware::new());
+        server.at("/orders").get(|_| async {
+            let error = anyhow::anyhow!("connection refused").context("Failed to load orders");
+            Err::<String, _>(tide::Error::new(StatusCode::InternalServerError, error))
+        });
+
+        let req = test_utils::request(Method::Get, "/orders");
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+
+        let error: JsonError = res.body_json().await?;
+        assert!(error.message.starts_with("Internal Server Error"));
+        assert_eq!(
+            error.causes[..2],
+            ["Failed to load orders", "connection refused"]
+        );
+        Ok(())
+    }
+
+    #[cfg(any(feature = "postgres", feature = "sqlite"))]
+    #[async_std::test]
+    async fn maps_database_errors() -> Result<()> {
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.with(JsonErrorMiddleware::new());
+        server
+            .at("/orders/:id")
+            .get(|_| async { Err::<String, _>(tide::Error::from(sqlx::Error::RowNotFound)) });
+
+        let req = test_utils::request(Method::Get, "/orders/42");
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.message, "Not found");
+        assert_eq!(error.correlation_id, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn sends_backoff_headers() -> Result<()> {
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.with(JsonErrorMiddleware::new());
+        server.at("/search").get(|_| async {
+            Err::<String, _>(
+                Backoff::new(Duration::from_millis(29_500))
+                    .rate_limit(100, 0, Duration::from_secs(30))
+                    .too_many_requests("Search is limited to 100 requests a minute"),
+            )
+        });
+        server.at("/maintenance").get(|_| async {
+            let mut res = Response::new(StatusCode::ServiceUnavailable);
+            res.insert_header(RETRY_AFTER, "120");
+            Ok(res)
+        });
+
+        let get = |path: &str| {
+            let req = test_utils::request(Method::Get, path);
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        let mut res = get("/search").await?;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res[RETRY_AFTER], "30");
+        assert_eq!(res["X-RateLimit-Limit"], "100");
+        assert_eq!(res["X-RateLimit-Remaining"], "0");
+        assert_eq!(res["X-RateLimit-Reset"], "30");
+        let error: JsonError = res.body_json().await?;
+        assert!(error
+            .message
+            .starts_with("Search is limited to 100 requests a minute"));
+        assert_eq!(error.retry_after_secs, Some(30));
+        assert_eq!(
+            error.rate_limit.map(|rate_limit| rate_limit.limit),
+            Some(100)
+        );
+
+        let mut res = get("/maintenance").await?;
+        assert_eq!(res[RETRY_AFTER], "120");
+        assert!(res.header("X-RateLimit-Limit").is_none());
+        let error: JsonError = res.body_json().await?;
+        assert_eq!(error.retry_after_secs, Some(120));
+        Ok(())
+    }
+}
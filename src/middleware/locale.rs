@@ -0,0 +1,70 @@
+use tide::http::headers::ACCEPT_LANGUAGE;
+use tide::{Middleware, Next, Request};
+
+use crate::config;
+use crate::i18n::{self, Locale};
+
+/// Negotiate each request's [`Locale`][] from its `Accept-Language` header. See [`i18n`][crate::i18n].
+#[derive(Clone, Debug)]
+pub struct LocaleMiddleware {
+    supported: Option<Vec<String>>,
+    default: String,
+}
+
+impl Default for LocaleMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocaleMiddleware {
+    /// Create a new instance of `LocaleMiddleware`, with `SUPPORTED_LOCALES` and `DEFAULT_LOCALE` from the environment.
+    #[must_use]
+    pub fn new() -> Self {
+        let supported = config::var("SUPPORTED_LOCALES").ok().map(|locales| {
+            locales
+                .split(',')
+                .map(str::trim)
+                .filter(|locale| !locale.is_empty())
+                .map(String::from)
+                .collect()
+        });
+
+        Self {
+            supported,
+            default: config::var("DEFAULT_LOCALE").unwrap_or_else(|_| "en".to_string()),
+        }
+    }
+
+    /// Set the request's locale.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let accepted = req
+            .header(ACCEPT_LANGUAGE)
+            .map(|values| i18n::parse_accept_language(values.last().as_str()))
+            .unwrap_or_default();
+
+        let locale = match &self.supported {
+            Some(supported) => i18n::negotiate(&accepted, supported).map(String::from),
+            None => {
+                let supported: Vec<String> = i18n::catalog()
+                    .map(|catalog| catalog.locales().map(String::from).collect())
+                    .unwrap_or_default();
+                i18n::negotiate(&accepted, &supported).map(String::from)
+            }
+        };
+
+        req.set_ext(Locale::new(locale.unwrap_or_else(|| self.default.clone())));
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for LocaleMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
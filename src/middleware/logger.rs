@@ -1,4 +1,8 @@
-use kv_log_macro::{error, info, trace, warn};
+use std::num::ParseIntError;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
 use tide::http::headers::{REFERER, USER_AGENT};
 use tide::{Middleware, Next, Request, Result};
 
@@ -6,6 +10,17 @@ use tide::{Middleware, Next, Request, Result};
 use tracing_honeycomb::TraceId;
 
 use super::extension_types::{CorrelationId, RequestId};
+use super::forwarded::client_ip_or_peer;
+use super::tenant::TenantId;
+use crate::builtins::scheduling::record_queue_delay;
+use crate::builtins::shutdown::track_request;
+use crate::builtins::stats::{record_response, ScopedStats};
+use crate::client::registry::failed_upstream;
+use crate::config;
+use crate::logging::{duration_ms, log_kv, LogContext, Pii};
+use crate::rewrite::AppliedRewrites;
+use crate::routes::RouteTemplate;
+use crate::sse::EventStream;
 
 /// Log all outgoing responses.
 #[derive(Debug, Default, Clone)]
@@ -15,6 +30,83 @@ pub struct LogMiddleware {
 
 struct LogMiddlewareHasBeenRun;
 
+/// The log target of access logs, which are written to the `ACCESS_LOG` sink, if it is set.
+pub(crate) const ACCESS_LOG_TARGET: &str = module_path!();
+
+static SLOW_REQUEST_THRESHOLD: Lazy<RwLock<Option<Duration>>> = Lazy::new(|| {
+    RwLock::new(slow_request_threshold().unwrap_or_else(|error| {
+        log::warn!(
+            "Invalid SLOW_REQUEST_MS, slow requests are not logged: {}",
+            error
+        );
+        None
+    }))
+});
+
+fn slow_request_threshold() -> std::result::Result<Option<Duration>, ParseIntError> {
+    config::var("SLOW_REQUEST_MS")
+        .ok()
+        .map(|v| v.parse().map(Duration::from_millis))
+        .transpose()
+}
+
+/// Re-read the `SLOW_REQUEST_MS` threshold.
+pub(crate) fn reload_slow_request_threshold() -> std::result::Result<(), ParseIntError> {
+    let threshold = slow_request_threshold()?;
+    if let Ok(mut current) = SLOW_REQUEST_THRESHOLD.write() {
+        *current = threshold;
+    }
+    Ok(())
+}
+
+static QUIET_PATHS: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(quiet_paths()));
+
+fn quiet_paths() -> Vec<String> {
+    config::var("QUIET_PATHS")
+        .map(|paths| {
+            paths
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Re-read the `QUIET_PATHS` patterns.
+pub(crate) fn reload_quiet_paths() {
+    let paths = quiet_paths();
+    if let Ok(mut current) = QUIET_PATHS.write() {
+        *current = paths;
+    }
+}
+
+/// Whether `path` matches a `QUIET_PATHS` pattern, which is either exact, or a prefix ending in `*`.
+pub(crate) fn is_quiet_path(path: &str) -> bool {
+    QUIET_PATHS
+        .read()
+        .map(|patterns| {
+            patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => path.starts_with(prefix),
+                    None => path == pattern,
+                })
+        })
+        .unwrap_or(false)
+}
+
+/// Whether a response took longer than the `SLOW_REQUEST_MS` threshold, if one is set.
+pub(crate) fn is_slow_request(elapsed: Duration) -> bool {
+    SLOW_REQUEST_THRESHOLD
+        .read()
+        .ok()
+        .and_then(|threshold| *threshold)
+        .map(|threshold| elapsed > threshold)
+        .unwrap_or(false)
+}
+
 impl LogMiddleware {
     /// Create a new instance of `LogMiddleware`.
     #[must_use]
@@ -32,6 +124,8 @@ impl LogMiddleware {
             return Ok(next.run(req).await);
         }
         req.set_ext(LogMiddlewareHasBeenRun);
+        let _in_flight = track_request();
+        let queue_ms = record_queue_delay(&req).map(|delay| delay.as_millis() as u64);
 
         let request_id = req
             .ext::<RequestId>()
@@ -44,120 +138,324 @@ impl LogMiddleware {
         let honeycomb_trace_id = Some("disabled");
 
         let path = req.url().path().to_owned();
+        let rewrites = req
+            .ext::<AppliedRewrites>()
+            .map(|applied| applied.0.join(","));
         let method = req.method();
-        // TODO(Jeremiah): Do we need to check the Forwarded header for the origin IP?
-        let ip = req.peer_addr().unwrap_or("(no Peer Address)").to_string();
-        let referer = req
+        let ip = Pii(client_ip_or_peer(&req));
+        let referer = Pii(req
             .header(REFERER)
             .map(|hvs| hvs.last().as_str())
             .unwrap_or("(no Referer)")
-            .to_string();
+            .to_string());
         let user_agent = req
             .header(USER_AGENT)
             .map(|hvs| hvs.last().as_str())
             .unwrap_or("(no User-Agent)")
             .to_string();
 
-        trace!("Incoming Request", {
-            method: method.as_ref(),
-            path: path,
-            ip: ip,
-            referer: referer,
-            user_agent: user_agent,
-            body_size: req.len(),
-            request_id: request_id,
-        });
+        log_kv!(
+            trace,
+            {
+                method: method.as_ref(),
+                path: path,
+                ip: ip,
+                referer: referer,
+                user_agent: user_agent,
+                body_size: req.len(),
+                request_id: request_id,
+                rewrites: rewrites,
+            },
+            "Incoming Request"
+        );
+
+        let scoped_stats = req.ext::<ScopedStats>().cloned();
+
+        let log_context = LogContext {
+            request_id: request_id.as_str().to_string(),
+            #[cfg(feature = "honeycomb")]
+            honeycomb_trace_id: honeycomb_trace_id.as_ref().map(|v| v.to_string()),
+            #[cfg(not(feature = "honeycomb"))]
+            honeycomb_trace_id: None,
+            tenant_id: None,
+        };
 
         let start = std::time::Instant::now();
-        let res = next.run(req).await;
+        let res = log_context.scope(next.run(req)).await;
+        let elapsed = start.elapsed();
         let status = res.status();
+        let tenant_id = res.ext::<TenantId>().map(|id| id.as_str().to_string());
+        let route = res
+            .ext::<RouteTemplate>()
+            .map(|route| route.as_str().to_string());
 
-        #[cfg(feature = "panic-on-error")]
-        #[allow(clippy::unwrap_used)]
-        if let Some(error) = res.error() {
-            Err::<(), &tide::Error>(error).unwrap();
-        }
+        record_response(
+            scoped_stats.as_ref(),
+            &path,
+            route.as_deref(),
+            status,
+            elapsed,
+        );
+
+        #[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+        let res = report_error(res, method, &path, &request_id);
 
         if let Some(correlation_id) = res.ext::<CorrelationId>() {
             if let Some(error) = res.error() {
-                error!("Internal Error", {
-                    status: status as u16,
-                    method: method.as_ref(),
-                    path: path,
-                    ip: ip,
-                    referer: referer,
-                    user_agent: user_agent,
-                    message: format!("{:?}", error),
-                    error_type: error.type_name(),
-                    correlation_id: correlation_id,
-                    request_id: request_id,
-                    honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
-                });
+                log_kv!(
+                    error,
+                    {
+                        status: status as u16,
+                        upstream: failed_upstream(error),
+                        method: method.as_ref(),
+                        path: path,
+                        route: route,
+                        ip: ip,
+                        referer: referer,
+                        user_agent: user_agent,
+                        message: format!("{:?}", error),
+                        error_type: error.type_name(),
+                        correlation_id: correlation_id,
+                        request_id: request_id,
+                        rewrites: rewrites,
+                        tenant_id: tenant_id,
+                        honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                        duration_ms: duration_ms(elapsed),
+                        queue_ms: queue_ms,
+                    },
+                    "Internal Error"
+                );
+            } else {
+                log_kv!(
+                    error,
+                    {
+                        status: status as u16,
+                        method: method.as_ref(),
+                        path: path,
+                        route: route,
+                        ip: ip,
+                        referer: referer,
+                        user_agent: user_agent,
+                        correlation_id: correlation_id,
+                        request_id: request_id,
+                        rewrites: rewrites,
+                        tenant_id: tenant_id,
+                        honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                        duration_ms: duration_ms(elapsed),
+                        queue_ms: queue_ms,
+                    },
+                    "Internal Error"
+                );
+            }
+        } else if status.is_server_error() {
+            // Programmer error, always expect there to be JsonErrorMiddleware,
+            // which will catch internal server errors first and assign them a correlation id.
+            log::error!(
+                "Internal Error -- JsonErrorMiddleware must be installed after LogMiddleware"
+            );
+        } else if status.is_client_error() {
+            if let Some(error) = res.error() {
+                log_kv!(
+                    warn,
+                    {
+                        status: status as u16,
+                        method: method.as_ref(),
+                        path: path,
+                        route: route,
+                        ip: ip,
+                        referer: referer,
+                        user_agent: user_agent,
+                        message: format!("{:?}", error),
+                        error_type: error.type_name(),
+                        request_id: request_id,
+                        rewrites: rewrites,
+                        tenant_id: tenant_id,
+                        honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                        duration_ms: duration_ms(elapsed),
+                        queue_ms: queue_ms,
+                    },
+                    "Client Error: {}", status.canonical_reason()
+                );
             } else {
-                error!("Internal Error", {
+                log_kv!(
+                    warn,
+                    {
+                        status: status as u16,
+                        method: method.as_ref(),
+                        path: path,
+                        route: route,
+                        ip: ip,
+                        referer: referer,
+                        user_agent: user_agent,
+                        request_id: request_id,
+                        rewrites: rewrites,
+                        tenant_id: tenant_id,
+                        honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
+                        duration_ms: duration_ms(elapsed),
+                        queue_ms: queue_ms,
+                    },
+                    "Client Error: {}", status.canonical_reason()
+                );
+            }
+        } else if is_slow_request(elapsed) && res.ext::<EventStream>().is_none() {
+            log_kv!(
+                warn,
+                {
                     status: status as u16,
                     method: method.as_ref(),
                     path: path,
+                    route: route,
                     ip: ip,
                     referer: referer,
                     user_agent: user_agent,
-                    correlation_id: correlation_id,
+                    body_size: res.len(),
                     request_id: request_id,
+                    rewrites: rewrites,
+                    tenant_id: tenant_id,
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
-                });
-            }
-        } else if status.is_server_error() {
-            // Programmer error, always expect there to be JsonErrorMiddleware,
-            // which will catch internal server errors first and assign them a correlation id.
-            error!("Internal Error -- JsonErrorMiddleware must be installed after LogMiddleware");
-        } else if status.is_client_error() {
-            if let Some(error) = res.error() {
-                warn!("Client Error: {}", status.canonical_reason(), {
+                    duration_ms: duration_ms(elapsed),
+                    queue_ms: queue_ms,
+                    slow_request: true,
+                },
+                "Slow Request: {}", status.canonical_reason()
+            );
+        } else if is_quiet_path(&path) {
+            log_kv!(
+                trace,
+                {
                     status: status as u16,
                     method: method.as_ref(),
                     path: path,
+                    route: route,
                     ip: ip,
                     referer: referer,
                     user_agent: user_agent,
-                    message: format!("{:?}", error),
-                    error_type: error.type_name(),
+                    body_size: res.len(),
                     request_id: request_id,
+                    rewrites: rewrites,
+                    tenant_id: tenant_id,
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
-                });
-            } else {
-                warn!("Client Error: {}", status.canonical_reason(), {
+                    duration_ms: duration_ms(elapsed),
+                    queue_ms: queue_ms,
+                },
+                "{}", status.canonical_reason()
+            );
+        } else {
+            log_kv!(
+                info,
+                {
                     status: status as u16,
                     method: method.as_ref(),
                     path: path,
+                    route: route,
                     ip: ip,
                     referer: referer,
                     user_agent: user_agent,
+                    body_size: res.len(),
                     request_id: request_id,
+                    rewrites: rewrites,
+                    tenant_id: tenant_id,
                     honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                    elapsed: format!("{:?}", start.elapsed()),
-                });
-            }
-        } else {
-            info!("{}", status.canonical_reason(), {
-                status: status as u16,
-                method: method.as_ref(),
-                path: path,
-                ip: ip,
-                referer: referer,
-                user_agent: user_agent,
-                body_size: res.len(),
-                request_id: request_id,
-                honeycomb_trace_id: honeycomb_trace_id.map(|v| v.to_string()),
-                elapsed: format!("{:?}", start.elapsed()),
-            });
+                    duration_ms: duration_ms(elapsed),
+                    queue_ms: queue_ms,
+                },
+                "{}", status.canonical_reason()
+            );
         }
         Ok(res)
     }
 }
 
+/// The color-eyre report of a failed request, which fails the in-process test client's request with `"fail-on-error"`.
+#[cfg(feature = "fail-on-error")]
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorReport(pub(crate) String);
+
+/// Print the color-eyre report of a response's error, with the request's method, path, and request id.
+///
+/// Then panic with `"panic-on-error"`, or else attach the report for the in-process test client to fail with.
+#[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+#[cfg_attr(feature = "panic-on-error", allow(unused_mut))]
+fn report_error(
+    mut res: tide::Response,
+    method: tide::http::Method,
+    path: &str,
+    request_id: &RequestId,
+) -> tide::Response {
+    use std::backtrace::BacktraceStatus;
+
+    use color_eyre::eyre::Report;
+    use color_eyre::{Section, SectionExt};
+
+    let error = match res.error() {
+        Some(error) => error,
+        None => return res,
+    };
+    let summary = format!("{} {} failed with a {}", method, path, res.status());
+
+    // Installed by `preroll::main!`, but not by `test_utils`.
+    color_eyre::install().ok();
+
+    let anyhow_error: &anyhow::Error = error.as_ref();
+    let mut report = Report::new(DetachedError::from_chain(anyhow_error))
+        .wrap_err(summary.clone())
+        .section(request_id.as_str().to_string().header("Request Id:"));
+    let backtrace = anyhow_error.backtrace();
+    if let BacktraceStatus::Captured = backtrace.status() {
+        report = report.section(backtrace.to_string().header("Error Backtrace:"));
+    }
+
+    #[cfg(feature = "panic-on-error")]
+    {
+        eprintln!("{:?}", report);
+        panic!("{}, request id {}: {}", summary, request_id, error);
+    }
+
+    #[cfg(not(feature = "panic-on-error"))]
+    {
+        res.insert_ext(ErrorReport(format!("{:?}", report)));
+        res
+    }
+}
+
+/// An error's message and causes, detached from the error, since a response's error can only be borrowed.
+#[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+#[derive(Debug)]
+struct DetachedError {
+    message: String,
+    source: Option<Box<DetachedError>>,
+}
+
+#[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+impl DetachedError {
+    fn from_chain(error: &anyhow::Error) -> Self {
+        let mut chain = error.chain().rev().map(ToString::to_string);
+        let root = Self {
+            message: chain.next().unwrap_or_default(),
+            source: None,
+        };
+        chain.fold(root, |source, message| Self {
+            message,
+            source: Some(Box::new(source)),
+        })
+    }
+}
+
+#[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+impl std::fmt::Display for DetachedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(any(feature = "panic-on-error", feature = "fail-on-error"))]
+impl std::error::Error for DetachedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let source: &(dyn std::error::Error + 'static) = self.source.as_deref()?;
+        Some(source)
+    }
+}
+
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> Result {
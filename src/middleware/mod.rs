@@ -1,15 +1,36 @@
 use cfg_if::cfg_if;
 
+pub mod cache;
 pub mod clacks;
+pub mod concurrency;
+pub mod csrf;
+pub mod etag;
 pub mod extension_types;
+pub mod forwarded;
+pub mod hardening;
+pub mod inject;
 pub mod json_error;
+pub mod locale;
 pub mod logger;
+pub mod priority;
 pub mod requestid;
+pub mod rewrite;
+pub mod single_flight;
+pub mod snapshot;
+pub mod tenant;
+pub mod webhook;
 
 pub use clacks::ClacksMiddleware;
+pub use concurrency::ConcurrencyLimitMiddleware;
+pub use forwarded::{ClientIpMiddleware, ForwardedUrlMiddleware};
+pub use hardening::HardeningMiddleware;
+pub use inject::InjectMiddleware;
 pub use json_error::JsonErrorMiddleware;
+pub use locale::LocaleMiddleware;
 pub use logger::LogMiddleware;
 pub use requestid::RequestIdMiddleware;
+pub use rewrite::RewriteMiddleware;
+pub use snapshot::SnapshotMiddleware;
 
 cfg_if! {
     if #[cfg(feature = "honeycomb")] {
@@ -30,6 +51,36 @@ cfg_if! {
         pub mod postgres;
 
         #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
-        pub use postgres::{PostgresMiddleware, PostgresRequestExt};
+        pub use postgres::PostgresMiddleware;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "sqlite")] {
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "sqlite")))]
+        pub mod sqlite;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "sqlite")))]
+        pub use sqlite::SqliteMiddleware;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "idempotency")] {
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "idempotency")))]
+        pub mod idempotency;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "idempotency")))]
+        pub use idempotency::IdempotencyMiddleware;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "sessions")] {
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+        pub mod sessions;
+
+        #[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+        pub use sessions::SessionMiddleware;
     }
 }
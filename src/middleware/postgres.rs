@@ -1,2 +1,82 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use sqlx::postgres::PgConnection;
+use sqlx::Connection;
+use tide::Request;
+
 pub use tide_sqlx::postgres::*;
 pub use tide_sqlx::*;
+
+/// An extension trait for [tide::Request][] which runs a closure within a postgres transaction.
+#[tide::utils::async_trait]
+pub trait PostgresTransactionExt {
+    /// Begin a transaction on the request's postgres connection and pass it to `tx_fn`,
+    /// committing if it returns `Ok`, or rolling back if it returns `Err` (including via an early `?`).
+    ///
+    /// The request's connection is already transacting under `PostgresMiddleware`, so this is a savepoint within it.
+    ///
+    /// Errors are returned as `tide::Error`s, and so are rendered as a [`JsonError`][crate::JsonError].
+    ///
+    /// ## Example:
+    ///
+    /// ```no_run
+    /// use preroll::prelude::*;
+    /// use tide::Request;
+    ///
+    /// # #[allow(dead_code)]
+    /// async fn transfer(req: Request<()>) -> tide::Result<String> {
+    ///     let moved = req
+    ///         .pg_transaction(|tx| Box::pin(async move {
+    ///             sqlx::query("UPDATE accounts SET balance = balance - 10 WHERE id = 1")
+    ///                 .execute(&mut *tx)
+    ///                 .await?;
+    ///             sqlx::query("UPDATE accounts SET balance = balance + 10 WHERE id = 2")
+    ///                 .execute(&mut *tx)
+    ///                 .await?;
+    ///             Ok(10)
+    ///         }))
+    ///         .await?;
+    ///
+    ///     Ok(format!("Moved {}", moved))
+    /// }
+    /// ```
+    async fn pg_transaction<T, TxFn>(&self, tx_fn: TxFn) -> tide::Result<T>
+    where
+        T: Send,
+        TxFn: for<'c> FnOnce(
+                &'c mut PgConnection,
+            )
+                -> Pin<Box<dyn Future<Output = tide::Result<T>> + Send + 'c>>
+            + Send;
+}
+
+#[tide::utils::async_trait]
+impl<State: Send + Sync + 'static> PostgresTransactionExt for Request<State> {
+    async fn pg_transaction<T, TxFn>(&self, tx_fn: TxFn) -> tide::Result<T>
+    where
+        T: Send,
+        TxFn: for<'c> FnOnce(
+                &'c mut PgConnection,
+            )
+                -> Pin<Box<dyn Future<Output = tide::Result<T>> + Send + 'c>>
+            + Send,
+    {
+        let mut pg_conn = self.pg_conn().await;
+        let conn: &mut PgConnection = &mut pg_conn;
+        let mut tx = conn.begin().await?;
+
+        match tx_fn(&mut tx).await {
+            Ok(value) => {
+                tx.commit().await?;
+                Ok(value)
+            }
+            Err(error) => {
+                if let Err(rollback_error) = tx.rollback().await {
+                    log::warn!("Transaction rollback failed: {}", rollback_error);
+                }
+                Err(error)
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use serde::Serialize;
+use tide::{Middleware, Next, Request};
+
+use super::concurrency::{pressure, shed_response};
+use crate::config;
+use crate::logging::log_kv;
+
+static TAGGED: AtomicBool = AtomicBool::new(false);
+static SHED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// The priority of a route, which decides how early its requests are shed under load. See [`PriorityMiddleware`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Shed once other requests use `SHED_LOW_PERCENT` (default `50`) of `MAX_INFLIGHT`.
+    Low,
+    /// Shed once other requests use `SHED_NORMAL_PERCENT` (default `80`) of `MAX_INFLIGHT`.
+    Normal,
+    /// Shed once other requests use `SHED_HIGH_PERCENT` (default `100`) of `MAX_INFLIGHT`, i.e. once requests are queued.
+    High,
+    /// Never shed by priority, such as for critical writes.
+    Critical,
+}
+
+impl Priority {
+    fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Critical => "critical",
+        }
+    }
+
+    /// The pressure at which requests of this priority are shed, if they ever are.
+    fn threshold(self) -> Option<f64> {
+        let (var, default) = match self {
+            Priority::Low => ("SHED_LOW_PERCENT", 50),
+            Priority::Normal => ("SHED_NORMAL_PERCENT", 80),
+            Priority::High => ("SHED_HIGH_PERCENT", 100),
+            Priority::Critical => return None,
+        };
+        let percent: u32 = config::var(var)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default);
+        Some(f64::from(percent) / 100.0)
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Tag routes with a [`Priority`][], so that under load low-priority routes are shed first, keeping capacity free
+/// for higher-priority routes.
+///
+/// Load is measured as the fraction of the global `MAX_INFLIGHT` [concurrency limit][crate::ConcurrencyLimitMiddleware]
+/// used by other requests, including queued requests. Without `MAX_INFLIGHT`, nothing is shed by priority.
+/// Routes without a priority are only subject to the concurrency limits, and `/monitor/` routes to neither.
+///
+/// Shed requests are rejected with a `503 Service Unavailable` `JsonError` and a `Retry-After: 1` header, logged at `WARN`,
+/// and counted by priority under `"shedding"` in `/monitor/status`, along with the current load as `"pressure"`.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::{Priority, PriorityMiddleware};
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server
+///         .at("recommendations")
+///         .with(PriorityMiddleware::new(Priority::Low))
+///         .get(|_| async { Ok("nice to have") });
+///     server
+///         .at("orders")
+///         .with(PriorityMiddleware::new(Priority::Critical))
+///         .post(|_| async { Ok("must work") });
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct PriorityMiddleware {
+    priority: Priority,
+    threshold: Option<f64>,
+}
+
+impl PriorityMiddleware {
+    /// Create a new instance of `PriorityMiddleware`, with thresholds from the environment.
+    #[must_use]
+    pub fn new(priority: Priority) -> Self {
+        TAGGED.store(true, Ordering::Relaxed);
+        Self {
+            priority,
+            threshold: priority.threshold(),
+        }
+    }
+
+    /// Handle the request, unless the load is at or above the priority's threshold.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return Ok(next.run(req).await),
+        };
+
+        let pressure = pressure();
+        if pressure < threshold {
+            return Ok(next.run(req).await);
+        }
+
+        SHED[self.priority as usize].fetch_add(1, Ordering::Relaxed);
+        log_kv!(
+            warn,
+            {
+                priority: self.priority.as_str(),
+                pressure: pressure,
+                method: req.method().as_ref(),
+                path: req.url().path(),
+            },
+            "Shed {} priority request under load (pressure {:.2})", self.priority, pressure
+        );
+
+        Ok(shed_response(format!(
+            "Shedding {} priority requests under load",
+            self.priority
+        )))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for PriorityMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// Load shedding by priority, reported under `"shedding"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+pub struct SheddingStats {
+    /// The fraction of `MAX_INFLIGHT` in use.
+    pressure: f64,
+    /// Requests shed, by priority.
+    shed: ShedCounts,
+}
+
+#[derive(Debug, Serialize)]
+struct ShedCounts {
+    low: u64,
+    normal: u64,
+    high: u64,
+}
+
+/// The shedding stats, if any routes have a priority.
+pub(crate) fn shedding_stats() -> Option<SheddingStats> {
+    if !TAGGED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    Some(SheddingStats {
+        pressure: pressure(),
+        shed: ShedCounts {
+            low: SHED[Priority::Low as usize].load(Ordering::Relaxed),
+            normal: SHED[Priority::Normal as usize].load(Ordering::Relaxed),
+            high: SHED[Priority::High as usize].load(Ordering::Relaxed),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tide::http::{self, Method};
+    use tide::StatusCode;
+
+    use super::*;
+    use crate::test_utils;
+    use crate::ConcurrencyLimitMiddleware;
+
+    #[async_std::test]
+    async fn sheds_low_priority_routes_first() -> tide::Result<()> {
+        let mut server = tide::new();
+        server.with(ConcurrencyLimitMiddleware::route("global", 2));
+        server
+            .at("/slow")
+            .with(PriorityMiddleware::new(Priority::Critical))
+            .get(|_| async {
+                async_std::task::sleep(Duration::from_millis(200)).await;
+                Ok("slow")
+            });
+        server
+            .at("/low")
+            .with(PriorityMiddleware::new(Priority::Low))
+            .get(|_| async { Ok("low") });
+        server
+            .at("/critical")
+            .with(PriorityMiddleware::new(Priority::Critical))
+            .get(|_| async { Ok("critical") });
+
+        let get = |path: &str| {
+            let req = test_utils::request(Method::Get, path);
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        let res = get("low").await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let slow = async_std::task::spawn(get("slow"));
+        async_std::task::sleep(Duration::from_millis(50)).await;
+
+        let res = get("low").await?;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        let res = get("critical").await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let stats = serde_json::to_value(shedding_stats())?;
+        assert_eq!(stats["shed"]["low"], 1);
+
+        assert_eq!(slow.await?.status(), StatusCode::Ok);
+        Ok(())
+    }
+}
@@ -1,8 +1,7 @@
 use tide::{Middleware, Next, Request};
 
 #[cfg(feature = "test")]
-use uuid::Uuid;
-
+use super::extension_types::next_test_request_id;
 use super::extension_types::RequestId;
 
 /// Attach a RequestId UUID to every request.
@@ -43,7 +42,7 @@ impl RequestIdMiddleware {
         }
         #[cfg(feature = "test")]
         {
-            request_id = Uuid::nil().into()
+            request_id = next_test_request_id().into()
         }
 
         req.set_ext(request_id.clone());
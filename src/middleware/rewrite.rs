@@ -0,0 +1,40 @@
+use tide::{Middleware, Next, Request};
+
+use crate::rewrite;
+
+/// Apply the rewrites registered with [`preroll::rewrite::register`][crate::rewrite::register], before routing.
+///
+/// Must be installed on the outermost server, which nests the routed server, so that routing sees the rewritten request.
+#[derive(Debug, Default, Clone)]
+pub struct RewriteMiddleware {
+    _priv: (),
+}
+
+impl RewriteMiddleware {
+    /// Create a new instance of `RewriteMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+
+    /// Rewrite every request, and record which rewrites applied.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let applied = rewrite::apply(req.as_mut());
+        if !applied.0.is_empty() {
+            req.set_ext(applied);
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RewriteMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
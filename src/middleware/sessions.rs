@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+use tide::http::headers::SET_COOKIE;
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::config;
+use crate::logging::log_kv;
+use crate::sessions::{self, Session, SessionState};
+use crate::utils::{hmac_sha256_hex, random_token, request_cookie, verify_hmac_sha256_hex};
+
+/// Load each request's session from the session store, and store it once changed.
+///
+/// See [`preroll::sessions`][crate::sessions] for details.
+#[derive(Clone)]
+pub struct SessionMiddleware {
+    cookie_name: String,
+    secret: Vec<u8>,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl std::fmt::Debug for SessionMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionMiddleware")
+            .field("cookie_name", &self.cookie_name)
+            .field("ttl", &self.ttl)
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+impl SessionMiddleware {
+    /// Create a new instance of `SessionMiddleware`, configured by `SESSION_COOKIE`, `SESSION_SECRET`, and `SESSION_TTL_SECS`.
+    #[must_use]
+    pub fn new() -> Self {
+        let secret = match config::var("SESSION_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                log::warn!("SESSION_SECRET is not set, sessions will not outlive this process.");
+                random_token().into_bytes()
+            }
+        };
+
+        Self {
+            cookie_name: config::var("SESSION_COOKIE")
+                .unwrap_or_else(|_| "preroll_session".to_string()),
+            secret,
+            ttl: Duration::from_secs(
+                config::var("SESSION_TTL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(86400),
+            ),
+            secure: config::var("ENVIRONMENT")
+                .map(|environment| environment.starts_with("prod"))
+                .unwrap_or(false),
+        }
+    }
+
+    /// The session id of a signed cookie value, if its signature is valid.
+    fn verify(&self, cookie: &str) -> Option<String> {
+        let (id, signature) = cookie.rsplit_once('.')?;
+        verify_hmac_sha256_hex(&self.secret, id.as_bytes(), signature).then(|| id.to_string())
+    }
+
+    /// A `Set-Cookie` header value for the session `id`, or which expires the cookie if `None`.
+    fn cookie(&self, id: Option<&str>) -> String {
+        let (value, max_age) = match id {
+            Some(id) => (
+                format!("{}.{}", id, hmac_sha256_hex(&self.secret, id.as_bytes())),
+                self.ttl.as_secs(),
+            ),
+            None => (String::new(), 0),
+        };
+
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+            self.cookie_name, value, max_age
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    /// Load the request's session, and store it if the handler changed it.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let store = sessions::store();
+
+        let mut state = SessionState::default();
+        if let Some(id) = request_cookie(&req, &self.cookie_name).and_then(|c| self.verify(&c)) {
+            match store.load(&id).await {
+                Ok(Some(data)) => {
+                    state.id = Some(id);
+                    state.data = data;
+                }
+                Ok(None) => (),
+                Err(error) => return Err(store_failed("load", error)),
+            }
+        }
+
+        let session = Session::new(state);
+        req.set_ext(session.clone());
+        let mut res = next.run(req).await;
+
+        let (id, data, changed, regenerate, destroyed) = {
+            let mut state = session.state();
+            (
+                state.id.take(),
+                std::mem::take(&mut state.data),
+                state.changed,
+                state.regenerate,
+                state.destroyed,
+            )
+        };
+
+        if destroyed {
+            if let Some(id) = id {
+                if let Err(error) = store.destroy(&id).await {
+                    return Err(store_failed("destroy", error));
+                }
+                res.append_header(SET_COOKIE, self.cookie(None));
+            }
+            return Ok(res);
+        }
+
+        if !changed {
+            return Ok(res);
+        }
+
+        let id = match id {
+            Some(id) if !regenerate => id,
+            previous => {
+                if let Some(previous) = previous {
+                    if let Err(error) = store.destroy(&previous).await {
+                        return Err(store_failed("destroy", error));
+                    }
+                }
+                random_token()
+            }
+        };
+        if let Err(error) = store.store(&id, &data, self.ttl).await {
+            return Err(store_failed("store", error));
+        }
+
+        res.append_header(SET_COOKIE, self.cookie(Some(&id)));
+        Ok(res)
+    }
+}
+
+impl Default for SessionMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SessionMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+fn store_failed(operation: &str, error: anyhow::Error) -> tide::Error {
+    log_kv!(
+        error,
+        { operation: operation },
+        "Session store failed: {:?}", error
+    );
+    tide::Error::from_str(StatusCode::ServiceUnavailable, "Session store unavailable")
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, headers::COOKIE, Method};
+
+    use super::*;
+    use crate::sessions::SessionRequestExt;
+    use crate::test_utils;
+
+    fn request(method: Method, path: &str, cookie: Option<&str>) -> http::Request {
+        let mut req = test_utils::request(method, path);
+        if let Some(cookie) = cookie {
+            req.insert_header(COOKIE, cookie);
+        }
+        req
+    }
+
+    fn session_cookie(res: &http::Response) -> Option<String> {
+        let set_cookie = res.header(SET_COOKIE)?.last().as_str();
+        set_cookie.split(';').next().map(str::to_string)
+    }
+
+    #[async_std::test]
+    async fn stores_changed_sessions() -> tide::Result<()> {
+        let mut server = tide::new();
+        server.with(SessionMiddleware::new());
+        server.at("/login").post(|req: Request<()>| async move {
+            req.session().regenerate();
+            req.session().insert("user_id", 42)?;
+            Ok("")
+        });
+        server.at("/me").get(|req: Request<()>| async move {
+            Ok(format!("{:?}", req.session().get::<u64>("user_id")))
+        });
+        server.at("/logout").post(|req: Request<()>| async move {
+            req.session().destroy();
+            Ok("")
+        });
+
+        let res: http::Response = server.respond(request(Method::Get, "/me", None)).await?;
+        assert!(res.header(SET_COOKIE).is_none());
+
+        let res: http::Response = server
+            .respond(request(Method::Post, "/login", None))
+            .await?;
+        let cookie = session_cookie(&res).unwrap_or_default();
+        assert!(cookie.starts_with("preroll_session="));
+
+        let mut res: http::Response = server
+            .respond(request(Method::Get, "/me", Some(&cookie)))
+            .await?;
+        assert_eq!(res.body_string().await?, "Some(42)");
+        assert!(res.header(SET_COOKIE).is_none());
+
+        // Tampered cookies are ignored.
+        let forged = format!("{}0", cookie);
+        let mut res: http::Response = server
+            .respond(request(Method::Get, "/me", Some(&forged)))
+            .await?;
+        assert_eq!(res.body_string().await?, "None");
+
+        let res: http::Response = server
+            .respond(request(Method::Post, "/logout", Some(&cookie)))
+            .await?;
+        assert_eq!(session_cookie(&res).as_deref(), Some("preroll_session="));
+        let mut res: http::Response = server
+            .respond(request(Method::Get, "/me", Some(&cookie)))
+            .await?;
+        assert_eq!(res.body_string().await?, "None");
+        Ok(())
+    }
+}
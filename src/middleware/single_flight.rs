@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_std::channel::{self, Receiver, Sender};
+use tide::http::headers::{HeaderName, HeaderValues, ACCEPT, AUTHORIZATION, COOKIE};
+use tide::http::{Method, Mime};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::middleware::forwarded::url_host;
+
+type Flights = Arc<Mutex<HashMap<String, Arc<Flight>>>>;
+
+/// Coalesce concurrent identical `GET` requests into a single run of the handler, sharing its response.
+///
+/// Requests are identical if they have the same host, path, query, `Authorization` and `Cookie` headers,
+/// and `Accept` header.
+/// While one such request is being handled, any others wait for it and receive a copy of its response,
+/// which protects expensive read endpoints from thundering herds, such as when a cache expires.
+///
+/// Only successful responses are shared. If the first request fails, each waiting request is handled itself.
+/// Nothing is cached: a request arriving after the response is complete is handled again.
+///
+/// This is not installed by default. Add it to specific routes, or to the whole server in `custom_setup`.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::SingleFlightMiddleware;
+/// use tide::Route;
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server
+///         .at("catalog")
+///         .with(SingleFlightMiddleware::new())
+///         .get(|_| async { Ok("an expensive catalog") });
+/// }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct SingleFlightMiddleware {
+    flights: Flights,
+}
+
+/// A request being handled, which identical requests wait on.
+#[derive(Debug)]
+struct Flight {
+    /// Closed once the response is available, or the request has failed.
+    done: Receiver<()>,
+    response: Mutex<Option<SharedResponse>>,
+}
+
+/// A buffered copy of a successful response.
+#[derive(Clone, Debug)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValues)>,
+    mime: Mime,
+    body: Vec<u8>,
+}
+
+impl SharedResponse {
+    fn to_response(&self) -> Response {
+        let mut res = Response::new(self.status);
+        for (name, values) in self.headers.iter() {
+            res.insert_header(name, values);
+        }
+
+        let mut body = Body::from_bytes(self.body.clone());
+        body.set_mime(self.mime.clone());
+        res.set_body(body);
+        res
+    }
+}
+
+/// Ends a flight when the first request completes, or is dropped, so that waiting requests never wait forever.
+struct FlightGuard {
+    flights: Flights,
+    key: String,
+    flight: Arc<Flight>,
+    _done: Sender<()>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        // Removed before the channel closes, so that later requests start a new flight rather than joining this one.
+        if let Ok(mut flights) = self.flights.lock() {
+            if flights
+                .get(&self.key)
+                .is_some_and(|flight| Arc::ptr_eq(flight, &self.flight))
+            {
+                flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl SingleFlightMiddleware {
+    /// Create a new instance of `SingleFlightMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the first of any identical requests, and have the rest wait for its response.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Get {
+            return Ok(next.run(req).await);
+        }
+
+        let key = flight_key(&req);
+        let (flight, guard) = {
+            let mut flights = self
+                .flights
+                .lock()
+                .map_err(|_| tide::Error::from_str(500, "Single-flight lock poisoned"))?;
+
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), None),
+                None => {
+                    // Nothing is ever sent: closing the channel is the signal, which wakes every waiting request.
+                    let (sender, receiver) = channel::bounded(1);
+                    let flight = Arc::new(Flight {
+                        done: receiver,
+                        response: Mutex::new(None),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+
+                    let guard = FlightGuard {
+                        flights: self.flights.clone(),
+                        key,
+                        flight: flight.clone(),
+                        _done: sender,
+                    };
+                    (flight, Some(guard))
+                }
+            }
+        };
+
+        if guard.is_none() {
+            flight.done.recv().await.ok();
+
+            let shared = flight
+                .response
+                .lock()
+                .ok()
+                .and_then(|response| response.clone());
+            return match shared {
+                Some(shared) => Ok(shared.to_response()),
+                None => Ok(next.run(req).await),
+            };
+        }
+
+        let mut res = next.run(req).await;
+        if res.status().is_success() && res.error().is_none() {
+            let body = res.take_body();
+            let mime = body.mime().clone();
+            let bytes = body.into_bytes().await?;
+
+            let mut body = Body::from_bytes(bytes.clone());
+            body.set_mime(mime.clone());
+            res.set_body(body);
+
+            let shared = SharedResponse {
+                status: res.status(),
+                headers: res
+                    .iter()
+                    .map(|(name, values)| (name.clone(), values.clone()))
+                    .collect(),
+                mime,
+                body: bytes,
+            };
+
+            if let Ok(mut response) = flight.response.lock() {
+                *response = Some(shared);
+            }
+        }
+
+        drop(guard);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SingleFlightMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// What makes requests identical: their host, path and query, credentials, and accepted content types.
+fn flight_key<State>(req: &Request<State>) -> String {
+    let header = |name| {
+        req.header(name)
+            .map(|values| values.to_string())
+            .unwrap_or_default()
+    };
+
+    let url = req.url();
+    format!(
+        "{}{}?{}\n{}\n{}\n{}",
+        url_host(url),
+        url.path(),
+        url.query().unwrap_or(""),
+        header(AUTHORIZATION),
+        header(COOKIE),
+        header(ACCEPT)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_std::task;
+    use tide::http;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn request(url: &str) -> http::Request {
+        test_utils::request(Method::Get, url)
+    }
+
+    #[async_std::test]
+    async fn coalesces_concurrent_identical_requests() -> tide::Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let mut server = tide::new();
+        server.with(SingleFlightMiddleware::new());
+        let handler_count = count.clone();
+        server.at("/catalog").get(move |_| {
+            let count = handler_count.clone();
+            async move {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                task::sleep(Duration::from_millis(50)).await;
+                Ok(format!("catalog {}", n))
+            }
+        });
+
+        let handles: Vec<_> = (0..5_usize)
+            .map(|_| {
+                let server = server.clone();
+                task::spawn(async move {
+                    let mut res: http::Response =
+                        server.respond(request("/catalog?page=1")).await?;
+                    res.body_string().await
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.await?, "catalog 0");
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Not cached once complete, and keyed by query.
+        let mut res: http::Response = server.respond(request("/catalog?page=1")).await?;
+        assert_eq!(res.body_string().await?, "catalog 1");
+        let mut res: http::Response = server.respond(request("/catalog?page=2")).await?;
+        assert_eq!(res.body_string().await?, "catalog 2");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn does_not_coalesce_requests_to_other_hosts() -> tide::Result<()> {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let mut server = tide::new();
+        server.with(SingleFlightMiddleware::new());
+        let handler_count = count.clone();
+        server.at("/catalog").get(move |req: Request<()>| {
+            let count = handler_count.clone();
+            async move {
+                count.fetch_add(1, Ordering::SeqCst);
+                task::sleep(Duration::from_millis(50)).await;
+                Ok(format!(
+                    "catalog of {}",
+                    req.url().host_str().unwrap_or_default()
+                ))
+            }
+        });
+
+        let handles: Vec<_> = ["http://a.example", "http://b.example"]
+            .iter()
+            .map(|origin| {
+                let server = server.clone();
+                task::spawn(async move {
+                    let mut res: http::Response = server
+                        .respond(request(&format!("{}/catalog", origin)))
+                        .await?;
+                    res.body_string().await
+                })
+            })
+            .collect();
+
+        let mut bodies = Vec::new();
+        for handle in handles {
+            bodies.push(handle.await?);
+        }
+        assert_eq!(bodies, ["catalog of a.example", "catalog of b.example"]);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use tide::{Middleware, Next, Request};
+
+use crate::snapshot::Snapshot;
+
+/// Take a [`Snapshot`][] of shared state at the start of every request.
+///
+/// See [`preroll::snapshot`][crate::snapshot].
+#[derive(Debug, Default, Clone)]
+pub struct SnapshotMiddleware {
+    _priv: (),
+}
+
+impl SnapshotMiddleware {
+    /// Create a new instance of `SnapshotMiddleware`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self { _priv: () }
+    }
+
+    /// Capture the snapshot into a request extension.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        req.set_ext(Snapshot::capture());
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SnapshotMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::{self, Debug};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use async_std::sync::{Mutex, MutexGuard};
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnection, SqlitePool};
+use sqlx::{Sqlite, Transaction};
+use tide::http::Method;
+use tide::{Middleware, Next, Request, StatusCode};
+
+/// A request's SQLite connection: a transaction, or a plain pooled connection for `GET` and `HEAD` requests.
+///
+/// Unlike postgres connections, SQLite connections cannot be shared between threads,
+/// so this is held behind a `Mutex` rather than tide-sqlx's `RwLock`.
+pub enum SqliteConnectionInner {
+    Transacting(Transaction<'static, Sqlite>),
+    Plain(PoolConnection<Sqlite>),
+}
+
+impl Debug for SqliteConnectionInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transacting(_) => f.write_str("SqliteConnectionInner::Transacting"),
+            Self::Plain(_) => f.write_str("SqliteConnectionInner::Plain"),
+        }
+    }
+}
+
+impl Deref for SqliteConnectionInner {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Transacting(tx) => tx,
+            Self::Plain(conn) => conn,
+        }
+    }
+}
+
+impl DerefMut for SqliteConnectionInner {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Transacting(tx) => tx,
+            Self::Plain(conn) => conn,
+        }
+    }
+}
+
+/// The request extension holding a request's [`SqliteConnectionInner`][].
+pub type SqliteConnectionWrap = Arc<Mutex<SqliteConnectionInner>>;
+
+/// Middleware providing a SQLite connection, or transaction, to each request via [`SqliteRequestExt`].
+///
+/// As with `PostgresMiddleware`, requests other than `GET` and `HEAD` run within a transaction,
+/// which is committed unless the response has an error.
+#[derive(Debug, Clone)]
+pub struct SqliteMiddleware {
+    pool: SqlitePool,
+}
+
+impl From<SqlitePool> for SqliteMiddleware {
+    fn from(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+impl AsRef<SqlitePool> for SqliteMiddleware {
+    fn as_ref(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SqliteMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Run once, or use the test transaction of `create_client_and_sqlite`.
+        if req.ext::<SqliteConnectionWrap>().is_some() {
+            return Ok(next.run(req).await);
+        }
+
+        let inner = if matches!(req.method(), Method::Get | Method::Head) {
+            SqliteConnectionInner::Plain(self.pool.acquire().await?)
+        } else {
+            SqliteConnectionInner::Transacting(self.pool.begin().await?)
+        };
+        let conn_wrap = Arc::new(Mutex::new(inner));
+        req.set_ext(conn_wrap.clone());
+
+        let res = next.run(req).await;
+
+        if res.error().is_none() {
+            // The transaction is rolled back on drop otherwise.
+            match Arc::try_unwrap(conn_wrap) {
+                Ok(inner) => {
+                    if let SqliteConnectionInner::Transacting(tx) = inner.into_inner() {
+                        tx.commit().await?;
+                    }
+                }
+                Err(_) => {
+                    return Err(tide::Error::from_str(
+                        StatusCode::InternalServerError,
+                        "The SQLite connection was kept past the request, so its transaction could not be committed",
+                    ));
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// An extension trait for [tide::Request][] which does proper unwrapping of the SQLite connection from [`SqliteMiddleware`][].
+///
+/// The same shape as `PostgresRequestExt`, so that handlers can be written against either for local development.
+#[tide::utils::async_trait]
+pub trait SqliteRequestExt {
+    /// Get the request's SQLite connection.
+    ///
+    /// The `MutexGuard` should be dropped before other calls which use the connection, such as `next.run(req)`.
+    async fn sqlite_conn<'req>(&'req self) -> MutexGuard<'req, SqliteConnectionInner>;
+}
+
+#[tide::utils::async_trait]
+impl<T: Send + Sync + 'static> SqliteRequestExt for Request<T> {
+    async fn sqlite_conn<'req>(&'req self) -> MutexGuard<'req, SqliteConnectionInner> {
+        let sqlite_conn: &SqliteConnectionWrap = self
+            .ext()
+            .expect("You must install SqliteMiddleware providing a SQLite connection");
+        sqlite_conn.lock().await
+    }
+}
@@ -0,0 +1,300 @@
+use std::fmt::{self, Display};
+
+use serde_json::Value;
+use tide::http::headers::{HeaderName, AUTHORIZATION};
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::logging::LogContext;
+
+/// The tenant of the current request, set by [`TenantMiddleware`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TenantId(String);
+
+impl TenantId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An extension trait for reading the current request's tenant.
+pub trait TenantRequestExt {
+    /// The tenant id resolved by [`TenantMiddleware`][], or `None` if it is not installed, or is optional and found none.
+    fn tenant_id(&self) -> Option<&str>;
+}
+
+impl<State> TenantRequestExt for Request<State> {
+    fn tenant_id(&self) -> Option<&str> {
+        self.ext::<TenantId>().map(TenantId::as_str)
+    }
+}
+
+/// Where a tenant id is read from, in the order they were added.
+#[derive(Clone, Debug)]
+enum TenantSource {
+    Header(HeaderName),
+    Subdomain(String),
+    JwtClaim(String),
+}
+
+/// Resolve the tenant of each request of a multi-tenant service.
+///
+/// The tenant id is read from the first of the configured sources which has one:
+/// - A [header][TenantMiddleware::header], such as `X-Tenant-Id`.
+/// - The [subdomain][TenantMiddleware::subdomain] of the `Host`, such as `acme` for `acme.example.com`.
+/// - A [claim][TenantMiddleware::jwt_claim] of the `Authorization: Bearer` JWT, such as `tenant_id`.
+///   The token's signature is not verified here, so the service's authentication must verify it.
+///
+/// Tenant ids must be at most 64 ASCII letters, digits, `-`, `_`, or `.`. Requests with an invalid tenant id,
+/// or with none unless the tenant is [optional][TenantMiddleware::optional], are rejected with a `400 Bad Request`.
+///
+/// Handlers can read the tenant id with [`req.tenant_id()`][TenantRequestExt::tenant_id], from the prelude,
+/// or as a [`TenantId`][] request extension. It is added as `tenant_id` to every log line of the request,
+/// and, with the `"honeycomb"` feature, to the request's trace as a custom field.
+///
+/// With the `"postgres"` feature, [`postgres_setting`][TenantMiddleware::postgres_setting] sets a setting such as
+/// `app.tenant_id` for the request's transaction, as with `SET LOCAL`, for row-level security policies.
+///
+/// This is not installed by default, and should be installed in `custom_setup` or on routes, after the default middleware.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::prelude::*;
+/// use preroll::TenantMiddleware;
+/// use tide::{Request, Route};
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server.with(
+///         TenantMiddleware::new()
+///             .header("X-Tenant-Id")
+///             .subdomain("example.com"),
+///     );
+///
+///     server
+///         .at("whoami")
+///         .get(|req: Request<Arc<()>>| async move {
+///             Ok(format!("Tenant {}", req.tenant_id().unwrap_or("(none)")))
+///         });
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TenantMiddleware {
+    sources: Vec<TenantSource>,
+    optional: bool,
+    #[cfg(feature = "postgres")]
+    postgres_setting: Option<String>,
+}
+
+impl TenantMiddleware {
+    /// Create a new instance of `TenantMiddleware`, without any sources yet.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the tenant id from the `name` request header.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>) -> Self {
+        self.sources.push(TenantSource::Header(name.into()));
+        self
+    }
+
+    /// Read the tenant id from the subdomain of `domain` in the request's host, e.g. `example.com`.
+    ///
+    /// Only a single subdomain is a tenant id, e.g. not `a.b.example.com`.
+    #[must_use]
+    pub fn subdomain(mut self, domain: impl Into<String>) -> Self {
+        self.sources.push(TenantSource::Subdomain(domain.into()));
+        self
+    }
+
+    /// Read the tenant id from the `claim` claim of the request's bearer JWT, which may be a string or a number.
+    #[must_use]
+    pub fn jwt_claim(mut self, claim: impl Into<String>) -> Self {
+        self.sources.push(TenantSource::JwtClaim(claim.into()));
+        self
+    }
+
+    /// Whether requests without a tenant id are handled, rather than rejected. Defaults to `false`.
+    #[must_use]
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Set the `name` postgres setting, such as `app.tenant_id`, to the tenant id for the request's transaction.
+    ///
+    /// Requires [`PostgresMiddleware`][crate::middleware::PostgresMiddleware], which preroll installs before `custom_setup`.
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn postgres_setting(mut self, name: impl Into<String>) -> Self {
+        self.postgres_setting = Some(name.into());
+        self
+    }
+
+    /// The tenant id from the first source which has one.
+    fn resolve<State>(&self, req: &Request<State>) -> Option<String> {
+        self.sources.iter().find_map(|source| match source {
+            TenantSource::Header(name) => req
+                .header(name)
+                .map(|values| values.last().as_str().trim().to_string())
+                .filter(|tenant| !tenant.is_empty()),
+            TenantSource::Subdomain(domain) => {
+                let host = req.host()?;
+                let host = host.split(':').next().unwrap_or(host);
+                let subdomain = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+                (!subdomain.is_empty() && !subdomain.contains('.')).then(|| subdomain.to_string())
+            }
+            TenantSource::JwtClaim(claim) => {
+                let token = req
+                    .header(AUTHORIZATION)?
+                    .last()
+                    .as_str()
+                    .strip_prefix("Bearer ")?;
+                jwt_claim(token, claim)
+            }
+        })
+    }
+
+    /// Resolve the request's tenant, and record it for the request's handler, logs, trace, and transaction.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let tenant = match self.resolve(&req) {
+            Some(tenant) if is_valid(&tenant) => TenantId(tenant),
+            Some(_) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "Invalid tenant id",
+                ))
+            }
+            None if self.optional => return Ok(next.run(req).await),
+            None => {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    "Missing tenant id",
+                ))
+            }
+        };
+
+        LogContext::set_tenant_id(tenant.to_string());
+
+        #[cfg(feature = "honeycomb")]
+        {
+            use crate::middleware::trace::TraceRequestExt;
+            req.trace_field("tenant_id", tenant.as_str());
+        }
+
+        #[cfg(feature = "postgres")]
+        if let Some(setting) = &self.postgres_setting {
+            use crate::middleware::postgres::PostgresRequestExt;
+
+            let mut pg_conn = req.pg_conn().await;
+            sqlx::query("SELECT set_config($1, $2, true)")
+                .bind(setting.as_str())
+                .bind(tenant.as_str())
+                .execute(&mut **pg_conn)
+                .await?;
+        }
+
+        req.set_ext(tenant.clone());
+        let mut res = next.run(req).await;
+        // For the response's log line, which is written after the request's log context has ended.
+        res.insert_ext(tenant);
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TenantMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// A claim of an unverified JWT, as a string.
+fn jwt_claim(token: &str, claim: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Value = serde_json::from_slice(&payload).ok()?;
+    match claims.get(claim)? {
+        Value::String(tenant) => Some(tenant.clone()),
+        Value::Number(tenant) => Some(tenant.to_string()),
+        _ => None,
+    }
+}
+
+fn is_valid(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= 64
+        && tenant
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn resolves_tenants_from_sources_in_order() -> tide::Result<()> {
+        let mut server = tide::new();
+        server.with(
+            TenantMiddleware::new()
+                .header("X-Tenant-Id")
+                .subdomain("example.com")
+                .jwt_claim("tenant_id"),
+        );
+        server.at("/").get(|req: Request<()>| async move {
+            Ok(req.tenant_id().unwrap_or_default().to_string())
+        });
+
+        let get = |host: &str, headers: &[(&str, &str)]| {
+            #[allow(clippy::unwrap_used)]
+            let mut req = http::Request::new(
+                Method::Get,
+                Url::parse(&format!("http://{}/", host)).unwrap(),
+            );
+            for (name, value) in headers {
+                req.insert_header(*name, *value);
+            }
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        let mut res = get("acme.example.com:8080", &[("X-Tenant-Id", "globex")]).await?;
+        assert_eq!(res.body_string().await?, "globex");
+        assert_eq!(
+            res.ext().get::<TenantId>().map(TenantId::as_str),
+            Some("globex")
+        );
+
+        let mut res = get("acme.example.com:8080", &[]).await?;
+        assert_eq!(res.body_string().await?, "acme");
+
+        // {"tenant_id":"initech"}
+        let jwt = "Bearer e30.eyJ0ZW5hbnRfaWQiOiJpbml0ZWNoIn0.c2ln";
+        let mut res = get("a.b.example.com", &[("Authorization", jwt)]).await?;
+        assert_eq!(res.body_string().await?, "initech");
+
+        let res = get("localhost", &[]).await?;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let res = get("localhost", &[("X-Tenant-Id", "acme; DROP TABLE")]).await?;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        Ok(())
+    }
+}
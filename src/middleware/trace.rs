@@ -1,9 +1,44 @@
+use std::sync::{Arc, Mutex};
+
+use serde_json::{Map, Value};
 use tide::{Middleware, Next, Request};
 use tracing::instrument;
 use tracing_honeycomb::{register_dist_tracing_root, SpanId, TraceId};
 
 use super::extension_types::RequestId;
+use super::forwarded::client_ip_or_peer;
 use super::honeycomb::propagation::{Propagation, PROPAGATION_HTTP_HEADER};
+use super::logger::{is_quiet_path, is_slow_request};
+use crate::builtins::scheduling::queue_delay;
+use crate::client::registry::failed_upstream;
+use crate::logging::{duration_ms, LogContext};
+use crate::routes::RouteTemplate;
+
+/// Custom fields for the current request's root span, set via [`TraceRequestExt::trace_field`][].
+#[derive(Clone, Debug, Default)]
+struct TraceFields(Arc<Mutex<Map<String, Value>>>);
+
+/// An extension trait for enriching the current request's trace.
+pub trait TraceRequestExt {
+    /// Attach a custom field to the request's root span, e.g. `req.trace_field("cart_id", cart_id)`.
+    ///
+    /// Fields are recorded on the span as a JSON object in `custom_fields` when the response is ready,
+    /// which Honeycomb expands into columns such as `custom_fields.cart_id` when the dataset unpacks nested JSON.
+    /// Setting the same field again replaces its value.
+    ///
+    /// Does nothing if [`TraceMiddleware`][] is not installed.
+    fn trace_field(&self, name: impl Into<String>, value: impl Into<Value>);
+}
+
+impl<State> TraceRequestExt for Request<State> {
+    fn trace_field(&self, name: impl Into<String>, value: impl Into<Value>) {
+        if let Some(TraceFields(fields)) = self.ext::<TraceFields>() {
+            if let Ok(mut fields) = fields.lock() {
+                fields.insert(name.into(), value.into());
+            }
+        }
+    }
+}
 
 /// Set up tracing for every request.
 #[derive(Debug, Default, Clone)]
@@ -19,7 +54,7 @@ impl TraceMiddleware {
     }
 
     /// Set up tracing for every request.
-    #[instrument(skip(req, next))]
+    #[instrument(skip(req, next), fields(custom_fields, duration_ms, http.path, http.route, http.status, queue_ms, slow_request, upstream))]
     async fn handle<'a, State: Clone + Send + Sync + 'static>(
         &'a self,
         mut req: Request<State>,
@@ -70,6 +105,7 @@ impl TraceMiddleware {
         }
 
         req.set_ext(trace_id.clone());
+        LogContext::set_honeycomb_trace_id(trace_id.to_string());
 
         if let Err(error) = register_dist_tracing_root(trace_id, parent_span) {
             log::error!("Failed to set honeycomb trace root: {:?}", error);
@@ -84,6 +120,7 @@ impl TraceMiddleware {
 
         tracing::info!(
             method = req.method().as_ref(),
+            client_ip = client_ip_or_peer(&req).as_str(),
             host = req.host().unwrap_or(""),
             path = req.url().path(),
             query = req.url().query().unwrap_or(""),
@@ -93,15 +130,42 @@ impl TraceMiddleware {
             "HTTP Request Info"
         );
 
+        let fields = TraceFields::default();
+        req.set_ext(fields.clone());
+
+        let span = tracing::Span::current();
+        span.record("http.path", req.url().path());
+        if let Some(delay) = queue_delay(&req) {
+            span.record("queue_ms", delay.as_millis() as u64);
+        }
+
+        let start = std::time::Instant::now();
         let mut res = next.run(req).await;
+        let elapsed = start.elapsed();
+
+        // Recorded on the root span for rule-based sampling, which decides once the span closes.
+        span.record("http.status", u64::from(res.status() as u16));
+        if let Some(route) = res.ext::<RouteTemplate>() {
+            span.record("http.route", route.as_str());
+        }
+        if let Some(upstream) = res.error().and_then(failed_upstream) {
+            span.record("upstream", upstream);
+        }
+        span.record("duration_ms", duration_ms(elapsed));
+        span.record("slow_request", is_slow_request(elapsed));
+
+        if let Ok(fields) = fields.0.lock() {
+            if !fields.is_empty() {
+                let custom_fields = Value::Object(fields.clone()).to_string();
+                span.record("custom_fields", custom_fields.as_str());
+            }
+        }
 
         tracing::info!(
             status = res.status() as u16,
-            body_size = res
-                .len()
-                .map(|v| v.to_string())
-                .as_deref()
-                .unwrap_or("chunked"),
+            body_size = res.len().map(|len| len as u64),
+            duration_ms = duration_ms(elapsed),
+            slow_request = is_slow_request(elapsed),
             "HTTP Response Info"
         );
 
@@ -116,6 +180,9 @@ impl TraceMiddleware {
 #[tide::utils::async_trait]
 impl<State: Clone + Send + Sync + 'static> Middleware<State> for TraceMiddleware {
     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if is_quiet_path(req.url().path()) {
+            return Ok(next.run(req).await);
+        }
         self.handle(req, next).await
     }
 }
@@ -0,0 +1,234 @@
+use std::fmt::{self, Debug};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tide::http::headers::HeaderName;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+use crate::body::{BodyLimits, BodyStreamExt};
+use crate::webhooks::{self, SignatureFormat};
+
+/// Verify the HMAC signatures of webhook requests, rejecting unsigned, forged, or replayed requests with a `401`.
+///
+/// The body is read to check its signature, up to [`max_bytes`][WebhookMiddleware::max_bytes] (default 1 MiB),
+/// and then handed on to the handler as usual. Larger bodies are rejected with a `413` without being verified.
+///
+/// See [`preroll::webhooks`][crate::webhooks] for details.
+#[derive(Clone)]
+pub struct WebhookMiddleware {
+    secret: Vec<u8>,
+    header_name: HeaderName,
+    format: SignatureFormat,
+    tolerance: Duration,
+    limits: BodyLimits,
+}
+
+impl Debug for WebhookMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookMiddleware")
+            .field("header_name", &self.header_name)
+            .field("format", &self.format)
+            .field("tolerance", &self.tolerance)
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl WebhookMiddleware {
+    /// Create a new instance of `WebhookMiddleware`, for [timestamped][SignatureFormat::Timestamped] signatures
+    /// in an `X-Webhook-Signature` header, made with `secret`.
+    #[must_use]
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            header_name: HeaderName::from("X-Webhook-Signature"),
+            format: SignatureFormat::Timestamped,
+            tolerance: Duration::from_secs(300),
+            limits: BodyLimits::new().max_bytes(1024 * 1024),
+        }
+    }
+
+    /// Verify Stripe webhooks, which are timestamped and signed in a `Stripe-Signature` header,
+    /// with the endpoint's signing secret, e.g. `whsec_...`.
+    #[must_use]
+    pub fn stripe(secret: impl Into<Vec<u8>>) -> Self {
+        Self::new(secret).header("Stripe-Signature")
+    }
+
+    /// Verify GitHub webhooks, which are signed in an `X-Hub-Signature-256` header, with the webhook's secret.
+    #[must_use]
+    pub fn github(secret: impl Into<Vec<u8>>) -> Self {
+        Self::new(secret)
+            .header("X-Hub-Signature-256")
+            .format(SignatureFormat::Sha256)
+    }
+
+    /// The request header of the signature.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>) -> Self {
+        self.header_name = name.into();
+        self
+    }
+
+    /// The format of the signature header.
+    #[must_use]
+    pub fn format(mut self, format: SignatureFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// How far a timestamped signature's time may be from now. Defaults to five minutes.
+    #[must_use]
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// The largest body which is read to be verified, rejecting larger ones with a `413`. Defaults to 1 MiB.
+    #[must_use]
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.limits = self.limits.max_bytes(max_bytes);
+        self
+    }
+
+    /// The request header of the signature.
+    pub fn header_name(&self) -> &HeaderName {
+        &self.header_name
+    }
+
+    /// The signature header value of `body`, signed now.
+    pub fn sign(&self, body: &[u8]) -> String {
+        self.sign_at(body, SystemTime::now())
+    }
+
+    /// The signature header value of `body`, signed at `time`.
+    pub fn sign_at(&self, body: &[u8], time: SystemTime) -> String {
+        webhooks::sign(self.format, &self.secret, body, unix_secs(time))
+    }
+
+    /// Check the request's signature before handling it.
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        let header = match req.header(&self.header_name) {
+            Some(values) => values.last().as_str().to_string(),
+            None => {
+                return Err(tide::Error::from_str(
+                    StatusCode::Unauthorized,
+                    format!("Missing {} header", self.header_name),
+                ))
+            }
+        };
+
+        let mime = req.content_type();
+        let bytes = req.body_stream(self.limits)?.into_bytes().await?;
+
+        if let Err(rejection) = webhooks::verify(
+            self.format,
+            &self.secret,
+            &header,
+            &bytes,
+            unix_secs(SystemTime::now()),
+            self.tolerance,
+        ) {
+            return Err(tide::Error::from_str(
+                StatusCode::Unauthorized,
+                rejection.message(),
+            ));
+        }
+
+        let mut body = Body::from_bytes(bytes);
+        if let Some(mime) = mime {
+            body.set_mime(mime);
+        }
+        req.set_body(body);
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for WebhookMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{self, TestClient, TestResult};
+
+    #[async_std::test]
+    async fn rejects_unsigned_and_replayed_webhooks() -> TestResult<()> {
+        let webhook = WebhookMiddleware::stripe("whsec_test");
+        let route_webhook = webhook.clone();
+        let client = test_utils::create_client((), move |mut server: tide::Route<'_, Arc<()>>| {
+            server.at("webhooks").with(route_webhook.clone()).post(
+                |mut req: Request<Arc<()>>| async move {
+                    let event: serde_json::Value = req.body_json().await?;
+                    Ok(event["type"].to_string())
+                },
+            );
+        })
+        .await?;
+        let client = TestClient::new(client);
+
+        let event = serde_json::json!({ "type": "charge.succeeded" });
+        let mut res = client.post_webhook("/webhooks", &webhook, &event)?.await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await?, r#""charge.succeeded""#);
+
+        let res = client.post("/webhooks").body_json(&event)?.await?;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let body = serde_json::to_vec(&event)?;
+        let replayed = webhook.sign_at(&body, SystemTime::now() - Duration::from_secs(600));
+        let res = client
+            .post("/webhooks")
+            .header("Stripe-Signature", replayed)
+            .body(body)
+            .await?;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let forged = WebhookMiddleware::stripe("other");
+        let res = client.post_webhook("/webhooks", &forged, &event)?.await?;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_oversized_webhooks_before_verifying() -> TestResult<()> {
+        let webhook = WebhookMiddleware::stripe("whsec_test").max_bytes(16);
+        let route_webhook = webhook.clone();
+        let client = test_utils::create_client((), move |mut server: tide::Route<'_, Arc<()>>| {
+            server
+                .at("webhooks")
+                .with(route_webhook.clone())
+                .post(|_| async { Ok("verified") });
+        })
+        .await?;
+        let client = TestClient::new(client);
+
+        let event = serde_json::json!({ "type": "charge.succeeded" });
+        let res = client.post_webhook("/webhooks", &webhook, &event)?.await?;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+
+        let res = client
+            .post("/webhooks")
+            .header("Stripe-Signature", "t=0,v1=forged")
+            .body(vec![b'x'; 1024])
+            .await?;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        Ok(())
+    }
+}
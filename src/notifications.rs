@@ -0,0 +1,360 @@
+//! Transactional notifications (email, SMS, push), using the outbox pattern.
+//!
+//! Notifications are written into the request's own postgres transaction with
+//! [`enqueue_notification`][NotificationRequestExt::enqueue_notification], and are only delivered by the
+//! background relay once that transaction has committed. A rolled-back operation therefore never sends a notification.
+//!
+//! Delivery is done by a [`NotificationProvider`][] registered per channel, e.g. `"email"`, and is retried with
+//! exponential backoff, up to `NOTIFICATION_MAX_ATTEMPTS` (default `10`) times.
+//!
+//! Providers must be registered during setup, e.g. in `setup_state`. If any are, the `preroll_notifications`
+//! table is created if it does not exist, and the relay is started, polling every `NOTIFICATION_RELAY_INTERVAL_MS`
+//! (default `1000`).
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::notifications::{self, Notification, NotificationProvider};
+//! use preroll::prelude::*;
+//! use tide::Request;
+//!
+//! struct EmailProvider;
+//!
+//! #[tide::utils::async_trait]
+//! impl NotificationProvider for EmailProvider {
+//!     async fn send(&self, notification: &Notification) -> anyhow::Result<()> {
+//!         // ... call the email service ...
+//!         # let _ = notification;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     notifications::register_provider("email", EmailProvider);
+//!     Ok(())
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn create_invoice(req: Request<Arc<()>>) -> tide::Result {
+//!     let mut pg_conn = req.pg_conn().await;
+//!
+//!     // ... insert the invoice ...
+//!
+//!     req.enqueue_notification(
+//!         &mut **pg_conn,
+//!         Notification::new("email", "billing@example.com", "Your invoice is ready.")
+//!             .subject("Invoice"),
+//!     )
+//!     .await?;
+//!
+//!     Ok("created".into())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::types::Json;
+use sqlx::Connection;
+use tide::Request;
+use uuid::Uuid;
+
+use crate::builtins::{relay, schema};
+use crate::config;
+use crate::logging::log_kv;
+use crate::middleware::extension_types::RequestId;
+
+static PROVIDERS: Lazy<RwLock<HashMap<&'static str, Arc<dyn NotificationProvider>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The most notifications delivered per relay transaction.
+const RELAY_BATCH_SIZE: i64 = 50;
+
+/// The longest wait between delivery attempts of a notification.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// A notification to deliver to `recipient` via the provider registered for `channel`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Notification {
+    pub channel: String,
+    pub recipient: String,
+    pub subject: Option<String>,
+    pub body: String,
+    /// Arbitrary provider-specific data, e.g. a template id.
+    #[serde(default)]
+    pub data: Value,
+}
+
+impl Notification {
+    /// Create a new notification, with no subject or data.
+    #[must_use]
+    pub fn new(
+        channel: impl Into<String>,
+        recipient: impl Into<String>,
+        body: impl Into<String>,
+    ) -> Self {
+        Self {
+            channel: channel.into(),
+            recipient: recipient.into(),
+            subject: None,
+            body: body.into(),
+            data: Value::Null,
+        }
+    }
+
+    /// Set the subject, e.g. of an email.
+    #[must_use]
+    pub fn subject(mut self, subject: impl Into<String>) -> Self {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    /// Set provider-specific data.
+    #[must_use]
+    pub fn data(mut self, data: Value) -> Self {
+        self.data = data;
+        self
+    }
+}
+
+/// Delivers notifications for a channel, e.g. via an email or SMS service.
+#[tide::utils::async_trait]
+pub trait NotificationProvider: Send + Sync + 'static {
+    /// Deliver a notification. Errors are retried with backoff.
+    async fn send(&self, notification: &Notification) -> anyhow::Result<()>;
+}
+
+/// Register the provider which delivers notifications for `channel`.
+///
+/// Registering a provider for the same channel as an existing provider replaces it.
+pub fn register_provider(channel: &'static str, provider: impl NotificationProvider) {
+    if let Ok(mut providers) = PROVIDERS.write() {
+        providers.insert(channel, Arc::new(provider));
+    }
+}
+
+fn provider(channel: &str) -> Option<Arc<dyn NotificationProvider>> {
+    PROVIDERS.read().ok()?.get(channel).cloned()
+}
+
+/// An extension trait for enqueueing notifications within the request's transaction.
+#[tide::utils::async_trait]
+pub trait NotificationRequestExt {
+    /// Write `notification` into `tx`, to be delivered once, and only if, the transaction commits.
+    ///
+    /// Returns the notification's id.
+    async fn enqueue_notification(
+        &self,
+        tx: &mut PgConnection,
+        notification: Notification,
+    ) -> sqlx::Result<Uuid>;
+}
+
+#[tide::utils::async_trait]
+impl<State: Send + Sync + 'static> NotificationRequestExt for Request<State> {
+    async fn enqueue_notification(
+        &self,
+        tx: &mut PgConnection,
+        notification: Notification,
+    ) -> sqlx::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let request_id = self.ext::<RequestId>().map(|id| id.as_str().to_string());
+
+        sqlx::query(
+            "INSERT INTO preroll_notifications (id, channel, payload, request_id) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(id)
+        .bind(&notification.channel)
+        .bind(Json(&notification))
+        .bind(request_id)
+        .execute(tx)
+        .await?;
+
+        Ok(id)
+    }
+}
+
+/// If any providers are registered, create the outbox table and start the relay.
+pub(crate) async fn setup(pool: &PgPool) -> Result<()> {
+    let has_providers = PROVIDERS
+        .read()
+        .map(|providers| !providers.is_empty())
+        .unwrap_or(false);
+    if !has_providers {
+        return Ok(());
+    }
+
+    schema::migrate(pool, schema::NOTIFICATIONS).await?;
+
+    let interval: u64 = config::var("NOTIFICATION_RELAY_INTERVAL_MS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(1000))?;
+    let max_attempts: i32 = config::var("NOTIFICATION_MAX_ATTEMPTS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(10))?;
+
+    let pool = pool.clone();
+    relay::spawn(
+        "Notification relay",
+        Duration::from_millis(interval),
+        move || {
+            let pool = pool.clone();
+            // A full batch means there may be more ready to deliver.
+            async move { Ok(deliver_due(&pool, max_attempts).await? as i64 == RELAY_BATCH_SIZE) }
+        },
+    );
+
+    Ok(())
+}
+
+/// Deliver one batch of due notifications, returning how many were attempted.
+async fn deliver_due(pool: &PgPool, max_attempts: i32) -> Result<usize> {
+    let mut conn = crate::builtins::pool_stats::acquire(pool).await?;
+    let mut tx = conn.begin().await?;
+
+    let due: Vec<(Uuid, Json<Notification>, i32, Option<String>)> = sqlx::query_as(
+        "SELECT id, payload, attempts, request_id FROM preroll_notifications \
+        WHERE delivered_at IS NULL AND attempts < $1 AND next_attempt_at <= now() \
+        ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(max_attempts)
+    .bind(RELAY_BATCH_SIZE)
+    .fetch_all(&mut tx)
+    .await?;
+
+    for (id, Json(notification), attempts, request_id) in due.iter() {
+        match deliver(notification).await {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE preroll_notifications SET delivered_at = now(), attempts = attempts + 1 WHERE id = $1",
+                )
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+            Err(error) => {
+                let retry_in = relay::backoff(*attempts as u32 + 1, MAX_BACKOFF);
+                log_kv!(
+                    warn,
+                    {
+                        notification_id: id.to_string(),
+                        channel: notification.channel,
+                        attempts: attempts + 1,
+                        request_id: request_id.as_deref().unwrap_or(""),
+                    },
+                    "Notification delivery failed: {:?}", error
+                );
+
+                sqlx::query(
+                    "UPDATE preroll_notifications SET attempts = attempts + 1, last_error = $2, \
+                    next_attempt_at = now() + make_interval(secs => $3) WHERE id = $1",
+                )
+                .bind(id)
+                .bind(format!("{:?}", error))
+                .bind(retry_in.as_secs_f64())
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(due.len())
+}
+
+/// Send `notification` with the provider registered for its channel.
+async fn deliver(notification: &Notification) -> anyhow::Result<()> {
+    match provider(&notification.channel) {
+        Some(provider) => provider.send(notification).await,
+        None => Err(anyhow::anyhow!(
+            "No provider registered for channel \"{}\"",
+            notification.channel
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingProvider {
+        sent: Arc<Mutex<Vec<Notification>>>,
+    }
+
+    #[tide::utils::async_trait]
+    impl NotificationProvider for RecordingProvider {
+        async fn send(&self, notification: &Notification) -> anyhow::Result<()> {
+            if let Ok(mut sent) = self.sent.lock() {
+                sent.push(notification.clone());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn builds_payloads() -> serde_json::Result<()> {
+        let notification = Notification::new("email", "ada@example.com", "Your order shipped")
+            .subject("Shipped")
+            .data(json!({ "template": "shipped" }));
+        assert_eq!(
+            serde_json::to_value(&notification)?,
+            json!({
+                "channel": "email",
+                "recipient": "ada@example.com",
+                "subject": "Shipped",
+                "body": "Your order shipped",
+                "data": { "template": "shipped" },
+            })
+        );
+
+        // Payloads without data, e.g. enqueued by older versions, are still read back.
+        let payload = json!({
+            "channel": "sms",
+            "recipient": "+15550100",
+            "subject": null,
+            "body": "Hi",
+        });
+        assert_eq!(
+            serde_json::from_value::<Notification>(payload)?,
+            Notification::new("sms", "+15550100", "Hi")
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn delivers_with_the_channel_provider() {
+        let provider = RecordingProvider::default();
+        let sent = provider.sent.clone();
+        register_provider("notifications-test", provider);
+
+        let notification = Notification::new("notifications-test", "ada@example.com", "Hi");
+        assert!(deliver(&notification).await.is_ok());
+        #[allow(clippy::unwrap_used)]
+        let sent = sent.lock().unwrap().clone();
+        assert_eq!(sent, vec![notification]);
+
+        let unregistered = Notification::new("pigeon", "ada@example.com", "Hi");
+        match deliver(&unregistered).await {
+            Ok(()) => panic!("A notification without a provider was delivered"),
+            Err(error) => assert_eq!(
+                error.to_string(),
+                "No provider registered for channel \"pigeon\""
+            ),
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Transactional event publishing, using the outbox pattern.
+//!
+//! Events are written into the request's own postgres transaction with
+//! [`enqueue_event`][OutboxRequestExt::enqueue_event], into the `events_outbox` table, and are published
+//! by the background relay to the configured [`OutboxSink`][] once that transaction has committed.
+//!
+//! Publishing is at-least-once: events are retried with exponential backoff until the sink accepts them,
+//! and an event may be published again if the relay stops between publishing and recording it.
+//! Sinks should be idempotent on the event's `id`.
+//!
+//! Events with the same [key][Event::key] are published in the order they were enqueued: while an event waits to be
+//! published or retried, later events with its key are held back, including by other instances. Events without a key
+//! are published in no particular order.
+//!
+//! The sink is either set during setup, e.g. in `setup_state`, with [`set_sink`][], such as for SQS or Kafka,
+//! or else is a [`WebhookSink`][] if `OUTBOX_WEBHOOK_URL` is set. If there is a sink, the `events_outbox` table is
+//! created if it does not exist, and the relay is started, polling every `OUTBOX_RELAY_INTERVAL_MS` (default `1000`)
+//! until the server is [shut down][crate::setup::shutdown_handle].
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::outbox::Event;
+//! use preroll::prelude::*;
+//! use serde_json::json;
+//! use tide::Request;
+//!
+//! # #[allow(dead_code)]
+//! async fn create_invoice(req: Request<Arc<()>>) -> tide::Result {
+//!     let mut pg_conn = req.pg_conn().await;
+//!
+//!     // ... insert the invoice ...
+//!
+//!     req.enqueue_event(
+//!         &mut **pg_conn,
+//!         Event::new("invoice.created", json!({ "invoiceId": 1 })).key("customer-1"),
+//!     )
+//!     .await?;
+//!
+//!     Ok("created".into())
+//! }
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::types::Json;
+use sqlx::Connection;
+use tide::Request;
+use uuid::Uuid;
+
+use crate::builtins::{relay, schema};
+use crate::config;
+use crate::logging::log_kv;
+use crate::middleware::extension_types::RequestId;
+
+static SINK: OnceCell<Arc<dyn OutboxSink>> = OnceCell::new();
+
+/// The most events published per relay transaction.
+const RELAY_BATCH_SIZE: i64 = 50;
+
+/// `id, topic, key, payload, attempts, request_id` of a due event.
+type EventRow = (
+    Uuid,
+    String,
+    Option<String>,
+    Json<Value>,
+    i32,
+    Option<String>,
+);
+
+/// An event to publish to `topic`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Event {
+    pub topic: String,
+    /// An optional partitioning or grouping key, e.g. for Kafka or SQS FIFO queues.
+    /// Events with the same key are published in order.
+    pub key: Option<String>,
+    pub payload: Value,
+}
+
+impl Event {
+    /// Create a new event, with no key.
+    #[must_use]
+    pub fn new(topic: impl Into<String>, payload: Value) -> Self {
+        Self {
+            topic: topic.into(),
+            key: None,
+            payload,
+        }
+    }
+
+    /// Set the partitioning or grouping key.
+    #[must_use]
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+}
+
+/// An event read back from the outbox, for publishing.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEvent {
+    /// Unique per event, and the same across retries, so that sinks can deduplicate.
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub event: Event,
+    /// The `X-Request-Id` of the request which enqueued the event.
+    pub request_id: Option<String>,
+}
+
+/// Publishes outbox events, e.g. to an HTTP webhook, SQS, or Kafka.
+#[tide::utils::async_trait]
+pub trait OutboxSink: Send + Sync + 'static {
+    /// Publish an event. Errors are retried with backoff.
+    async fn publish(&self, event: &OutboxEvent) -> anyhow::Result<()>;
+}
+
+/// Set the sink which outbox events are published to.
+///
+/// Only the first sink set is used.
+pub fn set_sink(sink: impl OutboxSink) {
+    if SINK.set(Arc::new(sink)).is_err() {
+        log::warn!("An outbox sink is already set, ignoring another.");
+    }
+}
+
+/// Publishes each event as a JSON `POST` to a url, with the event's id as the `Idempotency-Key` header.
+///
+/// Used as the sink if `OUTBOX_WEBHOOK_URL` is set, and no other sink is.
+#[derive(Debug, Clone)]
+pub struct WebhookSink {
+    url: String,
+}
+
+impl WebhookSink {
+    /// Create a new `WebhookSink` for `url`.
+    #[must_use]
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+#[tide::utils::async_trait]
+impl OutboxSink for WebhookSink {
+    async fn publish(&self, event: &OutboxEvent) -> anyhow::Result<()> {
+        let res = surf::post(&self.url)
+            .header("Idempotency-Key", event.id.to_string())
+            .body_json(event)
+            .map_err(|error| anyhow::anyhow!(error))?
+            .await
+            .map_err(|error| anyhow::anyhow!(error))?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Webhook responded with status {}",
+                res.status()
+            ))
+        }
+    }
+}
+
+/// An extension trait for enqueueing events within the request's transaction.
+#[tide::utils::async_trait]
+pub trait OutboxRequestExt {
+    /// Write `event` into `tx`, to be published once, and only if, the transaction commits.
+    ///
+    /// Returns the event's id.
+    async fn enqueue_event(&self, tx: &mut PgConnection, event: Event) -> sqlx::Result<Uuid>;
+}
+
+#[tide::utils::async_trait]
+impl<State: Send + Sync + 'static> OutboxRequestExt for Request<State> {
+    async fn enqueue_event(&self, tx: &mut PgConnection, event: Event) -> sqlx::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let request_id = self.ext::<RequestId>().map(|id| id.as_str().to_string());
+
+        sqlx::query(
+            "INSERT INTO events_outbox (id, topic, key, payload, request_id) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(id)
+        .bind(&event.topic)
+        .bind(&event.key)
+        .bind(Json(&event.payload))
+        .bind(request_id)
+        .execute(tx)
+        .await?;
+
+        Ok(id)
+    }
+}
+
+/// If there is a sink, create the outbox table and start the relay.
+pub(crate) async fn setup(pool: &PgPool) -> Result<()> {
+    if SINK.get().is_none() {
+        if let Ok(url) = config::var("OUTBOX_WEBHOOK_URL") {
+            SINK.set(Arc::new(WebhookSink::new(url))).ok();
+        }
+    }
+    let sink = match SINK.get() {
+        Some(sink) => sink.clone(),
+        None => return Ok(()),
+    };
+
+    schema::migrate(pool, schema::EVENTS_OUTBOX).await?;
+
+    let interval: u64 = config::var("OUTBOX_RELAY_INTERVAL_MS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(1000))?;
+
+    let pool = pool.clone();
+    relay::spawn("Outbox relay", Duration::from_millis(interval), move || {
+        let pool = pool.clone();
+        let sink = sink.clone();
+        // Publishing an event may have released the next one with its key.
+        async move { Ok(publish(&pool, &*sink).await? > 0) }
+    });
+
+    Ok(())
+}
+
+/// Publish one batch of due events, returning how many were published.
+///
+/// Only the earliest unpublished event of each key is due, so that an event is never published before an earlier one
+/// with its key, even if that one is waiting to be retried or is being published by another instance.
+async fn publish(pool: &PgPool, sink: &dyn OutboxSink) -> Result<usize> {
+    let mut conn = crate::builtins::pool_stats::acquire(pool).await?;
+    let mut tx = conn.begin().await?;
+
+    let due: Vec<EventRow> = sqlx::query_as(
+        "SELECT id, topic, key, payload, attempts, request_id FROM events_outbox pending \
+            WHERE published_at IS NULL AND next_attempt_at <= now() \
+            AND NOT EXISTS (SELECT 1 FROM events_outbox earlier \
+                WHERE earlier.key = pending.key AND earlier.seq < pending.seq AND earlier.published_at IS NULL) \
+            ORDER BY seq LIMIT $1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(RELAY_BATCH_SIZE)
+    .fetch_all(&mut tx)
+    .await?;
+
+    let mut published = 0;
+    for (id, topic, key, Json(payload), attempts, request_id) in due {
+        let event = OutboxEvent {
+            id,
+            event: Event {
+                topic,
+                key,
+                payload,
+            },
+            request_id,
+        };
+
+        match sink.publish(&event).await {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE events_outbox SET published_at = now(), attempts = attempts + 1 WHERE id = $1",
+                )
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+                published += 1;
+            }
+            Err(error) => {
+                let retry_in = relay::backoff(attempts as u32 + 1, relay::MAX_BACKOFF);
+                log_kv!(
+                    warn,
+                    {
+                        event_id: id.to_string(),
+                        topic: event.event.topic,
+                        attempts: attempts + 1,
+                        request_id: event.request_id.as_deref().unwrap_or(""),
+                    },
+                    "Outbox event publishing failed: {:?}", error
+                );
+
+                sqlx::query(
+                    "UPDATE events_outbox SET attempts = attempts + 1, last_error = $2, \
+                    next_attempt_at = now() + make_interval(secs => $3) WHERE id = $1",
+                )
+                .bind(id)
+                .bind(format!("{:?}", error))
+                .bind(retry_in.as_secs_f64())
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serializes_events_for_sinks() -> serde_json::Result<()> {
+        let id = Uuid::nil();
+        let event = OutboxEvent {
+            id,
+            event: Event::new("orders.created", json!({ "orderId": 7 })).key("customer-1"),
+            request_id: Some("req-1".to_string()),
+        };
+
+        assert_eq!(
+            serde_json::to_value(&event)?,
+            json!({
+                "id": id.to_string(),
+                "topic": "orders.created",
+                "key": "customer-1",
+                "payload": { "orderId": 7 },
+                "requestId": "req-1",
+            })
+        );
+
+        let event = OutboxEvent {
+            id,
+            event: Event::new("orders.created", json!({})),
+            request_id: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event)?,
+            json!({
+                "id": id.to_string(),
+                "topic": "orders.created",
+                "key": null,
+                "payload": {},
+                "requestId": null,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Cursor and offset pagination, with `Link` headers and a consistent JSON envelope.
+//!
+//! [`PageQuery::from_request`][] reads and validates the `limit`, `cursor`, and `offset` query parameters,
+//! within [`PageLimits`][]. Invalid parameters are rejected with a `400 Bad Request`.
+//!
+//! A [`Page`][] is then built from the fetched items, and [`Page::into_response`][] replies with the envelope:
+//!
+//! ```json
+//! {
+//!   "items": [ ... ],
+//!   "page": { "limit": 25, "next_cursor": "eyJpZCI6NDJ9", "prev_cursor": null, "offset": null, "total": null }
+//! }
+//! ```
+//!
+//! along with a `Link` header of the `next` (and `prev`, or `first`) pages, e.g.
+//! `</api/v1/products?limit=25&cursor=eyJpZCI6NDJ9>; rel="next"`. Other query parameters, such as filters, are kept.
+//!
+//! [`Cursor`][]s are opaque to clients: any serializable value, such as the sort key of the last item,
+//! encoded as URL-safe base64 JSON.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use preroll::pagination::{Cursor, Page, PageLimits, PageQuery};
+//! use serde::Serialize;
+//! use tide::Request;
+//!
+//! #[derive(Serialize)]
+//! struct Product {
+//!     id: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn list_products(req: Request<()>) -> tide::Result {
+//!     let query = PageQuery::from_request(&req, PageLimits::new())?;
+//!     let after: u64 = match &query.cursor {
+//!         Some(cursor) => cursor.decode()?,
+//!         None => 0,
+//!     };
+//!
+//!     // Fetch one more than the limit, to know whether there is a next page.
+//!     let products: Vec<Product> = (after + 1..)
+//!         .take(query.fetch_limit())
+//!         .map(|id| Product { id })
+//!         .collect();
+//!
+//!     Page::from_cursor(products, &query, |product| Cursor::new(&product.id))?.into_response(&req)
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use tide::http::Url;
+use tide::{Request, Response, StatusCode};
+
+/// The query parameters which pagination reads, and replaces in `Link` header urls.
+const PAGE_PARAMS: [&str; 3] = ["limit", "cursor", "offset"];
+
+/// An opaque pagination cursor: a serializable value, such as a sort key, encoded as URL-safe base64 JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+impl Cursor {
+    /// Encode `value` as a cursor.
+    pub fn new<T: Serialize + ?Sized>(value: &T) -> tide::Result<Self> {
+        let json = serde_json::to_vec(value)?;
+        Ok(Self(base64::encode_config(json, base64::URL_SAFE_NO_PAD)))
+    }
+
+    /// Decode the cursor's value, failing with a `400 Bad Request` if it is not a valid cursor for `T`.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> tide::Result<T> {
+        base64::decode_config(&self.0, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Invalid page cursor"))
+    }
+
+    /// The encoded cursor.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Limits on the page size clients may request.
+#[derive(Clone, Copy, Debug)]
+pub struct PageLimits {
+    default_limit: usize,
+    max_limit: usize,
+}
+
+impl Default for PageLimits {
+    fn default() -> Self {
+        Self {
+            default_limit: 25,
+            max_limit: 100,
+        }
+    }
+}
+
+impl PageLimits {
+    /// The default limits: 25 items per page, unless the client asks for up to 100.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The page size if the client does not specify a `limit`.
+    #[must_use]
+    pub fn default_limit(mut self, limit: usize) -> Self {
+        self.default_limit = limit.max(1);
+        self
+    }
+
+    /// The largest `limit` a client may request before failing with a `400 Bad Request`.
+    #[must_use]
+    pub fn max_limit(mut self, limit: usize) -> Self {
+        self.max_limit = limit.max(1);
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawPageQuery {
+    limit: Option<usize>,
+    cursor: Option<Cursor>,
+    offset: Option<u64>,
+}
+
+/// The validated pagination query parameters of a request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageQuery {
+    /// The most items to return.
+    pub limit: usize,
+    /// The `cursor` parameter, for cursor pagination.
+    pub cursor: Option<Cursor>,
+    /// The `offset` parameter, for offset pagination.
+    pub offset: Option<u64>,
+}
+
+impl PageQuery {
+    /// Read the request's `limit`, `cursor`, and `offset` query parameters, within `limits`.
+    ///
+    /// Fails with a `400 Bad Request` if `limit` is zero or over the maximum, or if both `cursor` and `offset` are given.
+    pub fn from_request<State>(req: &Request<State>, limits: PageLimits) -> tide::Result<Self> {
+        let raw: RawPageQuery = req.query()?;
+        let bad_request = |message: String| tide::Error::from_str(StatusCode::BadRequest, message);
+
+        let limit = raw.limit.unwrap_or(limits.default_limit);
+        if limit == 0 || limit > limits.max_limit {
+            return Err(bad_request(format!(
+                "Page limit must be between 1 and {}",
+                limits.max_limit
+            )));
+        }
+        if raw.cursor.is_some() && raw.offset.is_some() {
+            return Err(bad_request(
+                "Only one of cursor and offset may be given".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            limit,
+            cursor: raw.cursor,
+            offset: raw.offset,
+        })
+    }
+
+    /// How many items to fetch for [`Page::from_cursor`][]: one more than the limit, to know whether there is a next page.
+    #[must_use]
+    pub fn fetch_limit(&self) -> usize {
+        self.limit + 1
+    }
+
+    /// The offset to fetch from, for offset pagination. Defaults to `0`.
+    #[must_use]
+    pub fn offset(&self) -> u64 {
+        self.offset.unwrap_or(0)
+    }
+}
+
+/// Where a page is, and where the pages around it are.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PageInfo {
+    /// The page size.
+    pub limit: usize,
+    /// The cursor of the next page, if there is one.
+    pub next_cursor: Option<Cursor>,
+    /// The cursor of the previous page, if known.
+    pub prev_cursor: Option<Cursor>,
+    /// The offset of this page, for offset pagination.
+    pub offset: Option<u64>,
+    /// The total number of items, if known.
+    pub total: Option<u64>,
+}
+
+/// A page of items, which serializes as the pagination JSON envelope.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Page<T> {
+    /// The items of this page.
+    pub items: Vec<T>,
+    /// Where this page is.
+    pub page: PageInfo,
+    #[serde(skip)]
+    has_next: bool,
+}
+
+impl<T: Serialize> Page<T> {
+    /// A cursor-paginated page of `items`, fetched with [`PageQuery::fetch_limit`][].
+    ///
+    /// If there are more items than the limit, the extra item is dropped, and the next page's cursor is `cursor_fn`
+    /// of the last item kept.
+    pub fn from_cursor<F>(mut items: Vec<T>, query: &PageQuery, cursor_fn: F) -> tide::Result<Self>
+    where
+        F: FnOnce(&T) -> tide::Result<Cursor>,
+    {
+        let has_next = items.len() > query.limit;
+        items.truncate(query.limit);
+
+        let next_cursor = match items.last() {
+            Some(last) if has_next => Some(cursor_fn(last)?),
+            _ => None,
+        };
+
+        Ok(Self {
+            items,
+            page: PageInfo {
+                limit: query.limit,
+                next_cursor,
+                prev_cursor: None,
+                offset: None,
+                total: None,
+            },
+            has_next,
+        })
+    }
+
+    /// An offset-paginated page of `items`, fetched from [`PageQuery::offset`][] with [`PageQuery::limit`][PageQuery],
+    /// out of `total` items if known.
+    ///
+    /// Without a `total`, there is assumed to be a next page whenever this page is full.
+    #[must_use]
+    pub fn from_offset(items: Vec<T>, query: &PageQuery, total: Option<u64>) -> Self {
+        let offset = query.offset();
+        let has_next = match total {
+            Some(total) => offset + (items.len() as u64) < total,
+            None => items.len() >= query.limit,
+        };
+
+        Self {
+            items,
+            page: PageInfo {
+                limit: query.limit,
+                next_cursor: None,
+                prev_cursor: None,
+                offset: Some(offset),
+                total,
+            },
+            has_next,
+        }
+    }
+
+    /// Set the cursor of the previous page, for a `rel="prev"` link.
+    #[must_use]
+    pub fn prev_cursor(mut self, cursor: Cursor) -> Self {
+        self.page.prev_cursor = Some(cursor);
+        self
+    }
+
+    /// Set the total number of items.
+    #[must_use]
+    pub fn total(mut self, total: u64) -> Self {
+        self.page.total = Some(total);
+        self
+    }
+
+    /// A `200 OK` response of the JSON envelope, with a `Link` header of the pages around this one.
+    pub fn into_response<State>(self, req: &Request<State>) -> tide::Result<Response> {
+        let mut res = Response::new(StatusCode::Ok);
+        if let Some(link) = self.link(req.url()) {
+            res.insert_header("Link", link);
+        }
+        res.set_body(crate::json::body(&self)?);
+        Ok(res)
+    }
+
+    /// The `Link` header value for this page, with links relative to the server's root.
+    fn link(&self, url: &Url) -> Option<String> {
+        let limit = self.page.limit.to_string();
+        let mut links = Vec::new();
+
+        match self.page.offset {
+            Some(offset) => {
+                let limit_u64 = self.page.limit as u64;
+                if self.has_next {
+                    let next = (offset + limit_u64).to_string();
+                    links.push((
+                        page_url(url, &[("limit", &limit), ("offset", &next)]),
+                        "next",
+                    ));
+                }
+                if offset > 0 {
+                    let prev = offset.saturating_sub(limit_u64).to_string();
+                    links.push((
+                        page_url(url, &[("limit", &limit), ("offset", &prev)]),
+                        "prev",
+                    ));
+                    links.push((page_url(url, &[("limit", &limit)]), "first"));
+                }
+            }
+            None => {
+                if let Some(next) = &self.page.next_cursor {
+                    let params = [("limit", limit.as_str()), ("cursor", next.as_str())];
+                    links.push((page_url(url, &params), "next"));
+                }
+                if let Some(prev) = &self.page.prev_cursor {
+                    let params = [("limit", limit.as_str()), ("cursor", prev.as_str())];
+                    links.push((page_url(url, &params), "prev"));
+                }
+            }
+        }
+
+        if links.is_empty() {
+            return None;
+        }
+        let links: Vec<String> = links
+            .into_iter()
+            .map(|(url, rel)| format!("<{}>; rel=\"{}\"", url, rel))
+            .collect();
+        Some(links.join(", "))
+    }
+}
+
+/// The request's path and query, with its pagination parameters replaced by `params`.
+fn page_url(url: &Url, params: &[(&str, &str)]) -> String {
+    let mut url = url.clone();
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !PAGE_PARAMS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .extend_pairs(params.iter().copied());
+
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::test_utils;
+
+    fn request(url: &str) -> Request<()> {
+        test_utils::request(Method::Get, url).into()
+    }
+
+    #[test]
+    fn validates_queries() -> tide::Result<()> {
+        let limits = PageLimits::new().max_limit(50);
+
+        let query = PageQuery::from_request(&request("http://localhost/items"), limits)?;
+        assert_eq!(query.limit, 25);
+        assert_eq!(query.fetch_limit(), 26);
+
+        for invalid in ["limit=0", "limit=51", "limit=ten", "offset=5&cursor=abc"].iter() {
+            let url = format!("http://localhost/items?{}", invalid);
+            let status = PageQuery::from_request(&request(&url), limits).map_err(|e| e.status());
+            assert_eq!(status, Err(StatusCode::BadRequest), "{}", invalid);
+        }
+
+        let cursor = Cursor::new(&("2021-06-01", 42))?;
+        assert_eq!(
+            cursor.decode::<(String, u64)>()?,
+            ("2021-06-01".to_string(), 42)
+        );
+        assert_eq!(
+            Cursor("!".to_string())
+                .decode::<u64>()
+                .map_err(|e| e.status()),
+            Err(StatusCode::BadRequest)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn responds_with_envelope_and_links() -> tide::Result<()> {
+        let req = request("http://localhost/items?color=red&limit=2");
+        let query = PageQuery::from_request(&req, PageLimits::new())?;
+
+        let page = Page::from_cursor(vec![1, 2, 3], &query, Cursor::new)?;
+        let next = Cursor::new(&2)?;
+        let mut res: http::Response = page.into_response(&req)?.into();
+        assert_eq!(
+            res.header("Link").map(|v| v.as_str().to_string()),
+            Some(format!(
+                "</items?color=red&limit=2&cursor={}>; rel=\"next\"",
+                next.as_str()
+            ))
+        );
+        assert_eq!(
+            res.body_json::<serde_json::Value>().await?,
+            serde_json::json!({
+                "items": [1, 2],
+                "page": { "limit": 2, "next_cursor": next, "prev_cursor": null, "offset": null, "total": null },
+            })
+        );
+
+        let req = request("http://localhost/items?offset=2&limit=2");
+        let query = PageQuery::from_request(&req, PageLimits::new())?;
+        let page = Page::from_offset(vec![3, 4], &query, Some(5));
+        let res: http::Response = page.into_response(&req)?.into();
+        assert_eq!(
+            res.header("Link").map(|v| v.as_str()),
+            Some("</items?limit=2&offset=4>; rel=\"next\", </items?limit=2&offset=0>; rel=\"prev\", </items?limit=2>; rel=\"first\"")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+//! Postgres access outside of requests.
+//!
+//! [`PostgresRequestExt`][crate::prelude::PostgresRequestExt] only works within a request. Background tasks,
+//! startup warmers, and queue consumers can instead use the pool preroll connected during setup, which is shared with
+//! requests, and so is limited by the same `PGMAXCONNECTIONS`.
+//!
+//! The pool is also passed to `setup_state` as [`PrerollResources::pg_pool`][crate::PrerollResources::pg_pool].
+//!
+//! Queries through the shared pool are not part of any request's transaction, and so are committed immediately.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use async_std::task;
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state() -> preroll::SetupResult<()> {
+//!     task::spawn(async {
+//!         loop {
+//!             task::sleep(Duration::from_secs(60)).await;
+//!             if let Ok(mut conn) = preroll::postgres::acquire().await {
+//!                 sqlx::query("DELETE FROM carts WHERE updated_at < now() - interval '30 days'")
+//!                     .execute(&mut conn)
+//!                     .await
+//!                     .ok();
+//!             }
+//!         }
+//!     });
+//!     Ok(())
+//! }
+//! ```
+
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgPool, Postgres};
+
+use crate::builtins::pool_stats;
+
+/// The postgres pool preroll connected during setup, or `None` before then, or in tests.
+#[must_use]
+pub fn shared_pool() -> Option<PgPool> {
+    pool_stats::pool()
+}
+
+/// Acquire a connection from the [shared pool][shared_pool], recording how long was spent waiting for it
+/// in `/monitor/status` like request connections.
+///
+/// Errors with [`sqlx::Error::PoolClosed`][] if the pool has not been set up.
+pub async fn acquire() -> sqlx::Result<PoolConnection<Postgres>> {
+    match pool_stats::pool() {
+        Some(pool) => pool_stats::acquire(&pool).await,
+        None => Err(sqlx::Error::PoolClosed),
+    }
+}
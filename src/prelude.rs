@@ -1,5 +1,40 @@
 //! Auto-import of all preroll extension traits.
 
+pub use crate::body::BodyStreamExt;
+pub use crate::config::ConfigExt;
+pub use crate::di::InjectRequestExt;
+pub use crate::flags::FlagsRequestExt;
+pub use crate::i18n::LocaleRequestExt;
+pub use crate::middleware::csrf::CsrfRequestExt;
+pub use crate::middleware::forwarded::ClientIpRequestExt;
+pub use crate::middleware::tenant::TenantRequestExt;
+pub use crate::routes::RouteExt;
+pub use crate::snapshot::SnapshotRequestExt;
+
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub use crate::middleware::postgres::{PostgresRequestExt, PostgresTransactionExt};
+
 #[cfg(feature = "postgres")]
 #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
-pub use crate::middleware::postgres::PostgresRequestExt;
+pub use crate::notifications::NotificationRequestExt;
+
+#[cfg(feature = "pg-jobs")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "pg-jobs")))]
+pub use crate::jobs::JobRequestExt;
+
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub use crate::outbox::OutboxRequestExt;
+
+#[cfg(feature = "honeycomb")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "honeycomb")))]
+pub use crate::middleware::trace::TraceRequestExt;
+
+#[cfg(feature = "sessions")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sessions")))]
+pub use crate::sessions::SessionRequestExt;
+
+#[cfg(feature = "sqlite")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sqlite")))]
+pub use crate::middleware::sqlite::SqliteRequestExt;
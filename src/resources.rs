@@ -0,0 +1,122 @@
+//! The resources preroll constructs during setup, for reuse by the application's state.
+//!
+//! A `setup_state` function passed to `preroll::main!` may take a [`PrerollResources`][] argument, rather than no arguments,
+//! to reuse preroll's database pools, redis client, and configuration, rather than parsing the environment again and
+//! creating duplicate pools.
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use preroll::PrerollResources;
+//!
+//! # #[allow(dead_code)]
+//! struct AppState {
+//!     greeting: String,
+//!     orders: surf::Client,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn setup_state(resources: PrerollResources) -> preroll::SetupResult<AppState> {
+//!     let greeting = resources.config.config_value("greeting")?.unwrap_or_else(|| "Hello!".to_string());
+//!     let orders = resources
+//!         .http_client("http://orders.internal/api/v1/")
+//!         .map_err(|error| color_eyre::eyre::eyre!(error))?;
+//!     Ok(AppState { greeting, orders })
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: tide::Route<'_, Arc<AppState>>) {
+//!     server
+//!         .at("greeting")
+//!         .get(|req: tide::Request<Arc<AppState>>| async move { Ok(req.state().greeting.clone()) });
+//! }
+//!
+//! preroll::main!("hello-world", setup_state, setup_routes);
+//! ```
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+use surf::{Client, Url};
+
+use crate::Config;
+
+/// The default timeout of clients from [`PrerollResources::http_client`][].
+const HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Resources constructed by preroll before `setup_state` is called.
+///
+/// See [`preroll::resources`][crate::resources] for details.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PrerollResources {
+    /// The service name, from `preroll::main!("service_name", ...)`.
+    pub service_name: &'static str,
+    /// A snapshot of the configuration, read with [`ConfigExt`][crate::prelude::ConfigExt].
+    pub config: Config,
+    /// The postgres pool shared with [`PostgresRequestExt`][crate::prelude::PostgresRequestExt].
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    pub pg_pool: sqlx::PgPool,
+    /// The SQLite pool shared with [`SqliteRequestExt`][crate::prelude::SqliteRequestExt].
+    #[cfg(feature = "sqlite")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "sqlite")))]
+    pub sqlite_pool: sqlx::SqlitePool,
+    /// A redis client for `REDISURL`, if it is set.
+    #[cfg(feature = "redis")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "redis")))]
+    pub redis: Option<redis::Client>,
+}
+
+impl PrerollResources {
+    /// A new surf client for the upstream service at `base_url`, with a timeout of 30 seconds.
+    pub fn http_client(&self, base_url: &str) -> surf::Result<Client> {
+        let client = surf::Config::new()
+            .set_base_url(Url::parse(base_url)?)
+            .set_timeout(Some(HTTP_CLIENT_TIMEOUT))
+            .try_into()?;
+        Ok(client)
+    }
+}
+
+#[cfg(all(
+    test,
+    not(any(feature = "postgres", feature = "sqlite", feature = "redis"))
+))]
+mod tests {
+    use super::*;
+    use crate::setup::{setup_resources, StateSetup};
+    use crate::SetupResult;
+
+    async fn setup_without_resources() -> SetupResult<&'static str> {
+        Ok("no resources")
+    }
+
+    async fn setup_with_resources(resources: PrerollResources) -> SetupResult<&'static str> {
+        Ok(resources.service_name)
+    }
+
+    #[async_std::test]
+    async fn state_setup_takes_resources_or_nothing() -> SetupResult<()> {
+        let resources = setup_resources("resources-test").await?;
+
+        let state = setup_without_resources
+            .setup_state(resources.clone())
+            .await?;
+        assert_eq!(state, "no resources");
+        let state = setup_with_resources.setup_state(resources.clone()).await?;
+        assert_eq!(state, "resources-test");
+
+        let client = resources
+            .http_client("http://localhost:8080/api/v1/")
+            .map_err(|error| color_eyre::eyre::eyre!(error))?;
+        assert_eq!(
+            client.config().base_url.as_ref().map(Url::as_str),
+            Some("http://localhost:8080/api/v1/")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Consistent JSON success responses.
+//!
+//! As [`JsonError`][crate::JsonError] is for failures, [`JsonResponse`][] is the envelope for successful JSON responses:
+//!
+//! ```text
+//! {
+//!   "status": 201,
+//!   "data": { "id": 42, "name": "Tea" },
+//!   "request_id": "00000000-0000-0000-0000-000000000000"
+//! }
+//! ```
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::respond;
+//! use serde::Serialize;
+//! use tide::{Request, Route};
+//!
+//! #[derive(Serialize)]
+//! struct Product {
+//!     id: u64,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("products")
+//!         .post(|req: Request<Arc<()>>| async move {
+//!             let product = Product { id: 42 };
+//!             respond::created_json(&req, &product, &format!("/api/v1/products/{}", product.id))
+//!         });
+//!     server
+//!         .at("products/:id")
+//!         .get(|req: Request<Arc<()>>| async move {
+//!             let id = req.param("id")?.parse()?;
+//!             respond::ok_json(&req, &Product { id })
+//!         })
+//!         .delete(|_req| async { Ok(respond::no_content()) });
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use tide::http::headers::LOCATION;
+use tide::{Request, Response, StatusCode};
+
+use crate::json;
+use crate::middleware::extension_types::RequestId;
+
+/// The structure of a successful JSON response, as produced by [`ok_json`][] and [`created_json`][].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonResponse<T> {
+    /// The http status code.
+    pub status: u16,
+    /// The response data.
+    pub data: T,
+    /// The id assigned to the request, possibly from an incoming header.
+    pub request_id: Option<String>,
+}
+
+/// A `200 OK` response, with `data` in a [`JsonResponse`][] envelope.
+pub fn ok_json<State>(req: &Request<State>, data: &impl Serialize) -> tide::Result<Response> {
+    envelope(req, StatusCode::Ok, data)
+}
+
+/// A `201 Created` response, with `data` in a [`JsonResponse`][] envelope, and a `Location` header of `location`,
+/// the url of the created resource.
+pub fn created_json<State>(
+    req: &Request<State>,
+    data: &impl Serialize,
+    location: &str,
+) -> tide::Result<Response> {
+    let mut res = envelope(req, StatusCode::Created, data)?;
+    res.insert_header(LOCATION, location);
+    Ok(res)
+}
+
+/// A `204 No Content` response, without a body.
+#[must_use]
+pub fn no_content() -> Response {
+    Response::new(StatusCode::NoContent)
+}
+
+fn envelope<State>(
+    req: &Request<State>,
+    status: StatusCode,
+    data: &impl Serialize,
+) -> tide::Result<Response> {
+    let envelope = JsonResponse {
+        status: status as u16,
+        data,
+        request_id: req.ext::<RequestId>().map(|id| id.as_str().to_string()),
+    };
+
+    let mut res = Response::new(status);
+    res.set_body(json::body(&envelope)?);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tide::http::{self, Method};
+
+    use super::*;
+    use crate::middleware::requestid::RequestIdMiddleware;
+    use crate::test_utils;
+
+    #[async_std::test]
+    async fn responds_with_envelopes() -> tide::Result<()> {
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.at("/products").post(|req: Request<()>| async move {
+            created_json(&req, &json!({ "id": 42 }), "/products/42")
+        });
+
+        let mut req = test_utils::request(Method::Post, "/products");
+        req.insert_header("X-Request-Id", "4c4bd8b8-8d40-4d1e-8f5b-6a6f59d3c8e5");
+
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Created);
+        assert_eq!(
+            res.header(LOCATION).map(|v| v.as_str()),
+            Some("/products/42")
+        );
+
+        // The "test" feature assigns sequential ids rather than trusting the header.
+        let request_id = res.header("X-Request-Id").map(|v| v.as_str().to_string());
+        assert!(request_id.is_some());
+        assert_eq!(
+            res.body_json::<Value>().await?,
+            json!({
+                "status": 201,
+                "data": { "id": 42 },
+                "request_id": request_id,
+            })
+        );
+
+        assert_eq!(no_content().status(), StatusCode::NoContent);
+        Ok(())
+    }
+}
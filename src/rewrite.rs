@@ -0,0 +1,280 @@
+//! Request rewrites which run before routing.
+//!
+//! Rewrites replace rules such as legacy path aliases, header canonicalization, or tenant-prefix stripping
+//! which would otherwise live in a proxy in front of the service.
+//!
+//! Rewrites run in registration order, before routing and before any other middleware except `ClacksMiddleware`,
+//! including for `/monitor/` routes. Each rewrite which changes a request is recorded as `rewrites` in the
+//! response log record.
+//!
+//! ## Example:
+//!
+//! ```
+//! use preroll::rewrite;
+//!
+//! rewrite::register("legacy-users", rewrite::path_alias("/users", "/api/v1/users"));
+//! rewrite::register("tenant-prefix", rewrite::strip_path_prefix("/acme"));
+//! rewrite::register("legacy-request-id", rewrite::rename_header("X-Req-Id", "X-Request-Id"));
+//!
+//! // Any function which returns `true` when it has changed the request.
+//! rewrite::register("lowercase-path", |req: &mut tide::http::Request| {
+//!     let path = req.url().path().to_owned();
+//!     let lowercase = path.to_lowercase();
+//!     if path == lowercase {
+//!         return false;
+//!     }
+//!     req.url_mut().set_path(&lowercase);
+//!     true
+//! });
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+use tide::http::headers::{HeaderName, ACCEPT};
+use tide::http::Request;
+
+type RewriteFn = dyn Fn(&mut Request) -> bool + Send + Sync;
+
+static REWRITES: Lazy<RwLock<Vec<Rewrite>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+#[derive(Clone)]
+struct Rewrite {
+    name: &'static str,
+    rewrite: Arc<RewriteFn>,
+}
+
+/// Register a rewrite, to run before routing for every request.
+///
+/// The rewrite must return `true` if it changed the request, so that it can be recorded in the log.
+///
+/// Registering a rewrite with the same name as an existing rewrite replaces it.
+pub fn register(
+    name: &'static str,
+    rewrite: impl Fn(&mut Request) -> bool + Send + Sync + 'static,
+) {
+    if let Ok(mut rewrites) = REWRITES.write() {
+        let rewrite = Rewrite {
+            name,
+            rewrite: Arc::new(rewrite),
+        };
+        match rewrites.iter_mut().find(|existing| existing.name == name) {
+            Some(existing) => *existing = rewrite,
+            None => rewrites.push(rewrite),
+        }
+    }
+}
+
+/// Rewrite requests for exactly the path `from` to the path `to`.
+pub fn path_alias(
+    from: impl Into<String>,
+    to: impl Into<String>,
+) -> impl Fn(&mut Request) -> bool + Send + Sync + 'static {
+    let from = from.into();
+    let to = to.into();
+    move |req| {
+        if req.url().path() != from {
+            return false;
+        }
+        req.url_mut().set_path(&to);
+        true
+    }
+}
+
+/// Strip `prefix` from the start of request paths which begin with it, e.g. a tenant prefix.
+pub fn strip_path_prefix(
+    prefix: impl Into<String>,
+) -> impl Fn(&mut Request) -> bool + Send + Sync + 'static {
+    let prefix = prefix.into().trim_end_matches('/').to_string();
+    move |req| {
+        let stripped = match req.url().path().strip_prefix(&prefix) {
+            Some("") => "/".to_string(),
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            _ => return false,
+        };
+        req.url_mut().set_path(&stripped);
+        true
+    }
+}
+
+/// Rename the header `from` to `to`, unless `to` is already present.
+///
+/// Panics if either header name is not valid ASCII.
+pub fn rename_header(
+    from: &'static str,
+    to: &'static str,
+) -> impl Fn(&mut Request) -> bool + Send + Sync + 'static {
+    let from = HeaderName::from(from);
+    let to = HeaderName::from(to);
+    move |req| {
+        if req.header(&to).is_some() {
+            return false;
+        }
+        match req.remove_header(&from) {
+            Some(values) => {
+                req.insert_header(&to, &values);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Route requests by the API version of a vendor media type in their `Accept` header, e.g.
+/// `Accept: application/vnd.myservice.v2+json` for `/users` as `/api/v2/users`, for clients which can't change paths.
+///
+/// Requests to `/api/` or `/monitor/` paths, or without such a media type, are not rewritten.
+/// Set up by preroll with the `ACCEPT_VERSION_VENDOR` setting, e.g. `myservice`.
+pub fn accept_version(
+    vendor: impl Into<String>,
+) -> impl Fn(&mut Request) -> bool + Send + Sync + 'static {
+    let prefix = format!("application/vnd.{}.v", vendor.into().to_ascii_lowercase());
+    move |req| {
+        let path = req.url().path();
+        if path.starts_with("/api/") || path.starts_with("/monitor/") {
+            return false;
+        }
+
+        let version = req.header(ACCEPT).and_then(|accept| {
+            accept
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .filter_map(|media_range| {
+                    let media_type = media_range.split(';').next()?.trim().to_ascii_lowercase();
+                    let version = media_type.strip_prefix(&prefix)?;
+                    let version = version.strip_suffix("+json").unwrap_or(version);
+                    version.parse::<u32>().ok()
+                })
+                .next()
+        });
+
+        match version {
+            Some(version) => {
+                let path = match path {
+                    "/" => format!("/api/v{}", version),
+                    path => format!("/api/v{}{}", version, path),
+                };
+                req.url_mut().set_path(&path);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Whether a request was routed by [`accept_version`][].
+pub(crate) fn is_accept_versioned(applied: &AppliedRewrites) -> bool {
+    applied
+        .0
+        .iter()
+        .any(|rewrite| rewrite.starts_with(ACCEPT_VERSION_REWRITE))
+}
+
+/// The name [`accept_version`][] is registered with by preroll.
+pub(crate) const ACCEPT_VERSION_REWRITE: &str = "accept-version";
+
+/// The rewrites which changed a request, as recorded in the log.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AppliedRewrites(pub Vec<String>);
+
+/// Apply all registered rewrites to a request.
+pub(crate) fn apply(req: &mut Request) -> AppliedRewrites {
+    let rewrites = match REWRITES.read() {
+        Ok(rewrites) => rewrites.clone(),
+        Err(_) => return AppliedRewrites::default(),
+    };
+
+    apply_rewrites(&rewrites, req)
+}
+
+fn apply_rewrites(rewrites: &[Rewrite], req: &mut Request) -> AppliedRewrites {
+    let mut applied = Vec::new();
+    for rewrite in rewrites {
+        let path = req.url().path().to_owned();
+        if (rewrite.rewrite)(req) {
+            if path == req.url().path() {
+                applied.push(rewrite.name.to_string());
+            } else {
+                applied.push(format!(
+                    "{}({} -> {})",
+                    rewrite.name,
+                    path,
+                    req.url().path()
+                ));
+            }
+        }
+    }
+    AppliedRewrites(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::Method;
+
+    use super::*;
+    use crate::test_utils;
+
+    fn request(path: &str) -> Request {
+        test_utils::request(Method::Get, path)
+    }
+
+    #[test]
+    fn applies_rewrites_in_order() {
+        let rewrites = vec![
+            Rewrite {
+                name: "tenant",
+                rewrite: Arc::new(strip_path_prefix("/acme/")),
+            },
+            Rewrite {
+                name: "alias",
+                rewrite: Arc::new(path_alias("/users", "/api/v1/users")),
+            },
+            Rewrite {
+                name: "header",
+                rewrite: Arc::new(rename_header("x-req-id", "x-request-id")),
+            },
+        ];
+
+        let mut req = request("/acme/users");
+        req.insert_header("X-Req-Id", "abc");
+        let applied = apply_rewrites(&rewrites, &mut req);
+
+        assert_eq!(req.url().path(), "/api/v1/users");
+        assert_eq!(req.header("X-Request-Id").map(|v| v.as_str()), Some("abc"));
+        assert!(req.header("X-Req-Id").is_none());
+        assert_eq!(
+            applied.0,
+            vec![
+                "tenant(/acme/users -> /users)",
+                "alias(/users -> /api/v1/users)",
+                "header"
+            ]
+        );
+
+        let mut req = request("/acmeco/users");
+        assert!(apply_rewrites(&rewrites, &mut req).0.is_empty());
+        assert_eq!(req.url().path(), "/acmeco/users");
+    }
+
+    #[test]
+    fn routes_by_accept_version() {
+        let rewrite = accept_version("MyService");
+
+        let mut req = request("/users/42");
+        req.insert_header(
+            "Accept",
+            "text/html;q=0.9, application/vnd.myservice.v2+json",
+        );
+        assert!(rewrite(&mut req));
+        assert_eq!(req.url().path(), "/api/v2/users/42");
+
+        let mut req = request("/api/v1/users/42");
+        req.insert_header("Accept", "application/vnd.myservice.v2+json");
+        assert!(!rewrite(&mut req));
+        assert_eq!(req.url().path(), "/api/v1/users/42");
+
+        let mut req = request("/users/42");
+        req.insert_header("Accept", "application/vnd.otherservice.v2+json");
+        assert!(!rewrite(&mut req));
+    }
+}
@@ -0,0 +1,220 @@
+//! Route setup helpers for `setup_routes` functions, through [`RouteExt`][], from the prelude.
+//!
+//! ## Route templates
+//!
+//! Routes set up with [`route`][RouteExt::route] rather than `at` record their template, e.g. `/api/v1/users/:id`,
+//! as a [`RouteTemplate`][] request and response extension. It is logged as `route` with each response, recorded on the
+//! honeycomb span as `http.route`, and counted per template under `"stats"` in `/monitor/status`,
+//! so that responses can be grouped by route rather than by each unique path.
+//!
+//! Routes extended with `at` from a templated route share its template, unless they are set up with `route` too.
+//!
+//! ## Route middleware
+//!
+//! Middleware can be attached to a route, and the routes extended from it, with [`with_preroll`][RouteExt::with_preroll],
+//! such as to require authentication for part of a version's routes.
+//!
+//! Route middleware always runs after all of preroll's middleware, and any added in `custom_setup`, whichever order
+//! they are set up in: requests have their request id, locale, and database transaction, and errors returned by route
+//! middleware are logged and become [`JsonError`][crate::JsonError] responses, as errors from handlers do.
+//! Route middleware is listed by route as `route_middleware` in the startup summary.
+//!
+//! Route middleware only applies to endpoints set up after it is attached, so should be attached first.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use tide::{Middleware, Next, Request, Route};
+//!
+//! struct AuthRequired;
+//!
+//! #[tide::utils::async_trait]
+//! impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuthRequired {
+//!     async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+//!         if req.header("Authorization").is_none() {
+//!             return Err(tide::Error::from_str(401, "Authorization is required"));
+//!         }
+//!         Ok(next.run(req).await)
+//!     }
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server.route("/users/:id").get(get_user);
+//!
+//!     let mut admin = server.at("/admin");
+//!     admin.with_preroll(AuthRequired);
+//!     admin.route("/users/:id").delete(delete_user);
+//! }
+//!
+//! async fn get_user(req: Request<Arc<()>>) -> tide::Result<String> {
+//!     Ok(req.param("id")?.to_string())
+//! }
+//!
+//! async fn delete_user(_req: Request<Arc<()>>) -> tide::Result<String> {
+//!     Ok("Deleted".to_string())
+//! }
+//! ```
+
+use std::fmt::{self, Display};
+
+use tide::{Middleware, Next, Request, Route};
+
+use crate::builtins::startup::record_startup;
+
+/// The template of the route which handled a request, e.g. `/api/v1/users/:id`. See [`preroll::routes`][crate::routes].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteTemplate(String);
+
+impl RouteTemplate {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for RouteTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An extension trait for setting up routes. See [`preroll::routes`][crate::routes].
+pub trait RouteExt<State> {
+    /// Extend the route with `path`, as with [`Route::at`][], recording the route's template for logs, traces,
+    /// and stats.
+    fn route(&mut self, path: &str) -> Route<'_, State>;
+
+    /// Attach `middleware` to the endpoints of this route, and of the routes extended from it, set up afterwards.
+    /// It runs after all of preroll's middleware, and any added in `custom_setup`.
+    fn with_preroll<M: Middleware<State>>(&mut self, middleware: M) -> &mut Self;
+}
+
+impl<'a, State: Clone + Send + Sync + 'static> RouteExt<State> for Route<'a, State> {
+    fn route(&mut self, path: &str) -> Route<'_, State> {
+        let mut route = self.at(path);
+        let template = RouteTemplate(route.path().to_string());
+        route.with(RouteTemplateMiddleware(template));
+        route
+    }
+
+    fn with_preroll<M: Middleware<State>>(&mut self, middleware: M) -> &mut Self {
+        // e.g. `my_service::auth::AuthRequired<my_service::State>` as `AuthRequired`.
+        let name = middleware.name();
+        let name = name.split('<').next().unwrap_or(name);
+        let name = name.rsplit("::").next().unwrap_or(name);
+        let listing = format!("{}={}", self.path(), name);
+        record_startup(|summary| summary.route_middleware.push(listing));
+
+        // Route middleware is wrapped around each endpoint, and so runs within every server middleware.
+        self.with(middleware)
+    }
+}
+
+/// Sets the template of its route on each request and response.
+#[derive(Debug)]
+struct RouteTemplateMiddleware(RouteTemplate);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RouteTemplateMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Templated routes within templated routes run later, and so replace the template on the request.
+        req.set_ext(self.0.clone());
+        let mut res = next.run(req).await;
+        if res.ext::<RouteTemplate>().is_none() {
+            res.insert_ext(self.0.clone());
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn records_the_most_specific_template() -> tide::Result<()> {
+        let mut server = tide::new();
+        let mut api = server.at("/api/v1");
+        let mut users = api.route("/users");
+        users.get(|_| async { Ok("users") });
+        users.route("/:id").get(|req: Request<()>| async move {
+            Ok(req
+                .ext::<RouteTemplate>()
+                .map(RouteTemplate::to_string)
+                .unwrap_or_default())
+        });
+        api.at("/health").get(|_| async { Ok("ok") });
+
+        let get = |path: &str| {
+            #[allow(clippy::unwrap_used)]
+            let req = http::Request::new(
+                Method::Get,
+                Url::parse(&format!("http://example.com{}", path)).unwrap(),
+            );
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        let mut res = get("/api/v1/users/42").await?;
+        assert_eq!(res.body_string().await?, "/api/v1/users/:id");
+        assert_eq!(
+            res.ext().get::<RouteTemplate>().map(RouteTemplate::as_str),
+            Some("/api/v1/users/:id")
+        );
+
+        let res = get("/api/v1/users").await?;
+        assert_eq!(
+            res.ext().get::<RouteTemplate>().map(RouteTemplate::as_str),
+            Some("/api/v1/users")
+        );
+
+        let res = get("/api/v1/health").await?;
+        assert_eq!(res.ext().get::<RouteTemplate>(), None);
+        Ok(())
+    }
+
+    #[derive(Clone, Debug)]
+    struct RecordOrder(Arc<Mutex<Vec<&'static str>>>, &'static str);
+
+    #[tide::utils::async_trait]
+    impl Middleware<()> for RecordOrder {
+        async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(self.1);
+            Ok(next.run(req).await)
+        }
+    }
+
+    #[async_std::test]
+    async fn runs_route_middleware_within_server_middleware() -> tide::Result<()> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut server = tide::new();
+        let mut admin = server.at("/admin");
+        admin.with_preroll(RecordOrder(order.clone(), "route"));
+        admin.route("/users/:id").get(|_| async { Ok("ok") });
+        server.with(RecordOrder(order.clone(), "server"));
+
+        #[allow(clippy::unwrap_used)]
+        let req = http::Request::new(
+            Method::Get,
+            Url::parse("http://example.com/admin/users/42").unwrap(),
+        );
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            *order.lock().unwrap_or_else(PoisonError::into_inner),
+            ["server", "route"]
+        );
+        Ok(())
+    }
+}
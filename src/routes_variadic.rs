@@ -1,8 +1,11 @@
-use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
-use tide::Route;
+use tide::{Route, Server};
+
+use crate::guards::{Guard, GuardMiddleware};
+use crate::routes::RouteExt;
+use crate::versions::VersionPolicyMiddleware;
 
 /// [Variadic-argument][] route versioning is implemented via this struct for [`From<T>`][] with Single-argument, Tuple, and Vec types.
 ///
@@ -12,14 +15,22 @@ use tide::Route;
 /// - A single routes function argument.
 ///     - Will become `/api/v1`.
 ///     - E.g. `routes_v1` or `routes_setup`.
-/// - A [Tuple][] of 1 to 4 routes functions.
-///     - Will become `/api/v1` through `/api/v4`.
+/// - A [Tuple][] of 1 to 12 routes functions.
+///     - Will become `/api/v1` through `/api/v12`.
 ///     - E.g. `(routes_v1, routes_v2, routes_v3)`
+/// - A `VariadicRoutes` built with [`push`][VariadicRoutes::push], for any number of versions.
+///     - Will become `/api/v{N}` for the Nth routes function pushed.
+///     - E.g. `VariadicRoutes::new().push(routes_v1).push(routes_v2)`
 /// - A [Vec][] of boxed routes functions.
-///     - If you need this you may want to reconsider your architecture!
 ///     - Will become `/api/v{N}` where N is the index + 1.
 ///     - E.g. `vec![Box::new(routes_v1), Box::new(routes_v2)]`
 ///
+/// Routes common to every version, such as `/whoami`, can be set up once with [`shared`][VariadicRoutes::shared],
+/// e.g. `VariadicRoutes::from((routes_v1, routes_v2)).shared(routes_common)`.
+///
+/// Authorization requirements can be declared for all of the routes with [`guarded`][VariadicRoutes::guarded],
+/// e.g. `VariadicRoutes::from(routes_v2).guarded(RequireScope("admin"))`. See [`preroll::guards`][crate::guards].
+///
 /// [`From<T>`]: https://doc.rust-lang.org/std/convert/trait.From.html
 /// [Tuple]: https://doc.rust-lang.org/std/primitive.tuple.html
 /// [Variadic-argument]: https://en.wikipedia.org/wiki/Variadic_function
@@ -32,88 +43,136 @@ where
 {
     _phantom_state: PhantomData<*const State>,
     pub routes: Vec<Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>>,
+    shared: Vec<Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>>,
+    guards: Vec<Arc<dyn Guard<Arc<State>>>>,
 }
 
-impl<State, RoutesFn> From<RoutesFn> for VariadicRoutes<State>
+impl<State> VariadicRoutes<State>
 where
     State: Send + Sync + 'static,
-    RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
 {
-    fn from(routes: RoutesFn) -> Self {
+    /// An empty set of routes functions, to build with [`push`][VariadicRoutes::push].
+    #[must_use]
+    pub fn new() -> Self {
         VariadicRoutes {
             _phantom_state: PhantomData,
-            routes: vec![Box::new(routes)],
+            routes: Vec::new(),
+            shared: Vec::new(),
+            guards: Vec::new(),
         }
     }
-}
 
-// For completeness only
-impl<State, RoutesFn> From<(RoutesFn,)> for VariadicRoutes<State>
-where
-    State: Send + Sync + 'static,
-    RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + Debug + 'static,
-{
-    fn from(routes: (RoutesFn,)) -> Self {
-        VariadicRoutes {
-            _phantom_state: PhantomData,
-            routes: vec![Box::new(routes.0)],
-        }
+    /// Add the routes function of the next version, e.g. `/api/v3` after two others.
+    #[must_use]
+    pub fn push<RoutesFn>(mut self, routes: RoutesFn) -> Self
+    where
+        RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
+    {
+        self.routes.push(Box::new(routes));
+        self
     }
-}
 
-impl<State, RoutesFn1, RoutesFn2> From<(RoutesFn1, RoutesFn2)> for VariadicRoutes<State>
-where
-    State: Send + Sync + 'static,
-    RoutesFn1: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn2: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-{
-    fn from(routes: (RoutesFn1, RoutesFn2)) -> Self {
-        VariadicRoutes {
-            _phantom_state: PhantomData,
-            routes: vec![Box::new(routes.0), Box::new(routes.1)],
+    /// Add a routes function which is set up under every version, e.g. for `/api/v1/whoami` and `/api/v2/whoami`,
+    /// with each version's [policy][crate::versions]. A version's own routes take precedence over shared routes.
+    #[must_use]
+    pub fn shared<RoutesFn>(mut self, routes: RoutesFn) -> Self
+    where
+        RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
+    {
+        self.shared.push(Box::new(routes));
+        self
+    }
+
+    /// Add a [guard][crate::guards] which every request to these routes, of every version, must pass,
+    /// or be rejected with a `403 Forbidden`, e.g. `RequireScope("admin")`.
+    #[must_use]
+    pub fn guarded(mut self, guard: impl Guard<Arc<State>>) -> Self {
+        self.guards.push(Arc::new(guard));
+        self
+    }
+
+    /// Set up each routes function at `/api/v{N}`, with the [policy][crate::versions] of its version,
+    /// and the shared routes functions under each.
+    pub(crate) fn mount(self, server: &mut Server<Arc<State>>) {
+        for (version, routes_fn) in (1..).zip(self.routes) {
+            let mut route = server.at(&format!("/api/v{}", version));
+            route.with(VersionPolicyMiddleware::new(version));
+            if !self.guards.is_empty() {
+                route.with_preroll(GuardMiddleware::new(self.guards.clone()));
+            }
+            for shared_fn in &self.shared {
+                // `at("/")` is the same path, with the version's middleware.
+                shared_fn(route.at("/"));
+            }
+            routes_fn(route);
         }
     }
 }
 
-impl<State, RoutesFn1, RoutesFn2, RoutesFn3> From<(RoutesFn1, RoutesFn2, RoutesFn3)>
-    for VariadicRoutes<State>
+impl<State> Default for VariadicRoutes<State>
 where
     State: Send + Sync + 'static,
-    RoutesFn1: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn2: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn3: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
 {
-    fn from(routes: (RoutesFn1, RoutesFn2, RoutesFn3)) -> Self {
-        VariadicRoutes {
-            _phantom_state: PhantomData,
-            routes: vec![Box::new(routes.0), Box::new(routes.1), Box::new(routes.2)],
-        }
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<State, RoutesFn1, RoutesFn2, RoutesFn3, RoutesFn4>
-    From<(RoutesFn1, RoutesFn2, RoutesFn3, RoutesFn4)> for VariadicRoutes<State>
+impl<State, RoutesFn> From<RoutesFn> for VariadicRoutes<State>
 where
     State: Send + Sync + 'static,
-    RoutesFn1: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn2: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn3: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
-    RoutesFn4: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
+    RoutesFn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,
 {
-    fn from(routes: (RoutesFn1, RoutesFn2, RoutesFn3, RoutesFn4)) -> Self {
-        VariadicRoutes {
-            _phantom_state: PhantomData,
-            routes: vec![
-                Box::new(routes.0),
-                Box::new(routes.1),
-                Box::new(routes.2),
-                Box::new(routes.3),
-            ],
-        }
+    fn from(routes: RoutesFn) -> Self {
+        VariadicRoutes::new().push(routes)
     }
 }
 
-// If you have api versioning beyond ... I don't know... 5?? you probably should reconsider your architecture!!
+/// `From` a tuple of routes functions, for each tuple size up to 12, in version order.
+macro_rules! impl_from_tuple {
+    ($(($($routes_fn:ident . $index:tt),+)),+ $(,)?) => {
+        $(
+            impl<State, $($routes_fn),+> From<($($routes_fn,)+)> for VariadicRoutes<State>
+            where
+                State: Send + Sync + 'static,
+                $($routes_fn: for<'r> Fn(Route<'r, Arc<State>>) + 'static,)+
+            {
+                fn from(routes: ($($routes_fn,)+)) -> Self {
+                    VariadicRoutes::new()$(.push(routes.$index))+
+                }
+            }
+        )+
+    };
+}
+
+impl_from_tuple! {
+    (RoutesFn1.0),
+    (RoutesFn1.0, RoutesFn2.1),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6),
+    (RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6, RoutesFn8.7),
+    (
+        RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6, RoutesFn8.7,
+        RoutesFn9.8
+    ),
+    (
+        RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6, RoutesFn8.7,
+        RoutesFn9.8, RoutesFn10.9
+    ),
+    (
+        RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6, RoutesFn8.7,
+        RoutesFn9.8, RoutesFn10.9, RoutesFn11.10
+    ),
+    (
+        RoutesFn1.0, RoutesFn2.1, RoutesFn3.2, RoutesFn4.3, RoutesFn5.4, RoutesFn6.5, RoutesFn7.6, RoutesFn8.7,
+        RoutesFn9.8, RoutesFn10.9, RoutesFn11.10, RoutesFn12.11
+    ),
+}
+
+// If you have api versioning beyond 12 you probably should reconsider your architecture!!
 #[allow(clippy::type_complexity)]
 impl<State> From<Vec<Box<dyn for<'r> Fn(Route<'r, Arc<State>>)>>> for VariadicRoutes<State>
 where
@@ -123,6 +182,72 @@ where
         VariadicRoutes {
             _phantom_state: PhantomData,
             routes,
+            shared: Vec::new(),
+            guards: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes_v1(_: Route<'_, Arc<()>>) {}
+    fn routes_v2(_: Route<'_, Arc<()>>) {}
+
+    #[test]
+    fn versions_tuples_beyond_four() {
+        let routes: VariadicRoutes<()> = (
+            routes_v1, routes_v2, routes_v2, routes_v2, routes_v2, routes_v2,
+        )
+            .into();
+        assert_eq!(routes.routes.len(), 6);
+
+        let routes = VariadicRoutes::<()>::new()
+            .push(routes_v1)
+            .push(routes_v2)
+            .push(|_: Route<'_, Arc<()>>| {});
+        assert_eq!(routes.routes.len(), 3);
+    }
+
+    #[async_std::test]
+    async fn mounts_shared_routes_under_every_version() -> tide::Result<()> {
+        use tide::http::{self, Method, Url};
+
+        let mut server = tide::with_state(Arc::new(()));
+        VariadicRoutes::from((routes_v1, |mut route: Route<'_, Arc<()>>| {
+            route.at("/whoami").get(|_| async { Ok("v2") });
+        }))
+        .shared(|mut route: Route<'_, Arc<()>>| {
+            route.at("/whoami").get(|_| async { Ok("shared") });
+        })
+        .mount(&mut server);
+
+        for (path, body) in [("/api/v1/whoami", "shared"), ("/api/v2/whoami", "v2")] {
+            let req =
+                http::Request::new(Method::Get, Url::parse("http://example.com")?.join(path)?);
+            let mut res: http::Response = server.respond(req).await?;
+            assert_eq!(res.body_string().await?, body);
         }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn guards_every_version() -> tide::Result<()> {
+        use tide::http::{self, Method, Url};
+
+        use crate::guards::RequireScope;
+
+        let mut server = tide::with_state(Arc::new(()));
+        VariadicRoutes::from((routes_v1, |mut route: Route<'_, Arc<()>>| {
+            route.at("/users").get(|_| async { Ok("users") });
+        }))
+        .guarded(RequireScope("admin"))
+        .mount(&mut server);
+
+        let req = http::Request::new(Method::Get, Url::parse("http://example.com/api/v2/users")?);
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), 403);
+        Ok(())
     }
 }
@@ -0,0 +1,344 @@
+//! Resolution of `aws-sm://` and `aws-ssm://` setting values from AWS Secrets Manager / SSM Parameter Store.
+//!
+//! Requests are signed with AWS Signature Version 4, using credentials from `AWS_ACCESS_KEY_ID` /
+//! `AWS_SECRET_ACCESS_KEY` / `AWS_SESSION_TOKEN`, or else from the ECS container credentials endpoint.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use async_std::task;
+use chrono::Utc;
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
+use hmac::Mac;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::logging::log_kv;
+use crate::utils::hmac_sha256;
+
+const SECRETS_MANAGER_SCHEME: &str = "aws-sm://";
+const SSM_SCHEME: &str = "aws-ssm://";
+
+/// Resolved values, by reference, e.g. `aws-sm://prod/my-service/pgurl`.
+static RESOLVED: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The resolved value of a secret reference, if `value` is one.
+pub(crate) fn resolved(value: &str) -> Option<String> {
+    if !is_reference(value) {
+        return None;
+    }
+    RESOLVED.read().ok()?.get(value).cloned()
+}
+
+fn is_reference(value: &str) -> bool {
+    value.starts_with(SECRETS_MANAGER_SCHEME) || value.starts_with(SSM_SCHEME)
+}
+
+/// Resolve every secret reference in the configuration, failing if any cannot be resolved.
+///
+/// Returns the number of references resolved.
+pub(crate) async fn resolve_all() -> Result<usize> {
+    let mut references: Vec<String> = config::values()
+        .into_iter()
+        .filter(|value| is_reference(value))
+        .collect();
+    references.sort();
+    references.dedup();
+
+    if references.is_empty() {
+        return Ok(0);
+    }
+
+    let client = AwsClient::from_env().await?;
+
+    let mut resolved = HashMap::new();
+    for reference in references.iter() {
+        let value = client
+            .resolve(reference)
+            .await
+            .wrap_err_with(|| format!("Unable to resolve {}", reference))?;
+        resolved.insert(reference.clone(), value);
+    }
+
+    if let Ok(mut current) = RESOLVED.write() {
+        current.extend(resolved);
+    }
+
+    Ok(references.len())
+}
+
+/// Re-resolve all secret references every `interval`, keeping the previous values on failure.
+///
+/// Only settings which are read after a refresh see the refreshed values.
+pub(crate) fn spawn_refresh(interval: Duration) {
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            if let Err(error) = resolve_all().await {
+                log_kv!(
+                    warn,
+                    {
+                        refresh_secs: interval.as_secs(),
+                    },
+                    "Failed to refresh AWS secrets, keeping previous values: {:?}", error
+                );
+            }
+        }
+    });
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[derive(Debug)]
+struct AwsClient {
+    region: String,
+    credentials: Credentials,
+}
+
+impl AwsClient {
+    async fn from_env() -> Result<Self> {
+        let region = config::var("AWS_REGION")
+            .or_else(|_| config::var("AWS_DEFAULT_REGION"))
+            .map_err(|_| eyre!("AWS_REGION must be set to resolve AWS secrets"))?;
+
+        let credentials = match (
+            config::var("AWS_ACCESS_KEY_ID"),
+            config::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key_id), Ok(secret_access_key)) => Credentials {
+                access_key_id,
+                secret_access_key,
+                token: config::var("AWS_SESSION_TOKEN").ok(),
+            },
+            _ => match config::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+                Ok(uri) => surf::get(format!("http://169.254.170.2{}", uri))
+                    .recv_json()
+                    .await
+                    .map_err(|error| eyre!(error.into_inner()))
+                    .wrap_err("Unable to fetch ECS container credentials")?,
+                Err(_) => bail!(
+                    "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or ECS container credentials, are required to resolve AWS secrets"
+                ),
+            },
+        };
+
+        Ok(Self {
+            region,
+            credentials,
+        })
+    }
+
+    async fn resolve(&self, reference: &str) -> Result<String> {
+        if let Some(secret_id) = reference.strip_prefix(SECRETS_MANAGER_SCHEME) {
+            // `aws-sm://{secret-id}#{json-key}` selects a single key of a JSON secret.
+            let (secret_id, json_key) = match secret_id.split_once('#') {
+                Some((secret_id, json_key)) => (secret_id, Some(json_key)),
+                None => (secret_id, None),
+            };
+
+            let res = self
+                .call(
+                    "secretsmanager",
+                    "secretsmanager.GetSecretValue",
+                    json!({ "SecretId": secret_id }),
+                )
+                .await?;
+            let secret = res["SecretString"]
+                .as_str()
+                .ok_or_else(|| eyre!("Secret has no SecretString"))?;
+
+            match json_key {
+                Some(json_key) => {
+                    let values: Value = serde_json::from_str(secret)?;
+                    match &values[json_key] {
+                        Value::String(value) => Ok(value.clone()),
+                        Value::Null => bail!("Secret has no key {}", json_key),
+                        value => Ok(value.to_string()),
+                    }
+                }
+                None => Ok(secret.to_string()),
+            }
+        } else if let Some(name) = reference.strip_prefix(SSM_SCHEME) {
+            let res = self
+                .call(
+                    "ssm",
+                    "AmazonSSM.GetParameter",
+                    json!({ "Name": name, "WithDecryption": true }),
+                )
+                .await?;
+            res["Parameter"]["Value"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| eyre!("Parameter has no Value"))
+        } else {
+            bail!("Not an AWS secret reference")
+        }
+    }
+
+    /// Make a signed AWS JSON protocol request.
+    async fn call(&self, service: &str, target: &str, body: Value) -> Result<Value> {
+        let host = format!("{}.{}.amazonaws.com", service, self.region);
+        let body = body.to_string();
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+            ("x-amz-target", target.to_string()),
+        ];
+        if let Some(token) = &self.credentials.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.sort();
+
+        let authorization = sign(
+            &self.credentials,
+            &self.region,
+            service,
+            &amz_date,
+            &headers,
+            &body,
+        );
+
+        let mut req = surf::post(format!("https://{}/", host)).body(body);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            req = req.header(*name, value.as_str());
+        }
+
+        let mut res = req
+            .header("authorization", authorization)
+            .await
+            .map_err(|error| eyre!(error.into_inner()))?;
+
+        let status = res.status();
+        let res_body = res
+            .body_string()
+            .await
+            .map_err(|error| eyre!(error.into_inner()))?;
+        if !status.is_success() {
+            bail!("{} returned {}: {}", target, status, res_body);
+        }
+
+        Ok(serde_json::from_str(&res_body)?)
+    }
+}
+
+/// Compute the SigV4 `Authorization` header for a `POST /` request, with `headers` sorted by (lowercase) name.
+fn sign(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(body.as_bytes()))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id, scope, signed_headers, signature
+    )
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    hmac(&key, "aws4_request")
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    hmac_sha256(key, data.as_bytes())
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_signing_key() {
+        // From the AWS Signature Version 4 documentation examples.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20150830",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+    }
+
+    #[test]
+    fn signs_requests() {
+        // The `post-vanilla` case of the AWS Signature Version 4 test suite.
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            token: None,
+        };
+        let headers = vec![
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+
+        let authorization = sign(
+            &credentials,
+            "us-east-1",
+            "service",
+            "20150830T123600Z",
+            &headers,
+            "",
+        );
+
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+            SignedHeaders=host;x-amz-date, \
+            Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+}
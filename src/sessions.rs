@@ -0,0 +1,438 @@
+//! Cookie sessions, with pluggable stores.
+//!
+//! With the `"sessions"` feature, every request has a [`Session`][], read with [`req.session()`][SessionRequestExt::session]
+//! from the prelude. A session's data is kept in a store, and the client is given only its id, in a cookie
+//! (`SESSION_COOKIE`, default `preroll_session`) signed with `SESSION_SECRET`, which is `HttpOnly` and `SameSite=Lax`,
+//! and `Secure` in production. Cookies with invalid signatures are ignored.
+//!
+//! Sessions are only stored, and cookies only set, once data has been inserted. A session expires `SESSION_TTL_SECS`
+//! (default `86400`, one day) after it was last changed.
+//!
+//! Sessions are stored in redis if the `"redis"` feature is enabled and `REDISURL` is set, or else in the `sessions`
+//! postgres table, created if it does not exist, if the `"postgres"` feature is enabled, or else in memory, which is only
+//! suitable for a single instance. Other stores can be used by implementing [`SessionStore`][] and calling [`set_store`][]
+//! during setup, e.g. in `setup_state`.
+//!
+//! If `SESSION_SECRET` is not set, a random secret is generated, which invalidates every session when the service
+//! restarts, and which instances do not share. It is required in production.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::prelude::*;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("login")
+//!         .post(|req: Request<Arc<()>>| async move {
+//!             let session = req.session();
+//!             // A new id on login prevents session fixation.
+//!             session.regenerate();
+//!             session.insert("user_id", 42)?;
+//!             Ok("logged in")
+//!         });
+//!     server
+//!         .at("me")
+//!         .get(|req: Request<Arc<()>>| async move {
+//!             match req.session().get::<u64>("user_id") {
+//!                 Some(user_id) => Ok(format!("user {}", user_id)),
+//!                 None => Err(tide::Error::from_str(401, "Not logged in")),
+//!             }
+//!         });
+//!     server
+//!         .at("logout")
+//!         .post(|req: Request<Arc<()>>| async move {
+//!             req.session().destroy();
+//!             Ok("logged out")
+//!         });
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tide::Request;
+
+static STORE: OnceCell<Arc<dyn SessionStore>> = OnceCell::new();
+
+/// The data of a session.
+pub type SessionData = HashMap<String, Value>;
+
+/// Stores session data by session id.
+#[tide::utils::async_trait]
+pub trait SessionStore: Send + Sync + 'static {
+    /// The data of the session `id`, unless it does not exist or has expired.
+    async fn load(&self, id: &str) -> anyhow::Result<Option<SessionData>>;
+
+    /// Store `data` as the session `id`, expiring after `ttl`.
+    async fn store(&self, id: &str, data: &SessionData, ttl: Duration) -> anyhow::Result<()>;
+
+    /// Delete the session `id`.
+    async fn destroy(&self, id: &str) -> anyhow::Result<()>;
+}
+
+/// Set the session store.
+///
+/// Only the first store set is used.
+pub fn set_store(store: impl SessionStore) {
+    if STORE.set(Arc::new(store)).is_err() {
+        log::warn!("A session store is already set, ignoring another.");
+    }
+}
+
+/// The store which has been set, or else an in-memory store.
+pub(crate) fn store() -> Arc<dyn SessionStore> {
+    STORE
+        .get_or_init(|| Arc::new(MemoryStore::default()))
+        .clone()
+}
+
+/// Stores sessions in memory, for a single instance.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+#[tide::utils::async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str) -> anyhow::Result<Option<SessionData>> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Session store lock poisoned"))?;
+
+        let now = Instant::now();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        Ok(sessions.get(id).map(|(data, _)| data.clone()))
+    }
+
+    async fn store(&self, id: &str, data: &SessionData, ttl: Duration) -> anyhow::Result<()> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Session store lock poisoned"))?;
+
+        sessions.insert(id.to_string(), (data.clone(), Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn destroy(&self, id: &str) -> anyhow::Result<()> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Session store lock poisoned"))?;
+
+        sessions.remove(id);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionState {
+    /// The id the session was loaded with, if any.
+    pub(crate) id: Option<String>,
+    pub(crate) data: SessionData,
+    pub(crate) changed: bool,
+    pub(crate) regenerate: bool,
+    pub(crate) destroyed: bool,
+}
+
+/// The current request's session. Clones share the same session.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    pub(crate) fn new(state: SessionState) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The value of `key`, if set and deserializable as a `T`.
+    #[must_use]
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let state = self.state();
+        let value = state.data.get(key)?.clone();
+        serde_json::from_value(value).ok()
+    }
+
+    /// Set `key` to `value`.
+    pub fn insert(&self, key: impl Into<String>, value: impl Serialize) -> serde_json::Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut state = self.state();
+        state.data.insert(key.into(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    /// Remove `key`.
+    pub fn remove(&self, key: &str) {
+        let mut state = self.state();
+        if state.data.remove(key).is_some() {
+            state.changed = true;
+        }
+    }
+
+    /// Whether the session has no data.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.state().data.is_empty()
+    }
+
+    /// Move the session to a new id, deleting the old one, such as on login to prevent session fixation.
+    pub fn regenerate(&self) {
+        let mut state = self.state();
+        state.regenerate = true;
+        state.changed = true;
+    }
+
+    /// Delete the session, and expire its cookie.
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+/// An extension trait for the current request's session.
+pub trait SessionRequestExt {
+    /// The request's session, which is empty if the request has none.
+    ///
+    /// Changes are only kept if [`SessionMiddleware`][crate::SessionMiddleware] is installed,
+    /// as it is by preroll's setup.
+    fn session(&self) -> Session;
+}
+
+impl<State> SessionRequestExt for Request<State> {
+    fn session(&self) -> Session {
+        self.ext::<Session>().cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub(crate) use self::postgres::setup;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use color_eyre::eyre::Result;
+    use sqlx::postgres::PgPool;
+    use sqlx::types::Json;
+
+    use super::{SessionData, SessionStore, STORE};
+    use crate::builtins::schema;
+
+    /// Stores sessions in the `sessions` postgres table, shared by all instances.
+    #[derive(Debug, Clone)]
+    pub struct PostgresStore {
+        pool: PgPool,
+    }
+
+    impl PostgresStore {
+        /// Create a new `PostgresStore`, which expects the `sessions` table to exist.
+        #[must_use]
+        pub fn new(pool: PgPool) -> Self {
+            Self { pool }
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl SessionStore for PostgresStore {
+        async fn load(&self, id: &str) -> anyhow::Result<Option<SessionData>> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            let data: Option<(Json<SessionData>,)> =
+                sqlx::query_as("SELECT data FROM sessions WHERE id = $1 AND expires_at > now()")
+                    .bind(id)
+                    .fetch_optional(&mut conn)
+                    .await?;
+
+            Ok(data.map(|(Json(data),)| data))
+        }
+
+        async fn store(&self, id: &str, data: &SessionData, ttl: Duration) -> anyhow::Result<()> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            sqlx::query(
+                "INSERT INTO sessions (id, data, expires_at) \
+                    VALUES ($1, $2, now() + make_interval(secs => $3)) \
+                    ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires_at = EXCLUDED.expires_at",
+            )
+            .bind(id)
+            .bind(Json(data))
+            .bind(ttl.as_secs_f64())
+            .execute(&mut conn)
+            .await?;
+
+            Ok(())
+        }
+
+        async fn destroy(&self, id: &str) -> anyhow::Result<()> {
+            let mut conn = crate::builtins::pool_stats::acquire(&self.pool).await?;
+
+            // Expired sessions are deleted along the way.
+            sqlx::query("DELETE FROM sessions WHERE id = $1 OR expires_at <= now()")
+                .bind(id)
+                .execute(&mut conn)
+                .await?;
+
+            Ok(())
+        }
+    }
+
+    /// Unless another store has been set, create the `sessions` table and store sessions there.
+    pub(crate) async fn setup(pool: &PgPool) -> Result<()> {
+        if STORE.get().is_some() {
+            return Ok(());
+        }
+
+        schema::migrate(pool, schema::SESSIONS).await?;
+        STORE.set(Arc::new(PostgresStore::new(pool.clone()))).ok();
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::time::Duration;
+
+    use async_std::sync::Mutex;
+    use redis::aio::MultiplexedConnection;
+    use redis::Client;
+
+    use super::{SessionData, SessionStore};
+
+    /// The prefix of every session key in redis.
+    const KEY_PREFIX: &str = "preroll:session:";
+
+    /// Stores sessions in redis, as JSON strings which expire after their TTL.
+    pub struct RedisStore {
+        client: Client,
+        conn: Mutex<Option<MultiplexedConnection>>,
+    }
+
+    impl std::fmt::Debug for RedisStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisStore").finish()
+        }
+    }
+
+    impl RedisStore {
+        /// Create a new `RedisStore` for a `redis://` url, connecting once first used.
+        pub fn new(url: &str) -> redis::RedisResult<Self> {
+            Ok(Self {
+                client: Client::open(url)?,
+                conn: Mutex::new(None),
+            })
+        }
+
+        /// Run `cmd` on a shared connection, connecting if there is none, and reconnecting next time after an error.
+        async fn query<T: redis::FromRedisValue>(&self, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+            let mut conn = self.conn.lock().await;
+            let mut connection = match conn.as_ref() {
+                Some(connection) => connection.clone(),
+                None => {
+                    let connected = self.client.get_multiplexed_async_std_connection().await?;
+                    *conn = Some(connected.clone());
+                    connected
+                }
+            };
+            drop(conn);
+
+            let result = cmd.query_async(&mut connection).await;
+            if result.is_err() {
+                *self.conn.lock().await = None;
+            }
+            result
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl SessionStore for RedisStore {
+        async fn load(&self, id: &str) -> anyhow::Result<Option<SessionData>> {
+            let data: Option<Vec<u8>> = self
+                .query(redis::cmd("GET").arg(format!("{}{}", KEY_PREFIX, id)))
+                .await?;
+
+            Ok(match data {
+                Some(data) => Some(serde_json::from_slice(&data)?),
+                None => None,
+            })
+        }
+
+        async fn store(&self, id: &str, data: &SessionData, ttl: Duration) -> anyhow::Result<()> {
+            self.query::<()>(
+                redis::cmd("SET")
+                    .arg(format!("{}{}", KEY_PREFIX, id))
+                    .arg(serde_json::to_vec(data)?)
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64),
+            )
+            .await?;
+            Ok(())
+        }
+
+        async fn destroy(&self, id: &str) -> anyhow::Result<()> {
+            self.query::<()>(redis::cmd("DEL").arg(format!("{}{}", KEY_PREFIX, id)))
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// If `REDISURL` is set, and no other store has been, store sessions in redis.
+#[cfg(feature = "redis")]
+pub(crate) fn setup_redis() -> color_eyre::eyre::Result<()> {
+    if STORE.get().is_some() {
+        return Ok(());
+    }
+
+    if let Ok(url) = crate::config::var("REDISURL") {
+        STORE.set(Arc::new(RedisStore::new(&url)?)).ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn memory_store_loads_and_expires() -> anyhow::Result<()> {
+        let store = MemoryStore::default();
+        let mut data = SessionData::new();
+        data.insert("user_id".to_string(), Value::from(42));
+
+        store.store("a", &data, Duration::from_secs(60)).await?;
+        store.store("b", &data, Duration::ZERO).await?;
+        assert_eq!(store.load("a").await?, Some(data));
+        assert_eq!(store.load("b").await?, None);
+
+        store.destroy("a").await?;
+        assert_eq!(store.load("a").await?, None);
+        Ok(())
+    }
+}
@@ -3,52 +3,115 @@
 //! These are exposed in the event they need to be used more manually, but use is discouraged.
 //! Prefer using `preroll::main!` whenever possible.
 
-use std::env;
 use std::future::Future;
+use std::io;
 use std::sync::Arc;
 
 use cfg_if::cfg_if;
+use color_eyre::eyre::eyre;
+use futures_lite::future;
 use tide::{Request, Server};
 
 pub use async_std::task::block_on;
 
-use crate::builtins::monitor::setup_monitor;
+use crate::builtins::deprecations::log_startup_deprecations;
+use crate::builtins::monitor::{check_dependencies_at_startup, setup_monitor};
+#[cfg(all(unix, not(feature = "lambda-http")))]
+use crate::builtins::reload::listen_for_sighup;
+use crate::builtins::reload::on_reload;
+#[cfg(not(feature = "lambda-http"))]
+use crate::builtins::scheduling::start_lag_probe;
+use crate::builtins::shutdown::drain;
+#[cfg(all(unix, not(feature = "lambda-http")))]
+use crate::builtins::shutdown::listen_for_sigterm;
+pub use crate::builtins::shutdown::{shutdown_handle, ShutdownHandle};
+pub use crate::builtins::startup::log_startup_summary;
+use crate::builtins::startup::record_startup;
+use crate::config;
+use crate::logging::log_kv;
 
 cfg_if! {
     if #[cfg(feature = "honeycomb")] {
         use tracing_honeycomb::new_blackhole_telemetry_layer;
         use tracing_subscriber::filter::LevelFilter;
-        use tracing_subscriber::prelude::*;
-        use tracing_subscriber::Registry;
+        use tracing_subscriber::reload;
 
         use crate::middleware::TraceMiddleware;
     }
 }
 
+#[cfg(all(feature = "honeycomb", not(feature = "lambda-http")))]
+use crate::builtins::honeycomb::{
+    reload_sampling, telemetry_layer, HeadSampleRate, HoneycombExporter, ResilientReporter,
+};
+#[cfg(all(feature = "honeycomb", not(feature = "lambda-http")))]
+use crate::builtins::sampling::SampleRules;
+
 cfg_if! {
-    if #[cfg(feature = "postgres")] {
-        use std::time::Duration;
+    if #[cfg(feature = "tracing-logs")] {
+        use tracing_log::LogTracer;
 
+        use crate::logging::tracing_log_layer;
+    } else if #[cfg(feature = "honeycomb")] {
+        use tracing_subscriber::layer::Identity;
+    }
+}
+
+#[cfg(any(feature = "honeycomb", feature = "tracing-logs"))]
+use tracing_subscriber::{prelude::*, Registry};
+
+#[cfg(any(feature = "postgres", feature = "aws-secrets"))]
+use std::time::Duration;
+
+cfg_if! {
+    if #[cfg(not(feature = "lambda-http"))] {
+        use std::net::ToSocketAddrs;
+
+        use async_std::{channel, task};
+        use listenfd::ListenFd;
+        use socket2::{Domain, Protocol, Socket, Type};
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "postgres")] {
         use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
-        use sqlx::ConnectOptions;
 
         use crate::middleware::PostgresMiddleware;
     }
 }
 
 cfg_if! {
-    if #[cfg(feature = "lambda-http")] {
-        use tide_lambda_listener::LambdaListener;
-    } else {
-        use tide::listener::Listener;
+    if #[cfg(feature = "sqlite")] {
+        use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+        use crate::middleware::SqliteMiddleware;
     }
 }
 
-use crate::logging::{log_format_json, log_format_pretty};
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+use sqlx::ConnectOptions;
+
+use tide::listener::Listener;
+#[cfg(feature = "lambda-http")]
+use tide_lambda_listener::LambdaListener;
+
+#[doc(hidden)]
+pub use crate::builtins::info::set_service_build;
+#[cfg(not(feature = "tracing-logs"))]
+use crate::logging::{
+    init_reloadable_logger, log_format_json, log_format_pretty, reload_access_logger, reload_logger,
+};
+use crate::logging::{LogLevel, LogSink};
+use crate::middleware::concurrency::reload_concurrency_limits;
+use crate::middleware::extension_types::IdFormat;
+use crate::middleware::logger::{reload_quiet_paths, reload_slow_request_threshold};
 use crate::middleware::{
-    ClacksMiddleware, JsonErrorMiddleware, LogMiddleware, RequestIdMiddleware,
+    ClacksMiddleware, ClientIpMiddleware, ConcurrencyLimitMiddleware, ForwardedUrlMiddleware,
+    HardeningMiddleware, InjectMiddleware, JsonErrorMiddleware, LocaleMiddleware, LogMiddleware,
+    RequestIdMiddleware, RewriteMiddleware, SnapshotMiddleware,
 };
-use crate::VariadicRoutes;
+use crate::{PrerollResources, VariadicRoutes};
 
 /// The result type which is expected from functions passed to `preroll::main!`,
 /// and used in the return of `setup`'s functions.
@@ -56,7 +119,41 @@ use crate::VariadicRoutes;
 /// This is a `color_eyre::eyre::Result<T>`.
 pub type Result<T> = color_eyre::eyre::Result<T>;
 
-pub async fn setup<AppState, StateFn, StateFnFuture, ServerFn, ServerFnFuture>(
+/// A `setup_state` function, as passed to `preroll::main!`, which takes either no arguments,
+/// or the [`PrerollResources`][] preroll has constructed.
+pub trait StateSetup<AppState, Args> {
+    /// The future returned by the function.
+    type Future: Future<Output = Result<AppState>>;
+
+    /// Call the function, with `resources` if it takes them.
+    fn setup_state(&self, resources: PrerollResources) -> Self::Future;
+}
+
+impl<AppState, F, Fut> StateSetup<AppState, ()> for F
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<AppState>>,
+{
+    type Future = Fut;
+
+    fn setup_state(&self, _resources: PrerollResources) -> Fut {
+        self()
+    }
+}
+
+impl<AppState, F, Fut> StateSetup<AppState, (PrerollResources,)> for F
+where
+    F: Fn(PrerollResources) -> Fut,
+    Fut: Future<Output = Result<AppState>>,
+{
+    type Future = Fut;
+
+    fn setup_state(&self, resources: PrerollResources) -> Fut {
+        self(resources)
+    }
+}
+
+pub async fn setup<AppState, StateFn, StateArgs, ServerFn, ServerFnFuture>(
     service_name: &'static str,
     state_setup: StateFn,
     server_setup: ServerFn,
@@ -64,36 +161,91 @@ pub async fn setup<AppState, StateFn, StateFnFuture, ServerFn, ServerFnFuture>(
 ) -> Result<()>
 where
     AppState: Send + Sync + 'static,
-    StateFn: Fn() -> StateFnFuture,
-    StateFnFuture: Future<Output = Result<AppState>>,
+    StateFn: StateSetup<AppState, StateArgs>,
+    ServerFn: Fn(Server<Arc<AppState>>) -> ServerFnFuture,
+    ServerFnFuture: Future<Output = Result<Server<Arc<AppState>>>>,
+{
+    let base_server = build_server(service_name, state_setup, server_setup, routes_setups).await?;
+    start_server(base_server).await?;
+
+    Ok(())
+}
+
+/// Everything `preroll::main!` does except binding a listener, returning the fully configured server.
+///
+/// This allows a preroll service to be embedded in an existing binary, e.g. served by its own listener,
+/// or used as an endpoint alongside other protocols. Takes the same arguments as `preroll::main!`.
+///
+/// Reloading configuration on `SIGHUP`, and logging the startup summary, are done by [`start_server`][] once listening,
+/// so are left to the embedder, e.g. by calling [`log_startup_summary`][] once its own listeners are bound.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// # #[allow(unused_mut)]
+/// fn setup_routes(mut server: tide::Route<'_, Arc<()>>) {
+///     // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// async fn setup_state() -> preroll::SetupResult<()> {
+///     Ok(())
+/// }
+///
+/// async fn custom_setup(server: tide::Server<Arc<()>>) -> preroll::SetupResult<tide::Server<Arc<()>>> {
+///     Ok(server)
+/// }
+///
+/// #[async_std::main]
+/// async fn main() -> preroll::SetupResult<()> {
+///     let server = preroll::setup::build_server("embedded-service", setup_state, custom_setup, setup_routes).await?;
+///
+///     // Serve it alongside other listeners, or nest it within another server.
+///     server.listen("127.0.0.1:8080").await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn build_server<AppState, StateFn, StateArgs, ServerFn, ServerFnFuture>(
+    service_name: &'static str,
+    state_setup: StateFn,
+    server_setup: ServerFn,
+    routes_setups: impl Into<VariadicRoutes<AppState>>,
+) -> Result<Server<Arc<()>>>
+where
+    AppState: Send + Sync + 'static,
+    StateFn: StateSetup<AppState, StateArgs>,
     ServerFn: Fn(Server<Arc<AppState>>) -> ServerFnFuture,
     ServerFnFuture: Future<Output = Result<Server<Arc<AppState>>>>,
 {
     initial_setup(service_name)?;
 
-    let state = state_setup().await?;
+    let resources = setup_resources(service_name).await?;
+    let state = state_setup.setup_state(resources.clone()).await?;
 
-    let (mut base_server, server) = setup_server(service_name, state).await?;
+    let (mut base_server, server) =
+        setup_server_with_resources(service_name, state, &resources).await?;
 
     let mut server = server_setup(server).await?;
+    record_startup(|summary| summary.middleware.push("(custom_setup)"));
 
-    let mut version = 1;
-    for routes_fn in routes_setups.into().routes {
-        routes_fn(server.at(&format!("/api/v{}", version)));
-        version += 1;
-    }
+    routes_setups.into().mount(&mut server);
 
     #[cfg(debug_assertions)]
     server.at("/internal-error").get(get_internal_error);
 
     base_server.at("/").nest(server);
-    start_server(base_server).await?;
 
-    Ok(())
+    // All health checks are registered by now, including any from `setup_state` or `custom_setup`.
+    check_dependencies_at_startup().await?;
+
+    Ok(base_server)
 }
 
 #[cfg(debug_assertions)]
-async fn get_internal_error<AppState>(_req: Request<Arc<AppState>>) -> tide::Result<&'static str>
+pub(crate) async fn get_internal_error<AppState>(
+    _req: Request<Arc<AppState>>,
+) -> tide::Result<&'static str>
 where
     AppState: Send + Sync + 'static,
 {
@@ -103,48 +255,180 @@ where
     ))
 }
 
+/// The `env_logger` setup, in JSON to the `LOGFILE` sink in production, or else pretty-printed to stderr.
+#[cfg(not(feature = "tracing-logs"))]
+fn env_logger_builder(production: bool, log_level: &LogLevel) -> Result<env_logger::Builder> {
+    let mut builder = env_logger::builder();
+    builder.parse_filters(&log_level.to_string());
+
+    if production {
+        builder
+            .format(log_format_json)
+            .write_style(env_logger::WriteStyle::Never)
+            .target(LogSink::from_env()?.env_logger_target());
+    } else {
+        builder.format(log_format_pretty);
+    }
+
+    Ok(builder)
+}
+
+/// The `env_logger` setup of access logs written to their own `sink`, in JSON in production, or else pretty-printed.
+#[cfg(not(feature = "tracing-logs"))]
+fn access_logger_builder(
+    production: bool,
+    log_level: &LogLevel,
+    sink: LogSink,
+) -> env_logger::Builder {
+    let mut builder = env_logger::builder();
+    builder
+        .parse_filters(&log_level.to_string())
+        .target(sink.env_logger_target());
+
+    if production {
+        builder
+            .format(log_format_json)
+            .write_style(env_logger::WriteStyle::Never);
+    } else {
+        builder.format(log_format_pretty);
+    }
+
+    builder
+}
+
 #[cfg_attr(not(feature = "honeycomb"), allow(unused_variables))]
 pub fn initial_setup(service_name: &'static str) -> Result<()> {
     color_eyre::install()?;
 
-    let log_level = env::var("LOGLEVEL")
-        .map(|v| v.parse().expect("LOGLEVEL must be a valid log level."))
-        .unwrap_or(log::LevelFilter::Info);
+    let config_file = config::load_file()?;
+
+    let log_level = LogLevel::from_config(log::LevelFilter::Info)
+        .expect("LOGLEVEL must be valid log level directives.");
 
-    if env::var("FORCE_DOTENV").is_ok() || env::var("DEBUG_DOTENV").is_ok() {
+    if config::var("FORCE_DOTENV").is_ok() {
         dotenv::dotenv().ok();
     }
 
-    let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
+    let environment = config::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
 
     // Logging
-    if environment.starts_with("prod") {
-        env_logger::builder()
-            .format(log_format_json)
-            .filter_level(log_level)
-            .write_style(env_logger::WriteStyle::Never)
-            .try_init()?;
-    } else {
-        // Development
-        dotenv::dotenv().ok();
+    #[cfg(not(feature = "tracing-logs"))]
+    {
+        let production = environment.starts_with("prod");
+        if !production {
+            // Development
+            dotenv::dotenv().ok();
+        }
+
+        init_reloadable_logger(env_logger_builder(production, &log_level)?.build())?;
+
+        let access_sink = LogSink::access_from_env()?;
+        if let Some(sink) = &access_sink {
+            reload_access_logger(
+                access_logger_builder(production, &log_level, sink.clone()).build(),
+            );
+        }
 
-        env_logger::builder()
-            .format(log_format_pretty)
-            .filter_level(log_level)
-            .try_init()?;
+        on_reload("LOGLEVEL", move || {
+            let log_level = LogLevel::from_config(log::LevelFilter::Info)?;
+            reload_logger(env_logger_builder(production, &log_level)?.build());
+            if let Some(sink) = &access_sink {
+                reload_access_logger(
+                    access_logger_builder(production, &log_level, sink.clone()).build(),
+                );
+            }
+            Ok(())
+        });
     }
 
+    // Logging via tracing, with `log` records forwarded as tracing events.
+    #[cfg(feature = "tracing-logs")]
+    let log_layer = {
+        if !environment.starts_with("prod") {
+            // Development
+            dotenv::dotenv().ok();
+        }
+
+        LogTracer::builder()
+            .with_max_level(log_level.max_level())
+            .init()?;
+
+        let (log_layer, log_filter_handle) = tracing_log_layer(
+            environment.starts_with("prod"),
+            &log_level,
+            LogSink::from_env()?,
+            LogSink::access_from_env()?,
+        );
+
+        on_reload("LOGLEVEL", move || {
+            let log_level = LogLevel::from_config(log::LevelFilter::Info)?;
+            log_filter_handle.reload(log_level.targets())?;
+            log::set_max_level(log_level.max_level());
+            Ok(())
+        });
+
+        log_layer
+    };
+    #[cfg(all(feature = "honeycomb", not(feature = "tracing-logs")))]
+    let log_layer = Identity::new();
+
+    #[cfg(all(feature = "tracing-logs", not(feature = "honeycomb")))]
+    tracing::subscriber::set_global_default(Registry::default().with(log_layer))?;
+
     log::info!("Logger started - level: {}", log_level);
+    log_startup_deprecations();
+
+    if let Some(path) = config_file {
+        log::info!("Config file loaded: {}", path.display());
+        record_startup(|summary| summary.config_file = Some(path.display().to_string()));
+    }
+
+    // Secrets, resolved before anything else reads settings which may reference them.
+    #[cfg(feature = "aws-secrets")]
+    {
+        let count = block_on(crate::secrets::resolve_all())?;
+        log::info!("AWS secrets resolved: {}", count);
+
+        if let Ok(refresh_secs) = config::var("AWS_SECRETS_REFRESH_SECS") {
+            let refresh_secs: u64 = refresh_secs.parse()?;
+            crate::secrets::spawn_refresh(Duration::from_secs(refresh_secs));
+        }
+    }
+
+    // All settings are checked once secrets are resolved, so that every problem is reported at once.
+    config::validate()?;
+
+    record_startup(|summary| summary.environment = environment.clone());
 
     // Tracing (Honeycomb)
     #[cfg(feature = "honeycomb")]
     {
-        let trace_filter: LevelFilter = env::var("TRACELEVEL")
-            .map(|v| v.parse())
-            .unwrap_or(Ok(LevelFilter::INFO))?;
+        // In resilient mode, honeycomb setup problems are logged rather than preventing the service from starting.
+        let resilient = config::var("HONEYCOMB_RESILIENT")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let trace_filter: LevelFilter = match config::var("TRACELEVEL").map(|v| v.parse()) {
+            Ok(Ok(trace_filter)) => trace_filter,
+            Ok(Err(error)) if resilient => {
+                log::warn!("Invalid TRACELEVEL, defaulting to info: {}", error);
+                LevelFilter::INFO
+            }
+            Ok(Err(error)) => return Err(error.into()),
+            Err(_) => LevelFilter::INFO,
+        };
+
+        let (trace_filter_layer, trace_filter_handle) = reload::Layer::new(trace_filter);
+        on_reload("TRACELEVEL", move || {
+            let trace_filter: LevelFilter = config::var("TRACELEVEL")
+                .map(|v| v.parse())
+                .unwrap_or(Ok(LevelFilter::INFO))?;
+            trace_filter_handle.reload(trace_filter)?;
+            Ok(())
+        });
 
-        if let Ok(api_key) = env::var("HONEYCOMB_WRITEKEY") {
-            let maybe_sample_rate = env::var("HONEYCOMB_SAMPLE_RATE");
+        if let Ok(api_key) = config::var("HONEYCOMB_WRITEKEY") {
+            let maybe_sample_rate = config::var("HONEYCOMB_SAMPLE_RATE");
 
             #[cfg(feature = "lambda-http")]
             let telemetry_layer = {
@@ -152,6 +436,10 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
                 // In this setup the environemnt's consumer will have to have this.
                 drop(api_key);
 
+                record_startup(|summary| {
+                    summary.tracing = Some(format!("honeycomb stdout, filter={}", trace_filter));
+                });
+
                 let telemetry_builder = tracing_honeycomb::Builder::new_stdout(service_name);
 
                 if let Ok(sample_rate) = maybe_sample_rate.unwrap_or_default().parse() {
@@ -164,68 +452,116 @@ pub fn initial_setup(service_name: &'static str) -> Result<()> {
 
             #[cfg(not(feature = "lambda-http"))]
             let telemetry_layer = {
-                let dataset = env::var("HONEYCOMB_DATASET")
+                let dataset = config::var("HONEYCOMB_DATASET")
                     .unwrap_or_else(|_| format!("{}-{}", service_name, environment));
 
-                let api_host = env::var("HONEYCOMB_API_HOST")
+                let api_host = config::var("HONEYCOMB_API_HOST")
                     .unwrap_or_else(|_| "https://api.honeycomb.io/".to_string());
 
-                let honeycomb_config = libhoney::Config {
-                    options: libhoney::client::Options {
-                        api_key,
+                let sample_rules = SampleRules::from_config()?;
+
+                record_startup(|summary| {
+                    summary.tracing = Some(format!(
+                        "honeycomb {} dataset={}, filter={}, resilient={}, sampling={}",
                         api_host,
                         dataset,
-                        ..libhoney::client::Options::default()
-                    },
-                    transmission_options: libhoney::transmission::Options::default(),
-                };
+                        trace_filter,
+                        resilient,
+                        sample_rules
+                            .as_ref()
+                            .map_or_else(|| "head".to_string(), |rules| rules.to_string())
+                    ));
+                });
 
-                let telemetry_builder =
-                    tracing_honeycomb::Builder::new_libhoney(service_name, honeycomb_config);
+                let exporter = HoneycombExporter {
+                    api_key,
+                    api_host,
+                    dataset,
+                };
 
-                if let Ok(sample_rate) = maybe_sample_rate.unwrap_or_default().parse() {
-                    telemetry_builder.with_trace_sampling(sample_rate /* u32 */)
+                // The reporter drops telemetry until connected, so that a resilient exporter can connect later.
+                let reporter = match sample_rules.clone() {
+                    Some(rules) => ResilientReporter::default().with_sampling(rules),
+                    None => ResilientReporter::default(),
+                };
+                if resilient {
+                    exporter.spawn_connect(reporter.clone());
                 } else {
-                    telemetry_builder
+                    exporter.connect(&reporter);
                 }
-                .build()
+
+                // With sampling rules, the reporter samples whole traces once their outcome is known.
+                let sample_rate = HeadSampleRate::new(if sample_rules.is_some() {
+                    None
+                } else {
+                    maybe_sample_rate.unwrap_or_default().parse().ok()
+                });
+
+                let (hook_reporter, hook_sample_rate) = (reporter.clone(), sample_rate.clone());
+                on_reload("HONEYCOMB_SAMPLE_RATE", move || {
+                    reload_sampling(&hook_reporter, &hook_sample_rate)
+                });
+                let (hook_reporter, hook_sample_rate) = (reporter.clone(), sample_rate.clone());
+                on_reload("HONEYCOMB_SAMPLE_RULES", move || {
+                    reload_sampling(&hook_reporter, &hook_sample_rate)
+                });
+
+                telemetry_layer(service_name, reporter, sample_rate)
             };
 
             let subscriber = Registry::default()
-                .with(trace_filter) // filter out low-level debug tracing
-                // .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
-                .with(telemetry_layer); // publish to honeycomb backend
-
-            tracing::subscriber::set_global_default(subscriber)?;
+                .with(log_layer) // log to stderr, if "tracing-logs" is enabled
+                // filter out low-level debug tracing, publish to honeycomb backend
+                .with(telemetry_layer.with_filter(trace_filter_layer));
 
-            log::info!("Honeycomb Tracing enabled - filter: {}", trace_filter);
+            match tracing::subscriber::set_global_default(subscriber) {
+                Ok(()) => log::info!("Honeycomb Tracing enabled - filter: {}", trace_filter),
+                Err(error) if resilient => {
+                    log::warn!(
+                        "Honeycomb Tracing disabled, unable to set subscriber: {}",
+                        error
+                    )
+                }
+                Err(error) => return Err(error.into()),
+            }
         } else {
             let telemetry_layer = new_blackhole_telemetry_layer();
 
             let subscriber = Registry::default()
-                .with(trace_filter) // filter out low-level debug tracing
-                // .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
-                .with(telemetry_layer); // publish to honeycomb backend
+                .with(log_layer) // log to stderr, if "tracing-logs" is enabled
+                // filter out low-level debug tracing, publish to honeycomb backend
+                .with(telemetry_layer.with_filter(trace_filter_layer));
 
             tracing::subscriber::set_global_default(subscriber)?;
 
             log::info!("Honeycomb Tracing off");
+            record_startup(|summary| summary.tracing = Some("off".to_string()));
         }
     }
 
     Ok(())
 }
 
-#[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
-pub async fn setup_server<State>(
+/// Create the base server, with monitor routes, and the application server, with preroll's standard middleware.
+///
+/// The application server is nested within the base server once routes are set up.
+pub(crate) fn create_servers<State>(
     service_name: &'static str,
     state: State,
-) -> Result<(Server<Arc<()>>, Server<Arc<State>>)>
+) -> (Server<Arc<()>>, Server<Arc<State>>)
 where
     State: Send + Sync + 'static,
 {
     let mut base_server = tide::with_state(Arc::new(()));
     base_server.with(ClacksMiddleware::new());
+    base_server.with(RewriteMiddleware::new());
+
+    if let Ok(vendor) = config::var("ACCEPT_VERSION_VENDOR") {
+        crate::rewrite::register(
+            crate::rewrite::ACCEPT_VERSION_REWRITE,
+            crate::rewrite::accept_version(vendor),
+        );
+    }
 
     // Set handlers for /monitor/ping, etc.
     //
@@ -235,24 +571,52 @@ where
     let mut server = tide::with_state(Arc::new(state));
     server.with(ClacksMiddleware::new());
     server.with(RequestIdMiddleware::new());
+    server.with(ClientIpMiddleware::new());
+    server.with(ForwardedUrlMiddleware::new());
+    server.with(LocaleMiddleware::new());
     server.with(LogMiddleware::new());
     server.with(JsonErrorMiddleware::new());
+    server.with(HardeningMiddleware::new());
+    server.with(ConcurrencyLimitMiddleware::new());
+    server.with(SnapshotMiddleware::new());
+    server.with(InjectMiddleware::new());
+
+    // Within error handling, so that store failures are JSON errors, and in test servers too.
+    #[cfg(feature = "sessions")]
+    server.with(crate::middleware::SessionMiddleware::new());
 
     #[cfg(feature = "honeycomb")]
     server.with(TraceMiddleware::new());
 
+    (base_server, server)
+}
+
+/// Construct the [`PrerollResources`][], and then set up the servers as `preroll::main!` does, with `state`.
+pub async fn setup_server<State>(
+    service_name: &'static str,
+    state: State,
+) -> Result<(Server<Arc<()>>, Server<Arc<State>>)>
+where
+    State: Send + Sync + 'static,
+{
+    let resources = setup_resources(service_name).await?;
+    setup_server_with_resources(service_name, state, &resources).await
+}
+
+/// Connect the database pools and redis client, run any migrations, and register their health checks.
+pub async fn setup_resources(service_name: &'static str) -> Result<PrerollResources> {
     // Postgres
     #[cfg(feature = "postgres")]
-    {
-        let max_connections: u32 = env::var("PGMAXCONNECTIONS")
+    let pg_pool = {
+        let max_connections: u32 = config::var("PGMAXCONNECTIONS")
             .map(|v| v.parse())
             .unwrap_or(Ok(5))?;
-        let max_lifetime: u64 = env::var("PGMAXLIFETIME")
+        let max_lifetime: u64 = config::var("PGMAXLIFETIME")
             .map(|v| v.parse())
             .unwrap_or(Ok(30 /* 30 mins */))?;
 
-        let pgurl =
-            env::var("PGURL").unwrap_or_else(|_| format!("postgres://localhost/{}", service_name));
+        let pgurl = config::var("PGURL")
+            .unwrap_or_else(|_| format!("postgres://localhost/{}", service_name));
 
         let mut connect_opts: PgConnectOptions = pgurl.parse()?;
         connect_opts.log_statements(log::LevelFilter::Debug);
@@ -263,32 +627,546 @@ where
             .connect_with(connect_opts)
             .await?;
 
-        server.with(PostgresMiddleware::from(pg_pool));
+        crate::builtins::pool_stats::register(&pg_pool, max_connections);
+
+        let health_check_pool = pg_pool.clone();
+        crate::register_health_check("postgresReachability", move || {
+            let pool = health_check_pool.clone();
+            async move {
+                let mut conn = crate::builtins::pool_stats::acquire(&pool).await?;
+                sqlx::query("SELECT 1").execute(&mut conn).await?;
+                Ok(())
+            }
+        });
+
+        #[cfg(feature = "postgres-migrate")]
+        crate::builtins::migrate::run_if_enabled(&pg_pool).await?;
+
+        record_startup(|summary| {
+            summary.postgres_pool = Some(format!(
+                "url={},max_connections={},max_lifetime={}m",
+                elide_password(&pgurl),
+                max_connections,
+                max_lifetime
+            ));
+        });
+
+        pg_pool
+    };
+
+    // SQLite
+    #[cfg(feature = "sqlite")]
+    let sqlite_pool = {
+        let sqliteurl =
+            config::var("SQLITEURL").unwrap_or_else(|_| format!("sqlite://{}.db", service_name));
+
+        let mut connect_opts: SqliteConnectOptions = sqliteurl.parse()?;
+        connect_opts = connect_opts.create_if_missing(true);
+        connect_opts.log_statements(log::LevelFilter::Debug);
+
+        let sqlite_pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(connect_opts)
+            .await?;
+
+        let health_check_pool = sqlite_pool.clone();
+        crate::register_health_check("sqliteReachability", move || {
+            let pool = health_check_pool.clone();
+            async move {
+                sqlx::query("SELECT 1").execute(&pool).await?;
+                Ok(())
+            }
+        });
+
+        sqlite_pool
+    };
+
+    // Redis
+    #[cfg(feature = "redis")]
+    let redis = match config::var("REDISURL") {
+        Ok(url) => Some(redis::Client::open(url.as_str())?),
+        Err(_) => None,
+    };
+
+    Ok(PrerollResources {
+        service_name,
+        config: config::Config::snapshot(),
+        #[cfg(feature = "postgres")]
+        pg_pool,
+        #[cfg(feature = "sqlite")]
+        sqlite_pool,
+        #[cfg(feature = "redis")]
+        redis,
+    })
+}
+
+/// Set up the servers as `preroll::main!` does, with `state`, and preroll's middleware using `resources`.
+#[cfg_attr(
+    not(any(feature = "postgres", feature = "sqlite")),
+    allow(unused_variables)
+)]
+pub async fn setup_server_with_resources<State>(
+    service_name: &'static str,
+    state: State,
+    resources: &PrerollResources,
+) -> Result<(Server<Arc<()>>, Server<Arc<State>>)>
+where
+    State: Send + Sync + 'static,
+{
+    IdFormat::init()?;
+
+    #[cfg(feature = "redis")]
+    crate::cache::setup()?;
+    #[cfg(all(feature = "redis", feature = "sessions"))]
+    crate::sessions::setup_redis()?;
+    #[cfg(all(feature = "redis", feature = "idempotency"))]
+    crate::idempotency::setup_redis()?;
+    crate::flags::setup().await?;
+
+    #[cfg_attr(
+        not(any(feature = "postgres", feature = "sqlite", feature = "idempotency")),
+        allow(unused_mut)
+    )]
+    let (base_server, mut server) = create_servers(service_name, state);
+
+    on_reload("SLOW_REQUEST_MS", || {
+        reload_slow_request_threshold().map_err(Into::into)
+    });
+    on_reload("QUIET_PATHS", || {
+        reload_quiet_paths();
+        Ok(())
+    });
+    on_reload("MAX_INFLIGHT", || {
+        reload_concurrency_limits();
+        Ok(())
+    });
+
+    record_startup(|summary| {
+        summary.service = service_name;
+        summary.middleware = vec![
+            "Clacks",
+            "Rewrite",
+            "RequestId",
+            "ClientIp",
+            "ForwardedUrl",
+            "Locale",
+            "Log",
+            "JsonError",
+            "Hardening",
+            "ConcurrencyLimit",
+            "Snapshot",
+            "Inject",
+        ];
+
+        #[cfg(feature = "sessions")]
+        summary.middleware.push("Session");
+
+        #[cfg(feature = "honeycomb")]
+        summary.middleware.push("Trace");
+    });
+
+    // Outside of any database middleware, so that responses are only stored once their transaction has committed.
+    #[cfg(feature = "idempotency")]
+    {
+        server.with(crate::middleware::IdempotencyMiddleware::new());
+        record_startup(|summary| summary.middleware.push("Idempotency"));
+    }
+
+    // Postgres
+    #[cfg(feature = "postgres")]
+    {
+        let pg_pool = &resources.pg_pool;
+
+        crate::notifications::setup(pg_pool).await?;
+        crate::outbox::setup(pg_pool).await?;
+        crate::webhooks::setup(pg_pool).await?;
+        #[cfg(feature = "pg-jobs")]
+        crate::jobs::setup(pg_pool).await?;
+        #[cfg(feature = "idempotency")]
+        crate::idempotency::setup(pg_pool).await?;
+        #[cfg(feature = "sessions")]
+        crate::sessions::setup(pg_pool).await?;
+
+        server.with(PostgresMiddleware::from(pg_pool.clone()));
+        record_startup(|summary| summary.middleware.push("Postgres"));
+    }
+
+    // SQLite
+    #[cfg(feature = "sqlite")]
+    {
+        server.with(SqliteMiddleware::from(resources.sqlite_pool.clone()));
+        record_startup(|summary| summary.middleware.push("Sqlite"));
     }
 
     Ok((base_server, server))
 }
 
+/// A database url with any password replaced by `***`, for logging.
+#[cfg(feature = "postgres")]
+fn elide_password(url: &str) -> String {
+    match surf::Url::parse(url) {
+        Ok(mut url) => {
+            if url.password().is_some() {
+                url.set_password(Some("***")).ok();
+            }
+            url.to_string()
+        }
+        Err(_) => "(invalid)".to_string(),
+    }
+}
+
+/// Bind `server` to `host` and `port`, which may be `0` for an ephemeral port.
+///
+/// If `port` is in use, up to `fallback` successive ports are tried in turn.
+pub(crate) async fn bind_server<State>(
+    server: Server<Arc<State>>,
+    host: &str,
+    port: u16,
+    fallback: u16,
+) -> Result<impl Listener<Arc<State>>>
+where
+    State: Send + Sync + 'static,
+{
+    let (listener, _) = try_ports(host, port, fallback, |attempt| {
+        server.clone().bind((host, attempt))
+    })
+    .await?;
+
+    record_listening(&listener);
+    Ok(listener)
+}
+
+/// `workers` sockets listening on `host` and `port`, as [`bind_server`][], for acceptors of their own.
+///
+/// With `reuse_port`, each is a `SO_REUSEPORT` socket of its own, between which the kernel balances connections.
+/// Otherwise, they are the same socket.
+#[cfg(not(feature = "lambda-http"))]
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) async fn acceptor_sockets(
+    host: &str,
+    port: u16,
+    fallback: u16,
+    workers: usize,
+    reuse_port: bool,
+) -> Result<Vec<std::net::TcpListener>> {
+    let (first, _) = try_ports(host, port, fallback, |attempt| {
+        future::ready(bind_socket(host, attempt, reuse_port))
+    })
+    .await?;
+    // The port actually bound, in case it was `0`.
+    let port = first.local_addr()?.port();
+
+    let mut sockets = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        if reuse_port {
+            sockets.push(bind_socket(host, port, true)?);
+        } else {
+            sockets.push(first.try_clone()?);
+        }
+    }
+    sockets.insert(0, first);
+
+    if reuse_port {
+        log::info!("Listening on {} sockets with SO_REUSEPORT", workers);
+    }
+    Ok(sockets)
+}
+
+/// TCP sockets passed on by a supervisor, such as systemd socket activation, with `LISTEN_FDS`,
+/// each shared by `workers` acceptors.
+#[cfg(not(feature = "lambda-http"))]
+pub(crate) fn inherited_sockets(workers: usize) -> Result<Vec<std::net::TcpListener>> {
+    let mut fds = ListenFd::from_env();
+
+    let mut sockets = Vec::new();
+    for index in 0..fds.len() {
+        let socket = match fds.take_tcp_listener(index) {
+            Ok(Some(socket)) => socket,
+            Ok(None) => continue,
+            Err(error) => {
+                return Err(eyre!(error).wrap_err(format!(
+                    "Inherited socket {} (LISTEN_FDS) is not a TCP listener",
+                    index
+                )))
+            }
+        };
+        for _ in 1..workers {
+            sockets.push(socket.try_clone()?);
+        }
+        sockets.push(socket);
+    }
+
+    if !sockets.is_empty() {
+        log::info!(
+            "Listening on {} inherited socket(s) (LISTEN_FDS), rather than HOST and PORT",
+            fds.len()
+        );
+    }
+    Ok(sockets)
+}
+
+/// Bind a listener of `server` to each of `sockets`, to each be accepted from on its own task.
+#[cfg(not(feature = "lambda-http"))]
+pub(crate) async fn bind_acceptors<State>(
+    server: Server<Arc<State>>,
+    sockets: Vec<std::net::TcpListener>,
+) -> Result<Vec<impl Listener<Arc<State>>>>
+where
+    State: Send + Sync + 'static,
+{
+    let mut listeners = Vec::with_capacity(sockets.len());
+    let mut addresses = Vec::new();
+    for socket in sockets {
+        let address = socket.local_addr()?;
+        let listener = server.clone().bind(socket).await?;
+        // Sockets shared by several acceptors are only logged once.
+        if !addresses.contains(&address) {
+            addresses.push(address);
+            record_listening(&listener);
+        }
+        listeners.push(listener);
+    }
+
+    log::info!("Accepting connections on {} tasks", listeners.len());
+    Ok(listeners)
+}
+
+/// Bind with `bind`, to `port`, or if it is in use, up to `fallback` successive ports,
+/// returning what was bound and its port.
+async fn try_ports<T, F, Fut>(host: &str, port: u16, fallback: u16, mut bind: F) -> Result<(T, u16)>
+where
+    F: FnMut(u16) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let last = port.saturating_add(fallback);
+    let mut attempt = port;
+    loop {
+        match bind(attempt).await {
+            Ok(bound) => {
+                if attempt != port {
+                    log::warn!(
+                        "Port {} is in use, listening on port {} instead",
+                        port,
+                        attempt
+                    );
+                }
+                return Ok((bound, attempt));
+            }
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse && attempt < last => {
+                log::warn!(
+                    "{}:{} is already in use, trying port {}",
+                    host,
+                    attempt,
+                    attempt + 1
+                );
+                attempt += 1;
+            }
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse => {
+                let address = format!("{}:{}", host, attempt);
+                log_kv!(error, { address: address }, "Unable to listen on {}: {}", address, error);
+                return Err(eyre!(error).wrap_err(format!(
+                    "Unable to listen on {}, which is already in use. \
+                    Set PORT to a free port, or PORT_FALLBACK_RANGE to also try the following ports.",
+                    address
+                )));
+            }
+            Err(error) => {
+                return Err(
+                    eyre!(error).wrap_err(format!("Unable to listen on {}:{}", host, attempt))
+                )
+            }
+        }
+    }
+}
+
+/// A listening socket on `host` and `port`, with `SO_REUSEPORT` if `reuse_port` (on unix).
+#[cfg(not(feature = "lambda-http"))]
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn bind_socket(host: &str, port: u16, reuse_port: bool) -> io::Result<std::net::TcpListener> {
+    let addr = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} has no addresses", host),
+        )
+    })?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As the standard library's listeners do.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Log and record the addresses `listener` is listening on.
+fn record_listening<State>(listener: &impl Listener<Arc<State>>)
+where
+    State: Send + Sync + 'static,
+{
+    for info in listener.info().iter() {
+        log::info!("Server listening on {}", info);
+        record_startup(|summary| summary.listeners.push(info.to_string()));
+    }
+}
+
+/// Listen on `HOST` and `PORT`, sockets inherited with `LISTEN_FDS`, or the lambda runtime, until [stopped][shutdown_handle],
+/// e.g. by `SIGTERM`, and then wait up to `SHUTDOWN_GRACE_MS` for requests in flight to finish.
 pub async fn start_server<State>(server: Server<Arc<State>>) -> Result<()>
 where
     State: Send + Sync + 'static,
 {
+    let shutdown = shutdown_handle();
+
     #[cfg(feature = "lambda-http")]
     {
-        server.listen(LambdaListener::new()).await?;
+        record_startup(|summary| summary.listeners.push("lambda".to_string()));
+        log_startup_summary();
+
+        future::or(server.listen(LambdaListener::new()), async {
+            shutdown.stopped().await;
+            Ok(())
+        })
+        .await?;
     }
     #[cfg(not(feature = "lambda-http"))]
     {
-        let port: u16 = env::var("PORT").map(|v| v.parse()).unwrap_or(Ok(8080))?;
-        let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let port: u16 = config::var("PORT").map(|v| v.parse()).unwrap_or(Ok(8080))?;
+        let host = config::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let fallback: u16 = config::var("PORT_FALLBACK_RANGE")
+            .map(|v| v.parse())
+            .unwrap_or(Ok(0))?;
+
+        #[cfg(unix)]
+        {
+            listen_for_sighup()?;
+            listen_for_sigterm()?;
+        }
+        start_lag_probe();
+
+        let workers: usize = config::var("ACCEPT_WORKERS")
+            .map(|v| v.parse())
+            .unwrap_or(Ok(1))?;
+        let reuse_port: bool = config::var("REUSE_PORT")
+            .map(|v| v.parse())
+            .unwrap_or(Ok(false))?;
 
-        let mut listener = server.bind((host.as_str(), port)).await?;
-        for info in listener.info().iter() {
-            log::info!("Server listening on {}", info);
+        let inherited = inherited_sockets(workers.max(1))?;
+        if !inherited.is_empty() || workers > 1 || reuse_port {
+            let sockets = if inherited.is_empty() {
+                acceptor_sockets(&host, port, fallback, workers.max(1), reuse_port).await?
+            } else {
+                inherited
+            };
+            let listeners = bind_acceptors(server, sockets).await?;
+            log_startup_summary();
+
+            // Each listener accepts on its own task, until any of them fails, or the server is stopped.
+            let (sender, receiver) = channel::bounded(listeners.len());
+            let acceptors: Vec<_> = listeners
+                .into_iter()
+                .map(|mut listener| {
+                    let sender = sender.clone();
+                    task::spawn(async move {
+                        sender.send(listener.accept().await).await.ok();
+                    })
+                })
+                .collect();
+
+            let result = future::or(async { receiver.recv().await.unwrap_or(Ok(())) }, async {
+                shutdown.stopped().await;
+                Ok(())
+            })
+            .await;
+            for acceptor in acceptors {
+                acceptor.cancel().await;
+            }
+            result?;
+        } else {
+            let mut listener = bind_server(server, &host, port, fallback).await?;
+            log_startup_summary();
+            future::or(listener.accept(), async {
+                shutdown.stopped().await;
+                Ok(())
+            })
+            .await?;
         }
-        listener.accept().await?;
     }
 
-    // Essentially "never".
+    drain().await;
+    log::info!("Server stopped");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn falls_back_to_following_ports() -> Result<()> {
+        let taken = TcpListener::bind("127.0.0.1:0")?;
+        let port = taken.local_addr()?.port();
+
+        let error = bind_server(tide::with_state(Arc::new(())), "127.0.0.1", port, 0)
+            .await
+            .err()
+            .ok_or_else(|| eyre!("Bound to a port in use"))?;
+        assert!(error
+            .to_string()
+            .contains(&format!("Unable to listen on 127.0.0.1:{}", port)));
+
+        let listener = bind_server(tide::with_state(Arc::new(())), "127.0.0.1", port, 10).await?;
+        let listening = listener
+            .info()
+            .iter()
+            .map(|info| info.connection().to_string())
+            .collect::<Vec<_>>();
+        assert!(!listening.is_empty());
+        assert!(listening
+            .iter()
+            .all(|address| !address.ends_with(&format!(":{}", port))));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn accepts_on_several_tasks() -> Result<()> {
+        let mut server = tide::with_state(Arc::new(()));
+        server.at("/").get(|_| async { Ok("accepted") });
+
+        for reuse_port in [false, cfg!(unix)] {
+            let sockets = acceptor_sockets("127.0.0.1", 0, 0, 3, reuse_port).await?;
+            let listeners = bind_acceptors(server.clone(), sockets).await?;
+            assert_eq!(listeners.len(), 3);
+
+            let addresses: Vec<String> = listeners
+                .iter()
+                .flat_map(|listener| listener.info())
+                .map(|info| info.connection().to_string())
+                .collect();
+            assert!(addresses.windows(2).all(|pair| pair[0] == pair[1]));
+
+            let mut acceptors = Vec::new();
+            for mut listener in listeners {
+                acceptors.push(task::spawn(async move { listener.accept().await }));
+            }
+
+            for _ in 0..6 {
+                let body = surf::get(&addresses[0])
+                    .recv_string()
+                    .await
+                    .map_err(|error| eyre!(error))?;
+                assert_eq!(body, "accepted");
+            }
+
+            for acceptor in acceptors {
+                acceptor.cancel().await;
+            }
+        }
+        Ok(())
+    }
+}
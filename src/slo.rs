@@ -0,0 +1,324 @@
+//! Basic Service Level Objective (SLO) tracking.
+//!
+//! Objectives are registered per route group (by path prefix), and burn rates are computed over a rolling window
+//! from the responses recorded by preroll's request stats.
+//! Burn rates are reported at `/monitor/slo`, and a `WARN` log is emitted when an objective's burn rate exceeds its alert threshold.
+//!
+//! A burn rate of `1.0` means the error budget is being used up at exactly the rate which the objective allows,
+//! a burn rate of `2.0` means it would be exhausted in half the window, and so on.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use preroll::slo::{self, SloObjective};
+//!
+//! slo::register(
+//!     SloObjective::new("api-v1", "/api/v1")
+//!         .success_rate(0.999)
+//!         .latency(0.99, Duration::from_millis(300)),
+//! );
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tide::StatusCode;
+
+use crate::logging::log_kv;
+
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+static TRACKERS: Lazy<Mutex<Vec<Tracker>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// An objective for a group of routes, matched by path prefix.
+#[derive(Clone, Debug)]
+pub struct SloObjective {
+    name: &'static str,
+    path_prefix: String,
+    success_target: Option<f64>,
+    latency_target: Option<(f64, Duration)>,
+    alert_burn_rate: f64,
+    window: Duration,
+}
+
+impl SloObjective {
+    /// Create a new objective for all routes starting with `path_prefix`.
+    ///
+    /// By default there are no targets, the window is one hour, and alerts are logged at a burn rate of `2.0`.
+    #[must_use]
+    pub fn new(name: &'static str, path_prefix: impl Into<String>) -> Self {
+        Self {
+            name,
+            path_prefix: path_prefix.into(),
+            success_target: None,
+            latency_target: None,
+            alert_burn_rate: 2.0,
+            window: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// The target ratio of non-5XX responses, e.g. `0.999`.
+    #[must_use]
+    pub fn success_rate(mut self, target: f64) -> Self {
+        self.success_target = Some(target);
+        self
+    }
+
+    /// The target ratio of responses faster than `threshold`, e.g. `0.99` for "p99 < threshold".
+    #[must_use]
+    pub fn latency(mut self, percentile: f64, threshold: Duration) -> Self {
+        self.latency_target = Some((percentile, threshold));
+        self
+    }
+
+    /// The burn rate above which a `WARN` log is emitted.
+    #[must_use]
+    pub fn alert_burn_rate(mut self, burn_rate: f64) -> Self {
+        self.alert_burn_rate = burn_rate;
+        self
+    }
+
+    /// The rolling window which burn rates are computed over, at minute granularity.
+    #[must_use]
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+}
+
+/// Register an objective to be tracked.
+///
+/// Registering an objective with the same name as an existing objective replaces it.
+pub fn register(objective: SloObjective) {
+    Lazy::force(&EPOCH);
+
+    if let Ok(mut trackers) = TRACKERS.lock() {
+        trackers.retain(|tracker| tracker.objective.name != objective.name);
+        trackers.push(Tracker {
+            objective,
+            buckets: VecDeque::new(),
+        });
+    }
+}
+
+/// The current state of an objective, as reported at `/monitor/slo`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SloReport {
+    pub name: &'static str,
+    pub path_prefix: String,
+    pub window_secs: u64,
+    pub requests: u64,
+    pub success: Option<BurnReport>,
+    pub latency: Option<BurnReport>,
+}
+
+/// The burn of a single target within an objective.
+#[derive(Clone, Debug, Serialize)]
+pub struct BurnReport {
+    pub target: f64,
+    pub actual: f64,
+    pub burn_rate: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold_ms: Option<u64>,
+}
+
+impl BurnReport {
+    fn new(target: f64, total: u64, bad: u64, threshold: Option<Duration>) -> Self {
+        let (actual, burn_rate) = if total == 0 {
+            (1.0, 0.0)
+        } else {
+            let bad_ratio = bad as f64 / total as f64;
+            (
+                1.0 - bad_ratio,
+                bad_ratio / (1.0 - target).max(f64::EPSILON),
+            )
+        };
+
+        Self {
+            target,
+            actual,
+            burn_rate,
+            threshold_ms: threshold.map(|t| t.as_millis() as u64),
+        }
+    }
+}
+
+/// Reports for all registered objectives.
+pub fn reports() -> Vec<SloReport> {
+    TRACKERS
+        .lock()
+        .map(|trackers| trackers.iter().map(Tracker::report).collect())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    minute: u64,
+    total: u64,
+    errors: u64,
+    slow: u64,
+}
+
+#[derive(Debug)]
+struct Tracker {
+    objective: SloObjective,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Tracker {
+    fn record(&mut self, minute: u64, status: StatusCode, elapsed: Duration) {
+        if self.buckets.back().map(|b| b.minute) != Some(minute) {
+            // Check the burn rate at most once a minute, when a new bucket is started.
+            self.alert();
+
+            let window_minutes = (self.objective.window.as_secs() / 60).max(1);
+            while let Some(front) = self.buckets.front() {
+                if front.minute + window_minutes <= minute {
+                    self.buckets.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            self.buckets.push_back(Bucket {
+                minute,
+                ..Bucket::default()
+            });
+        }
+
+        if let Some(bucket) = self.buckets.back_mut() {
+            bucket.total += 1;
+            if status.is_server_error() {
+                bucket.errors += 1;
+            }
+            if let Some((_, threshold)) = self.objective.latency_target {
+                if elapsed > threshold {
+                    bucket.slow += 1;
+                }
+            }
+        }
+    }
+
+    fn report(&self) -> SloReport {
+        let (total, errors, slow) = self.buckets.iter().fold((0, 0, 0), |acc, b| {
+            (acc.0 + b.total, acc.1 + b.errors, acc.2 + b.slow)
+        });
+
+        SloReport {
+            name: self.objective.name,
+            path_prefix: self.objective.path_prefix.clone(),
+            window_secs: self.objective.window.as_secs(),
+            requests: total,
+            success: self
+                .objective
+                .success_target
+                .map(|target| BurnReport::new(target, total, errors, None)),
+            latency: self
+                .objective
+                .latency_target
+                .map(|(target, threshold)| BurnReport::new(target, total, slow, Some(threshold))),
+        }
+    }
+
+    fn alert(&self) {
+        let report = self.report();
+        let alert_burn_rate = self.objective.alert_burn_rate;
+
+        for (kind, burn) in [("success", &report.success), ("latency", &report.latency)] {
+            if let Some(burn) = burn {
+                if burn.burn_rate > alert_burn_rate {
+                    log_kv!(
+                        warn,
+                        {
+                            slo: report.name,
+                            slo_kind: kind,
+                            path_prefix: report.path_prefix,
+                            target: burn.target,
+                            actual: burn.actual,
+                            burn_rate: burn.burn_rate,
+                            alert_burn_rate: alert_burn_rate,
+                            requests: report.requests,
+                        },
+                        "SLO burn rate exceeded: {} ({})", report.name, kind
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Record a response against all objectives which match its path.
+pub(crate) fn record(path: &str, status: StatusCode, elapsed: Duration) {
+    let minute = EPOCH.elapsed().as_secs() / 60;
+
+    if let Ok(mut trackers) = TRACKERS.lock() {
+        for tracker in trackers
+            .iter_mut()
+            .filter(|tracker| path.starts_with(&tracker.objective.path_prefix))
+        {
+            tracker.record(minute, status, elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_burn_rates() {
+        let mut tracker = Tracker {
+            objective: SloObjective::new("test", "/api")
+                .success_rate(0.99)
+                .latency(0.9, Duration::from_millis(100)),
+            buckets: VecDeque::new(),
+        };
+
+        for _ in 0..98 {
+            tracker.record(0, StatusCode::Ok, Duration::from_millis(10));
+        }
+        tracker.record(
+            0,
+            StatusCode::InternalServerError,
+            Duration::from_millis(10),
+        );
+        tracker.record(0, StatusCode::Ok, Duration::from_millis(500));
+
+        let report = tracker.report();
+        assert_eq!(report.requests, 100);
+
+        let success = report.success.expect("success target was set");
+        assert!((success.actual - 0.99).abs() < 1e-9);
+        assert!((success.burn_rate - 1.0).abs() < 1e-9);
+
+        let latency = report.latency.expect("latency target was set");
+        assert!((latency.burn_rate - 0.1).abs() < 1e-9);
+        assert_eq!(latency.threshold_ms, Some(100));
+    }
+
+    #[test]
+    fn drops_buckets_outside_window() {
+        let mut tracker = Tracker {
+            objective: SloObjective::new("test", "/api")
+                .success_rate(0.99)
+                .window(Duration::from_secs(120)),
+            buckets: VecDeque::new(),
+        };
+
+        tracker.record(
+            0,
+            StatusCode::InternalServerError,
+            Duration::from_millis(10),
+        );
+        tracker.record(1, StatusCode::Ok, Duration::from_millis(10));
+        tracker.record(2, StatusCode::Ok, Duration::from_millis(10));
+
+        let report = tracker.report();
+        assert_eq!(report.requests, 2);
+        assert_eq!(report.success.map(|s| s.burn_rate), Some(0.0));
+    }
+}
@@ -0,0 +1,152 @@
+//! Per-request snapshots of mutable shared state, such as feature flags and configuration.
+//!
+//! Shared state may change while a request is being handled, e.g. a flag flip or a [config reload][crate::on_reload].
+//! A snapshot is taken once, before routing, so that a handler sees the same values for its whole execution,
+//! which matters for code paths such as billing where inconsistent reads cannot be tolerated.
+//!
+//! Every snapshot includes the configuration, which [`ConfigExt`][crate::prelude::ConfigExt] reads on a request
+//! use automatically. Other state is captured by registering a function which returns an owned copy of it.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//!
+//! use preroll::prelude::*;
+//! use preroll::snapshot;
+//! use tide::Request;
+//!
+//! static NEW_PRICING: AtomicBool = AtomicBool::new(false);
+//!
+//! #[derive(Clone, Debug)]
+//! struct Flags {
+//!     new_pricing: bool,
+//! }
+//!
+//! snapshot::register(|| Flags {
+//!     new_pricing: NEW_PRICING.load(Ordering::Relaxed),
+//! });
+//!
+//! # #[allow(dead_code)]
+//! async fn charge(req: Request<()>) -> tide::Result {
+//!     // Reads the value captured when the request started, no matter how often it is read.
+//!     let new_pricing = req.snapshot::<Flags>().map_or(false, |flags| flags.new_pricing);
+//!     Ok(format!("new pricing: {}", new_pricing).into())
+//! }
+//! ```
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+use tide::Request;
+
+use crate::config::Config;
+
+type CaptureFn = dyn Fn() -> Box<dyn Any + Send + Sync> + Send + Sync;
+
+static CAPTURES: Lazy<RwLock<HashMap<TypeId, Arc<CaptureFn>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Register a function which captures a copy of some shared state into every request's [`Snapshot`][].
+///
+/// Registering a capture for the same type as an existing capture replaces it.
+pub fn register<T: Send + Sync + 'static>(capture: impl Fn() -> T + Send + Sync + 'static) {
+    if let Ok(mut captures) = CAPTURES.write() {
+        captures.insert(
+            TypeId::of::<T>(),
+            Arc::new(move || -> Box<dyn Any + Send + Sync> { Box::new(capture()) }),
+        );
+    }
+}
+
+/// Immutable copies of shared state, taken once at the start of a request.
+pub struct Snapshot {
+    config: Config,
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the configuration, and of all registered state.
+    #[must_use]
+    pub fn capture() -> Self {
+        // Clone the captures out of the lock, so that a capture may itself register another.
+        let captures: Vec<(TypeId, Arc<CaptureFn>)> = CAPTURES
+            .read()
+            .map(|captures| {
+                captures
+                    .iter()
+                    .map(|(type_id, capture)| (*type_id, capture.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            config: Config::snapshot(),
+            values: captures
+                .into_iter()
+                .map(|(type_id, capture)| (type_id, capture()))
+                .collect(),
+        }
+    }
+
+    /// The configuration, as of the start of the request.
+    #[must_use]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The captured value of a registered type, if a capture for it was registered.
+    #[must_use]
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+impl Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("config", &self.config)
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
+
+/// An extension trait for reading the request's [`Snapshot`][].
+pub trait SnapshotRequestExt {
+    /// The value of a registered type, as captured at the start of the request.
+    ///
+    /// Returns `None` if no capture is registered for `T`, or the request was not handled by `SnapshotMiddleware`.
+    fn snapshot<T: Send + Sync + 'static>(&self) -> Option<&T>;
+}
+
+impl<State> SnapshotRequestExt for Request<State> {
+    fn snapshot<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.ext::<Snapshot>()?.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Rate(u32);
+
+    #[test]
+    fn snapshots_are_unaffected_by_later_changes() {
+        static RATE: AtomicU32 = AtomicU32::new(1);
+        register(|| Rate(RATE.load(Ordering::SeqCst)));
+
+        let snapshot = Snapshot::capture();
+        RATE.store(2, Ordering::SeqCst);
+
+        assert_eq!(snapshot.get::<Rate>(), Some(&Rate(1)));
+        assert_eq!(Snapshot::capture().get::<Rate>(), Some(&Rate(2)));
+        assert_eq!(snapshot.get::<String>(), None);
+    }
+}
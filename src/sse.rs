@@ -0,0 +1,349 @@
+//! Server-Sent Events, with keep-alives and disconnect detection.
+//!
+//! An [`endpoint`][] runs its handler on its own task with an [`SseSender`][], while the response streams to the client.
+//! A `: keep-alive` comment is sent whenever no event has been sent for `SSE_KEEP_ALIVE_SECS` (default `15`),
+//! so that proxies and load balancers do not close idle streams. Once the client disconnects, sending fails with
+//! [`Disconnected`][], and the handler should return. The stream ends when the handler returns.
+//!
+//! Streams are logged once opened, like any other response, but are never logged as slow requests.
+//! Once a stream ends, its duration and number of events are logged at `INFO`.
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use preroll::sse;
+//! use serde::Serialize;
+//! use tide::Route;
+//!
+//! #[derive(Serialize)]
+//! struct Progress {
+//!     percent: u8,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     server
+//!         .at("jobs/:id/progress")
+//!         .get(sse::endpoint(|_req, sender| async move {
+//!             for percent in (0..=100).step_by(10) {
+//!                 sender.send_json("progress", &Progress { percent }, None).await?;
+//!                 async_std::task::sleep(Duration::from_secs(1)).await;
+//!             }
+//!             Ok(())
+//!         }));
+//! }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::task;
+use futures_lite::io::{AsyncRead, BufReader};
+use futures_lite::Stream;
+use serde::Serialize;
+use tide::http::mime;
+use tide::{Body, Endpoint, Request, Response, StatusCode};
+
+use crate::config;
+use crate::logging::{duration_ms, log_kv, LogContext};
+use crate::middleware::extension_types::RequestId;
+use crate::middleware::tenant::TenantId;
+
+/// The comment sent to keep idle streams open.
+const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+/// The client has disconnected, so no more events can be sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The SSE client has disconnected")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// A response marker, which `LogMiddleware` uses to exempt event streams from slow request logging.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EventStream;
+
+/// Sends events to a connected SSE client.
+#[derive(Clone, Debug)]
+pub struct SseSender {
+    sender: Sender<Vec<u8>>,
+    events: Arc<AtomicU64>,
+}
+
+impl SseSender {
+    /// Send an event named `event`, with `data` and an optional `id`, which the client echoes as `Last-Event-ID` when reconnecting.
+    pub async fn send(
+        &self,
+        event: &str,
+        data: &str,
+        id: Option<&str>,
+    ) -> Result<(), Disconnected> {
+        let mut message = String::new();
+        if !event.is_empty() {
+            message.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = id {
+            message.push_str(&format!("id: {}\n", id));
+        }
+        for line in data.lines() {
+            message.push_str(&format!("data: {}\n", line));
+        }
+        message.push('\n');
+
+        self.sender
+            .send(message.into_bytes())
+            .await
+            .map_err(|_| Disconnected)?;
+        self.events.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Send an event with `data` serialized as JSON.
+    pub async fn send_json<T: Serialize>(
+        &self,
+        event: &str,
+        data: &T,
+        id: Option<&str>,
+    ) -> tide::Result<()> {
+        let data = serde_json::to_string(data)?;
+        self.send(event, &data, id).await?;
+        Ok(())
+    }
+
+    /// Whether the client is still connected.
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        !self.sender.is_closed()
+    }
+}
+
+/// The response body of an event stream, interleaving keep-alive comments.
+struct EventBody {
+    events: Receiver<Vec<u8>>,
+    keep_alive: Duration,
+    // Only ever accessed via `&mut self`, and locked only to make the body `Sync`.
+    timer: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl EventBody {
+    fn new(events: Receiver<Vec<u8>>, keep_alive: Duration) -> Self {
+        Self {
+            events,
+            keep_alive,
+            timer: Mutex::new(Box::pin(task::sleep(keep_alive))),
+            chunk: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+impl AsyncRead for EventBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.chunk.len() {
+                let len = buf.len().min(this.chunk.len() - this.read);
+                buf[..len].copy_from_slice(&this.chunk[this.read..this.read + len]);
+                this.read += len;
+                return Poll::Ready(Ok(len));
+            }
+
+            let timer = this.timer.get_mut().unwrap_or_else(PoisonError::into_inner);
+            match Pin::new(&mut this.events).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    this.chunk = chunk;
+                    *timer = Box::pin(task::sleep(this.keep_alive));
+                }
+                // The handler has returned.
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => match timer.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        this.chunk = KEEP_ALIVE.to_vec();
+                        *timer = Box::pin(task::sleep(this.keep_alive));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+            }
+            this.read = 0;
+        }
+    }
+}
+
+/// Create an endpoint which streams events sent by `handler`.
+///
+/// See [the module documentation][self] for an example.
+pub fn endpoint<F, Fut, State>(handler: F) -> SseEndpoint<F, State>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(Request<State>, SseSender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = tide::Result<()>> + Send + 'static,
+{
+    SseEndpoint {
+        handler: Arc::new(handler),
+        _state: PhantomData,
+    }
+}
+
+/// An endpoint which streams Server-Sent Events, created with [`endpoint`][].
+pub struct SseEndpoint<F, State> {
+    handler: Arc<F>,
+    _state: PhantomData<fn() -> State>,
+}
+
+impl<F, State> fmt::Debug for SseEndpoint<F, State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SseEndpoint").finish()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<F, Fut, State> Endpoint<State> for SseEndpoint<F, State>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(Request<State>, SseSender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = tide::Result<()>> + Send + 'static,
+{
+    async fn call(&self, req: Request<State>) -> tide::Result {
+        let keep_alive = Duration::from_secs(
+            config::var("SSE_KEEP_ALIVE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(15),
+        );
+
+        let (sender, receiver) = channel::bounded(16);
+        let sender = SseSender {
+            sender,
+            events: Arc::new(AtomicU64::new(0)),
+        };
+
+        let request_id = req
+            .ext::<RequestId>()
+            .map(|id| id.as_str().to_string())
+            .unwrap_or_default();
+        let path = req.url().path().to_string();
+        let log_context = LogContext {
+            request_id: request_id.clone(),
+            honeycomb_trace_id: None,
+            tenant_id: req.ext::<TenantId>().map(|id| id.as_str().to_string()),
+        };
+
+        let handler = self.handler.clone();
+        task::spawn(async move {
+            let start = Instant::now();
+            let events = sender.events.clone();
+            let connected = sender.clone();
+            let result = log_context.scope(handler(req, sender)).await;
+
+            let ended_by = match &result {
+                Err(error) if error.downcast_ref::<Disconnected>().is_some() => "disconnect",
+                Err(_) => "error",
+                Ok(()) if !connected.is_connected() => "disconnect",
+                Ok(()) => "handler",
+            };
+            if let (Err(error), "error") = (&result, ended_by) {
+                log_kv!(
+                    error,
+                    {
+                        path: path,
+                        request_id: request_id,
+                    },
+                    "SSE handler error: {:?}", error
+                );
+            }
+
+            log_kv!(
+                info,
+                {
+                    path: path,
+                    request_id: request_id,
+                    duration_ms: duration_ms(start.elapsed()),
+                    events: events.load(Ordering::Relaxed),
+                    ended_by: ended_by,
+                },
+                "SSE stream closed"
+            );
+        });
+
+        let mut res = Response::new(StatusCode::Ok);
+        res.insert_header("Cache-Control", "no-cache");
+        // Disables response buffering in nginx, which would otherwise hold events back.
+        res.insert_header("X-Accel-Buffering", "no");
+
+        let mut body =
+            Body::from_reader(BufReader::new(EventBody::new(receiver, keep_alive)), None);
+        body.set_mime(mime::SSE);
+        res.set_body(body);
+        res.insert_ext(EventStream);
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::AsyncReadExt;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn streams_events_and_keep_alives() -> tide::Result<()> {
+        let (sender, receiver) = channel::bounded(16);
+        let sender = SseSender {
+            sender,
+            events: Arc::new(AtomicU64::new(0)),
+        };
+        let mut body = EventBody::new(receiver, Duration::from_millis(20));
+
+        sender.send("greeting", "hello\nworld", Some("1")).await?;
+        task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            sender
+                .send_json("", &serde_json::json!({ "done": true }), None)
+                .await
+        });
+
+        let mut stream = String::new();
+        body.read_to_string(&mut stream).await?;
+        assert!(stream
+            .starts_with("event: greeting\nid: 1\ndata: hello\ndata: world\n\n: keep-alive\n\n"));
+        assert!(stream.ends_with("data: {\"done\":true}\n\n"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn detects_disconnects() {
+        let (sender, receiver) = channel::bounded(16);
+        let sender = SseSender {
+            sender,
+            events: Arc::new(AtomicU64::new(0)),
+        };
+
+        assert!(sender.is_connected());
+        drop(EventBody::new(receiver, Duration::from_secs(15)));
+        assert!(!sender.is_connected());
+        assert_eq!(sender.send("", "data", None).await, Err(Disconnected));
+    }
+}
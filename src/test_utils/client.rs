@@ -0,0 +1,206 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use surf::http::headers::{HeaderName, AUTHORIZATION, COOKIE, SET_COOKIE};
+use surf::{Client, RequestBuilder, Response};
+
+use super::{assert_ok, TestResult};
+use crate::webhooks::WebhookMiddleware;
+
+/// A thin wrapper over a test [`surf::Client`][], which prefixes paths with an API base path,
+/// sends default headers, and has typed JSON helpers.
+///
+/// Paths are prefixed with `/api/v1` by default, matching the first routes setup function. Use [`inner`][TestClient::inner]
+/// for unprefixed requests, e.g. to `/monitor/` routes.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestClient, TestResult};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Deserialize, Serialize)]
+/// struct Greeting {
+///     greeting: String,
+/// }
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("greeting").post(|mut req: tide::Request<_>| async move {
+///         let greeting: Greeting = req.body_json().await?;
+///         tide::Body::from_json(&greeting)
+///     });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await?;
+///     let client = TestClient::new(client).bearer("test-token");
+///
+///     let greeting: Greeting = client
+///         .post_json("/greeting", &Greeting { greeting: "Hello".to_string() })
+///         .await?;
+///     assert_eq!(greeting.greeting, "Hello");
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TestClient {
+    client: Client,
+    base_path: String,
+    headers: Vec<(HeaderName, String)>,
+}
+
+impl TestClient {
+    /// Wrap a client, e.g. from [`create_client`][super::create_client], prefixing paths with `/api/v1`.
+    #[must_use]
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            base_path: "/api/v1".to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Prefix paths with `/api/v{version}`, for routes from the `version`th routes setup function.
+    #[must_use]
+    pub fn api_version(self, version: usize) -> Self {
+        self.base_path(format!("/api/v{}", version))
+    }
+
+    /// Prefix paths with `base_path`, which may be empty.
+    #[must_use]
+    pub fn base_path(mut self, base_path: impl Into<String>) -> Self {
+        self.base_path = base_path.into();
+        self
+    }
+
+    /// Send a header with every request.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Send an `Authorization: Bearer {token}` header with every request.
+    #[must_use]
+    pub fn bearer(self, token: impl AsRef<str>) -> Self {
+        self.header(AUTHORIZATION, format!("Bearer {}", token.as_ref()))
+    }
+
+    /// `GET` the prefixed `path`, and send the CSRF token, and any double-submit cookie, it issues with every request,
+    /// for routes protected by [`CsrfMiddleware`][crate::CsrfMiddleware].
+    pub async fn csrf(mut self, path: &str) -> TestResult<Self> {
+        let res = self.get(path).await?;
+        let token = res
+            .header("X-CSRF-Token")
+            .map(|values| values.last().as_str().to_string())
+            .ok_or_else(|| surf::Error::from_str(500, "No X-CSRF-Token response header"))?;
+
+        if let Some(set_cookie) = res.header(SET_COOKIE) {
+            let cookie = set_cookie.last().as_str().split(';').next().unwrap_or("");
+            match self.headers.iter_mut().find(|(name, _)| name == &COOKIE) {
+                Some((_, cookies)) => {
+                    cookies.push_str("; ");
+                    cookies.push_str(cookie);
+                }
+                None => self.headers.push((COOKIE, cookie.to_string())),
+            }
+        }
+
+        Ok(self.header("X-CSRF-Token", token))
+    }
+
+    /// The wrapped client, which does not prefix paths or send default headers.
+    #[must_use]
+    pub fn inner(&self) -> &Client {
+        &self.client
+    }
+
+    fn with_defaults(&self, mut builder: RequestBuilder) -> RequestBuilder {
+        for (name, value) in &self.headers {
+            builder = builder.header(name.clone(), value.as_str());
+        }
+        builder
+    }
+
+    fn path(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    /// A `GET` request to the prefixed `path`.
+    #[must_use]
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.with_defaults(self.client.get(self.path(path)))
+    }
+
+    /// A `POST` request to the prefixed `path`.
+    #[must_use]
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.with_defaults(self.client.post(self.path(path)))
+    }
+
+    /// A `PUT` request to the prefixed `path`.
+    #[must_use]
+    pub fn put(&self, path: &str) -> RequestBuilder {
+        self.with_defaults(self.client.put(self.path(path)))
+    }
+
+    /// A `PATCH` request to the prefixed `path`.
+    #[must_use]
+    pub fn patch(&self, path: &str) -> RequestBuilder {
+        self.with_defaults(self.client.patch(self.path(path)))
+    }
+
+    /// A `DELETE` request to the prefixed `path`.
+    #[must_use]
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.with_defaults(self.client.delete(self.path(path)))
+    }
+
+    /// `GET` the prefixed `path`, assert a successful status, and deserialize the JSON response body.
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> TestResult<T> {
+        let res = self.get(path).await?;
+        Self::json_ok(res).await
+    }
+
+    /// `POST` `body` as JSON to the prefixed `path`, assert a successful status, and deserialize the JSON response body.
+    pub async fn post_json<B, T>(&self, path: &str, body: &B) -> TestResult<T>
+    where
+        B: Serialize,
+        T: DeserializeOwned,
+    {
+        let res = self.post(path).body_json(body)?.await?;
+        Self::json_ok(res).await
+    }
+
+    /// `PUT` `body` as JSON to the prefixed `path`, assert a successful status, and deserialize the JSON response body.
+    pub async fn put_json<B, T>(&self, path: &str, body: &B) -> TestResult<T>
+    where
+        B: Serialize,
+        T: DeserializeOwned,
+    {
+        let res = self.put(path).body_json(body)?.await?;
+        Self::json_ok(res).await
+    }
+
+    /// A `POST` request of `body` as JSON to the prefixed `path`, signed as `webhook` expects,
+    /// for routes protected by [`WebhookMiddleware`][].
+    pub fn post_webhook<B: Serialize>(
+        &self,
+        path: &str,
+        webhook: &WebhookMiddleware,
+        body: &B,
+    ) -> TestResult<RequestBuilder> {
+        let body = serde_json::to_vec(body)?;
+        Ok(self
+            .post(path)
+            .header(webhook.header_name().as_str(), webhook.sign(&body))
+            .body(body)
+            .content_type("application/json"))
+    }
+
+    async fn json_ok<T: DeserializeOwned>(res: Response) -> TestResult<T> {
+        let body = assert_ok(res).await;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
@@ -0,0 +1,253 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use cfg_if::cfg_if;
+use log::{Level, LevelFilter};
+
+cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        use tracing::{Event, Subscriber};
+        use tracing_subscriber::layer::{Context, Layer};
+
+        use crate::logging::EventFields;
+    } else {
+        use log::{kv, Log, Metadata, Record};
+        use once_cell::sync::OnceCell;
+
+        use crate::logging::LogContext;
+
+        static LOGGER: OnceCell<CaptureLogger> = OnceCell::new();
+    }
+}
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Arc<Mutex<Vec<CapturedLog>>>>> = const { RefCell::new(None) };
+}
+
+/// A log record captured by [`capture_logs`][].
+#[derive(Clone, Debug)]
+pub struct CapturedLog {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    /// The record's key-value fields, and those of the request's log context, such as `request_id`.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedLog {
+    /// The value of the field `name`, if the record has it.
+    #[must_use]
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// The log records captured by [`capture_logs`][], until this is dropped.
+#[derive(Debug)]
+pub struct LogCapture {
+    records: Arc<Mutex<Vec<CapturedLog>>>,
+    previous: Option<Arc<Mutex<Vec<CapturedLog>>>>,
+}
+
+impl LogCapture {
+    /// All records captured so far, in the order they were logged.
+    #[must_use]
+    pub fn records(&self) -> Vec<CapturedLog> {
+        self.records
+            .lock()
+            .map(|records| records.clone())
+            .unwrap_or_default()
+    }
+
+    /// The records captured so far at exactly `level`.
+    #[must_use]
+    pub fn at_level(&self, level: Level) -> Vec<CapturedLog> {
+        self.records()
+            .into_iter()
+            .filter(|record| record.level == level)
+            .collect()
+    }
+
+    /// The records captured so far which have the field `name` with `value`.
+    #[must_use]
+    pub fn with_field(&self, name: &str, value: &str) -> Vec<CapturedLog> {
+        self.records()
+            .into_iter()
+            .filter(|record| record.field(name) == Some(value))
+            .collect()
+    }
+}
+
+impl Drop for LogCapture {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CAPTURE.try_with(|capture| capture.replace(previous)).ok();
+    }
+}
+
+/// Capture log records, at all levels and regardless of `LOGLEVEL`, until the returned [`LogCapture`][] is dropped.
+///
+/// Capturing is for the current thread, on which in-process test clients, such as from [`create_client`][super::create_client],
+/// handle requests. Records are still written by the usual test logger, subject to `LOGLEVEL`.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///     let logs = test_utils::capture_logs();
+///
+///     let res = client.get("/api/v1/not-found").await?;
+///     assert_eq!(res.status(), 404);
+///
+///     let warnings = logs.at_level(log::Level::Warn);
+///     assert_eq!(warnings.len(), 1);
+///     assert!(warnings[0].field("request_id").is_some());
+///     Ok(())
+/// }
+/// ```
+#[must_use = "logs are only captured until the LogCapture is dropped"]
+pub fn capture_logs() -> LogCapture {
+    super::init_logging();
+    log::set_max_level(LevelFilter::Trace);
+
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let previous = CAPTURE
+        .try_with(|capture| capture.replace(Some(records.clone())))
+        .ok()
+        .flatten();
+
+    LogCapture { records, previous }
+}
+
+fn capture(record: impl FnOnce() -> CapturedLog) {
+    CAPTURE
+        .try_with(|capture| {
+            if let Some(records) = capture.borrow().as_ref() {
+                if let Ok(mut records) = records.lock() {
+                    records.push(record());
+                }
+            }
+        })
+        .ok();
+}
+
+cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        /// A `tracing` layer which records events, including forwarded `log` records, into any [`LogCapture`][] on the current thread.
+        pub(crate) struct CaptureLayer;
+
+        impl<S: Subscriber> Layer<S> for CaptureLayer {
+            fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+                capture(|| {
+                    let metadata = event.metadata();
+
+                    let mut fields = EventFields::default();
+                    event.record(&mut fields);
+                    fields.add_context(metadata.target());
+
+                    // Events forwarded from `log` records carry their original metadata as fields.
+                    let mut target = metadata.target().to_string();
+                    let mut captured = BTreeMap::new();
+                    for (key, value) in fields.pairs {
+                        match key {
+                            "log.target" => target = value,
+                            _ if key.starts_with("log.") => (),
+                            _ => {
+                                captured.insert(key.to_string(), value);
+                            }
+                        }
+                    }
+
+                    CapturedLog {
+                        level: match *metadata.level() {
+                            tracing::Level::ERROR => Level::Error,
+                            tracing::Level::WARN => Level::Warn,
+                            tracing::Level::INFO => Level::Info,
+                            tracing::Level::DEBUG => Level::Debug,
+                            tracing::Level::TRACE => Level::Trace,
+                        },
+                        target,
+                        message: fields.message,
+                        fields: captured,
+                    }
+                });
+            }
+        }
+    } else {
+        /// Install the global test logger, which writes via `inner` and additionally records into any [`LogCapture`][] on the current thread.
+        pub(super) fn install(inner: env_logger::Logger) {
+            let max_level = inner.filter();
+            if LOGGER.set(CaptureLogger { inner }).is_err() {
+                return;
+            }
+
+            if let Some(logger) = LOGGER.get() {
+                if log::set_logger(logger).is_ok() {
+                    log::set_max_level(max_level);
+                }
+            }
+        }
+
+        struct CaptureLogger {
+            inner: env_logger::Logger,
+        }
+
+        impl Log for CaptureLogger {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                capturing() || self.inner.enabled(metadata)
+            }
+
+            fn log(&self, record: &Record<'_>) {
+                capture(|| captured_log(record));
+                self.inner.log(record);
+            }
+
+            fn flush(&self) {
+                self.inner.flush();
+            }
+        }
+
+        fn capturing() -> bool {
+            CAPTURE
+                .try_with(|capture| capture.borrow().is_some())
+                .unwrap_or(false)
+        }
+
+        fn captured_log(record: &Record<'_>) -> CapturedLog {
+        let mut fields = FieldVisitor(BTreeMap::new());
+        record.key_values().visit(&mut fields).ok();
+
+        if let Some(context) = LogContext::for_target(record.target()) {
+            for (key, value) in context.pairs() {
+                fields.0.insert(key.to_string(), value.to_string());
+            }
+        }
+
+        CapturedLog {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            fields: fields.0,
+        }
+    }
+
+        struct FieldVisitor(BTreeMap<String, String>);
+
+        impl<'kvs> kv::Visitor<'kvs> for FieldVisitor {
+            fn visit_pair(&mut self, key: kv::Key<'kvs>, val: kv::Value<'kvs>) -> Result<(), kv::Error> {
+                self.0.insert(key.to_string(), val.to_string());
+                Ok(())
+            }
+        }
+    }
+}
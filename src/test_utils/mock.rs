@@ -0,0 +1,338 @@
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+use surf::{Client, Config, Url};
+use tide::http::Method;
+use tide::{Request, Response, StatusCode};
+
+/// A response returned by a mock [`Expectation`][].
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: StatusCode,
+    body: Option<Value>,
+}
+
+impl From<Value> for MockResponse {
+    fn from(body: Value) -> Self {
+        Self {
+            status: StatusCode::Ok,
+            body: Some(body),
+        }
+    }
+}
+
+impl From<StatusCode> for MockResponse {
+    fn from(status: StatusCode) -> Self {
+        Self { status, body: None }
+    }
+}
+
+impl From<(StatusCode, Value)> for MockResponse {
+    fn from((status, body): (StatusCode, Value)) -> Self {
+        Self {
+            status,
+            body: Some(body),
+        }
+    }
+}
+
+impl MockResponse {
+    fn to_response(&self) -> tide::Result<Response> {
+        let mut res = Response::new(self.status);
+        if let Some(body) = &self.body {
+            res.set_body(tide::Body::from_json(body)?);
+        }
+        Ok(res)
+    }
+}
+
+/// An expected mock request, and the responses to return for it.
+///
+/// Created by [`MockServer::expect`][].
+#[derive(Clone, Debug)]
+pub struct Expectation {
+    method: Method,
+    path: String,
+    responses: Vec<MockResponse>,
+    times: Option<usize>,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Expectation {
+    /// Add a response. Successive calls return successive responses, and the last response is repeated.
+    ///
+    /// With no responses, an empty `200 OK` is returned.
+    pub fn returning(&mut self, response: impl Into<MockResponse>) -> &mut Self {
+        self.responses.push(response.into());
+        self
+    }
+
+    /// Expect exactly `times` calls, rather than at least one.
+    pub fn times(&mut self, times: usize) -> &mut Self {
+        self.times = Some(times);
+        self
+    }
+
+    fn matches(&self, method: Method, path: &str) -> bool {
+        self.method == method && self.path == path
+    }
+
+    fn exhausted(&self) -> bool {
+        self.times
+            .map(|times| self.calls.load(Ordering::SeqCst) >= times)
+            .unwrap_or(false)
+    }
+
+    fn call(&self) -> tide::Result<Response> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        match self.responses.get(call).or_else(|| self.responses.last()) {
+            Some(response) => response.to_response(),
+            None => Ok(Response::new(StatusCode::Ok)),
+        }
+    }
+
+    fn verify(&self) -> Option<String> {
+        let calls = self.calls.load(Ordering::SeqCst);
+        match self.times {
+            Some(times) if calls != times => Some(format!(
+                "{} {}: expected {} calls, got {}",
+                self.method, self.path, times, calls
+            )),
+            None if calls == 0 => Some(format!(
+                "{} {}: expected at least one call, got none",
+                self.method, self.path
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A mock server built from per-call expectations, which are verified when it is dropped.
+///
+/// Expectations are matched by method and path, excluding any query string, in the order they were added,
+/// skipping any which have already been called `times` times.
+/// Requests which match no expectation receive a `501 Not Implemented`, and fail verification.
+///
+/// Expectations must be added before creating a [`client`][MockServer::client].
+///
+/// ## Example:
+/// ```
+/// use preroll::test_utils::MockServer;
+/// use serde_json::json;
+/// use tide::http::Method;
+/// use tide::StatusCode;
+///
+/// #[async_std::main]
+/// async fn main() {
+///     let mut mock = MockServer::new();
+///     mock.expect(Method::Get, "/users/1")
+///         .returning(json!({ "name": "Ada" }))
+///         .times(2);
+///     mock.expect(Method::Post, "/retry")
+///         .returning(StatusCode::InternalServerError)
+///         .returning(StatusCode::Ok)
+///         .times(2);
+///
+///     let client = mock.client("http://api.example_local.org/");
+///
+///     for _ in 0..2 {
+///         let user = client.get("/users/1").recv_string().await.unwrap();
+///         assert_eq!(user, r#"{"name":"Ada"}"#);
+///     }
+///
+///     let res = client.post("/retry").await.unwrap();
+///     assert_eq!(res.status(), 500);
+///     let res = client.post("/retry").await.unwrap();
+///     assert_eq!(res.status(), 200);
+///
+///     mock.verify();
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct MockServer {
+    expectations: Vec<Expectation>,
+    unexpected: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    /// Create a new `MockServer`, with no expectations.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect a request to `path` with `method`.
+    pub fn expect(&mut self, method: Method, path: impl Into<String>) -> &mut Expectation {
+        self.expectations.push(Expectation {
+            method,
+            path: path.into(),
+            responses: Vec::new(),
+            times: None,
+            calls: Arc::new(AtomicUsize::new(0)),
+        });
+        self.expectations
+            .last_mut()
+            .expect("an expectation was just added")
+    }
+
+    /// Create a client directly connected to a server for the expectations added so far.
+    pub fn client(&self, base_url: impl AsRef<str>) -> Client {
+        let expectations = Arc::new(self.expectations.clone());
+        let unexpected = self.unexpected.clone();
+
+        let mut mocks_server = tide::new();
+        let handler = move |req: Request<()>| {
+            let expectations = expectations.clone();
+            let unexpected = unexpected.clone();
+            async move {
+                let method = req.method();
+                let path = req.url().path();
+
+                let mut matching = expectations
+                    .iter()
+                    .filter(|expectation| expectation.matches(method, path));
+                let expectation = matching
+                    .clone()
+                    .find(|expectation| !expectation.exhausted())
+                    .or_else(|| matching.next_back());
+
+                match expectation {
+                    Some(expectation) => expectation.call(),
+                    None => {
+                        let request = format!("{} {}", method, path);
+                        if let Ok(mut unexpected) = unexpected.lock() {
+                            unexpected.push(request.clone());
+                        }
+                        Ok(Response::builder(StatusCode::NotImplemented)
+                            .body(format!("No mock expectation for {}", request))
+                            .build())
+                    }
+                }
+            }
+        };
+        mocks_server.at("/").all(handler.clone());
+        mocks_server.at("*").all(handler);
+
+        Config::new()
+            .set_http_client(mocks_server)
+            .set_base_url(Url::parse(base_url.as_ref()).unwrap())
+            .try_into()
+            .expect("async-h1 client from config is infallible")
+    }
+
+    /// Panic if any expectation was not called the expected number of times, or if there were unexpected requests.
+    ///
+    /// Also done when the `MockServer` is dropped.
+    pub fn verify(&self) {
+        let mut failures: Vec<String> = self
+            .expectations
+            .iter()
+            .filter_map(Expectation::verify)
+            .collect();
+
+        if let Ok(unexpected) = self.unexpected.lock() {
+            failures.extend(
+                unexpected
+                    .iter()
+                    .map(|request| format!("{}: unexpected request", request)),
+            );
+        }
+
+        if !failures.is_empty() {
+            panic!("Mock expectations failed:\n{}", failures.join("\n"));
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // Avoid a double panic, which would abort rather than report the original failure.
+        if !std::thread::panicking() {
+            self.verify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn responds_in_order() -> surf::Result<()> {
+        let mut mock = MockServer::new();
+        mock.expect(Method::Post, "/retry")
+            .returning(StatusCode::InternalServerError)
+            .returning(json!({ "ok": true }))
+            .times(3);
+        let client = mock.client("http://api.example_local.org/");
+
+        let res = client.post("/retry").await?;
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+
+        let mut res = client.post("/retry").await?;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await?, r#"{"ok":true}"#);
+
+        // The last response is repeated.
+        let mut res = client.post("/retry").await?;
+        assert_eq!(res.body_string().await?, r#"{"ok":true}"#);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn moves_on_from_exhausted_expectations() -> surf::Result<()> {
+        let mut mock = MockServer::new();
+        mock.expect(Method::Get, "/users/1")
+            .returning(json!({ "name": "Ada" }))
+            .times(1);
+        mock.expect(Method::Get, "/users/1")
+            .returning(StatusCode::NotFound)
+            .times(1);
+        let client = mock.client("http://api.example_local.org/");
+
+        let mut res = client.get("/users/1").await?;
+        assert_eq!(res.body_string().await?, r#"{"name":"Ada"}"#);
+
+        let res = client.get("/users/1").await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        // Once every matching expectation is exhausted, the last one is called again,
+        // which fails verification as too many calls.
+        let res = client.get("/users/1").await?;
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(mock.expectations[1].calls.load(Ordering::SeqCst), 2);
+        assert!(mock.expectations[1].verify().is_some());
+
+        // Leave the expectation satisfied for verification on drop.
+        mock.expectations[1].calls.store(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_unexpected_requests() -> surf::Result<()> {
+        let mock = MockServer::new();
+        let client = mock.client("http://api.example_local.org/");
+
+        let res = client.delete("/users/1").await?;
+        assert_eq!(res.status(), StatusCode::NotImplemented);
+
+        let unexpected = std::panic::catch_unwind(|| mock.verify());
+        assert!(unexpected.is_err());
+
+        // Leave nothing to fail verification on drop.
+        #[allow(clippy::unwrap_used)]
+        mock.unexpected.lock().unwrap().clear();
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "GET /users/1: expected 2 calls, got 0")]
+    fn verifies_on_drop() {
+        let mut mock = MockServer::new();
+        mock.expect(Method::Get, "/users/1").times(2);
+    }
+}
@@ -26,35 +26,92 @@
 
 #![allow(clippy::unwrap_used)]
 
+mod client;
+mod logs;
+mod mock;
+mod snapshot;
+#[cfg(any(feature = "honeycomb", feature = "tracing-logs"))]
+mod traces;
+
+pub use client::TestClient;
+pub use logs::{capture_logs, CapturedLog, LogCapture};
+pub use mock::{Expectation, MockResponse, MockServer};
+pub use snapshot::assert_json_snapshot;
+#[cfg(any(feature = "honeycomb", feature = "tracing-logs"))]
+#[cfg_attr(
+    feature = "docs",
+    doc(cfg(any(feature = "honeycomb", feature = "tracing-logs")))
+)]
+pub use traces::{capture_traces, CapturedEvent, CapturedSpan, TraceCapture};
+
 use std::convert::TryInto;
-use std::env;
 use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
+use async_std::task::JoinHandle;
+
 use cfg_if::cfg_if;
 use surf::{Client, Config, StatusCode, Url};
-use tide::{http, Server};
+use tide::listener::Listener;
+use tide::{http, Middleware, Next, Request, Server};
 
-use crate::builtins::monitor::setup_monitor;
+use crate::builtins::stats::ScopedStats;
+pub use crate::builtins::stats::StatsSnapshot;
+use crate::config;
+use crate::di::Overrides;
+use crate::flags::FlagOverrides;
+use crate::logging::LogLevel;
+#[cfg(not(feature = "tracing-logs"))]
 use crate::logging::{log_format_json, log_format_pretty};
 use crate::middleware::json_error::JsonError;
-use crate::middleware::{JsonErrorMiddleware, LogMiddleware, RequestIdMiddleware};
-use crate::VariadicRoutes;
+#[cfg(feature = "fail-on-error")]
+use crate::middleware::logger::ErrorReport;
+use crate::middleware::InjectMiddleware;
+#[cfg(debug_assertions)]
+use crate::setup::get_internal_error;
+use crate::setup::{bind_server, create_servers};
+use crate::{SetupResult, VariadicRoutes};
 
-#[cfg(feature = "honeycomb")]
+#[cfg(any(feature = "honeycomb", feature = "tracing-logs"))]
 use tracing_subscriber::Registry;
 
+cfg_if! {
+    if #[cfg(feature = "tracing-logs")] {
+        use tracing_log::LogTracer;
+        use tracing_subscriber::prelude::*;
+
+        use crate::logging::{tracing_log_layer, LogSink};
+    }
+}
+
 cfg_if! {
     if #[cfg(feature = "postgres")] {
+        use std::path::PathBuf;
+        use std::pin::Pin;
+
         use async_std::sync::RwLock;
-        use sqlx::postgres::{PgConnectOptions, PgPoolOptions, Postgres};
-        use sqlx::ConnectOptions;
-        use tide::{Middleware, Next, Request};
+        use sqlx::postgres::{PgConnectOptions, PgConnection, PgPoolOptions, Postgres};
+        use sqlx::Executor;
+
+        use crate::middleware::postgres::ConnectionWrapInner;
+    }
+}
 
-        use crate::middleware::postgres::{ConnectionWrap, ConnectionWrapInner};
+cfg_if! {
+    if #[cfg(feature = "sqlite")] {
+        use async_std::sync::Mutex;
+        use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+        use crate::middleware::sqlite::{SqliteConnectionInner, SqliteConnectionWrap};
     }
 }
 
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+use sqlx::ConnectOptions;
+
 /// The result type to use for tests.
 ///
 /// This is a `surf::Result<T>`.
@@ -93,12 +150,145 @@ where
 {
     let server = create_server(state, setup_routes_fns)?;
 
-    let client: Client = Config::new()
-        .set_http_client(server)
-        .set_base_url(Url::parse("http://localhost:8080")?) // Address not actually used.
-        .try_into()?;
+    in_process_client(server)
+}
 
-    Ok(client)
+/// Creates a test application with a `custom_setup` function, as passed to `preroll::main!`, and routes set up,
+/// and hands back a client which is already connected to the server.
+///
+/// `custom_setup` is run after preroll's middleware and before routes are added, as in `preroll::main!`,
+/// so that middleware and routes it adds are also present in tests.
+///
+/// ## Example:
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::test_utils::{self, assert_status, TestResult};
+/// use preroll::SetupResult;
+/// use tide::Server;
+///
+/// pub async fn setup_custom(mut server: Server<Arc<()>>) -> SetupResult<Server<Arc<()>>> {
+///     server.at("custom").get(|_| async { Ok("custom") });
+///     Ok(server)
+/// }
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client_with_custom((), setup_custom, setup_routes).await?;
+///
+///     let mut res = client.get("/custom").await?;
+///     assert_eq!(assert_status(&mut res, 200).await, "custom");
+///     Ok(())
+/// }
+/// ```
+pub async fn create_client_with_custom<State, CustomFn, CustomFut>(
+    state: State,
+    custom_setup: CustomFn,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+    CustomFn: FnOnce(Server<Arc<State>>) -> CustomFut,
+    CustomFut: Future<Output = SetupResult<Server<Arc<State>>>>,
+{
+    let (base_server, server) = create_app_servers(state);
+    let server = custom_setup(server)
+        .await
+        .map_err(|error| surf::Error::from_str(500, format!("{:?}", error)))?;
+    let server = route_server(base_server, server, setup_routes_fns);
+
+    in_process_client(server)
+}
+
+/// Creates a test application with routes set up, which [injects][crate::di] `overrides` rather than registered
+/// dependencies, and hands back a client which is already connected to the server.
+///
+/// ## Example:
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::di::{self, Overrides};
+/// use preroll::prelude::*;
+/// use preroll::test_utils::{self, assert_status, TestResult};
+///
+/// struct Greeting(String);
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, Arc<()>>) {
+///     server
+///         .at("greeting")
+///         .get(|req: tide::Request<Arc<()>>| async move { Ok(req.inject::<Greeting>()?.0.clone()) });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     di::register(|| Greeting("Hello!".to_string()));
+///
+///     let overrides = Overrides::new().with(|| Greeting("Mocked!".to_string()));
+///     let client = test_utils::create_client_with_overrides((), overrides, setup_routes).await?;
+///
+///     let mut res = client.get("/api/v1/greeting").await?;
+///     assert_eq!(assert_status(&mut res, 200).await, "Mocked!");
+///     Ok(())
+/// }
+/// ```
+pub async fn create_client_with_overrides<State>(
+    state: State,
+    overrides: Overrides,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+{
+    let (base_server, mut server) = create_app_servers(state);
+    server.with(InjectMiddleware::with_overrides(overrides));
+    let server = route_server(base_server, server, setup_routes_fns);
+
+    in_process_client(server)
+}
+
+/// Creates a test application with routes set up, whose [feature flags][crate::flags] are evaluated as in `flags`
+/// before the provider, and hands back a client which is already connected to the server.
+///
+/// ## Example:
+/// ```
+/// use std::sync::Arc;
+///
+/// use preroll::flags::FlagOverrides;
+/// use preroll::prelude::*;
+/// use preroll::test_utils::{self, assert_status, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, Arc<()>>) {
+///     server.at("pricing").get(|req: tide::Request<Arc<()>>| async move {
+///         let layout = req.flag_variant("checkout").unwrap_or_else(|| "classic".to_string());
+///         Ok(format!("new pricing: {}, layout: {}", req.flag_enabled("new-pricing"), layout))
+///     });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let flags = FlagOverrides::new().set("new-pricing", true).set("checkout", "one-page");
+///     let client = test_utils::create_client_with_flags((), flags, setup_routes).await?;
+///
+///     let mut res = client.get("/api/v1/pricing").await?;
+///     assert_eq!(assert_status(&mut res, 200).await, "new pricing: true, layout: one-page");
+///     Ok(())
+/// }
+/// ```
+pub async fn create_client_with_flags<State>(
+    state: State,
+    flags: FlagOverrides,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<Client>
+where
+    State: Send + Sync + 'static,
+{
+    let overrides = Overrides::new().with(move || flags.clone());
+    create_client_with_overrides(state, overrides, setup_routes_fns).await
 }
 
 /// Creates a test application with routes and mocks set up,
@@ -113,12 +303,26 @@ where
 /// `localhost` with a name matching `{crate_name}-test`, on the default postgres port `5432`.
 ///
 /// If necessary, the following env variable overrides are available:
+/// - `PGURL_TEST`: A `postgres://` url for the test database, used as-is.
+/// - `PGURL`: If `PGURL_TEST` is not set, the host, port, and credentials are used, with the default test database name.
 /// - `TEST_DATABASE_HOST`: Set the test database hostname.
 /// - `TEST_DATABASE_PORT`: Set the test database port.
 /// - `TEST_DATABASE_NAME`: Set the test database name.
+/// - `PGMAXCONNECTIONS`: The maximum number of pooled connections, default 5.
+///
+/// The `TEST_DATABASE_*` variables take precedence over either url.
 ///
 /// If the crate name cannot be found from `CARGO_PKG_NAME`, then the name `database_test` will be used.
 ///
+/// ## Isolation and migrations
+///
+/// - `TEST_DATABASE_ISOLATED=true`: Each test creates a uniquely named schema and sets it as the `search_path`,
+///   so that tests do not share tables and can run in parallel.
+/// - `TEST_RUN_MIGRATIONS=true`: With the `"postgres-migrate"` feature, migrations from `MIGRATIONS_DIR`
+///   (default `./migrations`) are run before the test, into the isolated schema if there is one.
+///
+/// Both happen within the test's transaction, and so are rolled back along with everything else.
+///
 /// ## Important!
 ///
 /// The `RwLockWriteGuard` returned from `pg_conn.write().await` MUST be [dropped][] before running
@@ -173,109 +377,459 @@ where
     // Fake PostgresConnectionMiddleware.
     //
     // We do this so that all connections within any test run can share the same Transaction and be rolled back on Drop.
-    let mut connect_opts = PgConnectOptions::new()
-        .host(
-            env::var("TEST_DATABASE_HOST")
-                .as_deref()
-                .unwrap_or("localhost"),
-        )
-        .port(
-            env::var("TEST_DATABASE_PORT")
-                .ok()
-                .map(|v| v.parse())
-                .transpose()?
-                .unwrap_or(5432),
-        )
-        .database(
-            env::var("TEST_DATABASE_NAME")
-                .or_else(|_| env::var("CARGO_PKG_NAME").map(|v| format!("{}-test", v)))
-                .as_deref()
-                .unwrap_or("database_test"),
-        );
+    let test_database_name = config::var("TEST_DATABASE_NAME")
+        .or_else(|_| config::var("CARGO_PKG_NAME").map(|v| format!("{}-test", v)))
+        .unwrap_or_else(|_| "database_test".to_string());
+
+    let mut connect_opts = match (config::var("PGURL_TEST"), config::var("PGURL")) {
+        (Ok(pgurl_test), _) => pgurl_test.parse::<PgConnectOptions>()?,
+        // Only the host, port, and credentials of `PGURL` are used, so as not to run tests against a service's own database.
+        (_, Ok(pgurl)) => pgurl
+            .parse::<PgConnectOptions>()?
+            .database(&test_database_name),
+        _ => PgConnectOptions::new()
+            .host("localhost")
+            .port(5432)
+            .database(&test_database_name),
+    };
+    if let Ok(host) = config::var("TEST_DATABASE_HOST") {
+        connect_opts = connect_opts.host(&host);
+    }
+    if let Ok(port) = config::var("TEST_DATABASE_PORT") {
+        connect_opts = connect_opts.port(port.parse()?);
+    }
+    if let Ok(name) = config::var("TEST_DATABASE_NAME") {
+        connect_opts = connect_opts.database(&name);
+    }
     connect_opts.log_statements(log::LevelFilter::Debug);
 
+    let max_connections: u32 = config::var("PGMAXCONNECTIONS")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(5))?;
+
     let pg_pool = PgPoolOptions::new()
-        .max_connections(5)
+        .max_connections(max_connections)
         .connect_with(connect_opts)
         .await?;
 
-    let conn_wrap = Arc::new(RwLock::new(ConnectionWrapInner::Transacting(
-        pg_pool.begin().await?,
-    )));
-    server.with(PostgresTestMiddleware(conn_wrap.clone()));
+    let mut tx = pg_pool.begin().await?;
 
-    let client: Client = Config::new()
-        .set_http_client(server)
-        .set_base_url(Url::parse("http://localhost:8080")?) // Address not actually used.
-        .try_into()?;
+    // Schema creation, and migrations, are within the test transaction, so are also rolled back.
+    if config::var("TEST_DATABASE_ISOLATED").as_deref() == Ok("true") {
+        let schema = format!("test_{}", uuid::Uuid::new_v4().to_simple());
+        tx.execute(format!("CREATE SCHEMA {}", schema).as_str())
+            .await?;
+        tx.execute(format!("SET LOCAL search_path TO {}", schema).as_str())
+            .await?;
+    }
+
+    #[cfg(feature = "postgres-migrate")]
+    if config::var("TEST_RUN_MIGRATIONS").as_deref() == Ok("true") {
+        let (_, migrator) = crate::builtins::migrate::migrator()
+            .await
+            .map_err(|error| surf::Error::from_str(500, format!("{:?}", error)))?;
+        migrator.run(&mut *tx).await?;
+    }
+
+    let conn_wrap = Arc::new(RwLock::new(ConnectionWrapInner::Transacting(tx)));
+    server.with(SQLxTestMiddleware(conn_wrap.clone()));
+
+    let client = in_process_client(server)?;
 
     Ok((client, conn_wrap))
 }
 
-#[allow(clippy::unnecessary_wraps)]
-pub(crate) fn create_server<State>(
+/// Creates a test application with routes and mocks set up, backed by a SQLite database,
+/// and hands back a client which is already connected to the server.
+///
+/// As with [`create_client_and_postgres`][], everything runs within one transaction which is rolled back on `Drop`.
+///
+/// The database defaults to an in-memory database, or may be set with `TEST_SQLITEURL`, e.g. `sqlite://test.db`.
+///
+/// ## Important!
+///
+/// The `MutexGuard` returned from `sqlite_conn.lock().await` MUST be [dropped][] before running
+/// the test cases, or else the connection stays locked and the test will hang indefinitely.
+///
+/// [dropped]: https://doc.rust-lang.org/reference/destructors.html
+#[cfg(feature = "sqlite")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "sqlite")))]
+pub async fn create_client_and_sqlite<State>(
     state: State,
     setup_routes_fns: impl Into<VariadicRoutes<State>>,
-) -> TestResult<Server<Arc<State>>>
+) -> TestResult<(Client, SqliteConnectionWrap)>
 where
     State: Send + Sync + 'static,
 {
+    let mut server = create_server(state, setup_routes_fns)?;
+
+    let sqliteurl = config::var("TEST_SQLITEURL").unwrap_or_else(|_| "sqlite::memory:".to_string());
+    let mut connect_opts: SqliteConnectOptions = sqliteurl.parse()?;
+    connect_opts = connect_opts.create_if_missing(true);
+    connect_opts.log_statements(log::LevelFilter::Debug);
+
+    // Only one connection is ever used, and each in-memory connection would otherwise be a separate database.
+    let sqlite_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(connect_opts)
+        .await?;
+
+    let conn_wrap = Arc::new(Mutex::new(SqliteConnectionInner::Transacting(
+        sqlite_pool.begin().await?,
+    )));
+    server.with(SQLxTestMiddleware(conn_wrap.clone()));
+
+    let client = in_process_client(server)?;
+
+    Ok((client, conn_wrap))
+}
+
+/// A step of test data setup for [`seed`][], run within the test transaction.
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub enum Seed {
+    /// Execute the contents of a `.sql` file, which may contain multiple statements.
+    File(PathBuf),
+    /// Execute SQL, which may contain multiple statements.
+    Sql(String),
+    /// Run a closure with the test connection.
+    Fn(Box<SeedFn>),
+}
+
+#[cfg(feature = "postgres")]
+type SeedFn = dyn for<'c> FnOnce(
+        &'c mut PgConnection,
+    ) -> Pin<Box<dyn Future<Output = sqlx::Result<()>> + Send + 'c>>
+    + Send;
+
+#[cfg(feature = "postgres")]
+impl Seed {
+    /// Execute the contents of a `.sql` file, relative to the crate root when run by `cargo test`.
+    #[must_use]
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::File(path.into())
+    }
+
+    /// Execute SQL, which may contain multiple statements.
+    #[must_use]
+    pub fn sql(sql: impl Into<String>) -> Self {
+        Self::Sql(sql.into())
+    }
+
+    /// Run a closure with the test connection, such as to insert data with bound parameters.
+    #[must_use]
+    pub fn with<SeedClosure>(seed_fn: SeedClosure) -> Self
+    where
+        SeedClosure: for<'c> FnOnce(
+                &'c mut PgConnection,
+            )
+                -> Pin<Box<dyn Future<Output = sqlx::Result<()>> + Send + 'c>>
+            + Send
+            + 'static,
+    {
+        Self::Fn(Box::new(seed_fn))
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Debug for Seed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Sql(sql) => f.debug_tuple("Sql").field(sql).finish(),
+            Self::Fn(_) => f.write_str("Fn(..)"),
+        }
+    }
+}
+
+/// Run test data setup within the test transaction from [`create_client_and_postgres`][], in order.
+///
+/// The connection's write lock is taken and released within this function,
+/// so there is no `RwLockWriteGuard` to remember to drop before running the test cases.
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::test_utils::{self, Seed, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///   // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let (client, pg_conn) = test_utils::create_client_and_postgres((), setup_routes).await.unwrap();
+///
+///     test_utils::seed(&pg_conn, vec![
+///         Seed::file("tests/fixtures/users.sql"),
+///         Seed::with(|conn| Box::pin(async move {
+///             sqlx::query("INSERT INTO users (name) VALUES ($1)")
+///                 .bind("Ada")
+///                 .execute(conn)
+///                 .await?;
+///             Ok(())
+///         })),
+///     ]).await?;
+///
+///     // ... (test cases) ...
+///
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "postgres")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+pub async fn seed(
+    pg_conn: &Arc<RwLock<ConnectionWrapInner<Postgres>>>,
+    seeds: impl IntoIterator<Item = Seed>,
+) -> TestResult<()> {
+    let mut pg_conn = pg_conn.write().await;
+    let conn: &mut PgConnection = &mut pg_conn;
+
+    for seed in seeds {
+        match seed {
+            Seed::File(path) => {
+                let sql = async_std::fs::read_to_string(&path)
+                    .await
+                    .map_err(|error| {
+                        surf::Error::from_str(
+                            500,
+                            format!("Unable to read seed file {}: {}", path.display(), error),
+                        )
+                    })?;
+                conn.execute(sql.as_str()).await?;
+            }
+            Seed::Sql(sql) => {
+                conn.execute(sql.as_str()).await?;
+            }
+            Seed::Fn(seed_fn) => seed_fn(conn).await?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Set up logging, as `preroll::main!` does, but off unless `LOGLEVEL` is set.
+fn init_logging() {
     dotenv::dotenv().ok();
 
-    let log_level: log::LevelFilter = env::var("LOGLEVEL")
-        .map(|v| v.parse().expect("LOGLEVEL must be a valid log level."))
-        .unwrap_or(log::LevelFilter::Off);
+    let log_level = LogLevel::from_config(log::LevelFilter::Off)
+        .expect("LOGLEVEL must be valid log level directives.");
 
-    let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
+    let environment = config::var("ENVIRONMENT").unwrap_or_else(|_| "development".to_string());
 
-    if environment.starts_with("prod") {
-        // Like Production
-        env_logger::builder()
-            .format(log_format_json)
-            .filter_level(log_level)
-            .write_style(env_logger::WriteStyle::Never)
-            .try_init()
-            .ok();
-    } else {
-        // Like Development
-        env_logger::builder()
-            .format(log_format_pretty)
-            .filter_level(log_level)
-            .try_init()
-            .ok();
+    #[cfg(not(feature = "tracing-logs"))]
+    {
+        let logger = if environment.starts_with("prod") {
+            // Like Production
+            env_logger::builder()
+                .format(log_format_json)
+                .parse_filters(&log_level.to_string())
+                .write_style(env_logger::WriteStyle::Never)
+                .build()
+        } else {
+            // Like Development
+            env_logger::builder()
+                .format(log_format_pretty)
+                .parse_filters(&log_level.to_string())
+                .build()
+        };
+        logs::install(logger);
     }
 
-    #[cfg(feature = "honeycomb")]
+    #[cfg(feature = "tracing-logs")]
+    {
+        if LogTracer::builder()
+            .with_max_level(log_level.max_level())
+            .init()
+            .is_ok()
+        {
+            let (log_layer, _) = tracing_log_layer(
+                environment.starts_with("prod"),
+                &log_level,
+                LogSink::default(),
+                None,
+            );
+            let subscriber = Registry::default().with(log_layer).with(logs::CaptureLayer);
+            tracing::subscriber::set_global_default(subscriber).ok();
+        }
+    }
+
+    #[cfg(all(feature = "honeycomb", not(feature = "tracing-logs")))]
     {
         let subscriber = Registry::default();
         // .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
         tracing::subscriber::set_global_default(subscriber).ok();
     }
+}
 
-    let mut server = tide::with_state(Arc::new(state));
-    server.with(RequestIdMiddleware::new());
-    server.with(LogMiddleware::new());
-    server.with(JsonErrorMiddleware::new());
+/// Starts a test application on a real, ephemeral, local port, and returns its address,
+/// and the handle of the task accepting connections.
+///
+/// Unlike [`create_client`][], the server is reached through the same listener as `preroll::main!`,
+/// for tests which need real network behavior, such as streaming or websockets.
+/// The server runs until the test process exits.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello").get(|_| async { Ok("Hello!") });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let (addr, _handle) = test_utils::spawn_server((), setup_routes).await?;
+///
+///     let body = surf::get(format!("http://{}/api/v1/hello", addr)).recv_string().await?;
+///     assert_eq!(body, "Hello!");
+///     Ok(())
+/// }
+/// ```
+pub async fn spawn_server<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<(SocketAddr, JoinHandle<io::Result<()>>)>
+where
+    State: Send + Sync + 'static,
+{
+    let server = create_server(state, setup_routes_fns)?;
 
-    setup_monitor("preroll_test_utils", &mut server);
+    let mut listener = bind_server(server, "127.0.0.1", 0, 0)
+        .await
+        .map_err(|error| surf::Error::from_str(500, format!("{:?}", error)))?;
 
-    let mut version = 1;
-    for routes_fn in setup_routes_fns.into().routes {
-        routes_fn(server.at(&format!("/api/v{}", version)));
-        version += 1;
-    }
+    let addr = listener
+        .info()
+        .iter()
+        .find_map(|info| {
+            info.connection()
+                .trim_start_matches("http://")
+                .parse::<SocketAddr>()
+                .ok()
+        })
+        .ok_or_else(|| surf::Error::from_str(500, "Test server has no local address"))?;
+
+    let handle = async_std::task::spawn(async move { listener.accept().await });
 
-    Ok(server)
+    Ok((addr, handle))
 }
 
-#[cfg(feature = "postgres")]
-#[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+/// A request to `url`, either a path such as `/orders?page=2` on `http://localhost`, or an absolute url,
+/// for unit tests which call [`Server::respond`][] directly.
+#[cfg(test)]
+pub(crate) fn request(method: http::Method, url: &str) -> http::Request {
+    let url = Url::parse("http://localhost/").unwrap().join(url).unwrap();
+    http::Request::new(method, url)
+}
+
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn create_server<State>(
+    state: State,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> TestResult<Server<Arc<()>>>
+where
+    State: Send + Sync + 'static,
+{
+    let (base_server, server) = create_app_servers(state);
+    Ok(route_server(base_server, server, setup_routes_fns))
+}
+
+/// The base server, with monitor routes, and the application server, with the same middleware as `preroll::main!`,
+/// but not yet application routes.
+fn create_app_servers<State>(state: State) -> (Server<Arc<()>>, Server<Arc<State>>)
+where
+    State: Send + Sync + 'static,
+{
+    init_logging();
+
+    let (mut base_server, server) = create_servers("preroll_test_utils", state);
+    base_server.with(ScopedStatsMiddleware(ScopedStats::default()));
+
+    (base_server, server)
+}
+
+/// Add application routes to `server`, and nest it within `base_server`, as in `preroll::main!`.
+fn route_server<State>(
+    mut base_server: Server<Arc<()>>,
+    mut server: Server<Arc<State>>,
+    setup_routes_fns: impl Into<VariadicRoutes<State>>,
+) -> Server<Arc<()>>
+where
+    State: Send + Sync + 'static,
+{
+    setup_routes_fns.into().mount(&mut server);
+
+    #[cfg(debug_assertions)]
+    server.at("/internal-error").get(get_internal_error);
+
+    base_server.at("/").nest(server);
+
+    base_server
+}
+
+/// A client which sends requests to `server` in-process.
+fn in_process_client(server: Server<Arc<()>>) -> TestResult<Client> {
+    let client: Client = Config::new()
+        .set_http_client(server)
+        .set_base_url(Url::parse("http://localhost:8080")?) // Address not actually used.
+        .try_into()?;
+
+    #[cfg(feature = "fail-on-error")]
+    let client = client.with(FailOnErrorMiddleware);
+
+    Ok(client)
+}
+
+/// Fails requests whose handler errored with the error's report, with the `"fail-on-error"` feature.
+#[cfg(feature = "fail-on-error")]
+#[derive(Debug)]
+struct FailOnErrorMiddleware;
+
+#[cfg(feature = "fail-on-error")]
+#[surf::utils::async_trait]
+impl surf::middleware::Middleware for FailOnErrorMiddleware {
+    async fn handle(
+        &self,
+        req: surf::Request,
+        client: Client,
+        next: surf::middleware::Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        let res = next.run(req, client).await?;
+        match res.ext::<ErrorReport>() {
+            Some(report) => Err(surf::Error::from_str(res.status(), report.0.clone())),
+            None => Ok(res),
+        }
+    }
+}
+
+/// Records request stats for each test server separately, so that tests running in parallel do not share counters.
 #[derive(Debug, Clone)]
-struct PostgresTestMiddleware(ConnectionWrap<Postgres>);
+struct ScopedStatsMiddleware(ScopedStats);
 
-#[cfg(feature = "postgres")]
 #[tide::utils::async_trait]
-impl<State: Clone + Send + Sync + 'static> Middleware<State> for PostgresTestMiddleware {
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ScopedStatsMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(self.0.clone());
+        Ok(next.run(req).await)
+    }
+}
+
+/// Fake SQLx connection middleware, sharing one test transaction, `ConnWrap`, with every request.
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+#[derive(Debug)]
+struct SQLxTestMiddleware<ConnWrap>(ConnWrap);
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+#[tide::utils::async_trait]
+impl<State, ConnWrap> Middleware<State> for SQLxTestMiddleware<ConnWrap>
+where
+    State: Clone + Send + Sync + 'static,
+    ConnWrap: Clone + Send + Sync + 'static,
+{
     async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
         req.set_ext(self.0.clone());
         Ok(next.run(req).await)
@@ -322,6 +876,41 @@ where
     mock_client
 }
 
+/// Restart the sequential test request and correlation ids from `00000000-0000-0000-0000-000000000001`.
+///
+/// With the `"test"` feature, request ids and correlation ids are each assigned sequentially, per thread,
+/// rather than randomly. In-process test clients handle requests on the test's own thread,
+/// so each test starts from `...0001` unless its thread was used by an earlier test, which this resets.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello").get(|_| async { Ok("Hello!") });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///     test_utils::reset_ids();
+///
+///     let first = client.get("/api/v1/hello").await?;
+///     let second = client.get("/api/v1/hello").await?;
+///
+///     assert_eq!(first["X-Request-Id"], "00000000-0000-0000-0000-000000000001");
+///     assert_eq!(second["X-Request-Id"], "00000000-0000-0000-0000-000000000002");
+///     Ok(())
+/// }
+/// ```
+#[cfg(feature = "test")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "test")))]
+pub fn reset_ids() {
+    crate::middleware::extension_types::reset_test_ids();
+}
+
 /// A test helper to check all fields of a [`JsonError`][crate::JsonError].
 ///
 /// ## Example:
@@ -497,3 +1086,124 @@ where
 
     body
 }
+
+/// A test helper to check that a response has a successful (`2xx`) status, which returns the response body.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_ok, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/monitor/ping").await?;
+///
+///     let body = assert_ok(res).await;
+///     assert!(!body.is_empty());
+///     Ok(())
+/// }
+/// ```
+pub async fn assert_ok(mut res: impl AsMut<http::Response>) -> String {
+    let res = res.as_mut();
+
+    let body = res.body_string().await.unwrap();
+
+    assert!(
+        res.status().is_success(),
+        "Expected a successful status, got {}. Response body: {}",
+        res.status(),
+        body
+    );
+
+    body
+}
+
+/// Read the request stats reported under `"stats"` in the test server's `/monitor/status`.
+///
+/// Each test server (i.e. each call to [`create_client`][]) counts its own requests from zero,
+/// and requests to `/monitor/` routes are not counted.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello").get(|_| async { Ok("Hello!") });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     client.get("/api/v1/hello").await?;
+///     client.get("/api/v1/missing").await?;
+///
+///     let stats = test_utils::request_stats(&client).await?;
+///     assert_eq!(stats.request_count, 2);
+///     assert_eq!(stats.status_count(200), 1);
+///     assert_eq!(stats.status_count(404), 1);
+///     Ok(())
+/// }
+/// ```
+pub async fn request_stats(client: &Client) -> TestResult<StatsSnapshot> {
+    #[derive(serde::Deserialize)]
+    struct Status {
+        stats: StatsSnapshot,
+    }
+
+    let status: Status = client.get("/monitor/status").recv_json().await?;
+    Ok(status.stats)
+}
+
+/// Assert that the test server has recorded exactly `request_count` requests, with the given counts per status.
+///
+/// Statuses which are not listed are not checked.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, assert_request_stats, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello").get(|_| async { Ok("Hello!") });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     client.get("/api/v1/hello").await?;
+///     client.get("/api/v1/hello").await?;
+///
+///     assert_request_stats(&client, 2, &[(200, 2)]).await;
+///     Ok(())
+/// }
+/// ```
+pub async fn assert_request_stats(client: &Client, request_count: u64, statuses: &[(u16, u64)]) {
+    let stats = request_stats(client)
+        .await
+        .expect("/monitor/status must report stats");
+
+    assert_eq!(
+        stats.request_count, request_count,
+        "Unexpected request count, stats: {:?}",
+        stats
+    );
+    for (status, count) in statuses {
+        assert_eq!(
+            stats.status_count(*status),
+            *count,
+            "Unexpected count of {} responses, stats: {:?}",
+            status,
+            stats
+        );
+    }
+}
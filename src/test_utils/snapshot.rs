@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+
+use serde_json::Value;
+use tide::http;
+use uuid::Uuid;
+
+use crate::config;
+use crate::middleware::extension_types::decode_ulid;
+
+/// Replace volatile values, such as ids and timestamps, with stable placeholders.
+fn normalize(value: &mut Value) {
+    match value {
+        Value::String(string) => {
+            if Uuid::parse_str(string).is_ok() || decode_ulid(string).is_some() {
+                *string = "[id]".to_string();
+            } else if chrono::DateTime::parse_from_rfc3339(string).is_ok() {
+                *string = "[timestamp]".to_string();
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(normalize),
+        Value::Object(map) => map.values_mut().for_each(normalize),
+        _ => (),
+    }
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    let dir = config::var("TEST_SNAPSHOT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            config::var("CARGO_MANIFEST_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_default()
+                .join("tests")
+                .join("snapshots")
+        });
+    dir.join(format!("{}.json", name))
+}
+
+/// A line diff of `expected` and `actual`, with removed lines prefixed by `-` and added lines by `+`.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence lengths, from the end.
+    let mut lcs = vec![vec![0_usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        } else {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+/// A test helper which compares a JSON response body against a snapshot stored on disk.
+///
+/// Ids (UUIDs and ULIDs) and RFC 3339 timestamps anywhere in the body are replaced with `"[id]"` and `"[timestamp]"`
+/// before comparison, so that request ids and creation times do not cause spurious failures.
+///
+/// Snapshots are stored as `tests/snapshots/{name}.json` in the crate being tested, or in `TEST_SNAPSHOT_DIR`.
+/// - If the snapshot does not exist, it is written and the assertion passes, unless `CI` is set, in which case it fails.
+/// - If `UPDATE_SNAPSHOTS=true` is set, the snapshot is overwritten.
+/// - Otherwise, a mismatch fails with a line diff of the snapshot (`-`) and the response (`+`).
+///
+/// ## Example:
+///
+/// ```no_run
+/// use preroll::test_utils::{self, assert_json_snapshot, TestResult};
+///
+/// # #[allow(unused_mut)]
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     // Normally imported from your service's crate (lib.rs).
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///
+///     let res = client.get("/api/v1/missing").await?;
+///
+///     assert_json_snapshot(res, "missing_route").await;
+///     Ok(())
+/// }
+/// ```
+pub async fn assert_json_snapshot(mut res: impl AsMut<http::Response>, name: &str) {
+    let res = res.as_mut();
+    let body = res.body_string().await.unwrap();
+
+    let mut actual: Value = serde_json::from_str(&body).unwrap_or_else(|error| {
+        panic!(
+            "Response body is not JSON ({}). Response body: {}",
+            error, body
+        )
+    });
+    normalize(&mut actual);
+    let actual_pretty = serde_json::to_string_pretty(&actual).unwrap();
+
+    let path = snapshot_path(name);
+    let update = config::var("UPDATE_SNAPSHOTS").as_deref() == Ok("true");
+
+    let existing = std::fs::read_to_string(&path).ok();
+    match existing {
+        Some(expected_pretty) if !update => {
+            let expected: Value = serde_json::from_str(&expected_pretty).unwrap_or_else(|error| {
+                panic!("Snapshot {} is not JSON: {}", path.display(), error)
+            });
+            if expected != actual {
+                panic!(
+                    "Response does not match snapshot {} (run with UPDATE_SNAPSHOTS=true to update it):\n{}",
+                    path.display(),
+                    diff(&expected_pretty, &actual_pretty)
+                );
+            }
+        }
+        None if !update && config::var("CI").is_ok() => {
+            panic!(
+                "Snapshot {} does not exist, and is not written when CI is set. Response:\n{}",
+                path.display(),
+                actual_pretty
+            );
+        }
+        _ => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+            std::fs::write(&path, format!("{}\n", actual_pretty)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn normalize_replaces_volatile_values() {
+        let mut value = json!({
+            "requestId": "01ARZ3NDEKTSV4RRFFQ69G5FAV",
+            "correlationId": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "nested": [{ "createdAt": "2021-03-04T05:06:07.123Z" }],
+            "title": "Not Found",
+            "status": 404,
+        });
+
+        normalize(&mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "requestId": "[id]",
+                "correlationId": "[id]",
+                "nested": [{ "createdAt": "[timestamp]" }],
+                "title": "Not Found",
+                "status": 404,
+            })
+        );
+    }
+
+    #[test]
+    fn diff_marks_changed_lines() {
+        assert_eq!(diff("a\nb\nc", "a\nx\nc"), "  a\n+ x\n- b\n  c");
+    }
+}
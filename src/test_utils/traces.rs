@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::DefaultGuard;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// A span captured by [`capture_traces`][], with all fields recorded on it.
+#[derive(Clone, Debug)]
+pub struct CapturedSpan {
+    pub name: String,
+    pub target: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// An event captured by [`capture_traces`][], including log records forwarded into `tracing`.
+#[derive(Clone, Debug)]
+pub struct CapturedEvent {
+    pub level: Level,
+    pub target: String,
+    /// The name of the span the event occurred within, if any.
+    pub span: Option<String>,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedEvent {
+    /// The event's message, if it has one.
+    #[must_use]
+    pub fn message(&self) -> Option<&str> {
+        self.fields.get("message").map(String::as_str)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Captured {
+    spans: Vec<CapturedSpan>,
+    events: Vec<CapturedEvent>,
+}
+
+/// The spans and events captured by [`capture_traces`][], until this is dropped.
+#[derive(Debug)]
+pub struct TraceCapture {
+    captured: Arc<Mutex<Captured>>,
+    _guard: DefaultGuard,
+}
+
+impl TraceCapture {
+    /// All spans captured so far, in the order they were created.
+    #[must_use]
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.captured
+            .lock()
+            .map(|captured| captured.spans.clone())
+            .unwrap_or_default()
+    }
+
+    /// All events captured so far, in the order they occurred.
+    #[must_use]
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.captured
+            .lock()
+            .map(|captured| captured.events.clone())
+            .unwrap_or_default()
+    }
+
+    /// The first captured span named `name`, if any.
+    #[must_use]
+    pub fn span(&self, name: &str) -> Option<CapturedSpan> {
+        self.spans().into_iter().find(|span| span.name == name)
+    }
+}
+
+/// Capture `tracing` spans and events in memory, rather than exporting them, until the returned [`TraceCapture`][] is dropped.
+///
+/// Capturing is for the current thread, on which in-process test clients, such as from [`create_client`][super::create_client],
+/// handle requests. Field values are captured as strings, with `Debug` formatting for all but strings, numbers, and booleans.
+///
+/// ## Example:
+///
+/// ```
+/// use preroll::test_utils::{self, TestResult};
+///
+/// pub fn setup_routes(mut server: tide::Route<'_, std::sync::Arc<()>>) {
+///     server.at("hello").get(|_| async {
+///         let span = tracing::info_span!("greeting", language = "en");
+///         let _entered = span.enter();
+///         tracing::info!(greeted = true, "Greeted");
+///         Ok("Hello!")
+///     });
+/// }
+///
+/// #[async_std::main] // Would be #[async_std::test] instead.
+/// async fn main() -> TestResult<()> {
+///     let client = test_utils::create_client((), setup_routes).await.unwrap();
+///     let traces = test_utils::capture_traces();
+///
+///     client.get("/api/v1/hello").await?;
+///
+///     let span = traces.span("greeting").unwrap();
+///     assert_eq!(span.fields["language"], "en");
+///
+///     let event = traces.events().into_iter().find(|e| e.message() == Some("Greeted")).unwrap();
+///     assert_eq!(event.span.as_deref(), Some("greeting"));
+///     assert_eq!(event.fields["greeted"], "true");
+///     Ok(())
+/// }
+/// ```
+#[must_use = "traces are only captured until the TraceCapture is dropped"]
+pub fn capture_traces() -> TraceCapture {
+    let captured = Arc::new(Mutex::new(Captured::default()));
+    let subscriber = Registry::default().with(CaptureLayer {
+        captured: captured.clone(),
+    });
+    // Events replace the global subscriber's on this thread, so must also reach any log capture.
+    #[cfg(feature = "tracing-logs")]
+    let subscriber = subscriber.with(super::logs::CaptureLayer);
+
+    TraceCapture {
+        captured,
+        _guard: tracing::subscriber::set_default(subscriber),
+    }
+}
+
+struct CaptureLayer {
+    captured: Arc<Mutex<Captured>>,
+}
+
+/// The index of a span in `Captured::spans`, stored in the span's extensions.
+struct SpanIndex(usize);
+
+impl<S> Layer<S> for CaptureLayer
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+
+        let index = match self.captured.lock() {
+            Ok(mut captured) => {
+                captured.spans.push(CapturedSpan {
+                    name: attrs.metadata().name().to_string(),
+                    target: attrs.metadata().target().to_string(),
+                    fields: fields.0,
+                });
+                captured.spans.len() - 1
+            }
+            Err(_) => return,
+        };
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanIndex(index));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let index = match ctx
+            .span(id)
+            .and_then(|span| span.extensions().get::<SpanIndex>().map(|index| index.0))
+        {
+            Some(index) => index,
+            None => return,
+        };
+
+        let mut fields = FieldVisitor::default();
+        values.record(&mut fields);
+
+        if let Ok(mut captured) = self.captured.lock() {
+            if let Some(span) = captured.spans.get_mut(index) {
+                span.fields.extend(fields.0);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+
+        let span = ctx.event_span(event).map(|span| span.name().to_string());
+
+        if let Ok(mut captured) = self.captured.lock() {
+            captured.events.push(CapturedEvent {
+                level: *event.metadata().level(),
+                target: event.metadata().target().to_string(),
+                span,
+                fields: fields.0,
+            });
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor(BTreeMap<String, String>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
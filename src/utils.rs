@@ -1,6 +1,20 @@
 //! Miscellaneous utilities.
 
+use hmac::{Hmac, Mac};
 use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::Sha256;
+use tide::http::headers::{HeaderName, CONTENT_LENGTH, COOKIE, ETAG, HOST};
+use tide::http::{self, mime, Url};
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::logging::log_kv;
+use crate::middleware::etag::strong_etag;
+use crate::middleware::extension_types::RequestId;
+use crate::middleware::forwarded::parse_hop;
+#[cfg(feature = "honeycomb")]
+use crate::middleware::honeycomb::propagation::{Propagation, PROPAGATION_HTTP_HEADER};
 
 lazy_static! {
     pub(crate) static ref HOSTNAME: String =
@@ -17,3 +31,354 @@ lazy_static! {
 pub fn type_name_of<T: ?Sized>(_val: &T) -> &'static str {
     std::any::type_name::<T>()
 }
+
+/// A new random token of 256 bits, as 64 hex digits, e.g. for CSRF tokens and session ids.
+pub(crate) fn random_token() -> String {
+    let mut token = [0_u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+/// The HMAC-SHA256 of `message` with `key`, to be finalized or verified.
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac
+}
+
+/// The HMAC-SHA256 of `message` with `key`, as hex digits.
+pub(crate) fn hmac_sha256_hex(key: &[u8], message: &[u8]) -> String {
+    hex::encode(hmac_sha256(key, message).finalize().into_bytes())
+}
+
+/// Whether `signature`, as hex digits, is the HMAC-SHA256 of `message` with `key`, compared in constant time.
+pub(crate) fn verify_hmac_sha256_hex(key: &[u8], message: &[u8], signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) => hmac_sha256(key, message).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Compare secrets without leaking the length of their common prefix through timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The value of the request's cookie named `name`.
+pub(crate) fn request_cookie<State>(req: &Request<State>, name: &str) -> Option<String> {
+    req.header(COOKIE)?
+        .iter()
+        .flat_map(|value| value.as_str().split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Headers which apply to a single connection, and so are never forwarded by [`proxy`][].
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A `200 OK` JSON response of `body`, with a strong ETag computed from the serialized body.
+///
+/// [`ETagMiddleware`][crate::ETagMiddleware] tags most JSON responses of the routes it is added to, but this tags
+/// a response regardless of its size. Add the middleware too to reply `304 Not Modified` to requests whose
+/// `If-None-Match` matches.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use tide::{Request, Route};
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<()>>) {
+///     server.at("catalog").get(|_req: Request<Arc<()>>| async {
+///         preroll::utils::with_etag(&serde_json::json!({ "products": [] }))
+///     });
+/// }
+/// ```
+pub fn with_etag(body: &impl Serialize) -> tide::Result<Response> {
+    let bytes = serde_json::to_vec(body)?;
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header(ETAG, strong_etag(&bytes));
+
+    let mut body = Body::from_bytes(bytes);
+    body.set_mime(mime::JSON);
+    res.set_body(body);
+    Ok(res)
+}
+
+/// Forward a request to `path` on an upstream service, and stream the upstream response back.
+///
+/// `path` is joined to `client`'s base url, if it has one, and the request's query is kept unless `path` has its own.
+/// Neither body is buffered: the request body is streamed upstream as it arrives, and the upstream response body
+/// is streamed back as the response, with its status and headers. Hop-by-hop headers such as `Connection` are dropped,
+/// the request's `X-Request-Id` is forwarded, and the connection's peer address is appended to `X-Forwarded-For`.
+///
+/// With the `"honeycomb"` feature, the upstream request is recorded as a child `proxy` span, which is propagated
+/// to the upstream service via `X-Honeycomb-Trace`.
+///
+/// Fails with a `400 Bad Request` if `path` would leave the origin of `client`'s base url, such as an absolute url
+/// or a scheme-relative `//other-host/`, so that a path built from request input cannot reach another host.
+/// Fails with a `502 Bad Gateway` if the upstream service cannot be reached.
+///
+/// ## Example:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use tide::{Request, Route};
+///
+/// # #[allow(dead_code)]
+/// fn setup_routes(mut server: Route<'_, Arc<surf::Client>>) {
+///     server
+///         .at("orders/:id")
+///         .get(|req: Request<Arc<surf::Client>>| async move {
+///             let client = req.state().clone();
+///             let path = format!("v2/orders/{}", req.param("id")?);
+///             preroll::utils::proxy(req, &client, &path).await
+///         });
+/// }
+/// ```
+pub async fn proxy<State>(
+    mut req: Request<State>,
+    client: &surf::Client,
+    path: &str,
+) -> tide::Result<Response> {
+    let mut url = match &client.config().base_url {
+        Some(base_url) => {
+            let url = base_url.join(path)?;
+            if url.origin() != base_url.origin() {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    format!("Proxy path {:?} is not on the upstream's origin", path),
+                ));
+            }
+            url
+        }
+        None => Url::parse(path)?,
+    };
+    if url.query().is_none() {
+        url.set_query(req.url().query());
+    }
+
+    let mut upstream = http::Request::new(req.method(), url.clone());
+    for (name, values) in req.iter() {
+        if !is_hop_by_hop(name) && name != &HOST && name != &CONTENT_LENGTH {
+            upstream.insert_header(name, values);
+        }
+    }
+    if let Some(request_id) = req.ext::<RequestId>() {
+        upstream.insert_header("X-Request-Id", request_id.as_str());
+    }
+    // Append the address this request came from, not `req.remote()`, which is whatever the client forwarded.
+    if let Some(peer_addr) = req.peer_addr() {
+        let peer = parse_hop(peer_addr).map_or_else(|| peer_addr.to_string(), |ip| ip.to_string());
+        let forwarded_for = match req.header("X-Forwarded-For") {
+            Some(values) => {
+                let previous: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+                format!("{}, {}", previous.join(", "), peer)
+            }
+            None => peer,
+        };
+        upstream.insert_header("X-Forwarded-For", forwarded_for);
+    }
+    upstream.set_body(req.take_body());
+
+    #[cfg(feature = "honeycomb")]
+    let span = tracing::info_span!(
+        "proxy",
+        upstream.url = url.as_str(),
+        http.status = tracing::field::Empty
+    );
+    #[cfg(feature = "honeycomb")]
+    if let Ok((trace_id, span_id)) = span.in_scope(tracing_honeycomb::current_dist_trace_ctx) {
+        let propagation = Propagation {
+            trace_id: trace_id.to_string(),
+            parent_id: span_id.to_string(),
+            dataset: String::new(),
+            trace_context: libhoney::Value::Object(Default::default()),
+        };
+        upstream.insert_header(PROPAGATION_HTTP_HEADER, propagation.marshal_trace_context());
+    }
+
+    let sent = client.send(upstream);
+    #[cfg(feature = "honeycomb")]
+    let sent = tracing_futures::Instrument::instrument(sent, span.clone());
+
+    let upstream_res = match sent.await {
+        Ok(upstream_res) => upstream_res,
+        Err(error) => {
+            log_kv!(
+                warn,
+                { upstream_url: url.as_str() },
+                "Proxy upstream request failed: {}", error
+            );
+            return Err(tide::Error::from_str(
+                StatusCode::BadGateway,
+                format!("Upstream request to {} failed", url.path()),
+            ));
+        }
+    };
+
+    #[cfg(feature = "honeycomb")]
+    span.record("http.status", u64::from(upstream_res.status() as u16));
+
+    let upstream_res: http::Response = upstream_res.into();
+    let mut res = Response::from(upstream_res);
+    let hop_by_hop: Vec<HeaderName> = res
+        .iter()
+        .map(|(name, _)| name.clone())
+        .filter(is_hop_by_hop)
+        .collect();
+    for name in hop_by_hop.iter() {
+        res.remove_header(name);
+    }
+    Ok(res)
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use async_std::task::JoinHandle;
+    use surf::Config;
+    use tide::http::Method;
+
+    use super::*;
+    use crate::middleware::requestid::RequestIdMiddleware;
+    use crate::test_utils;
+
+    /// A server proxying `POST /facade` to an upstream which echoes the request back,
+    /// and `POST /open?path={path}` to `path` on it.
+    async fn facade() -> tide::Result<(tide::Server<()>, JoinHandle<io::Result<()>>)> {
+        let (addr, handle) =
+            test_utils::spawn_server((), |mut server: tide::Route<'_, Arc<()>>| {
+                server
+                    .at("echo")
+                    .post(|mut req: Request<Arc<()>>| async move {
+                        let mut res = Response::new(StatusCode::Created);
+                        res.insert_header("X-Echo-Query", req.url().query().unwrap_or(""));
+                        res.insert_header(
+                            "X-Echo-Request-Id",
+                            req.header("X-Request-Id").map(|v| v.as_str()).unwrap_or(""),
+                        );
+                        res.insert_header(
+                            "X-Echo-Forwarded-For",
+                            req.header("X-Forwarded-For")
+                                .map(|v| v.as_str())
+                                .unwrap_or(""),
+                        );
+                        res.set_body(req.body_string().await?);
+                        Ok(res)
+                    });
+            })
+            .await?;
+
+        let base_url = Url::parse(&format!("http://{}/api/v1/", addr))?;
+        let client: surf::Client = Config::new().set_base_url(base_url).try_into()?;
+        let client = Arc::new(client);
+        let proxy_client = client.clone();
+
+        let mut server = tide::new();
+        server.with(RequestIdMiddleware::new());
+        server.at("/facade").post(move |req: Request<()>| {
+            let client = client.clone();
+            async move { proxy(req, &client, "echo").await }
+        });
+        server.at("/open").post(move |req: Request<()>| {
+            let client = proxy_client.clone();
+            async move {
+                let path = req
+                    .url()
+                    .query_pairs()
+                    .find(|(name, _)| name == "path")
+                    .map(|(_, path)| path.into_owned())
+                    .unwrap_or_default();
+                proxy(req, &client, &path).await
+            }
+        });
+        Ok((server, handle))
+    }
+
+    #[async_std::test]
+    async fn proxies_requests_and_responses() -> tide::Result<()> {
+        let (server, _handle) = facade().await?;
+
+        let mut req = test_utils::request(Method::Post, "/facade?page=2");
+        req.insert_header("X-Request-Id", "4c4bd8b8-8d40-4d1e-8f5b-6a6f59d3c8e5");
+        req.insert_header("Connection", "close");
+        req.set_body("a request body");
+
+        let mut res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Created);
+        assert_eq!(
+            res.header("X-Echo-Query").map(|v| v.as_str()),
+            Some("page=2")
+        );
+
+        // The "test" feature assigns sequential ids rather than trusting the header.
+        let request_id = res.header("X-Request-Id").map(|v| v.as_str().to_string());
+        assert!(request_id.is_some());
+        assert_eq!(
+            res.header("X-Echo-Request-Id")
+                .map(|v| v.as_str().to_string()),
+            request_id
+        );
+        assert_eq!(res.body_string().await?, "a request body");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn appends_the_peer_to_forwarded_for() -> tide::Result<()> {
+        let (server, _handle) = facade().await?;
+
+        let mut req = test_utils::request(Method::Post, "/facade");
+        req.set_peer_addr(Some("192.0.2.10:54321"));
+        req.insert_header("X-Forwarded-For", "203.0.113.7");
+
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Created);
+        assert_eq!(
+            res.header("X-Echo-Forwarded-For").map(|v| v.as_str()),
+            Some("203.0.113.7, 192.0.2.10")
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_paths_off_the_upstream_origin() -> tide::Result<()> {
+        let (server, _handle) = facade().await?;
+
+        let req = test_utils::request(Method::Post, "/open?path=echo");
+        let res: http::Response = server.respond(req).await?;
+        assert_eq!(res.status(), StatusCode::Created);
+
+        for path in [
+            "http:%2F%2F169.254.169.254%2Flatest",
+            "%2F%2F169.254.169.254%2Flatest",
+            "https:%2F%2Fattacker.example%2F",
+        ] {
+            let req = test_utils::request(Method::Post, &format!("/open?path={}", path));
+            let res: http::Response = server.respond(req).await?;
+            assert_eq!(res.status(), StatusCode::BadRequest, "{}", path);
+        }
+        Ok(())
+    }
+}
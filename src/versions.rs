@@ -0,0 +1,331 @@
+//! API version policies, for the `/api/v{N}` versions of a service's [routes][crate::VariadicRoutes].
+//!
+//! A version can be [deprecated][deprecate] during setup, with an optional sunset date. Responses from a deprecated
+//! version then have a `Deprecation: true` header, and, once it has one, a `Sunset` header with its sunset date, as in
+//! [RFC 8594][], and a `Link` to its migration guide with `rel="sunset"`.
+//!
+//! Requests to deprecated versions are counted per version, and the count is logged at `WARN` at most once a minute.
+//! Deprecated versions, with their sunset dates and request counts, are reported under `"apiVersions"` in
+//! `/monitor/status`.
+//!
+//! With [`reject_after_sunset`][VersionDeprecation::reject_after_sunset], requests after the sunset date are rejected
+//! with a `410 Gone` [`JsonError`][crate::JsonError].
+//!
+//! ## Accept header versions
+//!
+//! For clients which can't change the paths they request, versions can also be requested with a vendor media type
+//! in the `Accept` header, by setting `ACCEPT_VERSION_VENDOR`. For example, with `ACCEPT_VERSION_VENDOR=myservice`,
+//! `GET /users/42` with `Accept: application/vnd.myservice.v2+json` is routed to `/api/v2/users/42`, and its response
+//! has a `Vary: Accept` header. See [`rewrite::accept_version`][crate::rewrite::accept_version].
+//!
+//! ## Example:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::versions::{self, VersionDeprecation};
+//!
+//! # #[allow(dead_code)]
+//! async fn custom_setup(server: tide::Server<Arc<()>>) -> preroll::SetupResult<tide::Server<Arc<()>>> {
+//!     versions::deprecate(
+//!         VersionDeprecation::new(1)
+//!             .sunset("2023-06-30T00:00:00Z".parse()?)
+//!             .link("https://docs.example.com/api/v2-migration")
+//!             .reject_after_sunset(),
+//!     );
+//!     Ok(server)
+//! }
+//! ```
+//!
+//! [RFC 8594]: https://www.rfc-editor.org/rfc/rfc8594
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::logging::log_kv;
+use crate::rewrite::{is_accept_versioned, AppliedRewrites};
+
+/// How often the requests to each deprecated version are logged, at most.
+const LOG_INTERVAL_SECS: i64 = 60;
+
+static DEPRECATIONS: Lazy<RwLock<BTreeMap<u32, Arc<DeprecatedVersion>>>> =
+    Lazy::new(Default::default);
+
+/// The deprecation of an API version. See [`preroll::versions`][crate::versions].
+#[derive(Clone, Debug)]
+pub struct VersionDeprecation {
+    version: u32,
+    sunset: Option<DateTime<Utc>>,
+    link: Option<String>,
+    reject_after_sunset: bool,
+}
+
+impl VersionDeprecation {
+    /// Deprecate `/api/v{version}`.
+    #[must_use]
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            sunset: None,
+            link: None,
+            reject_after_sunset: false,
+        }
+    }
+
+    /// When the version will stop being served, sent as the `Sunset` header.
+    #[must_use]
+    pub fn sunset(mut self, sunset: DateTime<Utc>) -> Self {
+        self.sunset = Some(sunset);
+        self
+    }
+
+    /// A page about the deprecation, such as a migration guide, sent as a `Link` header with `rel="sunset"`.
+    #[must_use]
+    pub fn link(mut self, url: impl Into<String>) -> Self {
+        self.link = Some(url.into());
+        self
+    }
+
+    /// Reject requests to the version after its sunset date with a `410 Gone`.
+    #[must_use]
+    pub fn reject_after_sunset(mut self) -> Self {
+        self.reject_after_sunset = true;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct DeprecatedVersion {
+    policy: VersionDeprecation,
+    requests: AtomicU64,
+    rejected: AtomicU64,
+    /// Requests since the count was last logged.
+    unlogged: AtomicU64,
+    last_logged: AtomicI64,
+}
+
+/// Deprecate an API version, replacing any previous deprecation of it.
+///
+/// This may be called at any time, such as in `custom_setup` or a routes setup function,
+/// and applies to requests from then on.
+pub fn deprecate(deprecation: VersionDeprecation) {
+    let version = deprecation.version;
+    let deprecated = DeprecatedVersion {
+        policy: deprecation,
+        requests: AtomicU64::new(0),
+        rejected: AtomicU64::new(0),
+        unlogged: AtomicU64::new(0),
+        last_logged: AtomicI64::new(0),
+    };
+    DEPRECATIONS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(version, Arc::new(deprecated));
+}
+
+fn deprecation(version: u32) -> Option<Arc<DeprecatedVersion>> {
+    DEPRECATIONS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&version)
+        .cloned()
+}
+
+/// The `Sunset` header's HTTP-date format, e.g. `Fri, 30 Jun 2023 00:00:00 GMT`.
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Applies the version policy of one API version to its routes.
+#[derive(Debug)]
+pub(crate) struct VersionPolicyMiddleware {
+    version: u32,
+}
+
+impl VersionPolicyMiddleware {
+    pub(crate) fn new(version: u32) -> Self {
+        Self { version }
+    }
+
+    /// Count requests to a deprecated version, logging the count at most once a minute.
+    fn count(&self, deprecated: &DeprecatedVersion) {
+        deprecated.requests.fetch_add(1, Ordering::Relaxed);
+        deprecated.unlogged.fetch_add(1, Ordering::Relaxed);
+
+        let now = Utc::now().timestamp();
+        let last_logged = deprecated.last_logged.load(Ordering::Relaxed);
+        if now - last_logged < LOG_INTERVAL_SECS {
+            return;
+        }
+        if deprecated
+            .last_logged
+            .compare_exchange(last_logged, now, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        let requests = deprecated.unlogged.swap(0, Ordering::Relaxed);
+        let sunset = deprecated.policy.sunset.as_ref().map(http_date);
+        log_kv!(
+            warn,
+            { api_version: self.version, deprecated_requests: requests, sunset: sunset },
+            "Deprecated API v{} was requested {} time(s)", self.version, requests
+        );
+    }
+
+    async fn handle<'a, State: Clone + Send + Sync + 'static>(
+        &'a self,
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> tide::Result {
+        // Responses to requests routed by their `Accept` header differ by it, for caches.
+        let accept_versioned = req
+            .ext::<AppliedRewrites>()
+            .map(is_accept_versioned)
+            .unwrap_or(false);
+
+        let deprecated = deprecation(self.version);
+        if let Some(deprecated) = &deprecated {
+            self.count(deprecated);
+
+            let policy = &deprecated.policy;
+            if let Some(sunset) = policy.sunset {
+                if policy.reject_after_sunset && Utc::now() >= sunset {
+                    deprecated.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(tide::Error::from_str(
+                        StatusCode::Gone,
+                        format!(
+                            "API v{} was sunset on {}",
+                            self.version,
+                            sunset.format("%Y-%m-%d")
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let mut res = next.run(req).await;
+        if accept_versioned {
+            res.append_header("Vary", "Accept");
+        }
+        if let Some(deprecated) = &deprecated {
+            let policy = &deprecated.policy;
+            res.insert_header("Deprecation", "true");
+            if let Some(sunset) = &policy.sunset {
+                res.insert_header("Sunset", http_date(sunset));
+            }
+            if let Some(link) = &policy.link {
+                res.append_header("Link", format!("<{}>; rel=\"sunset\"", link));
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for VersionPolicyMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.handle(req, next).await
+    }
+}
+
+/// A deprecated API version's policy and usage, reported under `"apiVersions"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionStats {
+    deprecated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sunset: Option<DateTime<Utc>>,
+    reject_after_sunset: bool,
+    requests: u64,
+    rejected: u64,
+}
+
+/// The policy and usage of every deprecated API version, if there are any.
+pub(crate) fn version_stats() -> Option<BTreeMap<String, VersionStats>> {
+    let deprecations = DEPRECATIONS.read().unwrap_or_else(PoisonError::into_inner);
+    if deprecations.is_empty() {
+        return None;
+    }
+
+    let stats = deprecations
+        .iter()
+        .map(|(version, deprecated)| {
+            let stats = VersionStats {
+                deprecated: true,
+                sunset: deprecated.policy.sunset,
+                reject_after_sunset: deprecated.policy.reject_after_sunset,
+                requests: deprecated.requests.load(Ordering::Relaxed),
+                rejected: deprecated.rejected.load(Ordering::Relaxed),
+            };
+            (format!("v{}", version), stats)
+        })
+        .collect();
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+
+    #[async_std::test]
+    async fn deprecates_and_sunsets_versions() -> tide::Result<()> {
+        deprecate(
+            VersionDeprecation::new(7)
+                .sunset("2049-06-30T00:00:00Z".parse()?)
+                .link("https://docs.example.com/v8"),
+        );
+        deprecate(
+            VersionDeprecation::new(8)
+                .sunset(Utc::now() - Duration::days(1))
+                .reject_after_sunset(),
+        );
+
+        let mut server = tide::new();
+        for version in 7..=9 {
+            server
+                .at(&format!("/api/v{}", version))
+                .with(VersionPolicyMiddleware::new(version))
+                .get(|_| async { Ok("ok") });
+        }
+
+        let get = |version: u32| {
+            #[allow(clippy::unwrap_used)]
+            let req = http::Request::new(
+                Method::Get,
+                Url::parse(&format!("http://example.com/api/v{}", version)).unwrap(),
+            );
+            let server = server.clone();
+            async move { server.respond::<_, http::Response>(req).await }
+        };
+
+        let res = get(7).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["Deprecation"], "true");
+        assert_eq!(res["Sunset"], "Wed, 30 Jun 2049 00:00:00 GMT");
+        assert_eq!(res["Link"], "<https://docs.example.com/v8>; rel=\"sunset\"");
+
+        let res = get(8).await?;
+        assert_eq!(res.status(), 410);
+
+        let res = get(9).await?;
+        assert_eq!(res.status(), 200);
+        assert!(res.header("Deprecation").is_none());
+
+        let stats = serde_json::to_value(version_stats())?;
+        assert_eq!(stats["v7"]["requests"], 1);
+        assert_eq!(stats["v8"]["rejected"], 1);
+        assert!(stats.get("v9").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+//! Warmers: async functions run on a cron schedule, such as to pre-populate caches or refresh tokens.
+//!
+//! A [`Warmer`][] is given a [`WarmerContext`][] on each run, with the application's `Arc<State>`,
+//! [registered upstream clients][WarmerContext::client], and a correlation id for the run.
+//!
+//! Schedules are cron expressions with a leading seconds field, e.g. `0 */5 * * * *` for every five minutes,
+//! in UTC. Warmers can also [run at startup][Warmer::run_at_startup], before their first scheduled time.
+//!
+//! A run which is still going when the next is due is not overlapped: the next run is skipped, and logged at `WARN`.
+//! Failed runs are logged at `ERROR`, with the warmer's name and the run's `correlation_id`.
+//! Every warmer's runs, failures, and skipped runs are reported under `"warmers"` in `/monitor/status`.
+//!
+//! Warmers stop once the server is [shut down][crate::setup::shutdown_handle].
+//!
+//! Every instance of a service runs its warmers, unless, with the `"postgres"` feature, they are run only by the
+//! [leader][crate::leader] with [`leader_only`][Warmer::leader_only].
+//!
+//! ## Example:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use async_std::sync::RwLock;
+//! use preroll::warmers::{self, Warmer, WarmerContext};
+//!
+//! # #[allow(dead_code)]
+//! struct AppState {
+//!     prices: RwLock<String>,
+//! }
+//!
+//! # #[allow(dead_code)]
+//! async fn custom_setup(
+//!     server: tide::Server<Arc<AppState>>,
+//! ) -> preroll::SetupResult<tide::Server<Arc<AppState>>> {
+//!     let warmer = Warmer::new(
+//!         "prices",
+//!         "0 */5 * * * *",
+//!         |ctx: WarmerContext<AppState>| async move {
+//!             let prices = ctx
+//!                 .client("pricing")?
+//!                 .get("prices")
+//!                 .header("X-Correlation-Id", ctx.correlation_id())
+//!                 .recv_string()
+//!                 .await
+//!                 .map_err(|error| error.into_inner())?;
+//!             *ctx.state().prices.write().await = prices;
+//!             Ok(())
+//!         },
+//!     )?
+//!     .run_at_startup();
+//!
+//!     warmers::start(server.state().clone(), warmer);
+//!     Ok(server)
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Result};
+use cron::Schedule;
+use futures_lite::future;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::builtins::shutdown::shutdown_handle;
+use crate::logging::log_kv;
+#[cfg(feature = "test")]
+use crate::middleware::extension_types::next_test_correlation_id;
+use crate::middleware::extension_types::CorrelationId;
+
+static WARMERS: Lazy<RwLock<BTreeMap<&'static str, Arc<WarmerCounters>>>> =
+    Lazy::new(Default::default);
+
+type WarmerFn<State> = dyn Fn(WarmerContext<State>) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>
+    + Send
+    + Sync;
+
+/// An async function run on a cron schedule. See [`preroll::warmers`][crate::warmers].
+pub struct Warmer<State> {
+    name: &'static str,
+    schedule: Schedule,
+    run_at_startup: bool,
+    #[cfg(feature = "postgres")]
+    leadership: Option<crate::leader::Leadership>,
+    warm: Arc<WarmerFn<State>>,
+}
+
+impl<State> Debug for Warmer<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Warmer")
+            .field("name", &self.name)
+            .field("schedule", &self.schedule.to_string())
+            .field("run_at_startup", &self.run_at_startup)
+            .finish()
+    }
+}
+
+impl<State: Send + Sync + 'static> Warmer<State> {
+    /// Create a new `Warmer`, which runs `warm` on the cron `schedule`, e.g. `0 */5 * * * *`.
+    ///
+    /// Errors if the schedule is not a valid cron expression.
+    pub fn new<F, Fut>(name: &'static str, schedule: &str, warm: F) -> Result<Self>
+    where
+        F: Fn(WarmerContext<State>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let schedule = Schedule::from_str(schedule)
+            .map_err(|error| eyre!("Invalid schedule for warmer {}: {}", name, error))?;
+
+        Ok(Self {
+            name,
+            schedule,
+            run_at_startup: false,
+            #[cfg(feature = "postgres")]
+            leadership: None,
+            warm: Arc::new(move |ctx| Box::pin(warm(ctx))),
+        })
+    }
+
+    /// Also run once when started, rather than waiting for the first scheduled time.
+    #[must_use]
+    pub fn run_at_startup(mut self) -> Self {
+        self.run_at_startup = true;
+        self
+    }
+
+    /// Only run while this instance is the [leader][crate::leader] of the election of the warmer's name,
+    /// so that only one instance of the service runs it.
+    #[cfg(feature = "postgres")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "postgres")))]
+    #[must_use]
+    pub fn leader_only(mut self) -> Self {
+        self.leadership = Some(crate::leader::elect(self.name));
+        self
+    }
+}
+
+/// What a warmer is given on each run.
+pub struct WarmerContext<State> {
+    state: Arc<State>,
+    name: &'static str,
+    correlation_id: CorrelationId,
+}
+
+impl<State> Debug for WarmerContext<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WarmerContext")
+            .field("name", &self.name)
+            .field("correlation_id", &self.correlation_id)
+            .finish()
+    }
+}
+
+impl<State> WarmerContext<State> {
+    /// The application's state.
+    pub fn state(&self) -> &Arc<State> {
+        &self.state
+    }
+
+    /// The warmer's name.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The correlation id of this run, which is logged if it fails.
+    pub fn correlation_id(&self) -> &str {
+        self.correlation_id.as_str()
+    }
+
+    /// A client for the upstream `name`, from the [client registry][crate::client::registry].
+    pub fn client(&self, name: &str) -> anyhow::Result<surf::Client> {
+        crate::client::registry::client(name).map_err(|error| error.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct WarmerCounters {
+    schedule: String,
+    running: AtomicBool,
+    runs: AtomicU64,
+    failures: AtomicU64,
+    skipped: AtomicU64,
+    last_success: Mutex<Option<DateTime<Utc>>>,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Start running `warmer` on its schedule, with the application's `state`, until the server is shut down.
+///
+/// A warmer started with the same name as another replaces its stats in `/monitor/status`, but does not stop it.
+pub fn start<State: Send + Sync + 'static>(state: Arc<State>, warmer: Warmer<State>) {
+    let counters = Arc::new(WarmerCounters {
+        schedule: warmer.schedule.to_string(),
+        ..WarmerCounters::default()
+    });
+    let previous = WARMERS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(warmer.name, counters.clone());
+    if previous.is_some() {
+        log::warn!("A warmer named {} was already started.", warmer.name);
+    }
+
+    task::spawn(async move {
+        let shutdown = shutdown_handle();
+
+        if warmer.run_at_startup {
+            run(&warmer, &state, &counters);
+        }
+
+        for next in warmer.schedule.upcoming(Utc) {
+            let wait = (next - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            let stopped = future::or(
+                async {
+                    task::sleep(wait).await;
+                    false
+                },
+                async {
+                    shutdown.stopped().await;
+                    true
+                },
+            )
+            .await;
+            if stopped {
+                break;
+            }
+
+            run(&warmer, &state, &counters);
+        }
+    });
+}
+
+/// Run the warmer on its own task, unless it is still running, or it is leader-only and this instance is not the leader.
+fn run<State: Send + Sync + 'static>(
+    warmer: &Warmer<State>,
+    state: &Arc<State>,
+    counters: &Arc<WarmerCounters>,
+) {
+    let name = warmer.name;
+    #[cfg(feature = "postgres")]
+    if let Some(leadership) = &warmer.leadership {
+        if !leadership.is_leader() {
+            log::trace!("Not the leader of {}, skipping a run", name);
+            return;
+        }
+    }
+
+    if counters.running.swap(true, Ordering::SeqCst) {
+        counters.skipped.fetch_add(1, Ordering::Relaxed);
+        log_kv!(warn, { warmer: name }, "Warmer {} is still running, skipping a run", name);
+        return;
+    }
+
+    #[cfg(not(feature = "test"))]
+    let correlation_id = CorrelationId::new();
+    #[cfg(feature = "test")]
+    let correlation_id: CorrelationId = next_test_correlation_id().into();
+
+    let warm = warmer.warm.clone();
+    let state = state.clone();
+    let counters = counters.clone();
+    task::spawn(async move {
+        let correlation_id_string = correlation_id.to_string();
+        let start = Instant::now();
+        let result = warm(WarmerContext {
+            state,
+            name,
+            correlation_id,
+        })
+        .await;
+        let duration = start.elapsed().as_millis() as u64;
+
+        counters.runs.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(()) => {
+                *counters
+                    .last_success
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(Utc::now());
+                log_kv!(
+                    debug,
+                    { warmer: name, correlation_id: correlation_id_string, duration: duration },
+                    "Warmer {} succeeded", name
+                );
+            }
+            Err(error) => {
+                counters.failures.fetch_add(1, Ordering::Relaxed);
+                *counters
+                    .last_error
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(error.to_string());
+                log_kv!(
+                    error,
+                    { warmer: name, correlation_id: correlation_id_string, duration: duration },
+                    "Warmer {} failed: {:?}", name, error
+                );
+            }
+        }
+        counters.running.store(false, Ordering::SeqCst);
+    });
+}
+
+/// A warmer's statistics, reported under `"warmers"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmerStats {
+    schedule: String,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    /// Runs skipped because the previous run was still going.
+    skipped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// The statistics of every started warmer, if there are any.
+pub(crate) fn warmer_stats() -> Option<BTreeMap<&'static str, WarmerStats>> {
+    let warmers = WARMERS.read().unwrap_or_else(PoisonError::into_inner);
+    if warmers.is_empty() {
+        return None;
+    }
+
+    let stats = warmers
+        .iter()
+        .map(|(name, counters)| {
+            let stats = WarmerStats {
+                schedule: counters.schedule.clone(),
+                running: counters.running.load(Ordering::Relaxed),
+                runs: counters.runs.load(Ordering::Relaxed),
+                failures: counters.failures.load(Ordering::Relaxed),
+                skipped: counters.skipped.load(Ordering::Relaxed),
+                last_success: *counters
+                    .last_success
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+                last_error: counters
+                    .last_error
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone(),
+            };
+            (*name, stats)
+        })
+        .collect();
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let warmer = Warmer::new(
+            "invalid",
+            "every five minutes",
+            |_: WarmerContext<()>| async { Ok(()) },
+        );
+        assert!(warmer.is_err());
+    }
+
+    #[async_std::test]
+    async fn runs_on_schedule_without_overlapping() -> anyhow::Result<()> {
+        let state = Arc::new(AtomicU64::new(0));
+
+        let slow = Warmer::new(
+            "slow",
+            "* * * * * *",
+            |ctx: WarmerContext<AtomicU64>| async move {
+                ctx.state().fetch_add(1, Ordering::SeqCst);
+                task::sleep(Duration::from_millis(2500)).await;
+                Ok(())
+            },
+        )
+        .map_err(|error| anyhow::anyhow!(error))?
+        .run_at_startup();
+        start(state.clone(), slow);
+
+        let failing = Warmer::new(
+            "failing",
+            "* * * * * *",
+            |_: WarmerContext<AtomicU64>| async { Err(anyhow::anyhow!("Upstream unavailable")) },
+        )
+        .map_err(|error| anyhow::anyhow!(error))?;
+        start(state.clone(), failing);
+
+        task::sleep(Duration::from_millis(2200)).await;
+
+        // Started at startup, with the runs due meanwhile skipped.
+        assert_eq!(state.load(Ordering::SeqCst), 1);
+
+        let stats = serde_json::to_value(warmer_stats())?;
+        assert_eq!(stats["slow"]["running"], true);
+        assert!(stats["slow"]["skipped"].as_u64() >= Some(1));
+        assert!(stats["failing"]["failures"].as_u64() >= Some(1));
+        assert_eq!(stats["failing"]["lastError"], "Upstream unavailable");
+        Ok(())
+    }
+}
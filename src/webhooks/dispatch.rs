@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tide::http::headers::HeaderName;
+use tide::http::mime;
+use uuid::Uuid;
+
+#[cfg(feature = "postgres")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "postgres")]
+use sqlx::postgres::PgPool;
+
+use super::{sign, SignatureFormat};
+use crate::builtins::relay;
+use crate::config;
+use crate::logging::log_kv;
+
+static ENDPOINTS: Lazy<RwLock<BTreeMap<String, Endpoint>>> = Lazy::new(Default::default);
+static QUEUE: Lazy<Mutex<Vec<Delivery>>> = Lazy::new(Default::default);
+#[cfg(feature = "postgres")]
+static POOL: OnceCell<PgPool> = OnceCell::new();
+
+static RELAY_STARTED: AtomicBool = AtomicBool::new(false);
+static DELIVERED: AtomicU64 = AtomicU64::new(0);
+static RETRIED: AtomicU64 = AtomicU64::new(0);
+static DEAD_LETTERED: AtomicU64 = AtomicU64::new(0);
+
+/// The most persisted deliveries attempted per relay transaction.
+#[cfg(feature = "postgres")]
+const RELAY_BATCH_SIZE: i64 = 50;
+
+/// A subscriber's url, which is sent events signed with its secret.
+#[derive(Clone)]
+pub struct Endpoint {
+    id: String,
+    url: String,
+    secret: Vec<u8>,
+    topics: Vec<String>,
+    header_name: HeaderName,
+}
+
+impl Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("id", &self.id)
+            .field("url", &self.url)
+            .field("topics", &self.topics)
+            .field("header_name", &self.header_name)
+            .finish()
+    }
+}
+
+impl Endpoint {
+    /// Create a new `Endpoint` for every topic, signing in an `X-Webhook-Signature` header with `secret`,
+    /// as a default [`WebhookMiddleware`][super::WebhookMiddleware] verifies.
+    #[must_use]
+    pub fn new(id: impl Into<String>, url: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id: id.into(),
+            url: url.into(),
+            secret: secret.into(),
+            topics: Vec::new(),
+            header_name: HeaderName::from("X-Webhook-Signature"),
+        }
+    }
+
+    /// Only send events of these topics.
+    #[must_use]
+    pub fn topics<I: IntoIterator<Item = S>, S: Into<String>>(mut self, topics: I) -> Self {
+        self.topics = topics.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The request header of the signature.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>) -> Self {
+        self.header_name = name.into();
+        self
+    }
+
+    fn accepts(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|accepted| accepted == topic)
+    }
+}
+
+/// The JSON body of each webhook request.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookEvent {
+    /// Unique per event, and the same across retries and endpoints, so that receivers can deduplicate.
+    pub id: Uuid,
+    pub topic: String,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An event waiting to be delivered to an endpoint, in memory.
+#[derive(Clone, Debug)]
+struct Delivery {
+    endpoint_id: String,
+    event: WebhookEvent,
+    attempts: u32,
+    due: Instant,
+}
+
+/// Register an endpoint, replacing any with the same id.
+pub fn register_endpoint(endpoint: Endpoint) {
+    ENDPOINTS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(endpoint.id.clone(), endpoint);
+}
+
+/// Remove the endpoint with `id`, returning whether there was one. Its pending deliveries are dead-lettered.
+pub fn remove_endpoint(id: &str) -> bool {
+    ENDPOINTS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(id)
+        .is_some()
+}
+
+/// Send an event of `topic` with `payload` to every endpoint which accepts `topic`, in the background.
+///
+/// Returns the event's id, as sent in its body and `Idempotency-Key` header.
+pub async fn dispatch(topic: impl Into<String>, payload: &impl Serialize) -> anyhow::Result<Uuid> {
+    let event = WebhookEvent {
+        id: Uuid::new_v4(),
+        topic: topic.into(),
+        payload: serde_json::to_value(payload)?,
+        created_at: Utc::now(),
+    };
+
+    let endpoint_ids: Vec<String> = ENDPOINTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .values()
+        .filter(|endpoint| endpoint.accepts(&event.topic))
+        .map(|endpoint| endpoint.id.clone())
+        .collect();
+
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = POOL.get() {
+        for endpoint_id in &endpoint_ids {
+            sqlx::query(
+                "INSERT INTO webhook_deliveries (id, endpoint_id, event) VALUES ($1, $2, $3)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(endpoint_id)
+            .bind(sqlx::types::Json(&event))
+            .execute(pool)
+            .await?;
+        }
+        start_relay();
+        return Ok(event.id);
+    }
+
+    let now = Instant::now();
+    QUEUE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .extend(endpoint_ids.into_iter().map(|endpoint_id| Delivery {
+            endpoint_id,
+            event: event.clone(),
+            attempts: 0,
+            due: now,
+        }));
+    start_relay();
+
+    Ok(event.id)
+}
+
+/// With `WEBHOOK_PERSIST=true`, create the deliveries table, persist deliveries in it, and resume pending deliveries.
+#[cfg(feature = "postgres")]
+pub(crate) async fn setup(pool: &PgPool) -> color_eyre::eyre::Result<()> {
+    let persist = config::var("WEBHOOK_PERSIST")
+        .map(|v| v.parse())
+        .unwrap_or(Ok(false))?;
+    if !persist {
+        return Ok(());
+    }
+
+    crate::builtins::schema::migrate(pool, crate::builtins::schema::WEBHOOK_DELIVERIES).await?;
+    POOL.set(pool.clone()).ok();
+    start_relay();
+
+    Ok(())
+}
+
+/// Start delivering due deliveries every `WEBHOOK_POLL_MS`, once.
+fn start_relay() {
+    if RELAY_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let interval = Duration::from_millis(
+        config::var("WEBHOOK_POLL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1000),
+    );
+    let timeout = Duration::from_millis(
+        config::var("WEBHOOK_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10_000),
+    );
+    let client: surf::Client = match surf::Config::new().set_timeout(Some(timeout)).try_into() {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Failed to create the webhook client: {}", error);
+            return;
+        }
+    };
+
+    relay::spawn("Webhook relay", interval, move || {
+        let client = client.clone();
+        async move {
+            #[cfg(feature = "postgres")]
+            if let Some(pool) = POOL.get() {
+                // A full batch means there may be more ready to deliver.
+                return relay_persisted(pool, &client).await;
+            }
+
+            relay_in_memory(&client).await;
+            Ok(false)
+        }
+    });
+}
+
+/// Attempt every due in-memory delivery.
+async fn relay_in_memory(client: &surf::Client) {
+    let now = Instant::now();
+    let due: Vec<Delivery> = {
+        let mut queue = QUEUE.lock().unwrap_or_else(PoisonError::into_inner);
+        let (due, pending) = queue.drain(..).partition(|delivery| delivery.due <= now);
+        *queue = pending;
+        due
+    };
+
+    for mut delivery in due {
+        delivery.attempts += 1;
+        if let Err(error) = attempt(client, &delivery.endpoint_id, &delivery.event).await {
+            if let Some(retry_in) = failed(&delivery, &error) {
+                delivery.due = Instant::now() + retry_in;
+                QUEUE
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(delivery);
+            }
+        }
+    }
+}
+
+/// Attempt one batch of due persisted deliveries, returning whether it was a full batch.
+#[cfg(feature = "postgres")]
+async fn relay_persisted(pool: &PgPool, client: &surf::Client) -> color_eyre::eyre::Result<bool> {
+    use sqlx::types::Json;
+    use sqlx::Connection;
+
+    let mut conn = crate::builtins::pool_stats::acquire(pool).await?;
+    let mut tx = conn.begin().await?;
+
+    let due: Vec<(Uuid, String, Json<WebhookEvent>, i32)> = sqlx::query_as(
+        "SELECT id, endpoint_id, event, attempts FROM webhook_deliveries \
+            WHERE delivered_at IS NULL AND dead_at IS NULL AND next_attempt_at <= now() \
+            ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(RELAY_BATCH_SIZE)
+    .fetch_all(&mut tx)
+    .await?;
+    let full = due.len() as i64 == RELAY_BATCH_SIZE;
+
+    for (id, endpoint_id, Json(event), attempts) in due {
+        let delivery = Delivery {
+            endpoint_id,
+            event,
+            attempts: u32::try_from(attempts).unwrap_or(0) + 1,
+            due: Instant::now(),
+        };
+
+        match attempt(client, &delivery.endpoint_id, &delivery.event).await {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE webhook_deliveries SET delivered_at = now(), attempts = attempts + 1 WHERE id = $1",
+                )
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+            Err(error) => {
+                let update = match failed(&delivery, &error) {
+                    Some(_) => {
+                        "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = $2, \
+                        next_attempt_at = now() + make_interval(secs => $3) WHERE id = $1"
+                    }
+                    None => {
+                        "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = $2, \
+                        dead_at = now() WHERE id = $1"
+                    }
+                };
+                sqlx::query(update)
+                    .bind(id)
+                    .bind(format!("{:?}", error))
+                    .bind(relay::backoff(delivery.attempts, relay::MAX_BACKOFF).as_secs_f64())
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(full)
+}
+
+/// Send `event` to the endpoint with `endpoint_id`, signed now.
+async fn attempt(
+    client: &surf::Client,
+    endpoint_id: &str,
+    event: &WebhookEvent,
+) -> anyhow::Result<()> {
+    let endpoint = ENDPOINTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(endpoint_id)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No webhook endpoint {}", endpoint_id))?;
+
+    let body = serde_json::to_vec(event)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let signature = sign(SignatureFormat::Timestamped, &endpoint.secret, &body, now);
+
+    let res = client
+        .post(&endpoint.url)
+        .header(endpoint.header_name.clone(), signature)
+        .header("Idempotency-Key", event.id.to_string())
+        .header("X-Webhook-Topic", event.topic.as_str())
+        .body(body)
+        .content_type(mime::JSON)
+        .await
+        .map_err(|error| anyhow::anyhow!(error))?;
+
+    if res.status().is_success() {
+        DELIVERED.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Webhook endpoint responded with status {}",
+            res.status()
+        ))
+    }
+}
+
+/// Log a failed attempt, and return how long to wait before retrying it, or `None` once it is dead-lettered.
+fn failed(delivery: &Delivery, error: &anyhow::Error) -> Option<Duration> {
+    let max_attempts: u32 = config::var("WEBHOOK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+
+    let registered = ENDPOINTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains_key(&delivery.endpoint_id);
+
+    if delivery.attempts >= max_attempts || !registered {
+        DEAD_LETTERED.fetch_add(1, Ordering::Relaxed);
+        log_kv!(
+            error,
+            {
+                event_id: delivery.event.id.to_string(),
+                topic: delivery.event.topic,
+                endpoint_id: delivery.endpoint_id,
+                attempts: delivery.attempts,
+                payload: delivery.event.payload.to_string(),
+            },
+            "Webhook delivery dead-lettered: {:?}", error
+        );
+        return None;
+    }
+
+    let retry_in = relay::backoff(delivery.attempts, relay::MAX_BACKOFF);
+    RETRIED.fetch_add(1, Ordering::Relaxed);
+    log_kv!(
+        warn,
+        {
+            event_id: delivery.event.id.to_string(),
+            topic: delivery.event.topic,
+            endpoint_id: delivery.endpoint_id,
+            attempts: delivery.attempts,
+        },
+        "Webhook delivery failed, retrying in {:?}: {:?}", retry_in, error
+    );
+    Some(retry_in)
+}
+
+/// Webhook deliveries, reported under `"webhooks"` in `/monitor/status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookStats {
+    endpoints: usize,
+    /// Deliveries waiting in memory, if they are not persisted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<usize>,
+    delivered: u64,
+    retried: u64,
+    dead_lettered: u64,
+}
+
+/// The webhook stats, if any endpoints are registered, or any events have been dispatched.
+pub(crate) fn webhook_stats() -> Option<WebhookStats> {
+    let endpoints = ENDPOINTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .len();
+    if endpoints == 0 && !RELAY_STARTED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    #[cfg(feature = "postgres")]
+    let persisted = POOL.get().is_some();
+    #[cfg(not(feature = "postgres"))]
+    let persisted = false;
+
+    Some(WebhookStats {
+        endpoints,
+        pending: (!persisted).then(|| QUEUE.lock().unwrap_or_else(PoisonError::into_inner).len()),
+        delivered: DELIVERED.load(Ordering::Relaxed),
+        retried: RETRIED.load(Ordering::Relaxed),
+        dead_lettered: DEAD_LETTERED.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use async_std::task;
+    use tide::{Request, StatusCode};
+
+    use super::*;
+    use crate::test_utils::{self, TestResult};
+    use crate::webhooks::WebhookMiddleware;
+
+    #[async_std::test]
+    async fn delivers_signed_events_with_retries() -> TestResult<()> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (addr, _handle) = test_utils::spawn_server((), {
+            let received = received.clone();
+            move |mut server: tide::Route<'_, Arc<()>>| {
+                let received = received.clone();
+                let calls = calls.clone();
+                server
+                    .at("hooks")
+                    .with(WebhookMiddleware::new("hook-secret"))
+                    .post(move |mut req: Request<Arc<()>>| {
+                        let received = received.clone();
+                        let calls = calls.clone();
+                        async move {
+                            // Fail the first attempt, so that it is retried.
+                            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                                return Ok(tide::Response::new(StatusCode::ServiceUnavailable));
+                            }
+                            let event: WebhookEvent = req.body_json().await?;
+                            received
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .push(event);
+                            Ok(tide::Response::new(StatusCode::Ok))
+                        }
+                    });
+            }
+        })
+        .await?;
+
+        register_endpoint(
+            Endpoint::new(
+                "orders",
+                format!("http://{}/api/v1/hooks", addr),
+                "hook-secret",
+            )
+            .topics(["order.created"]),
+        );
+        let id = dispatch("order.created", &serde_json::json!({ "orderId": 1 })).await?;
+        dispatch("order.deleted", &serde_json::json!({ "orderId": 1 })).await?;
+
+        for _ in 0..50 {
+            if !received
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_empty()
+            {
+                break;
+            }
+            task::sleep(Duration::from_millis(100)).await;
+        }
+
+        let received = received.lock().unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].id, id);
+        assert_eq!(received[0].payload["orderId"], 1);
+
+        let stats = serde_json::to_value(webhook_stats())?;
+        assert_eq!(stats["retried"], 1);
+        assert_eq!(stats["delivered"], 1);
+        Ok(())
+    }
+}
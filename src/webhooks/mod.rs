@@ -0,0 +1,219 @@
+//! Sending webhooks with retries, and receiving webhooks with HMAC signature verification by [`WebhookMiddleware`][].
+//!
+//! ## Sending
+//!
+//! Subscribers are [registered][register_endpoint] as [`Endpoint`][]s, each with a url, a secret, and optionally the topics
+//! it is sent. [`dispatch`][] sends an event to every endpoint which accepts its topic, in the background,
+//! as a JSON [`WebhookEvent`][] with an `Idempotency-Key` header of the event's id, and a timestamped signature
+//! which a default `WebhookMiddleware` with the same secret verifies.
+//!
+//! Failed deliveries are retried after one second, doubling after each attempt up to five minutes,
+//! until `WEBHOOK_MAX_ATTEMPTS` (default `10`) attempts have failed. Then, or if the endpoint has been removed,
+//! the delivery is dead-lettered: logged at `ERROR` with its payload. Deliveries are attempted every `WEBHOOK_POLL_MS`
+//! (default `1000`), with a `WEBHOOK_TIMEOUT_MS` (default `10000`) timeout, and are counted under `"webhooks"`
+//! in `/monitor/status`.
+//!
+//! Pending deliveries are kept in memory, and lost if the service stops. With the `"postgres"` feature and
+//! `WEBHOOK_PERSIST=true`, they are instead stored in a `webhook_deliveries` table, created at startup, which every
+//! instance delivers from, and in which dead-lettered deliveries are kept with their last error.
+//!
+//! ```no_run
+//! use preroll::webhooks::{self, Endpoint};
+//! use serde_json::json;
+//!
+//! # #[allow(dead_code)]
+//! async fn order_created() -> anyhow::Result<()> {
+//!     webhooks::register_endpoint(
+//!         Endpoint::new("billing", "https://billing.internal/hooks", "a-shared-secret")
+//!             .topics(["order.created"]),
+//!     );
+//!
+//!     webhooks::dispatch("order.created", &json!({ "orderId": 1 })).await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Receiving
+//!
+//! Requests to routes with a `WebhookMiddleware` must carry a signature of their body, made with a shared secret,
+//! or are rejected with a `401 Unauthorized`. Signatures are compared in constant time. Bodies larger than
+//! [`max_bytes`][WebhookMiddleware::max_bytes] (default 1 MiB) are rejected with a `413` before they are verified.
+//!
+//! Presets are provided for [Stripe][WebhookMiddleware::stripe] and [GitHub][WebhookMiddleware::github] webhooks.
+//! Other senders can be configured with a [header][WebhookMiddleware::header] and a [`SignatureFormat`][].
+//!
+//! Timestamped signatures protect against replays: requests signed longer ago, or further in the future,
+//! than the [tolerance][WebhookMiddleware::tolerance] (default five minutes) are rejected.
+//!
+//! Tests can send signed payloads with [`TestClient::post_webhook`][crate::test_utils::TestClient::post_webhook],
+//! or sign them with [`WebhookMiddleware::sign_at`][], e.g. to test replays.
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use preroll::webhooks::WebhookMiddleware;
+//! use tide::{Request, Route};
+//!
+//! # #[allow(dead_code)]
+//! fn setup_routes(mut server: Route<'_, Arc<()>>) {
+//!     let secret = std::env::var("STRIPE_WEBHOOK_SECRET").unwrap_or_default();
+//!
+//!     server
+//!         .at("webhooks/stripe")
+//!         .with(WebhookMiddleware::stripe(secret))
+//!         .post(|mut req: Request<Arc<()>>| async move {
+//!             let event: serde_json::Value = req.body_json().await?;
+//!             Ok(format!("Received {}", event["type"]))
+//!         });
+//! }
+//! ```
+
+use std::time::Duration;
+
+mod dispatch;
+
+#[cfg(feature = "postgres")]
+pub(crate) use dispatch::setup;
+pub(crate) use dispatch::webhook_stats;
+pub use dispatch::{
+    dispatch, register_endpoint, remove_endpoint, Endpoint, WebhookEvent, WebhookStats,
+};
+
+pub use crate::middleware::webhook::WebhookMiddleware;
+use crate::utils::{hmac_sha256_hex, verify_hmac_sha256_hex};
+
+/// How a webhook signature header is formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// `t={timestamp},v1={signature}`, as sent by Stripe, where the signature is the hex HMAC-SHA256
+    /// of `{timestamp}.{body}`, and the timestamp is in Unix seconds. Several `v1` signatures may be sent,
+    /// such as while a secret is rotated, and any one of them must match.
+    Timestamped,
+    /// `sha256={signature}`, as sent by GitHub, where the signature is the hex HMAC-SHA256 of the body.
+    /// These cannot be checked for replays.
+    Sha256,
+}
+
+/// Why a webhook signature was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    Malformed,
+    Expired,
+    Invalid,
+}
+
+impl Rejection {
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Rejection::Malformed => "Malformed webhook signature",
+            Rejection::Expired => "Webhook timestamp is outside the tolerance",
+            Rejection::Invalid => "Invalid webhook signature",
+        }
+    }
+}
+
+/// The signature header value of `body`, signed with `secret` at `timestamp`, in Unix seconds.
+pub(crate) fn sign(format: SignatureFormat, secret: &[u8], body: &[u8], timestamp: u64) -> String {
+    match format {
+        SignatureFormat::Timestamped => format!(
+            "t={},v1={}",
+            timestamp,
+            hmac_sha256_hex(secret, &timestamped(timestamp, body))
+        ),
+        SignatureFormat::Sha256 => format!("sha256={}", hmac_sha256_hex(secret, body)),
+    }
+}
+
+/// Check the signature header value `header` of `body`, at `now`, in Unix seconds.
+pub(crate) fn verify(
+    format: SignatureFormat,
+    secret: &[u8],
+    header: &str,
+    body: &[u8],
+    now: u64,
+    tolerance: Duration,
+) -> Result<(), Rejection> {
+    let (message, signatures) = match format {
+        SignatureFormat::Timestamped => {
+            let mut timestamp = None;
+            let mut signatures = Vec::new();
+            for item in header.split(',') {
+                match item.trim().split_once('=') {
+                    Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+                    Some(("v1", signature)) => signatures.push(signature),
+                    _ => {}
+                }
+            }
+
+            let timestamp = timestamp.ok_or(Rejection::Malformed)?;
+            if now.abs_diff(timestamp) > tolerance.as_secs() {
+                return Err(Rejection::Expired);
+            }
+            (timestamped(timestamp, body), signatures)
+        }
+        SignatureFormat::Sha256 => {
+            let signature = header
+                .trim()
+                .strip_prefix("sha256=")
+                .ok_or(Rejection::Malformed)?;
+            (body.to_vec(), vec![signature])
+        }
+    };
+
+    if signatures.is_empty() {
+        return Err(Rejection::Malformed);
+    }
+    // Every signature is compared, so that the time taken does not depend on which one matches.
+    let matched = signatures.iter().fold(false, |matched, signature| {
+        verify_hmac_sha256_hex(secret, &message, signature) | matched
+    });
+    if matched {
+        Ok(())
+    } else {
+        Err(Rejection::Invalid)
+    }
+}
+
+fn timestamped(timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signatures() {
+        let tolerance = Duration::from_secs(300);
+        let body = br#"{"type":"charge.succeeded"}"#;
+
+        let header = sign(SignatureFormat::Timestamped, b"secret", body, 1_000);
+        let verify_at = |header: &str, now| {
+            verify(
+                SignatureFormat::Timestamped,
+                b"secret",
+                header,
+                body,
+                now,
+                tolerance,
+            )
+        };
+        assert_eq!(verify_at(&header, 1_100), Ok(()));
+        assert_eq!(verify_at(&header, 1_400), Err(Rejection::Expired));
+        assert_eq!(
+            verify_at(&format!("t=1000,v1=00,{}", &header[7..]), 1_000),
+            Ok(())
+        );
+        assert_eq!(verify_at("t=1000,v1=00", 1_000), Err(Rejection::Invalid));
+        assert_eq!(verify_at("v1=00", 1_000), Err(Rejection::Malformed));
+
+        let header = sign(SignatureFormat::Sha256, b"secret", body, 1_000);
+        assert!(header.starts_with("sha256="));
+        let verify_sha256 =
+            |secret: &[u8]| verify(SignatureFormat::Sha256, secret, &header, body, 0, tolerance);
+        assert_eq!(verify_sha256(b"secret"), Ok(()));
+        assert_eq!(verify_sha256(b"other"), Err(Rejection::Invalid));
+    }
+}